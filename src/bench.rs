@@ -0,0 +1,241 @@
+use crate::fs::{
+    buffer::sync_all,
+    file::{
+        fileclose, fileopen, fileopen_nobarrier, fileread, fileseek, fileunlink, filewrite, mkdir,
+        OpenMode,
+    },
+    filedisk::FileDisk,
+    fs::{BlockDevice, BLOCK_SIZE},
+    log::LOG_MANAGER,
+    superblock::{init_superblock, sb},
+};
+use crate::mkfs::mkfs;
+use clap::ValueEnum;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    fs::OpenOptions,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+const BENCH_IMAGE_SIZE: u32 = 512 * 512 * 8;
+// keep the bench file within the direct and single indirect blocks
+const BENCH_FILE_SIZE: usize = 64 * 1024;
+const CHUNK_SIZE: usize = 1024;
+const RAND_READS: usize = 256;
+const META_FILES: usize = 32;
+// fixed seed, so every run issues the same requests
+const SEED: u64 = 0x14451100;
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Workload {
+    SeqWrite,
+    // sequential writes that skip the log
+    NobarrierWrite,
+    SeqRead,
+    RandRead,
+    Meta,
+    All,
+}
+
+pub struct BenchResult {
+    pub name: &'static str,
+    pub bytes: u64,
+    pub ops: u64,
+    pub elapsed: Duration,
+}
+
+impl std::fmt::Display for BenchResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let secs = self.elapsed.as_secs_f64().max(f64::EPSILON);
+        write!(
+            f,
+            "{:<16} {:>10.2} MB/s {:>12.0} ops/s ({} ops, {} bytes in {:?})",
+            self.name,
+            self.bytes as f64 / secs / (1024.0 * 1024.0),
+            self.ops as f64 / secs,
+            self.ops,
+            self.bytes,
+            self.elapsed
+        )
+    }
+}
+
+pub(crate) fn mount(path: PathBuf) -> Arc<dyn BlockDevice> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(false)
+        .open(path)
+        .unwrap();
+    let filedisk = Arc::new(FileDisk::new(file));
+    init_superblock(filedisk.clone()).unwrap();
+    LOG_MANAGER.init(&sb(), filedisk.clone());
+    filedisk
+}
+
+// format a fresh image at path and run the workload on it `rounds` times
+pub fn bench(path: PathBuf, workload: Workload, rounds: u32) -> Vec<BenchResult> {
+    mkfs(path.clone(), BENCH_IMAGE_SIZE);
+    let dev = mount(path);
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let mut results = vec![];
+    let file = PathBuf::from("/bench");
+    // the read workloads need the file in place, so always write it
+    let write = seq_write(dev.clone(), &file, rounds, false);
+    if workload == Workload::SeqWrite || workload == Workload::All {
+        results.push(write);
+    }
+    if workload == Workload::NobarrierWrite || workload == Workload::All {
+        let file = PathBuf::from("/bench-nobarrier");
+        results.push(seq_write(dev.clone(), &file, rounds, true));
+    }
+    if workload == Workload::SeqRead || workload == Workload::All {
+        results.push(seq_read(dev.clone(), &file, rounds));
+    }
+    if workload == Workload::RandRead || workload == Workload::All {
+        results.push(rand_read(dev.clone(), &file, rounds, &mut rng));
+    }
+    if workload == Workload::Meta || workload == Workload::All {
+        results.push(meta(dev.clone(), rounds));
+    }
+    results
+}
+
+fn seq_write(dev: Arc<dyn BlockDevice>, path: &Path, rounds: u32, nobarrier: bool) -> BenchResult {
+    let mut file = if nobarrier {
+        fileopen_nobarrier(dev.clone(), path, OpenMode::OCreate).unwrap()
+    } else {
+        fileopen(dev.clone(), path, OpenMode::OCreate).unwrap()
+    };
+    let buf = [0xa5u8; CHUNK_SIZE];
+    let (mut bytes, mut ops) = (0, 0);
+    let start = Instant::now();
+    for _ in 0..rounds {
+        fileseek(&mut file, 0, 0).unwrap();
+        for _ in 0..BENCH_FILE_SIZE / CHUNK_SIZE {
+            bytes += filewrite(&file, &buf).unwrap() as u64;
+            ops += 1;
+        }
+    }
+    if nobarrier {
+        // the data is only durable once the cache is flushed
+        sync_all();
+    }
+    let elapsed = start.elapsed();
+    fileclose(file);
+    BenchResult {
+        name: if nobarrier {
+            "nobarrier-write"
+        } else {
+            "seq-write"
+        },
+        bytes,
+        ops,
+        elapsed,
+    }
+}
+
+fn seq_read(dev: Arc<dyn BlockDevice>, path: &Path, rounds: u32) -> BenchResult {
+    let mut file = fileopen(dev.clone(), path, OpenMode::ORdonly).unwrap();
+    let mut buf = [0u8; CHUNK_SIZE];
+    let (mut bytes, mut ops) = (0, 0);
+    let start = Instant::now();
+    for _ in 0..rounds {
+        fileseek(&mut file, 0, 0).unwrap();
+        loop {
+            let n = fileread(&file, &mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            bytes += n as u64;
+            ops += 1;
+        }
+    }
+    let elapsed = start.elapsed();
+    fileclose(file);
+    BenchResult {
+        name: "seq-read",
+        bytes,
+        ops,
+        elapsed,
+    }
+}
+
+fn rand_read(dev: Arc<dyn BlockDevice>, path: &Path, rounds: u32, rng: &mut StdRng) -> BenchResult {
+    let mut file = fileopen(dev.clone(), path, OpenMode::ORdonly).unwrap();
+    let mut buf = [0u8; BLOCK_SIZE as usize];
+    let nblocks = BENCH_FILE_SIZE / BLOCK_SIZE as usize;
+    let (mut bytes, mut ops) = (0, 0);
+    let start = Instant::now();
+    for _ in 0..rounds as usize * RAND_READS {
+        let off = rng.gen_range(0..nblocks) * BLOCK_SIZE as usize;
+        fileseek(&mut file, off as u64, 0).unwrap();
+        bytes += fileread(&file, &mut buf).unwrap() as u64;
+        ops += 1;
+    }
+    let elapsed = start.elapsed();
+    fileclose(file);
+    BenchResult {
+        name: "rand-read",
+        bytes,
+        ops,
+        elapsed,
+    }
+}
+
+// create and unlink many small files, one op each
+fn meta(dev: Arc<dyn BlockDevice>, rounds: u32) -> BenchResult {
+    let dir = PathBuf::from("/meta");
+    mkdir(dev.clone(), &dir).unwrap();
+    let mut ops = 0;
+    let start = Instant::now();
+    for round in 0..rounds {
+        let names = (0..META_FILES)
+            .map(|i| dir.join(format!("r{}f{}", round, i)))
+            .collect::<Vec<_>>();
+        for name in names.iter() {
+            fileclose(fileopen(dev.clone(), name, OpenMode::OCreate).unwrap());
+            ops += 1;
+        }
+        for name in names.iter() {
+            fileunlink(dev.clone(), name).unwrap();
+            ops += 1;
+        }
+    }
+    let elapsed = start.elapsed();
+    BenchResult {
+        name: "meta",
+        bytes: 0,
+        ops,
+        elapsed,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fs::testutil::TestImage;
+
+    #[test]
+    fn test_bench_smoke() {
+        let image = TestImage::new("bench_smoke");
+        let results = bench(image.path.clone(), Workload::All, 1);
+        let names = results.iter().map(|r| r.name).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "seq-write",
+                "nobarrier-write",
+                "seq-read",
+                "rand-read",
+                "meta"
+            ]
+        );
+        assert_eq!(results[0].bytes, BENCH_FILE_SIZE as u64);
+        assert_eq!(results[1].bytes, BENCH_FILE_SIZE as u64);
+        assert_eq!(results[2].bytes, BENCH_FILE_SIZE as u64);
+        assert!(results.iter().all(|r| r.ops > 0));
+    }
+}
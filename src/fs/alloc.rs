@@ -0,0 +1,340 @@
+// the policies that pick free blocks in the bitmap. the one in use is chosen
+// at mount, the bitmap format is the same for all of them
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc, Mutex, RwLock,
+};
+
+use clap::ValueEnum;
+use once_cell::sync::Lazy;
+
+use super::{
+    buffer::wait_buffer_block,
+    fs::{BlockDevice, BLOCK_SIZE, BPB},
+    log::log_write,
+    superblock::sb,
+};
+
+pub trait BlockAllocator: Send + Sync {
+    // mark a run of n free blocks used and return its first block.
+    // the bits are logged, so they commit with the inode using the blocks
+    fn alloc(&self, dev: Arc<dyn BlockDevice>, n: u32) -> Option<u32>;
+    // mark the n blocks from start free
+    fn free(&self, dev: Arc<dyn BlockDevice>, start: u32, n: u32);
+}
+
+// the lowest free run, so the used blocks stay packed at the start
+pub struct FirstFit;
+
+impl BlockAllocator for FirstFit {
+    fn alloc(&self, dev: Arc<dyn BlockDevice>, n: u32) -> Option<u32> {
+        let size = sb().size;
+        let start = find_run(dev.clone(), 0, size, n)?;
+        set_bits(dev, start, n, true);
+        Some(start)
+    }
+
+    fn free(&self, dev: Arc<dyn BlockDevice>, start: u32, n: u32) {
+        set_bits(dev, start, n, false);
+    }
+}
+
+// the search starts after the last run handed out and wraps at the end,
+// so writes spread over the whole device instead of reusing the same blocks
+#[derive(Default)]
+pub struct Rotating {
+    next: AtomicU32,
+}
+
+impl BlockAllocator for Rotating {
+    fn alloc(&self, dev: Arc<dyn BlockDevice>, n: u32) -> Option<u32> {
+        let size = sb().size;
+        let next = self.next.load(Ordering::SeqCst).min(size);
+        let start =
+            find_run(dev.clone(), next, size, n).or_else(|| find_run(dev.clone(), 0, size, n))?;
+        set_bits(dev, start, n, true);
+        self.next.store((start + n) % size, Ordering::SeqCst);
+        Some(start)
+    }
+
+    fn free(&self, dev: Arc<dyn BlockDevice>, start: u32, n: u32) {
+        set_bits(dev, start, n, false);
+    }
+}
+
+// the first run of n clear bits in [from, to)
+fn find_run(dev: Arc<dyn BlockDevice>, from: u32, to: u32, n: u32) -> Option<u32> {
+    let bmapstart = sb().bmapstart;
+    let mut run = 0;
+    let mut b = from;
+    while b < to {
+        let bitmap = wait_buffer_block(bmapstart + b / BPB, dev.clone())
+            .read()
+            .unwrap()
+            .read(0, |buf: &[u8; BLOCK_SIZE as usize]| *buf);
+        // the blocks this bitmap block covers
+        let end = to.min((b / BPB + 1) * BPB);
+        while b < end {
+            let bi = b % BPB;
+            if bitmap[bi as usize / 8] & (1 << (bi % 8)) == 0 {
+                run += 1;
+                if run == n {
+                    return Some(b + 1 - n);
+                }
+            } else {
+                run = 0;
+            }
+            b += 1;
+        }
+    }
+    None
+}
+
+fn set_bits(dev: Arc<dyn BlockDevice>, start: u32, n: u32, used: bool) {
+    let bmapstart = sb().bmapstart;
+    for b in start..start + n {
+        let bi = b % BPB;
+        let blk = wait_buffer_block(bmapstart + b / BPB, dev.clone());
+        let mut guard = blk.write().unwrap();
+        guard.write(bi as usize / 8, |data: &mut u8| {
+            if used {
+                *data |= 1 << (bi % 8);
+            } else {
+                *data &= !(1 << (bi % 8));
+            }
+        });
+        log_write(guard);
+    }
+}
+
+// discard every run of free data blocks, so a sparse host file gives their
+// space back. the bitmap stays locked, so no block is taken while it is read.
+// returns the number of blocks discarded
+pub fn discard_free(dev: Arc<dyn BlockDevice>) -> u32 {
+    let _guard = ALLOC_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut discarded = 0;
+    for (start, n) in find_free_runs(dev.clone()) {
+        dev.discard_blocks(start, n);
+        discarded += n;
+    }
+    discarded
+}
+
+// the runs of free data blocks as (start, length), in block order. what
+// discard_free would drop, and where a run of n blocks could be allocated
+pub fn free_extents(dev: Arc<dyn BlockDevice>) -> Vec<(u32, u32)> {
+    let _guard = ALLOC_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    find_free_runs(dev)
+}
+
+// the caller holds ALLOC_LOCK
+fn find_free_runs(dev: Arc<dyn BlockDevice>) -> Vec<(u32, u32)> {
+    let sb = sb();
+    let (start, end) = (sb.data_start(), sb.size - 1);
+    let mut runs = vec![];
+    let mut run = None;
+    let mut b = start;
+    while b < end {
+        let bitmap = wait_buffer_block(sb.bmapstart + b / BPB, dev.clone())
+            .read()
+            .unwrap()
+            .read(0, |buf: &[u8; BLOCK_SIZE as usize]| *buf);
+        let last = end.min((b / BPB + 1) * BPB);
+        while b < last {
+            let bi = b % BPB;
+            let free = bitmap[bi as usize / 8] & (1 << (bi % 8)) == 0;
+            match (free, run) {
+                (true, None) => run = Some(b),
+                (false, Some(from)) => {
+                    runs.push((from, b - from));
+                    run = None;
+                }
+                _ => {}
+            }
+            b += 1;
+        }
+    }
+    if let Some(from) = run {
+        runs.push((from, end - from));
+    }
+    runs
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum AllocPolicy {
+    FirstFit,
+    Rotating,
+}
+
+static ALLOCATOR: Lazy<RwLock<Arc<dyn BlockAllocator>>> =
+    Lazy::new(|| RwLock::new(Arc::new(FirstFit)));
+
+// the bitmap is read and then written, one allocation at a time
+static ALLOC_LOCK: Mutex<()> = Mutex::new(());
+
+pub fn set_alloc_policy(policy: AllocPolicy) {
+    let allocator: Arc<dyn BlockAllocator> = match policy {
+        AllocPolicy::FirstFit => Arc::new(FirstFit),
+        AllocPolicy::Rotating => Arc::new(Rotating::default()),
+    };
+    *ALLOCATOR.write().unwrap() = allocator;
+}
+
+pub fn alloc_blocks(dev: Arc<dyn BlockDevice>, n: u32) -> Option<u32> {
+    let _guard = ALLOC_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let allocator = ALLOCATOR.read().unwrap().clone();
+    allocator.alloc(dev, n)
+}
+
+pub fn free_blocks(dev: Arc<dyn BlockDevice>, start: u32, n: u32) {
+    let _guard = ALLOC_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let allocator = ALLOCATOR.read().unwrap().clone();
+    allocator.free(dev, start, n)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fs::{
+        log::{log_begin, log_end},
+        testutil::TestImage,
+    };
+
+    fn used(dev: Arc<dyn BlockDevice>, b: u32) -> bool {
+        let bmapstart = sb().bmapstart;
+        let bi = b % BPB;
+        wait_buffer_block(bmapstart + b / BPB, dev)
+            .read()
+            .unwrap()
+            .read(bi as usize / 8, |byte: &u8| byte & (1 << (bi % 8)) != 0)
+    }
+
+    // what every policy has to do, whichever blocks it picks
+    fn check_runs(allocator: &dyn BlockAllocator, dev: Arc<dyn BlockDevice>) {
+        let a = allocator.alloc(dev.clone(), 1).unwrap();
+        assert!(used(dev.clone(), a));
+        let run = allocator.alloc(dev.clone(), 5).unwrap();
+        assert!((run..run + 5).all(|b| used(dev.clone(), b)));
+        assert!(!(run..run + 5).contains(&a));
+        allocator.free(dev.clone(), run, 5);
+        allocator.free(dev.clone(), a, 1);
+        assert!(!used(dev.clone(), a));
+        assert!((run..run + 5).all(|b| !used(dev.clone(), b)));
+        // more than the device holds
+        assert_eq!(allocator.alloc(dev.clone(), sb().size + 1), None);
+    }
+
+    #[test]
+    fn test_first_fit() {
+        let image = TestImage::new("alloc_first_fit");
+        let dev = image.mount();
+        log_begin();
+        check_runs(&FirstFit, dev.clone());
+        // a freed block is the first one handed out again
+        let a = FirstFit.alloc(dev.clone(), 1).unwrap();
+        let b = FirstFit.alloc(dev.clone(), 1).unwrap();
+        assert_eq!(b, a + 1);
+        FirstFit.free(dev.clone(), a, 1);
+        assert_eq!(FirstFit.alloc(dev.clone(), 1), Some(a));
+        // a run skips the hole too small for it
+        FirstFit.free(dev.clone(), a, 1);
+        let run = FirstFit.alloc(dev.clone(), 2).unwrap();
+        assert!(run > b);
+        log_end();
+    }
+
+    #[test]
+    fn test_rotating() {
+        let image = TestImage::new("alloc_rotating");
+        let dev = image.mount();
+        log_begin();
+        let rotating = Rotating::default();
+        check_runs(&rotating, dev.clone());
+        // a freed block is not reused while later ones are free
+        let a = rotating.alloc(dev.clone(), 1).unwrap();
+        rotating.free(dev.clone(), a, 1);
+        let b = rotating.alloc(dev.clone(), 1).unwrap();
+        assert_eq!(b, a + 1);
+        rotating.free(dev.clone(), b, 1);
+        // past the last free block, the search wraps to the lowest one
+        let size = sb().size;
+        let last = (0..size).rev().find(|b| !used(dev.clone(), *b)).unwrap();
+        let rotating = Rotating {
+            next: AtomicU32::new(last),
+        };
+        assert_eq!(rotating.alloc(dev.clone(), 1), Some(last));
+        let lowest = find_run(dev.clone(), 0, size, 1);
+        assert_eq!(rotating.alloc(dev.clone(), 1), lowest);
+        log_end();
+    }
+
+    #[test]
+    fn test_free_extents() {
+        let image = TestImage::new("alloc_free_extents");
+        let dev = image.mount();
+        let end = sb().size - 1;
+        // a fresh image is free from the first block the root left
+        let extents = free_extents(dev.clone());
+        assert_eq!(extents.len(), 1);
+        let (a, n) = extents[0];
+        assert_eq!(a + n, end);
+        log_begin();
+        assert_eq!(alloc_blocks(dev.clone(), 10), Some(a));
+        free_blocks(dev.clone(), a + 2, 3);
+        free_blocks(dev.clone(), a + 7, 1);
+        log_end();
+        assert_eq!(
+            free_extents(dev.clone()),
+            [(a + 2, 3), (a + 7, 1), (a + 10, end - a - 10)]
+        );
+        // a run is only as long as its blocks are free
+        log_begin();
+        assert_eq!(alloc_blocks(dev.clone(), 2), Some(a + 2));
+        log_end();
+        assert_eq!(
+            free_extents(dev.clone()),
+            [(a + 4, 1), (a + 7, 1), (a + 10, end - a - 10)]
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_discard_free() {
+        use crate::fs::{
+            buffer::sync_all,
+            file::{file_read_to_end, fileclose, fileopen, fileunlink, filewrite_all, OpenMode},
+            fs::MAXFILE,
+            fsck::fsck,
+        };
+        use std::{os::unix::fs::MetadataExt, path::PathBuf};
+
+        let image = TestImage::new("alloc_discard");
+        let dev = image.mount();
+        let allocated = || std::fs::metadata(&image.path).unwrap().blocks();
+        let path = PathBuf::from("/big");
+        let file = fileopen(dev.clone(), &path, OpenMode::OCreate).unwrap();
+        filewrite_all(&file, &vec![7u8; (MAXFILE * BLOCK_SIZE) as usize]).unwrap();
+        fileclose(file);
+        let kept = PathBuf::from("/kept");
+        let file = fileopen(dev.clone(), &kept, OpenMode::OCreate).unwrap();
+        filewrite_all(&file, &[9u8; 3000]).unwrap();
+        fileclose(file);
+        sync_all();
+        fileunlink(dev.clone(), &path).unwrap();
+        sync_all();
+        let before = allocated();
+        let len = std::fs::metadata(&image.path).unwrap().len();
+        let freed = discard_free(dev.clone());
+        assert!(freed >= MAXFILE);
+        // the host file has fewer blocks, in 512 byte units, and the same length.
+        // the host keeps the ones it shares with blocks in use
+        assert!(allocated() + (MAXFILE * BLOCK_SIZE / 512 / 2) as u64 <= before);
+        assert_eq!(std::fs::metadata(&image.path).unwrap().len(), len);
+        // a block in use is left alone, and the image still checks out
+        let dev = image.mount();
+        let file = fileopen(dev.clone(), &kept, OpenMode::ORdonly).unwrap();
+        assert_eq!(file_read_to_end(&file).unwrap(), [9u8; 3000]);
+        fileclose(file);
+        assert_eq!(fsck(image.disk()), []);
+    }
+}
@@ -0,0 +1,129 @@
+// an append-only record of the operations that change the image, a line each:
+// the time, the operation, the inode and its size after the operation.
+// it is for debugging and forensics, and has nothing to do with the log that
+// makes operations atomic: the records go straight to a host file and never
+// wait for a transaction, so a crash can leave records of operations it undid
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use log::warn;
+
+use super::clock::now;
+
+static AUDITING: AtomicBool = AtomicBool::new(false);
+static AUDIT: Mutex<Option<File>> = Mutex::new(None);
+
+// append the records to the file at path from now on, None stops auditing
+pub fn set_audit(path: Option<&Path>) -> std::io::Result<()> {
+    let file = path
+        .map(|path| OpenOptions::new().create(true).append(true).open(path))
+        .transpose()?;
+    let mut audit = AUDIT.lock().unwrap();
+    AUDITING.store(file.is_some(), Ordering::SeqCst);
+    *audit = file;
+    Ok(())
+}
+
+// an operation checks this before it does any work for its record
+pub fn auditing() -> bool {
+    AUDITING.load(Ordering::Relaxed)
+}
+
+pub fn audit(op: &str, inum: u32, size: u32) {
+    if !auditing() {
+        return;
+    }
+    let mut audit = AUDIT.lock().unwrap();
+    if let Some(file) = audit.as_mut() {
+        // taken under the lock, so the times in the file never go back
+        let time = now();
+        let record = format!(
+            "{}.{:06} {} inum={} size={}\n",
+            time / 1_000_000,
+            time % 1_000_000,
+            op,
+            inum,
+            size
+        );
+        // a record is one write, so a crash leaves whole lines
+        if let Err(e) = file.write_all(record.as_bytes()) {
+            warn!("audit: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::fs::{
+        file::{
+            fileclose, fileopen, filerename, filestat, fileunlink, filewrite_all, mkdir, OpenMode,
+        },
+        inode::resolve,
+        testutil::TestImage,
+    };
+
+    #[test]
+    fn test_audit() {
+        let image = TestImage::new("audit");
+        let dev = image.mount();
+        let path = image.path.with_extension("audit");
+        // not audited
+        mkdir(dev.clone(), &PathBuf::from("/before")).unwrap();
+        let before = resolve(dev.clone(), &PathBuf::from("/before"))
+            .unwrap()
+            .0
+            .inum;
+        set_audit(Some(&path)).unwrap();
+        mkdir(dev.clone(), &PathBuf::from("/dir")).unwrap();
+        let dir = resolve(dev.clone(), &PathBuf::from("/dir")).unwrap().0.inum;
+        let file = fileopen(dev.clone(), &PathBuf::from("/dir/a"), OpenMode::OCreate).unwrap();
+        let inum = filestat(&file).unwrap().ino;
+        filewrite_all(&file, &[1; 100]).unwrap();
+        filewrite_all(&file, &[2; 50]).unwrap();
+        fileclose(file);
+        filerename(dev.clone(), &PathBuf::from("/dir/a"), &PathBuf::from("/b")).unwrap();
+        fileunlink(dev.clone(), &PathBuf::from("/b")).unwrap();
+        set_audit(None).unwrap();
+        mkdir(dev.clone(), &PathBuf::from("/after")).unwrap();
+
+        let records = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let records = records
+            .lines()
+            .map(|line| {
+                let fields = line.split(' ').collect::<Vec<_>>();
+                assert_eq!(fields.len(), 4, "{}", line);
+                let time = fields[0].parse::<f64>().unwrap();
+                (time, fields[1..].join(" "))
+            })
+            .collect::<Vec<_>>();
+        assert!(records.windows(2).all(|w| w[0].0 <= w[1].0));
+        let ops = records.into_iter().map(|(_, op)| op).collect::<Vec<_>>();
+        let expected = [
+            format!("mkdir inum={} size=64", dir),
+            format!("create inum={} size=0", inum),
+            format!("write inum={} size=100", inum),
+            format!("write inum={} size=150", inum),
+            format!("rename inum={} size=150", inum),
+            format!("unlink inum={} size=150", inum),
+        ];
+        // tests on other images may run meanwhile, their records can come in between
+        let mut rest = ops.iter();
+        for op in expected.iter() {
+            assert!(rest.any(|o| o == op), "{} not in {:?}", op, ops);
+        }
+        assert!(!ops
+            .iter()
+            .any(|op| op.contains(&format!("inum={} ", before))));
+    }
+}
@@ -1,14 +1,84 @@
-use super::fs::{BlockDevice, BLOCK_NUM, BLOCK_SIZE, SHARD_NUM};
+use super::error::FsError;
+use super::fs::{
+    device_id, BlockDevice, BLOCK_NUM, BLOCK_SIZE, META_BLOCK_NUM, PROTECTED_PERCENT, SHARD_NUM,
+};
+use super::log::in_transaction;
+use clap::ValueEnum;
 use std::{
     collections::HashMap,
     fmt::{Debug, Formatter},
     marker::PhantomData,
+    ops::Range,
     ptr::NonNull,
-    sync::{Arc, Mutex, RwLock},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
     vec,
 };
+
+// when a modified block reaches the disk
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum CacheMode {
+    // on eviction, writeback or sync_all
+    WriteBack,
+    // before write returns, unless a transaction is open: a logged block must
+    // not reach its home before the commit, which writes it synchronously
+    WriteThrough,
+}
+
+static WRITE_THROUGH: AtomicBool = AtomicBool::new(false);
+// keep the inode blocks of the mounted image cached, see keep_resident
+static RESIDENT_INODES: AtomicBool = AtomicBool::new(false);
+
+pub fn set_cache_mode(mode: CacheMode) {
+    WRITE_THROUGH.store(mode == CacheMode::WriteThrough, Ordering::SeqCst);
+}
+
+pub fn cache_mode() -> CacheMode {
+    if WRITE_THROUGH.load(Ordering::SeqCst) {
+        CacheMode::WriteThrough
+    } else {
+        CacheMode::WriteBack
+    }
+}
+
+pub fn set_resident_inodes(on: bool) {
+    RESIDENT_INODES.store(on, Ordering::SeqCst);
+}
+
+pub fn resident_inodes() -> bool {
+    RESIDENT_INODES.load(Ordering::SeqCst)
+}
+
+// read every synced block back and compare, to catch a device that loses or
+// garbles writes. twice the i/o, so only when asked for
+static VERIFY_WRITES: AtomicBool = AtomicBool::new(false);
+
+pub fn set_verify_writes(on: bool) {
+    VERIFY_WRITES.store(on, Ordering::SeqCst);
+}
+
+// a get in a shard where every block is held sleeps and retries, each sleep
+// twice as long as the last up to BACKOFF_MAX, and warns after the retries
+const BACKOFF_START: Duration = Duration::from_micros(10);
+const BACKOFF_MAX: Duration = Duration::from_millis(10);
+// about five seconds
+pub const DEFAULT_BUFFER_RETRIES: u32 = 500;
+// a get still waiting after this many retries is logged
+const RETRY_WARN: u32 = 100;
+
+static BUFFER_RETRIES: AtomicU32 = AtomicU32::new(DEFAULT_BUFFER_RETRIES);
+
+pub fn set_buffer_retries(retries: u32) {
+    BUFFER_RETRIES.store(retries, Ordering::SeqCst);
+}
+
 pub struct BufferBlock {
     dirty: bool,
+    dirty_since: Option<Instant>, // when the block first became dirty
     block_id: u32,
     block_device: Option<Arc<dyn BlockDevice>>,
     data: Vec<u8>,
@@ -18,17 +88,31 @@ impl BufferBlock {
     pub fn new() -> Self {
         Self {
             dirty: false,
+            dirty_since: None,
             block_id: 0,
             block_device: None,
             data: vec![0; BLOCK_SIZE as usize],
         }
     }
 
-    fn init_block(block_id: u32, block_device: Arc<dyn BlockDevice>) -> Self {
-        let mut data = [0u8; BLOCK_SIZE as usize];
-        block_device.read_block(block_id, &mut data);
+    // the block as the device holds it, or as `data` when the caller is
+    // about to overwrite all of it anyway
+    fn init_block(
+        block_id: u32,
+        block_device: Arc<dyn BlockDevice>,
+        data: Option<&[u8; BLOCK_SIZE as usize]>,
+    ) -> Self {
+        let data = match data {
+            Some(data) => *data,
+            None => {
+                let mut data = [0u8; BLOCK_SIZE as usize];
+                block_device.read_block(block_id, &mut data);
+                data
+            }
+        };
         Self {
             dirty: false,
+            dirty_since: None,
             block_id,
             block_device: Some(block_device),
             data: Vec::from(data),
@@ -39,10 +123,17 @@ impl BufferBlock {
         // log sync
         if self.dirty {
             self.dirty = false;
-            self.block_device
-                .as_ref()
-                .unwrap()
-                .write_block(self.block_id, &self.data);
+            self.dirty_since = None;
+            let dev = self.block_device.as_ref().unwrap();
+            dev.write_block(self.block_id, &self.data);
+            if VERIFY_WRITES.load(Ordering::SeqCst) {
+                // sync runs on drop too, there is no caller to hand an error to
+                let mut back = [0u8; BLOCK_SIZE as usize];
+                dev.read_block(self.block_id, &mut back);
+                if back[..] != self.data[..] {
+                    panic!("block {}: read back differs from the write", self.block_id);
+                }
+            }
         }
     }
 
@@ -67,6 +158,7 @@ impl BufferBlock {
         let type_size = core::mem::size_of::<T>();
         assert!(offset + type_size <= BLOCK_SIZE as usize);
         self.dirty = true;
+        self.dirty_since.get_or_insert_with(Instant::now);
         let addr = self.offset_addr(offset);
         unsafe { &mut *(addr as *mut T) }
     }
@@ -74,6 +166,14 @@ impl BufferBlock {
     pub fn id(&self) -> u32 {
         self.block_id
     }
+
+    // the key of the block in the buffer pool
+    fn key(&self) -> (usize, u32) {
+        (
+            self.block_device.as_ref().map_or(0, device_id),
+            self.block_id,
+        )
+    }
 }
 
 impl BufferBlock {
@@ -94,9 +194,15 @@ impl BufferBlock {
             self.block_id,
             offset
         );
-        f(self.as_mut(offset))
+        let ret = f(self.as_mut(offset));
+        if cache_mode() == CacheMode::WriteThrough && !in_transaction() {
+            self.sync();
+        }
+        ret
     }
 
+    // write through whatever the cache mode, the log needs its header
+    // and blocks on disk in order
     pub fn sync_write<T, V>(&mut self, offset: usize, f: impl FnOnce(&mut T) -> V) -> V {
         info!(
             "{:?} sync write at block: {} offset: {}",
@@ -120,14 +226,43 @@ struct Node {
     data: Arc<RwLock<BufferBlock>>,
     next: Option<NonNull<Node>>,
     prev: Option<NonNull<Node>>,
+    protected: bool, // in the protected segment
+    resident: bool,  // never evicted, see keep_resident
 }
 
 type NodePtr = NonNull<Node>;
 
+impl Node {
+    // a buffer holding no block yet
+    fn empty() -> NodePtr {
+        NodePtr::new(Box::into_raw(Box::new(Node {
+            data: Arc::new(RwLock::new(BufferBlock::new())),
+            next: None,
+            prev: None,
+            protected: false,
+            resident: false,
+        })))
+        .unwrap()
+    }
+}
+
+// segmented lru:
+// a missed block enters the probationary list (head..tail),
+// and moves to the protected list (protected_head..protected_tail) on its next hit,
+// so a one-shot scan only churns the probationary list and hot metadata stays cached.
+// the protected list holds at most protected_cap blocks,
+// its lru blocks are demoted back to the probationary list
 struct LruHandle {
-    map: HashMap<u32, NodePtr>,
+    map: HashMap<(usize, u32), NodePtr>,
     head: Option<NodePtr>,
     tail: Option<NodePtr>,
+    protected_head: Option<NodePtr>,
+    protected_tail: Option<NodePtr>,
+    nprotected: usize,
+    protected_cap: usize,
+    nresident: usize,
+    resident_cap: usize, // the most resident blocks, the others must leave room for the rest
+    misses: u64,         // the blocks read from the device
     marker: PhantomData<Node>,
 }
 
@@ -135,27 +270,44 @@ unsafe impl Send for LruHandle {}
 unsafe impl Sync for LruHandle {}
 
 impl LruHandle {
-    fn new() -> Self {
-        // dummy head and dummy tail
+    fn new(protected_cap: usize) -> Self {
+        let (head, tail) = Self::dummy_list();
+        let (protected_head, protected_tail) = Self::dummy_list();
+        Self {
+            map: HashMap::new(),
+            head: Some(head),
+            tail: Some(tail),
+            protected_head: Some(protected_head),
+            protected_tail: Some(protected_tail),
+            nprotected: 0,
+            protected_cap,
+            nresident: 0,
+            resident_cap: 0,
+            misses: 0,
+            marker: PhantomData,
+        }
+    }
+
+    // dummy head and dummy tail
+    fn dummy_list() -> (NodePtr, NodePtr) {
         unsafe {
             let mut head = NonNull::new_unchecked(Box::leak(Box::new(Node {
                 data: Arc::new(RwLock::new(BufferBlock::new())),
                 next: None,
                 prev: None,
+                protected: false,
+                resident: false,
             })));
             let mut tail = NonNull::new_unchecked(Box::leak(Box::new(Node {
                 data: Arc::new(RwLock::new(BufferBlock::new())),
                 next: None,
                 prev: None,
+                protected: false,
+                resident: false,
             })));
             head.as_mut().next = Some(tail);
             tail.as_mut().prev = Some(head);
-            Self {
-                map: HashMap::new(),
-                head: Some(head),
-                tail: Some(tail),
-                marker: PhantomData,
-            }
+            (head, tail)
         }
     }
 
@@ -163,38 +315,121 @@ impl LruHandle {
         &mut self,
         block_id: &u32,
         block_device: Arc<dyn BlockDevice>,
+        data: Option<&[u8; BLOCK_SIZE as usize]>,
     ) -> Option<Arc<RwLock<BufferBlock>>> {
-        // print block_id
-        if let Some(node) = self.map.get(&block_id) {
+        let key = (device_id(&block_device), *block_id);
+        if let Some(node) = self.map.get(&key) {
             // buffer hit!
-            let node = unsafe { NonNull::new_unchecked(Box::leak(self.unlink_node(*node))) };
-            self.push_back(node);
+            let mut node = unsafe { NonNull::new_unchecked(Box::leak(self.unlink_node(*node))) };
+            unsafe {
+                if !node.as_ref().protected {
+                    node.as_mut().protected = true;
+                    self.nprotected += 1;
+                }
+            }
+            self.push_back_protected(node);
+            self.demote();
             unsafe { Some(node.as_ref().data.clone()) }
         } else {
+            // evict the lru unpinned block, probationary blocks first
+            let victim = self
+                .find_unpinned(self.head.unwrap())
+                .or_else(|| self.find_unpinned(self.protected_head.unwrap()))?;
             unsafe {
-                let mut cursor = self.head.unwrap().as_mut().next;
-                while let Some(mut node) = cursor.unwrap().as_mut().next {
-                    node = cursor.unwrap();
-                    if Arc::strong_count(&node.as_ref().data) == 1 {
-                        self.map
-                            .remove(&node.as_ref().data.read().unwrap().block_id);
-                        let _ = self.unlink_node(node);
-                        let new_node = NodePtr::new(Box::into_raw(Box::new(Node {
-                            data: Arc::new(RwLock::new(BufferBlock::init_block(
-                                *block_id,
-                                block_device,
-                            ))),
-                            next: None,
-                            prev: None,
-                        })))
-                        .unwrap();
-                        self.push_back(new_node);
-                        self.map.insert(*block_id, new_node);
-                        return Some(new_node.as_ref().data.clone());
-                    }
-                    cursor = node.as_mut().next;
+                self.map.remove(&victim.as_ref().data.read().unwrap().key());
+                if victim.as_ref().protected {
+                    self.nprotected -= 1;
+                }
+            }
+            let _ = self.unlink_node(victim);
+            if data.is_none() {
+                self.misses += 1;
+            }
+            let new_node = NodePtr::new(Box::into_raw(Box::new(Node {
+                data: Arc::new(RwLock::new(BufferBlock::init_block(
+                    *block_id,
+                    block_device,
+                    data,
+                ))),
+                next: None,
+                prev: None,
+                protected: false,
+                resident: false,
+            })))
+            .unwrap();
+            self.push_back(new_node);
+            self.map.insert(key, new_node);
+            unsafe { Some(new_node.as_ref().data.clone()) }
+        }
+    }
+
+    // the first block from head on that only the pool holds and is not resident
+    fn find_unpinned(&self, head: NodePtr) -> Option<NodePtr> {
+        unsafe {
+            let mut cursor = head.as_ref().next.unwrap();
+            // the dummy tail has no next
+            while cursor.as_ref().next.is_some() {
+                if !cursor.as_ref().resident && Arc::strong_count(&cursor.as_ref().data) == 1 {
+                    return Some(cursor);
                 }
-                None
+                cursor = cursor.as_ref().next.unwrap();
+            }
+            None
+        }
+    }
+
+    // write back a block no one else holds and free its buffer
+    fn evict(&mut self, node: NodePtr) {
+        let node = self.unlink_node(node);
+        if node.protected {
+            self.nprotected -= 1;
+        }
+        if node.resident {
+            self.nresident -= 1;
+        }
+        self.map.remove(&node.data.read().unwrap().key());
+        // the block is synced when its node is dropped
+        drop(node);
+        self.push_front(Node::empty());
+    }
+
+    // cache the block and keep it until release_resident, false when
+    // resident_cap blocks already are or no buffer is free
+    fn make_resident(&mut self, block_id: &u32, block_device: Arc<dyn BlockDevice>) -> bool {
+        if self.nresident == self.resident_cap {
+            return false;
+        }
+        let key = (device_id(&block_device), *block_id);
+        if self.get(block_id, block_device, None).is_none() {
+            return false;
+        }
+        let mut node = self.map[&key];
+        unsafe {
+            if !node.as_ref().resident {
+                node.as_mut().resident = true;
+                self.nresident += 1;
+            }
+        }
+        true
+    }
+
+    // let the resident blocks be evicted like any other
+    fn release_resident(&mut self) {
+        for node in self.map.values_mut() {
+            unsafe { node.as_mut().resident = false };
+        }
+        self.nresident = 0;
+    }
+
+    // move the lru protected blocks back to probation until the protected list fits
+    fn demote(&mut self) {
+        while self.nprotected > self.protected_cap {
+            unsafe {
+                let lru = self.protected_head.unwrap().as_ref().next.unwrap();
+                let mut node = NonNull::new_unchecked(Box::leak(self.unlink_node(lru)));
+                node.as_mut().protected = false;
+                self.nprotected -= 1;
+                self.push_back(node);
             }
         }
     }
@@ -210,12 +445,22 @@ impl LruHandle {
     }
 
     #[inline]
-    fn push_back(&self, mut node: NonNull<Node>) {
+    fn push_back(&self, node: NonNull<Node>) {
+        Self::link_before(self.tail.unwrap(), node);
+    }
+
+    #[inline]
+    fn push_back_protected(&self, node: NonNull<Node>) {
+        Self::link_before(self.protected_tail.unwrap(), node);
+    }
+
+    #[inline]
+    fn link_before(mut tail: NonNull<Node>, mut node: NonNull<Node>) {
         unsafe {
-            node.as_mut().prev = self.tail.unwrap().as_mut().prev;
-            node.as_mut().next = Some(self.tail.unwrap());
-            self.tail.unwrap().as_mut().prev.unwrap().as_mut().next = Some(node);
-            self.tail.unwrap().as_mut().prev = Some(node);
+            node.as_mut().prev = tail.as_mut().prev;
+            node.as_mut().next = Some(tail);
+            tail.as_mut().prev.unwrap().as_mut().next = Some(node);
+            tail.as_mut().prev = Some(node);
         }
     }
 
@@ -233,16 +478,18 @@ impl LruHandle {
 
 impl Drop for LruHandle {
     fn drop(&mut self) {
-        unsafe {
-            let mut cursor = self.head.unwrap().as_mut().next;
-            while let Some(mut node) = cursor.unwrap().as_mut().next {
-                node = cursor.unwrap();
-                println!(
-                    "drop block_id: {}",
-                    node.as_ref().data.read().unwrap().block_id
-                );
-                cursor = node.as_mut().next;
-                let _ = self.unlink_node(node);
+        for head in [self.head, self.protected_head] {
+            unsafe {
+                let mut node = head.unwrap().as_mut().next.unwrap();
+                // every node but the tail has a next
+                while let Some(next) = node.as_mut().next {
+                    println!(
+                        "drop block_id: {}",
+                        node.as_ref().data.read().unwrap().block_id
+                    );
+                    let _ = self.unlink_node(node);
+                    node = next;
+                }
             }
         }
     }
@@ -250,86 +497,451 @@ impl Drop for LruHandle {
 
 impl Debug for LruHandle {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
-        unsafe {
-            let mut cursor = self.head.unwrap().as_mut().next;
-            while let Some(_) = cursor.unwrap().as_mut().next {
-                let _ = write!(
-                    f,
-                    "{:?}-",
-                    cursor.unwrap().as_ref().data.read().unwrap().block_id
-                );
-                cursor = Some(cursor.unwrap().as_mut().next.unwrap());
+        for head in [self.head, self.protected_head] {
+            unsafe {
+                let mut cursor = head.unwrap().as_mut().next;
+                while cursor.unwrap().as_mut().next.is_some() {
+                    let _ = write!(
+                        f,
+                        "{:?}-",
+                        cursor.unwrap().as_ref().data.read().unwrap().block_id
+                    );
+                    cursor = Some(cursor.unwrap().as_mut().next.unwrap());
+                }
             }
+            let _ = write!(f, "|");
         }
         Ok(())
     }
 }
 
+// the shard a block is cached in
+pub type ShardRoute = Arc<dyn Fn(u32) -> usize + Send + Sync>;
+
 pub struct HandleTable {
     handles: Vec<Arc<Mutex<LruHandle>>>,
+    shard_num: u32, // the shards blocks are spread over by id, a pinned shard comes after them
+    route: RwLock<ShardRoute>,
 }
 
 impl HandleTable {
+    #[allow(unused)]
     fn new(shard_num: u32, block_num: u32) -> Self {
+        Self::with_pinned_shard(shard_num, block_num, 0)
+    }
+
+    // shard_num shards sharing block_num buffers, and a shard of pinned_num
+    // buffers on its own for the blocks pin() sends there
+    fn with_pinned_shard(shard_num: u32, block_num: u32, pinned_num: u32) -> Self {
         assert_eq!(block_num % shard_num, 0);
         let handles = (0..shard_num)
-            .map(|_| {
-                let handle = LruHandle::new();
-                (0..(block_num / shard_num)).for_each(|_| {
-                    let node = NodePtr::new(Box::into_raw(Box::new(Node {
-                        data: Arc::new(RwLock::new(BufferBlock::new())),
-                        next: None,
-                        prev: None,
-                    })))
-                    .unwrap();
-                    handle.push_front(node);
-                });
+            .map(|_| block_num / shard_num)
+            .chain((pinned_num > 0).then_some(pinned_num))
+            .map(|nblocks| {
+                let mut handle = LruHandle::new((nblocks * PROTECTED_PERCENT / 100) as usize);
+                handle.resident_cap = (nblocks / 2) as usize;
+                (0..nblocks).for_each(|_| handle.push_front(Node::empty()));
                 Arc::new(Mutex::new(handle))
             })
             .collect::<Vec<_>>();
-        Self { handles }
+        Self {
+            handles,
+            shard_num,
+            route: RwLock::new(Arc::new(move |block_id| (block_id % shard_num) as usize)),
+        }
+    }
+
+    // cache the blocks in the range in the pinned shard, where they only
+    // evict each other, and spread the rest by id
+    fn pin(&self, blocks: Range<u32>) -> Result<(), FsError> {
+        let shard_num = self.shard_num;
+        assert!(self.handles.len() > shard_num as usize, "no pinned shard");
+        self.set_route(Arc::new(move |block_id| {
+            if blocks.contains(&block_id) {
+                shard_num as usize
+            } else {
+                (block_id % shard_num) as usize
+            }
+        }))
+    }
+
+    // route blocks with `route` from now on. a cached block it sends to another
+    // shard is written back and dropped, and read again into that one. a held
+    // block cannot be moved, so it is waited for like a buffer in a full shard,
+    // and the route is left as it was if one is still held after the retries
+    fn set_route(&self, route: ShardRoute) -> Result<(), FsError> {
+        let retries = BUFFER_RETRIES.load(Ordering::SeqCst);
+        let mut backoff = BACKOFF_START;
+        for retry in 0..=retries {
+            if retry > 0 {
+                if retry == RETRY_WARN {
+                    warn!(
+                        "set_route: a moved block still held after {} retries",
+                        retry
+                    );
+                }
+                std::thread::sleep(backoff);
+                backoff = (backoff * 2).min(BACKOFF_MAX);
+            }
+            // a get finds its shard under the route lock, so none runs in between
+            let mut current = self.route.write().unwrap();
+            let mut handles = self
+                .handles
+                .iter()
+                .map(|handle| handle.lock().unwrap())
+                .collect::<Vec<_>>();
+            let to = &route;
+            let moved = handles
+                .iter()
+                .enumerate()
+                .flat_map(|(shard_id, handle)| {
+                    handle
+                        .map
+                        .iter()
+                        .filter(move |((_, block_id), _)| to(*block_id) != shard_id)
+                        .map(move |(_, node)| (shard_id, *node))
+                })
+                .collect::<Vec<_>>();
+            let held = moved
+                .iter()
+                .any(|(_, node)| Arc::strong_count(unsafe { &node.as_ref().data }) > 1);
+            if held {
+                continue;
+            }
+            for (shard_id, node) in moved {
+                handles[shard_id].evict(node);
+            }
+            *current = route;
+            return Ok(());
+        }
+        Err(FsError::NoBuffer)
+    }
+
+    // read the blocks in the range into the cache and keep them there, in place of
+    // the blocks kept before. a shard keeps at most half its buffers, the blocks
+    // past that are cached as usual. returns how many are kept
+    fn keep_resident(&self, blocks: Range<u32>, block_device: Arc<dyn BlockDevice>) -> u32 {
+        self.handles
+            .iter()
+            .for_each(|handle| handle.lock().unwrap().release_resident());
+        let route = self.route.read().unwrap();
+        blocks
+            .filter(|block_id| {
+                let mut handle = self.handles[route(*block_id)].lock().unwrap();
+                handle.make_resident(block_id, block_device.clone())
+            })
+            .count() as u32
+    }
+
+    // the blocks read from a device since the table was made
+    fn misses(&self) -> u64 {
+        self.handles
+            .iter()
+            .map(|handle| handle.lock().unwrap().misses)
+            .sum()
     }
 
+    fn writeback(&self, age: Duration, limit: usize) -> usize {
+        let mut n = 0;
+        for handle in self.handles.iter() {
+            n += handle.lock().unwrap().writeback(age, limit - n);
+        }
+        n
+    }
+
+    // whether the block is cached, without reading it in or touching the lru
+    fn cached(&self, block_id: u32, block_device: &Arc<dyn BlockDevice>) -> bool {
+        self.bypass(block_id, block_device, || ()).is_none()
+    }
+
+    // run f, a transfer of the block between the caller and the device, if the
+    // block is not cached. its shard stays locked until f returns, so a get can
+    // not cache the block while the transfer goes around it
+    fn bypass<V>(
+        &self,
+        block_id: u32,
+        block_device: &Arc<dyn BlockDevice>,
+        f: impl FnOnce() -> V,
+    ) -> Option<V> {
+        let route = self.route.read().unwrap();
+        let handle = self.handles[route(block_id)].lock().unwrap();
+        if handle
+            .map
+            .contains_key(&(device_id(block_device), block_id))
+        {
+            return None;
+        }
+        Some(f())
+    }
+
+    // a shard that stays full past the retries fails the get with NoBuffer
     fn get(
-        &mut self,
+        &self,
         block_id: &u32,
         block_device: Arc<dyn BlockDevice>,
-    ) -> Arc<RwLock<BufferBlock>> {
-        let shard_id = block_id % (SHARD_NUM as u32);
-        // continue get until the block is in the buffer pool
+    ) -> Result<Arc<RwLock<BufferBlock>>, FsError> {
+        self.get_with(block_id, block_device, None)
+    }
+
+    // get, a miss taking data in place of the device's block
+    fn get_with(
+        &self,
+        block_id: &u32,
+        block_device: Arc<dyn BlockDevice>,
+        data: Option<&[u8; BLOCK_SIZE as usize]>,
+    ) -> Result<Arc<RwLock<BufferBlock>>, FsError> {
+        let retries = BUFFER_RETRIES.load(Ordering::SeqCst);
+        self.try_get(block_id, block_device, retries, data)
+    }
+
+    // get for a caller that can not stop halfway: a full shard is waited
+    // out, with a warning after every round of retries
+    fn wait(&self, block_id: &u32, block_device: Arc<dyn BlockDevice>) -> Arc<RwLock<BufferBlock>> {
+        let retries = BUFFER_RETRIES.load(Ordering::SeqCst).max(1);
         loop {
-            let mut handle = self.handles[shard_id as usize].lock().unwrap();
-            if let Some(block) = handle.get(block_id, block_device.clone()) {
+            match self.try_get(block_id, block_device.clone(), retries, None) {
+                Ok(block) => return block,
+                Err(e) => warn!(
+                    "block {}: {} after {} retries, still waiting",
+                    block_id, e, retries
+                ),
+            }
+        }
+    }
+
+    // wait for a buffer to come free, for at most `retries` backoffs
+    fn try_get(
+        &self,
+        block_id: &u32,
+        block_device: Arc<dyn BlockDevice>,
+        retries: u32,
+        data: Option<&[u8; BLOCK_SIZE as usize]>,
+    ) -> Result<Arc<RwLock<BufferBlock>>, FsError> {
+        let mut backoff = BACKOFF_START;
+        for retry in 0..=retries {
+            if retry > 0 {
+                if retry == RETRY_WARN {
+                    warn!(
+                        "block {}: no free buffer in shard {} after {} retries",
+                        block_id,
+                        self.route.read().unwrap()(*block_id),
+                        retry
+                    );
+                }
+                // the shard is unlocked while sleeping, so its blocks can be let go
+                std::thread::sleep(backoff);
+                backoff = (backoff * 2).min(BACKOFF_MAX);
+            }
+            // the route stays locked until the block is in its shard
+            let route = self.route.read().unwrap();
+            let mut handle = self.handles[route(*block_id)].lock().unwrap();
+            if let Some(block) = handle.get(block_id, block_device.clone(), data) {
                 info!(
                     "{:?} get block_id: {}",
                     std::thread::current().id(),
                     block_id
                 );
-                return block;
+                return Ok(block);
             }
         }
+        Err(FsError::NoBuffer)
     }
 }
 
-pub fn sync_all() {
-    unsafe {
-        BUFFER_LAYER.handles.iter().for_each(|handle| {
-            handle.lock().unwrap().map.iter().for_each(|(_, node)| {
-                node.as_ref().data.write().unwrap().sync();
-            })
-        })
+impl LruHandle {
+    // sync up to `limit` dirty blocks no one else holds, that have been dirty for at least `age`
+    // a block held by the log (or any user) has strong_count > 1 and is left alone,
+    // and new holders are kept out by the shard lock the caller holds
+    fn writeback(&self, age: Duration, limit: usize) -> usize {
+        let mut n = 0;
+        for node in self.map.values() {
+            if n == limit {
+                break;
+            }
+            let data = unsafe { &node.as_ref().data };
+            if Arc::strong_count(data) > 1 {
+                continue;
+            }
+            if let Ok(mut block) = data.try_write() {
+                if block.dirty_since.is_some_and(|t| t.elapsed() >= age) {
+                    block.sync();
+                    n += 1;
+                }
+            }
+        }
+        n
+    }
+}
+
+// the background writeback, stopped and joined on drop
+pub struct Writeback {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for Writeback {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            handle.join().unwrap();
+        }
+    }
+}
+
+// a token bucket: `rate` blocks a second, saving up at most a second's worth
+struct RateLimit {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimit {
+    fn new(rate: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    // the blocks that may be written now
+    fn available(&mut self) -> usize {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate);
+        self.last = now;
+        self.tokens as usize
+    }
+
+    fn spend(&mut self, n: usize) {
+        self.tokens -= n as f64;
+    }
+}
+
+// every `interval`, sync the blocks that have been dirty for at least `age`,
+// at most `rate` blocks a second if given, so foreground I/O is not starved
+pub fn start_writeback(interval: Duration, age: Duration, rate: Option<u32>) -> Writeback {
+    let stop = Arc::new(AtomicBool::new(false));
+    let flag = stop.clone();
+    let handle = std::thread::spawn(move || {
+        let mut limit = rate.map(RateLimit::new);
+        while !flag.load(Ordering::Relaxed) {
+            std::thread::sleep(interval);
+            let budget = limit.as_mut().map_or(usize::MAX, RateLimit::available);
+            let n = writeback(age, budget);
+            if let Some(limit) = limit.as_mut() {
+                limit.spend(n);
+            }
+            if n > 0 {
+                info!("writeback: synced {} blocks", n);
+            }
+        }
+    });
+    Writeback {
+        stop,
+        handle: Some(handle),
     }
 }
 
-use log::info;
+fn writeback(age: Duration, limit: usize) -> usize {
+    BUFFER_LAYER.writeback(age, limit)
+}
+
+pub fn sync_all() {
+    BUFFER_LAYER.handles.iter().for_each(|handle| {
+        // the blocks are synced after the shard is unlocked, a thread holding
+        // a block may be waiting for the shard to get another one
+        let blocks: Vec<_> = handle
+            .lock()
+            .unwrap()
+            .map
+            .values()
+            // a node stays in the map while its shard is locked
+            .map(|node| unsafe { node.as_ref() }.data.clone())
+            .collect();
+        blocks
+            .iter()
+            .for_each(|block| block.write().unwrap().sync());
+    })
+}
+
+use log::{info, warn};
 use once_cell::sync::Lazy;
-static mut BUFFER_LAYER: Lazy<HandleTable> = Lazy::new(|| HandleTable::new(SHARD_NUM, BLOCK_NUM));
+static BUFFER_LAYER: Lazy<HandleTable> =
+    Lazy::new(|| HandleTable::with_pinned_shard(SHARD_NUM, BLOCK_NUM, META_BLOCK_NUM));
+
+// keep the blocks in the range, the metadata of the mounted image, in a shard
+// of their own, so a data scan does not evict them and they do not crowd out data
+pub fn pin_blocks(blocks: Range<u32>) -> Result<(), FsError> {
+    BUFFER_LAYER.pin(blocks)
+}
+
+// read the blocks in the range, the inode blocks of the mounted image, and keep
+// them cached so an inode lookup never waits for the device. it costs up to
+// half the buffers of the pinned shard, the maps and the rest share the others
+pub fn keep_resident(blocks: Range<u32>, block_device: Arc<dyn BlockDevice>) {
+    let n = blocks.len();
+    let kept = BUFFER_LAYER.keep_resident(blocks, block_device);
+    if (kept as usize) < n {
+        warn!(
+            "keep_resident: {} of {} blocks kept, no room for more",
+            kept, n
+        );
+    }
+}
 
+// the blocks read from the device rather than found in the cache
+pub fn buffer_misses() -> u64 {
+    BUFFER_LAYER.misses()
+}
+
+// the buffer of a block, NoBuffer if its shard stays full for --buffer-retries
 pub fn get_buffer_block(
     block_id: u32,
     block_device: Arc<dyn BlockDevice>,
+) -> Result<Arc<RwLock<BufferBlock>>, FsError> {
+    BUFFER_LAYER.get(&block_id, block_device)
+}
+
+// get_buffer_block for a caller that overwrites the whole block: on a miss
+// the buffer starts out holding data and the device is not read. a cached
+// block keeps what it holds, the caller still writes data into it
+pub fn get_buffer_block_with(
+    block_id: u32,
+    block_device: Arc<dyn BlockDevice>,
+    data: &[u8; BLOCK_SIZE as usize],
+) -> Result<Arc<RwLock<BufferBlock>>, FsError> {
+    BUFFER_LAYER.get_with(&block_id, block_device, Some(data))
+}
+
+// get_buffer_block that waits as long as the shard is full. for the callers
+// that can not stop halfway or have no error to return: the log, the
+// superblock, the inode table and the maps beside it, which live in the
+// pinned shard and only wait out a full transaction, truncate in drop, and
+// the offline tools
+pub fn wait_buffer_block(
+    block_id: u32,
+    block_device: Arc<dyn BlockDevice>,
 ) -> Arc<RwLock<BufferBlock>> {
-    unsafe { BUFFER_LAYER.get(&block_id, block_device).clone() }
+    BUFFER_LAYER.wait(&block_id, block_device)
+}
+
+// whether the buffer cache holds the block
+#[allow(unused)]
+pub fn buffer_cached(block_id: u32, block_device: Arc<dyn BlockDevice>) -> bool {
+    BUFFER_LAYER.cached(block_id, &block_device)
+}
+
+// a direct transfer of a block the buffer cache does not hold: f runs with no
+// get of the block in between, so none caches a copy the transfer makes stale.
+// None, and f is not run, if the block is cached and the transfer must go
+// through the cache
+pub fn buffer_bypass<V>(
+    block_id: u32,
+    block_device: Arc<dyn BlockDevice>,
+    f: impl FnOnce() -> V,
+) -> Option<V> {
+    BUFFER_LAYER.bypass(block_id, &block_device, f)
 }
 
 // test
@@ -344,11 +956,13 @@ mod tests {
     use super::*;
     #[test]
     fn test_lru() {
-        let lru = LruHandle::new();
+        let lru = LruHandle::new(0);
         let node1 = NodePtr::new(Box::into_raw(Box::new(Node {
             data: Arc::new(RwLock::new(BufferBlock::new())),
             next: None,
             prev: None,
+            protected: false,
+            resident: false,
         })))
         .unwrap();
         unsafe { node1.as_ref().data.write().unwrap().block_id = 0 };
@@ -356,6 +970,8 @@ mod tests {
             data: Arc::new(RwLock::new(BufferBlock::new())),
             next: None,
             prev: None,
+            protected: false,
+            resident: false,
         })))
         .unwrap();
         unsafe { node2.as_ref().data.write().unwrap().block_id = 1 };
@@ -363,6 +979,8 @@ mod tests {
             data: Arc::new(RwLock::new(BufferBlock::new())),
             next: None,
             prev: None,
+            protected: false,
+            resident: false,
         })))
         .unwrap();
         unsafe { node3.as_ref().data.write().unwrap().block_id = 2 };
@@ -514,6 +1132,8 @@ mod tests {
             data: block,
             next: None,
             prev: None,
+            protected: false,
+            resident: false,
         })))
         .unwrap();
 
@@ -535,7 +1155,7 @@ mod tests {
 
     #[test]
     fn test_get() {
-        let mut table = HandleTable::new(SHARD_NUM, BLOCK_NUM);
+        let table = HandleTable::new(SHARD_NUM, BLOCK_NUM);
         use super::super::filedisk::FileDisk;
         let file: File = OpenOptions::new()
             .read(true)
@@ -552,11 +1172,280 @@ mod tests {
         }
         // loop test
         for i in 0..640 {
-            let buffer = table.get(&(i % 64), file_disk.clone());
+            let buffer = table.get(&(i % 64), file_disk.clone()).unwrap();
             assert_eq!(buffer.read().unwrap().data, [(i % 64) as u8; 512]);
         }
     }
 
+    #[test]
+    fn test_scan_resistance() {
+        use super::super::filedisk::FileDisk;
+        let path = std::env::temp_dir().join(format!("fatpigeorz_scan_{}.img", std::process::id()));
+        let file: File = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let filedisk: Arc<dyn BlockDevice> = Arc::new(FileDisk::new(file));
+        let table = HandleTable::new(SHARD_NUM, BLOCK_NUM);
+        // block 0 plays a hot inode block, hit twice as a read-modify-write does
+        let hot = 0;
+        table.get(&hot, filedisk.clone()).unwrap();
+        table.get(&hot, filedisk.clone()).unwrap();
+        // a long scan over blocks of the same shard, each read once,
+        // the hot block is touched again only once per two shard sizes,
+        // which plain lru would have evicted in between
+        let shard_size = BLOCK_NUM / SHARD_NUM;
+        for i in 1..=(shard_size * 20) {
+            if i % (shard_size * 2) == 1 {
+                table.get(&hot, filedisk.clone()).unwrap();
+            }
+            table.get(&(i * SHARD_NUM), filedisk.clone()).unwrap();
+        }
+        let key = (device_id(&filedisk), hot);
+        assert!(table.handles[0].lock().unwrap().map.contains_key(&key));
+        // a scanned block was only seen once and is gone
+        let key = (device_id(&filedisk), SHARD_NUM);
+        assert!(!table.handles[0].lock().unwrap().map.contains_key(&key));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_pinned_shard() {
+        use super::super::filedisk::FileDisk;
+        let path =
+            std::env::temp_dir().join(format!("fatpigeorz_pinned_{}.img", std::process::id()));
+        let file: File = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(1024 * 1024).unwrap();
+        let filedisk: Arc<dyn BlockDevice> = Arc::new(FileDisk::new(file));
+        // blocks 2..34 play the inode blocks, block 4 a hot one
+        let (meta, hot) = (2..34, 4);
+        let shard_size = BLOCK_NUM / SHARD_NUM;
+        // a data block of the hot block's shard read twice, as rereading a file does,
+        // enough of them to push the hot block out of the protected list
+        let churn = |table: &HandleTable| {
+            for i in 0..(shard_size * 2) {
+                let block = 1000 + i * SHARD_NUM;
+                table.get(&block, filedisk.clone()).unwrap();
+                table.get(&block, filedisk.clone()).unwrap();
+            }
+        };
+        let key = (device_id(&filedisk), hot);
+        let cached = |table: &HandleTable, shard_id: usize| {
+            table.handles[shard_id]
+                .lock()
+                .unwrap()
+                .map
+                .contains_key(&key)
+        };
+
+        // sharing a shard, hot data evicts the metadata
+        let table = HandleTable::new(SHARD_NUM, BLOCK_NUM);
+        table.get(&hot, filedisk.clone()).unwrap();
+        table.get(&hot, filedisk.clone()).unwrap();
+        churn(&table);
+        assert!(!cached(&table, (hot % SHARD_NUM) as usize));
+
+        let table = HandleTable::with_pinned_shard(SHARD_NUM, BLOCK_NUM, META_BLOCK_NUM);
+        let pinned = SHARD_NUM as usize;
+        // a block cached before the pin is written back and moved
+        table
+            .get(&hot, filedisk.clone())
+            .unwrap()
+            .write()
+            .unwrap()
+            .write(0, |data: &mut u8| *data = 7);
+        table.pin(meta).unwrap();
+        assert!(!cached(&table, (hot % SHARD_NUM) as usize));
+        let mut buf = [0u8; BLOCK_SIZE as usize];
+        filedisk.read_block(hot, &mut buf);
+        assert_eq!(buf[0], 7);
+        let block = table.get(&hot, filedisk.clone()).unwrap();
+        assert_eq!(block.read().unwrap().read(0, |data: &u8| *data), 7);
+        drop(block);
+        assert!(cached(&table, pinned));
+        // the same churn leaves it cached, and keeps to the data shards
+        table.get(&hot, filedisk.clone()).unwrap();
+        churn(&table);
+        assert!(cached(&table, pinned));
+        let block = Arc::as_ptr(&table.get(&hot, filedisk.clone()).unwrap());
+        assert_eq!(table.handles[pinned].lock().unwrap().map.len(), 1);
+        assert_eq!(
+            block,
+            Arc::as_ptr(&table.get(&hot, filedisk.clone()).unwrap())
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_resident() {
+        use super::super::filedisk::FileDisk;
+        let path =
+            std::env::temp_dir().join(format!("fatpigeorz_resident_{}.img", std::process::id()));
+        let file: File = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(1024 * 1024).unwrap();
+        let filedisk: Arc<dyn BlockDevice> = Arc::new(FileDisk::new(file));
+        // the metadata is three times the pinned shard, its first half shard
+        // plays the inode blocks and the rest the maps
+        let meta = 2..2 + META_BLOCK_NUM * 3;
+        let inodes = 2..2 + META_BLOCK_NUM / 2;
+        // a large data scan, and the maps read twice each as allocating does
+        let scan = |table: &HandleTable| {
+            for block in 1000..2000 {
+                table.get(&block, filedisk.clone()).unwrap();
+            }
+            for block in inodes.end..meta.end {
+                table.get(&block, filedisk.clone()).unwrap();
+                table.get(&block, filedisk.clone()).unwrap();
+            }
+        };
+        let reread = |table: &HandleTable| {
+            let misses = table.misses();
+            for block in inodes.clone() {
+                table.get(&block, filedisk.clone()).unwrap();
+            }
+            table.misses() - misses
+        };
+
+        let table = HandleTable::with_pinned_shard(SHARD_NUM, BLOCK_NUM, META_BLOCK_NUM);
+        table.pin(meta.clone()).unwrap();
+        inodes.clone().for_each(|block| {
+            table.get(&block, filedisk.clone()).unwrap();
+        });
+        scan(&table);
+        assert_eq!(reread(&table), inodes.len() as u64);
+
+        let table = HandleTable::with_pinned_shard(SHARD_NUM, BLOCK_NUM, META_BLOCK_NUM);
+        table.pin(meta.clone()).unwrap();
+        assert_eq!(
+            table.keep_resident(inodes.clone(), filedisk.clone()),
+            inodes.len() as u32
+        );
+        scan(&table);
+        assert_eq!(reread(&table), 0);
+        // no more than half a shard is kept, the maps still get buffers
+        assert_eq!(
+            table.keep_resident(meta.clone(), filedisk.clone()),
+            META_BLOCK_NUM / 2
+        );
+        scan(&table);
+        // keeping an empty range lets them all go
+        assert_eq!(table.keep_resident(0..0, filedisk.clone()), 0);
+        scan(&table);
+        assert_eq!(reread(&table), inodes.len() as u64);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_bypass() {
+        use super::super::filedisk::FileDisk;
+        let path =
+            std::env::temp_dir().join(format!("fatpigeorz_bypass_{}.img", std::process::id()));
+        let file: File = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len((BLOCK_NUM * BLOCK_SIZE) as u64).unwrap();
+        let filedisk: Arc<dyn BlockDevice> = Arc::new(FileDisk::new(file));
+        let table = HandleTable::new(SHARD_NUM, BLOCK_NUM);
+        let data = [9u8; BLOCK_SIZE as usize];
+        let (started, wait) = std::sync::mpsc::channel();
+        thread::scope(|scope| {
+            let (table, disk) = (&table, filedisk.clone());
+            scope.spawn(move || {
+                table.bypass(5, &disk, || {
+                    started.send(()).unwrap();
+                    thread::sleep(Duration::from_millis(20));
+                    disk.write_block(5, &data);
+                })
+            });
+            // a get while the write goes around the cache waits for it,
+            // rather than caching what was there before
+            wait.recv().unwrap();
+            let block = table.get(&5, filedisk.clone()).unwrap();
+            let cached = block
+                .read()
+                .unwrap()
+                .read(0, |d: &[u8; BLOCK_SIZE as usize]| *d);
+            assert_eq!(cached, data);
+        });
+        // a cached block is not bypassed
+        assert_eq!(table.bypass(5, &filedisk, || ()), None);
+        assert!(table.bypass(6, &filedisk, || ()).is_some());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_full_shard() {
+        use super::super::filedisk::FileDisk;
+        let path = std::env::temp_dir().join(format!("fatpigeorz_full_{}.img", std::process::id()));
+        let file: File = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let filedisk: Arc<dyn BlockDevice> = Arc::new(FileDisk::new(file));
+        let table = HandleTable::new(SHARD_NUM, BLOCK_NUM);
+        // hold every buffer of shard 0
+        let shard_size = BLOCK_NUM / SHARD_NUM;
+        let mut held = (0..shard_size)
+            .map(|i| table.get(&(i * SHARD_NUM), filedisk.clone()).unwrap())
+            .collect::<Vec<_>>();
+        // a block already cached is found, held or not
+        assert!(table.try_get(&0, filedisk.clone(), 0, None).is_ok());
+        // a new one gives up after the retries instead of spinning
+        let start = Instant::now();
+        let missing = shard_size * SHARD_NUM;
+        assert_eq!(
+            table.try_get(&missing, filedisk.clone(), 8, None).err(),
+            Some(FsError::NoBuffer)
+        );
+        assert!(start.elapsed() < Duration::from_secs(1));
+        // the other shards are not affected
+        assert!(table.try_get(&1, filedisk.clone(), 0, None).is_ok());
+        // a buffer let go while the get backs off is taken
+        std::thread::scope(|scope| {
+            let block = held.pop().unwrap();
+            scope.spawn(move || {
+                std::thread::sleep(Duration::from_millis(5));
+                drop(block);
+            });
+            let block = table.try_get(&missing, filedisk.clone(), 50, None).unwrap();
+            assert_eq!(block.read().unwrap().block_id, missing);
+        });
+        // wait does not give up, it waits until a buffer is let go
+        held.push(table.get(&missing, filedisk.clone()).unwrap());
+        std::thread::scope(|scope| {
+            let block = held.pop().unwrap();
+            scope.spawn(move || {
+                std::thread::sleep(Duration::from_millis(50));
+                drop(block);
+            });
+            let block = table.wait(&(missing + SHARD_NUM), filedisk.clone());
+            assert_eq!(block.read().unwrap().block_id, missing + SHARD_NUM);
+        });
+        std::fs::remove_file(path).unwrap();
+    }
+
     #[test]
     fn test_drop() {
         use super::super::filedisk::FileDisk;
@@ -576,14 +1465,125 @@ mod tests {
         }
 
         // get buffer
-        let mut table = HandleTable::new(SHARD_NUM, BLOCK_NUM);
+        let table = HandleTable::new(SHARD_NUM, BLOCK_NUM);
         for i in 0..32 {
-            let buffer = table.get(&((i * 4) % 64), filedisk.clone());
+            let buffer = table.get(&((i * 4) % 64), filedisk.clone()).unwrap();
             assert_eq!(Arc::strong_count(&buffer), 2);
             assert_eq!(buffer.read().unwrap().data, [((i * 4) % 64) as u8; 512]);
         }
     }
 
+    #[test]
+    fn test_writeback() {
+        use super::super::testutil::TestImage;
+        use std::os::unix::fs::FileExt;
+        let image = TestImage::new("writeback");
+        let dev = image.mount();
+        // a free data block, not touched by the log
+        let bno = 1000;
+        get_buffer_block(bno, dev.clone())
+            .unwrap()
+            .write()
+            .unwrap()
+            .write(0, |data: &mut [u8; BLOCK_SIZE as usize]| data.fill(0x5a));
+        let interval = Duration::from_millis(20);
+        let writeback = start_writeback(interval, interval, None);
+        std::thread::sleep(interval * 5);
+        drop(writeback);
+        // read the host file directly, bypassing the cache
+        let file = File::open(&image.path).unwrap();
+        let mut buf = [0u8; BLOCK_SIZE as usize];
+        file.read_exact_at(&mut buf, bno as u64 * BLOCK_SIZE as u64)
+            .unwrap();
+        assert_eq!(buf, [0x5a; BLOCK_SIZE as usize]);
+    }
+
+    #[test]
+    fn test_writeback_rate() {
+        use super::super::filedisk::FileDisk;
+        let path = std::env::temp_dir().join(format!("fatpigeorz_rate_{}.img", std::process::id()));
+        let file: File = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(64 * BLOCK_SIZE as u64).unwrap();
+        let filedisk: Arc<dyn BlockDevice> = Arc::new(FileDisk::new(file));
+        // a table of its own, so other tests do not sync or evict the blocks
+        let table = HandleTable::new(SHARD_NUM, BLOCK_NUM);
+        let nblocks = 32;
+        for bno in 0..nblocks {
+            table
+                .get(&bno, filedisk.clone())
+                .unwrap()
+                .write()
+                .unwrap()
+                .write(0, |data: &mut [u8; BLOCK_SIZE as usize]| data.fill(0x3c));
+        }
+        // the writeback loop, at 20 blocks a second
+        let rate = 20;
+        let mut limit = RateLimit::new(rate);
+        let mut synced = 0;
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(300) {
+            let n = table.writeback(Duration::ZERO, limit.available());
+            limit.spend(n);
+            synced += n;
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        // a second's worth at once, then no faster than the rate
+        assert!(synced >= rate as usize);
+        assert!(synced as f64 <= rate as f64 * (1.0 + elapsed) + 1.0);
+        assert!(synced < nblocks as usize);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    // the cache mode is global, this puts it back when the test ends, also
+    // when it fails, so the tests after it run in the mode they expect
+    struct CacheModeGuard(CacheMode);
+
+    impl CacheModeGuard {
+        fn set(mode: CacheMode) -> Self {
+            let old = cache_mode();
+            set_cache_mode(mode);
+            Self(old)
+        }
+    }
+
+    impl Drop for CacheModeGuard {
+        fn drop(&mut self) {
+            set_cache_mode(self.0);
+        }
+    }
+
+    #[test]
+    fn test_write_through() {
+        use super::super::testutil::TestImage;
+        use std::os::unix::fs::FileExt;
+        // the image holds the lock of the tests on one, none of them runs
+        // in the mode set here. the guard drops first and restores it
+        let image = TestImage::new("write_through");
+        let dev = image.mount();
+        let mode = CacheModeGuard::set(CacheMode::WriteThrough);
+        let bno = 1000;
+        get_buffer_block(bno, dev.clone())
+            .unwrap()
+            .write()
+            .unwrap()
+            .write(0, |data: &mut [u8; BLOCK_SIZE as usize]| data.fill(0xa5));
+        drop(mode);
+        assert_eq!(cache_mode(), CacheMode::WriteBack);
+        // no sync, the block is already on disk
+        let file = File::open(&image.path).unwrap();
+        let mut buf = [0u8; BLOCK_SIZE as usize];
+        file.read_exact_at(&mut buf, bno as u64 * BLOCK_SIZE as u64)
+            .unwrap();
+        assert_eq!(buf, [0xa5; BLOCK_SIZE as usize]);
+    }
+
     #[test]
     fn test_layer() {
         use super::super::filedisk::FileDisk;
@@ -606,7 +1606,7 @@ mod tests {
             let filedisk = filedisk.clone();
             let handle = thread::spawn(move || {
                 for j in 0..64 {
-                    let buffer = get_buffer_block(j, filedisk.clone());
+                    let buffer = get_buffer_block(j, filedisk.clone()).unwrap();
                     assert_eq!(buffer.clone().read().unwrap().data, [(64 - j) as u8; 512]);
                 }
             });
@@ -616,4 +1616,31 @@ mod tests {
             .into_iter()
             .for_each(|handle| handle.join().unwrap());
     }
+
+    #[test]
+    fn test_verify_writes() {
+        use super::super::testutil::{CrashDisk, TestImage};
+
+        // the image lock keeps out the tests whose devices drop writes on purpose
+        let image = TestImage::new("verify_writes");
+        let disk = Arc::new(CrashDisk::new(image.disk()));
+        let dev: Arc<dyn BlockDevice> = disk.clone();
+        let mut block = BufferBlock::init_block(7, dev.clone(), None);
+        set_verify_writes(true);
+        block.sync_write(0, |data: &mut [u8; BLOCK_SIZE as usize]| {
+            *data = [1; BLOCK_SIZE as usize]
+        });
+        // the device drops every write from here on
+        disk.crash_after(0);
+        let lost = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            block.sync_write(0, |data: &mut [u8; BLOCK_SIZE as usize]| {
+                *data = [2; BLOCK_SIZE as usize]
+            });
+        }));
+        set_verify_writes(false);
+        assert!(lost.is_err());
+        assert_eq!(disk.lost(), 1);
+        // nothing is left dirty to panic again on drop
+        drop(block);
+    }
 }
@@ -0,0 +1,104 @@
+// where the file system gets the time, microseconds since the unix epoch.
+// the wall clock unless a test sets one it can move by hand, so what records
+// the time can be checked without sleeping
+use std::{
+    sync::{Arc, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use once_cell::sync::Lazy;
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64
+    }
+}
+
+static CLOCK: Lazy<RwLock<Arc<dyn Clock>>> = Lazy::new(|| RwLock::new(Arc::new(SystemClock)));
+
+// the clock now() reads from now on
+#[allow(unused)]
+pub fn set_clock(clock: Arc<dyn Clock>) {
+    *CLOCK.write().unwrap() = clock;
+}
+
+pub fn now() -> u64 {
+    CLOCK.read().unwrap().now()
+}
+
+// a clock that stands still until advanced
+#[cfg(test)]
+pub struct MockClock(std::sync::atomic::AtomicU64);
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new(start: u64) -> Self {
+        Self(std::sync::atomic::AtomicU64::new(start))
+    }
+
+    pub fn advance(&self, micros: u64) {
+        self.0
+            .fetch_add(micros, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> u64 {
+        self.0.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::fs::{
+        audit::set_audit,
+        file::{fileclose, fileopen, filestat, filewrite_all, OpenMode},
+        testutil::TestImage,
+    };
+
+    #[test]
+    fn test_mock_clock() {
+        let image = TestImage::new("clock");
+        let dev = image.mount();
+        let path = image.path.with_extension("clock");
+        let clock = Arc::new(MockClock::new(1_700_000_000_000_000));
+        set_clock(clock.clone());
+        set_audit(Some(&path)).unwrap();
+        let file = fileopen(dev.clone(), &PathBuf::from("/a"), OpenMode::OCreate).unwrap();
+        let inum = filestat(&file).unwrap().ino;
+        filewrite_all(&file, &[1; 100]).unwrap();
+        clock.advance(2_500_017);
+        filewrite_all(&file, &[2; 100]).unwrap();
+        fileclose(file);
+        set_audit(None).unwrap();
+        set_clock(Arc::new(SystemClock));
+
+        let records = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let writes = records
+            .lines()
+            .filter(|line| {
+                line.ends_with(&format!("write inum={} size=100", inum))
+                    || line.ends_with(&format!("write inum={} size=200", inum))
+            })
+            .map(|line| {
+                let (secs, micros) = line.split(' ').next().unwrap().split_once('.').unwrap();
+                secs.parse::<u64>().unwrap() * 1_000_000 + micros.parse::<u64>().unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(writes, [1_700_000_000_000_000, 1_700_000_002_500_017]);
+    }
+}
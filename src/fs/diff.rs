@@ -0,0 +1,103 @@
+// compare an image with an older copy of it, so backup tools can ship only
+// the blocks that changed and know which files they belong to
+use std::{collections::BTreeSet, sync::Arc};
+
+use super::{
+    fs::{BlockDevice, BLOCK_SIZE, ROOTINO, SB_BLOCK},
+    fsck::{inode_blocks, read_inode, read_xattr_block},
+    superblock::read_superblock,
+};
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ImageDiff {
+    // the blocks whose bytes differ, in order
+    pub blocks: Vec<u32>,
+    // the inodes that changed themselves or own a changed block, in order
+    pub inodes: Vec<u32>,
+}
+
+pub fn diff(base: Arc<dyn BlockDevice>, current: Arc<dyn BlockDevice>) -> ImageDiff {
+    let base_sb = read_superblock(base.clone(), SB_BLOCK);
+    let sb = read_superblock(current.clone(), SB_BLOCK);
+    let mut blocks = vec![];
+    let mut a = [0u8; BLOCK_SIZE as usize];
+    let mut b = [0u8; BLOCK_SIZE as usize];
+    let common = base_sb.size.min(sb.size);
+    for block in 0..common {
+        base.read_block(block, &mut a);
+        current.read_block(block, &mut b);
+        if a != b {
+            blocks.push(block);
+        }
+    }
+    // a grown image changed all its new blocks
+    blocks.extend(common..sb.size);
+
+    let mut inodes = vec![];
+    if base_sb.valid() && sb.valid() {
+        let changed: BTreeSet<u32> = blocks.iter().copied().collect();
+        for inum in ROOTINO..base_sb.ninodes.min(sb.ninodes) {
+            let old = read_inode(base.clone(), &base_sb, inum);
+            let new = read_inode(current.clone(), &sb, inum);
+            // a block the file dropped counts as much as one it wrote
+            let touched = inode_blocks(base.clone(), &base_sb, &old)
+                .into_iter()
+                .chain(inode_blocks(current.clone(), &sb, &new))
+                .chain([
+                    read_xattr_block(base.clone(), &base_sb, inum),
+                    read_xattr_block(current.clone(), &sb, inum),
+                ])
+                .any(|block| block != 0 && changed.contains(&block));
+            if old != new || touched {
+                inodes.push(inum);
+            }
+        }
+    }
+    ImageDiff { blocks, inodes }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs::File, path::PathBuf};
+
+    use super::*;
+    use crate::fs::{
+        buffer::sync_all,
+        file::{fileclose, fileopen, fileopen_nobarrier, filepwrite, filewrite, OpenMode},
+        filedisk::FileDisk,
+        inode::find_inode,
+        testutil::TestImage,
+    };
+
+    #[test]
+    fn test_diff() {
+        let image = TestImage::new("diff");
+        let dev = image.mount();
+        for name in ["/a", "/b"] {
+            let file = fileopen(dev.clone(), &PathBuf::from(name), OpenMode::OCreate).unwrap();
+            filewrite(&file, &[1u8; 3 * BLOCK_SIZE as usize]).unwrap();
+            fileclose(file);
+        }
+        sync_all();
+        let snapshot = image.path.with_extension("snap");
+        std::fs::copy(&image.path, &snapshot).unwrap();
+        let base: Arc<dyn BlockDevice> = Arc::new(FileDisk::new(File::open(&snapshot).unwrap()));
+        assert_eq!(diff(base.clone(), image.disk()), ImageDiff::default());
+
+        // skip the log, so the only block written is the one of the file
+        let b = find_inode(dev.clone(), &PathBuf::from("/b")).unwrap();
+        let second = b.read_disk_inode(|diskinode| diskinode.addrs[1]);
+        let file = fileopen_nobarrier(dev.clone(), &PathBuf::from("/b"), OpenMode::ORdwr).unwrap();
+        filepwrite(&file, &[2u8; 10], BLOCK_SIZE as u64 + 5).unwrap();
+        fileclose(file);
+        sync_all();
+        assert_eq!(
+            diff(base, image.disk()),
+            ImageDiff {
+                blocks: vec![second],
+                inodes: vec![b.0.inum],
+            }
+        );
+        std::fs::remove_file(&snapshot).unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+use libc::c_int;
+
+// errors returned by the file system layer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsError {
+    // the image has fewer blocks than the superblock claims
+    Truncated { size: u32, expected: u32 },
+    NotFound,
+    AlreadyExists,
+    NotDirectory,
+    IsDirectory,
+    NoSpace,
+    TooManyOpenFiles,
+    // a path component is empty, contains a NUL byte or is not UTF-8
+    InvalidName,
+    NameTooLong,
+    // the inode a handle names was freed and maybe reused
+    StaleHandle,
+    // the file ended before the buffer was filled
+    UnexpectedEof,
+    // another process holds the lock on the image
+    InUse,
+    // the image uses incompat features this code does not know
+    UnsupportedFeatures { incompat: u32 },
+    // the image was made by a later version of the format
+    UnsupportedVersion { version: u16 },
+    // the image has unknown ro-compat features, so it is mounted read-only
+    ReadOnly,
+    // a path lookup followed more than MAXSYMLINKS symlinks
+    TooManyLinks,
+    // a directory still holding entries was to be replaced
+    NotEmpty,
+    // neither superblock copy has the magic number, mkfs did not finish
+    NotFormatted,
+    // a compressed image that is not gzip, or is damaged
+    BadArchive,
+    // the log is too small for one transaction, too big for its header,
+    // or runs into the inode blocks
+    BadLog { logstart: u32, nlog: u32 },
+    // a read from a file not opened for reading, or a write to one not opened for writing
+    BadFileDescriptor,
+    // one more link would overflow the nlink of an inode
+    MaxLinks,
+    // every buffer of a cache shard stayed held while a block waited for one
+    NoBuffer,
+    // a write past the MAXFILE blocks a file can have
+    FileTooBig,
+    // the image was made without the feature the operation needs
+    NotSupported,
+}
+
+// Display
+impl std::fmt::Display for FsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FsError::Truncated { size, expected } => write!(
+                f,
+                "image truncated: {} blocks, but the superblock claims {}",
+                size, expected
+            ),
+            FsError::NotFound => write!(f, "no such file or directory"),
+            FsError::AlreadyExists => write!(f, "file exists"),
+            FsError::NotDirectory => write!(f, "not a directory"),
+            FsError::IsDirectory => write!(f, "is a directory"),
+            FsError::NoSpace => write!(f, "no space left on device"),
+            FsError::TooManyOpenFiles => write!(f, "too many open files"),
+            FsError::InvalidName => write!(f, "invalid file name"),
+            FsError::NameTooLong => write!(f, "file name too long"),
+            FsError::StaleHandle => write!(f, "stale file handle"),
+            FsError::UnexpectedEof => write!(f, "unexpected end of file"),
+            FsError::InUse => write!(f, "image in use"),
+            FsError::UnsupportedFeatures { incompat } => {
+                write!(f, "unsupported image features {:#x}", incompat)
+            }
+            FsError::UnsupportedVersion { version } => {
+                write!(f, "unsupported image version {}", version)
+            }
+            FsError::ReadOnly => write!(f, "read-only file system"),
+            FsError::TooManyLinks => write!(f, "too many levels of symbolic links"),
+            FsError::NotEmpty => write!(f, "directory not empty"),
+            FsError::NotFormatted => write!(f, "image not formatted"),
+            FsError::BadArchive => write!(f, "bad gzip archive"),
+            FsError::BadLog { logstart, nlog } => {
+                write!(f, "bad log: {} blocks at block {}", nlog, logstart)
+            }
+            FsError::BadFileDescriptor => write!(f, "bad file descriptor"),
+            FsError::MaxLinks => write!(f, "too many links"),
+            FsError::NoBuffer => write!(f, "no free buffer"),
+            FsError::FileTooBig => write!(f, "file too large"),
+            FsError::NotSupported => write!(f, "operation not supported"),
+        }
+    }
+}
+
+impl std::error::Error for FsError {}
+
+impl FsError {
+    // the errno a mount hands back to the kernel, as in reply.error(e.errno())
+    #[allow(unused)]
+    pub fn errno(&self) -> c_int {
+        match self {
+            FsError::NotFound => libc::ENOENT,
+            FsError::AlreadyExists => libc::EEXIST,
+            FsError::NotDirectory => libc::ENOTDIR,
+            FsError::IsDirectory => libc::EISDIR,
+            FsError::NoSpace => libc::ENOSPC,
+            // the file table is shared by everything using the file system
+            FsError::TooManyOpenFiles => libc::ENFILE,
+            FsError::InvalidName => libc::EINVAL,
+            FsError::NameTooLong => libc::ENAMETOOLONG,
+            FsError::StaleHandle => libc::ESTALE,
+            FsError::InUse => libc::EBUSY,
+            FsError::ReadOnly => libc::EROFS,
+            FsError::TooManyLinks => libc::ELOOP,
+            FsError::NotEmpty => libc::ENOTEMPTY,
+            FsError::BadFileDescriptor => libc::EBADF,
+            FsError::MaxLinks => libc::EMLINK,
+            FsError::NoBuffer => libc::ENOBUFS,
+            FsError::FileTooBig => libc::EFBIG,
+            FsError::NotSupported => libc::EOPNOTSUPP,
+            // the image itself is unusable
+            FsError::UnsupportedFeatures { .. }
+            | FsError::UnsupportedVersion { .. }
+            | FsError::NotFormatted => libc::EINVAL,
+            FsError::Truncated { .. }
+            | FsError::UnexpectedEof
+            | FsError::BadArchive
+            | FsError::BadLog { .. } => libc::EIO,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_errno() {
+        let cases = [
+            (FsError::NotFound, libc::ENOENT),
+            (FsError::AlreadyExists, libc::EEXIST),
+            (FsError::NotDirectory, libc::ENOTDIR),
+            (FsError::IsDirectory, libc::EISDIR),
+            (FsError::NoSpace, libc::ENOSPC),
+            (FsError::TooManyOpenFiles, libc::ENFILE),
+            (FsError::InvalidName, libc::EINVAL),
+            (FsError::NameTooLong, libc::ENAMETOOLONG),
+            (FsError::StaleHandle, libc::ESTALE),
+            (FsError::InUse, libc::EBUSY),
+            (FsError::ReadOnly, libc::EROFS),
+            (FsError::TooManyLinks, libc::ELOOP),
+            (FsError::NotEmpty, libc::ENOTEMPTY),
+            (FsError::BadFileDescriptor, libc::EBADF),
+            (FsError::MaxLinks, libc::EMLINK),
+            (FsError::NoBuffer, libc::ENOBUFS),
+            (FsError::FileTooBig, libc::EFBIG),
+            (FsError::NotSupported, libc::EOPNOTSUPP),
+            (FsError::UnsupportedFeatures { incompat: 4 }, libc::EINVAL),
+            (FsError::UnsupportedVersion { version: 9 }, libc::EINVAL),
+            (FsError::NotFormatted, libc::EINVAL),
+            (
+                FsError::Truncated {
+                    size: 10,
+                    expected: 20,
+                },
+                libc::EIO,
+            ),
+            (FsError::UnexpectedEof, libc::EIO),
+            (FsError::BadArchive, libc::EIO),
+            (
+                FsError::BadLog {
+                    logstart: 2,
+                    nlog: 0,
+                },
+                libc::EIO,
+            ),
+        ];
+        for (e, errno) in cases {
+            assert_eq!(e.errno(), errno, "{:?}", e);
+        }
+    }
+}
@@ -1,16 +1,23 @@
 use std::{
-    cell::RefCell,
-    path::PathBuf,
+    collections::HashMap,
+    io::{self, Write},
+    path::{Path, PathBuf},
     sync::{Arc, Mutex, MutexGuard},
 };
 
 use once_cell::sync::Lazy;
 
-use crate::fs::log::{log_begin, log_end};
+use crate::fs::log::{log_begin, log_end, log_end_sync, nobarrier};
 
 use super::{
-    fs::{BlockDevice, FileType, NFILE},
+    audit::{audit, auditing},
+    error::FsError,
+    fs::{BlockDevice, FileType, LittleEndian, BLOCK_NUM, BLOCK_SIZE, MAXFILE, NFILE},
+    fsck::inode_blocks,
     inode::{self, *},
+    pipe::{pipe_open, PipeEnd},
+    sha256::Sha256,
+    superblock::{read_only, sb},
 };
 
 #[derive(Default, Copy, Clone, PartialEq)]
@@ -18,8 +25,8 @@ pub enum FDType {
     #[default]
     Free = 0,
     INODE = 1,
-    // Device
-    // PIPE
+    Device = 2,
+    Pipe = 3,
     // Socket
     // ...
 }
@@ -29,14 +36,23 @@ pub struct FileInner {
     pub ty: FDType,
     pub readable: bool,
     pub writable: bool,
-    pub offset: u32,
+    pub offset: u64,
+    // held from reading the offset to moving it past a transfer, so the
+    // holders of a dup'ed entry each get a range of their own
+    pub seek: Arc<Mutex<()>>,
     pub path: PathBuf,
     pub ip: Option<InodePtr>,
+    // the inode as it was at open, a later one reusing the inum is not it
+    pub handle: Option<InodeHandle>,
     pub dev: Option<Arc<dyn BlockDevice>>,
+    pub nobarrier: bool, // writes bypass the log
+    pub sync: bool,      // writes are durable when they return
+    pub direct: bool,    // whole blocks go around the buffer cache
+    pub pipe: Option<PipeEnd>,
 }
 
 #[derive(Default, Clone)]
-pub struct OpenFile(pub Arc<RefCell<FileInner>>);
+pub struct OpenFile(pub Arc<Mutex<FileInner>>);
 
 pub struct FileTable(Mutex<Vec<OpenFile>>);
 
@@ -49,27 +65,59 @@ impl FileTable {
     }
 }
 
-pub static mut FTABLE: Lazy<FileTable> = Lazy::new(|| FileTable::new());
+pub static FTABLE: Lazy<FileTable> = Lazy::new(FileTable::new);
 
 fn lock_table() -> MutexGuard<'static, Vec<OpenFile>> {
-    unsafe { FTABLE.0.lock().unwrap() }
+    FTABLE.0.lock().unwrap()
 }
 
 pub struct Stat {
     pub dev: u32, // always 0
     pub ino: u32, // inode number
+    pub generation: u32,
     pub ty: FileType,
+    pub major: u16, // device number, 0 unless ty is Device
+    pub minor: u16,
     pub nlink: u32, // number of links to inode in file system
     pub size: u32,
 }
 
-#[derive(Debug, PartialEq)]
+// reads and writes on a device inode go to the handler
+// registered for its major number instead of the data blocks
+pub trait DeviceHandler: Send + Sync {
+    fn read(&self, minor: u16, dst: &mut [u8], offset: u64) -> usize;
+    fn write(&self, minor: u16, src: &[u8], offset: u64) -> usize;
+}
+
+static DEVSW: Lazy<Mutex<HashMap<u16, Arc<dyn DeviceHandler>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[allow(unused)]
+pub fn register_device(major: u16, handler: Arc<dyn DeviceHandler>) {
+    DEVSW.lock().unwrap().insert(major, handler);
+}
+
+// the handler of a device file and the minor number to pass it,
+// None if nothing is registered for the major number
+fn device_handler(file: &FileInner) -> Option<(Arc<dyn DeviceHandler>, u16)> {
+    let (major, minor) = file.ip.as_ref().unwrap().read_disk_inode(device_number);
+    let handler = DEVSW.lock().unwrap().get(&major).cloned();
+    handler.map(|handler| (handler, minor))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum OpenMode {
     ORdonly,
     OWronly,
     ORdwr,
+    // create the file and open it for reading and writing
     OCreate,
+    // empty the file and open it for reading and writing
     OTrunc,
+    // read only, and the path must be a directory
+    ODirectory,
+    // read and write, each write is committed and flushed before it returns
+    OSync,
 }
 
 pub fn filealloc() -> Option<OpenFile> {
@@ -77,55 +125,120 @@ pub fn filealloc() -> Option<OpenFile> {
     ft.iter().find(|f| Arc::strong_count(&f.0) == 1).cloned()
 }
 
+// an entry only the table still references was dropped without fileclose,
+// clear it so its slot is free again and its inode is released
+fn filereclaim() {
+    let ft = lock_table();
+    let stale = ft
+        .iter()
+        .filter(|f| Arc::strong_count(&f.0) == 1 && f.0.lock().unwrap().ty != FDType::Free)
+        .collect::<Vec<_>>();
+    if stale.is_empty() {
+        return;
+    }
+    // dropping the last reference to an unlinked inode frees it
+    log_begin();
+    for f in stale {
+        *f.0.lock().unwrap() = FileInner::default();
+    }
+    log_end();
+}
+
+// an entry of the file table, as listed by lsof
+pub struct OpenFileInfo {
+    pub path: PathBuf,
+    pub readable: bool,
+    pub writable: bool,
+    pub offset: u64,
+    pub refs: usize, // references besides the table's own, 0 once every holder dropped it
+}
+
+impl OpenFileInfo {
+    pub fn mode(&self) -> &'static str {
+        match (self.readable, self.writable) {
+            (true, true) => "rw",
+            (true, false) => "r",
+            (false, true) => "w",
+            (false, false) => "-",
+        }
+    }
+}
+
+// the files in use in the table, for finding leaked handles
+pub fn lsof() -> Vec<OpenFileInfo> {
+    let ft = lock_table();
+    ft.iter()
+        .filter_map(|f| {
+            let inner = f.0.lock().unwrap();
+            (inner.ty != FDType::Free).then(|| OpenFileInfo {
+                path: inner.path.clone(),
+                readable: inner.readable,
+                writable: inner.writable,
+                offset: inner.offset,
+                refs: Arc::strong_count(&f.0) - 1,
+            })
+        })
+        .collect()
+}
+
+fn readable(omod: OpenMode) -> bool {
+    omod != OpenMode::OWronly
+}
+
+fn writable(omod: OpenMode) -> bool {
+    !matches!(omod, OpenMode::ORdonly | OpenMode::ODirectory)
+}
+
 /// path should be absolute path
 pub fn fileopen(
     dev: Arc<dyn BlockDevice>,
-    path: &PathBuf,
+    path: &Path,
     omod: OpenMode,
-) -> Result<OpenFile, String> {
-    // if exists in table
-    {
-        let ft = unsafe { FTABLE.0.lock().unwrap() };
-        if let Some(f) = ft.iter().find(|f| f.0.borrow().path == *path) {
-            if omod == OpenMode::OCreate {
-                return Err("file exists".to_string());
-            } else {
-                unsafe {
-                    (*f.0.as_ptr()).offset = 0;
-                }
-                return Ok(f.clone());
-            }
-        }
+) -> Result<OpenFile, FsError> {
+    let writes = writable(omod);
+    if writes && read_only() {
+        return Err(FsError::ReadOnly);
     }
-    // find inode
+    // reclaim first, so a dropped entry is neither reused by path nor counted as in use
+    filereclaim();
+    // every open gets an entry of its own, with its own mode and offset,
+    // only filedup hands out a second reference to one
     let ip;
     log_begin();
     if omod == OpenMode::OCreate {
-        ip = inode::create(dev.clone(), &path, FileType::File);
-        if ip.is_err() {
-            log_end();
-            return Err("file exists".to_string());
+        ip = inode::create(dev.clone(), path, FileType::File);
+        match &ip {
+            Ok(ip) => audit_inode("create", ip),
+            Err(e) => {
+                log_end();
+                return Err(*e);
+            }
         }
     } else {
-        let ip_ = inode::find_inode(dev.clone(), &path);
-        if ip_.is_none() {
+        ip = inode::resolve(dev.clone(), path);
+        if let Err(e) = ip {
             log_end();
-            return Err("file not found".to_string());
+            return Err(e);
         }
-        ip = Ok(ip_.unwrap());
         // check mode
-        if ip
+        let is_dir = ip
             .as_ref()
             .unwrap()
-            .read_disk_inode(|diskinode| omod != OpenMode::ORdonly && diskinode.ftype == 2)
-        {
+            .read_disk_inode(|diskinode| diskinode.ftype == FileType::Dir as u8);
+        if writes && is_dir {
             log_end();
-            return Err("file is a directory".to_string());
+            return Err(FsError::IsDirectory);
+        }
+        if omod == OpenMode::ODirectory && !is_dir {
+            log_end();
+            return Err(FsError::NotDirectory);
         }
         if omod == OpenMode::OTrunc {
             ip.as_ref().unwrap().modify_disk_inode(|diskinode| {
-                diskinode.size = 0;
-                Inode::truncate(dev.clone(), diskinode);
+                if diskinode.ftype != FileType::Device as u8 {
+                    diskinode.size = 0;
+                    Inode::truncate(dev.clone(), diskinode);
+                }
             });
         }
     }
@@ -133,119 +246,654 @@ pub fn fileopen(
     // alloc file
     let file = filealloc();
     if file.is_none() {
-        return Err("no free file in table".to_string());
+        return Err(FsError::TooManyOpenFiles);
     }
     let file = file.unwrap();
-    let mut file_ptr = file.0.as_ptr();
-    unsafe {
-        (*file_ptr).ty = FDType::INODE;
-        (*file_ptr).readable = omod == OpenMode::ORdonly || omod == OpenMode::ORdwr;
-        (*file_ptr).writable = omod == OpenMode::OWronly || omod == OpenMode::ORdwr;
-        (*file_ptr).offset = 0;
-        (*file_ptr).path = path.clone();
-        (*file_ptr).ip = Some(ip.unwrap());
-        (*file_ptr).dev = Some(dev);
-    }
+    let ip = ip.unwrap();
+    let (ftype, generation) =
+        ip.read_disk_inode(|diskinode| (diskinode.ftype, diskinode.generation));
+    let (ty, pipe) = match ftype {
+        ftype if ftype == FileType::Device as u8 => (FDType::Device, None),
+        ftype if ftype == FileType::Fifo as u8 => (
+            FDType::Pipe,
+            Some(pipe_open(
+                dev.clone(),
+                ip.0.inum,
+                generation,
+                readable(omod),
+                writable(omod),
+            )),
+        ),
+        _ => (FDType::INODE, None),
+    };
+    *file.0.lock().unwrap() = FileInner {
+        ty,
+        readable: readable(omod),
+        writable: writable(omod),
+        offset: 0,
+        seek: Arc::default(),
+        path: path.to_path_buf(),
+        handle: Some(ip.handle()),
+        ip: Some(ip),
+        dev: Some(dev),
+        nobarrier: false,
+        sync: omod == OpenMode::OSync,
+        direct: false,
+        pipe,
+    };
 
     Ok(file)
 }
 
-pub fn mkdir(dev: Arc<dyn BlockDevice>, path: &PathBuf) -> Result<(), String> {
+/// like fileopen, but the writes through the file skip the log.
+/// this trades crash safety for speed: the data is only on disk after sync_all,
+/// and a crash before that can leave the file and the bitmap inconsistent
+pub fn fileopen_nobarrier(
+    dev: Arc<dyn BlockDevice>,
+    path: &Path,
+    omod: OpenMode,
+) -> Result<OpenFile, FsError> {
+    let file = fileopen(dev, path, omod)?;
+    file.0.lock().unwrap().nobarrier = true;
+    Ok(file)
+}
+
+// a transfer of at least this many bytes through the cache would evict all of it
+pub const DIRECT_MIN: u64 = (BLOCK_NUM * BLOCK_SIZE) as u64;
+
+/// like fileopen, but the whole blocks read and written through the file
+/// that are not cached go straight to the device and stay out of the cache,
+/// for streaming a large file once. the head and tail of an unaligned
+/// transfer still go through it
+pub fn fileopen_direct(
+    dev: Arc<dyn BlockDevice>,
+    path: &Path,
+    omod: OpenMode,
+) -> Result<OpenFile, FsError> {
+    let file = fileopen(dev, path, omod)?;
+    file.0.lock().unwrap().direct = true;
+    Ok(file)
+}
+
+// the inode and its size, for the audit log
+fn audit_inode(op: &str, ip: &InodePtr) {
+    if auditing() {
+        audit(
+            op,
+            ip.0.inum,
+            ip.read_disk_inode(|diskinode| diskinode.size),
+        );
+    }
+}
+
+pub fn mkdir(dev: Arc<dyn BlockDevice>, path: &Path) -> Result<(), FsError> {
     log_begin();
     let ret = inode::create(dev.clone(), path, FileType::Dir);
     log_end();
     match ret {
-        Ok(_) => Ok(()),
+        Ok(ip) => {
+            audit_inode("mkdir", &ip);
+            Ok(())
+        }
         Err(e) => Err(e),
     }
 }
 
+// create path and the directories above it that are missing, like mkdir -p.
+// a directory already there is left alone
+pub fn mkdir_all(dev: Arc<dyn BlockDevice>, path: &Path) -> Result<(), FsError> {
+    let path = inode::canonicalize(path)?;
+    let mut dir = PathBuf::from("/");
+    for name in path.iter().skip(1) {
+        dir.push(name);
+        match mkdir(dev.clone(), &dir) {
+            Ok(()) => {}
+            Err(FsError::AlreadyExists) => {
+                let ip = inode::resolve(dev.clone(), &dir)?;
+                if ip.read_disk_inode(|diskinode| diskinode.ftype) != FileType::Dir as u8 {
+                    return Err(FsError::NotDirectory);
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// create a device inode, reads and writes on it go to the handler of major
+#[allow(unused)]
+pub fn mknod(
+    dev: Arc<dyn BlockDevice>,
+    path: &Path,
+    major: u16,
+    minor: u16,
+) -> Result<(), FsError> {
+    log_begin();
+    let ret = inode::create(dev.clone(), path, FileType::Device).map(|ip| {
+        ip.modify_disk_inode(|diskinode| {
+            diskinode.addrs[0] = (major as u32) << 16 | minor as u32;
+        })
+    });
+    log_end();
+    ret
+}
+
+// create a fifo, its bytes pass from writers to readers in memory
+pub fn mkfifo(dev: Arc<dyn BlockDevice>, path: &Path) -> Result<(), FsError> {
+    log_begin();
+    let ret = inode::create(dev.clone(), path, FileType::Fifo);
+    log_end();
+    ret.map(|_| ())
+}
+
+// create a symlink at path holding target,
+// a relative target is looked up from the directory holding the link
+pub fn symlink(dev: Arc<dyn BlockDevice>, target: &str, path: &Path) -> Result<(), FsError> {
+    if target.is_empty() || target.contains('\0') {
+        return Err(FsError::InvalidName);
+    }
+    // a single block, so the write fits in the transaction
+    if target.len() > BLOCK_SIZE as usize {
+        return Err(FsError::NameTooLong);
+    }
+    log_begin();
+    let ret = inode::create(dev.clone(), path, FileType::Symlink)
+        .and_then(|mut ip| winode(&mut ip, target.as_bytes(), 0, target.len()).map(|_| ()));
+    log_end();
+    ret
+}
+
+// a second name dst for the inode at src
+pub fn filelink(dev: Arc<dyn BlockDevice>, src: &Path, dst: &Path) -> Result<(), FsError> {
+    log_begin();
+    let ret = inode::link(dev, src, dst);
+    log_end();
+    ret
+}
+
+// dst shares the data blocks of src until one of them is written
+pub fn filereflink(dev: Arc<dyn BlockDevice>, src: &Path, dst: &Path) -> Result<(), FsError> {
+    log_begin();
+    let ret = inode::reflink(dev, src, dst);
+    log_end();
+    ret.map(|_| ())
+}
+
+// move src to dst, replacing dst if it exists, in one transaction
+pub fn filerename(dev: Arc<dyn BlockDevice>, src: &Path, dst: &Path) -> Result<(), FsError> {
+    log_begin();
+    let ret = inode::rename(dev.clone(), src, dst);
+    log_end();
+    if ret.is_ok() && auditing() {
+        if let Ok(ip) = inode::resolve_nofollow(dev, dst) {
+            audit_inode("rename", &ip);
+        }
+    }
+    ret
+}
+
+// a second handle on the same entry, sharing its offset
+pub fn filedup(file: &OpenFile) -> OpenFile {
+    file.clone()
+}
+
+// make newfd a handle on the entry of oldfd, like dup2.
+// the entry newfd held before is closed, unless it was never opened
+pub fn filedup2(oldfd: &OpenFile, newfd: &mut OpenFile) {
+    if Arc::ptr_eq(&oldfd.0, &newfd.0) {
+        return;
+    }
+    let old = std::mem::replace(newfd, filedup(oldfd));
+    let opened = old.0.lock().unwrap().ty != FDType::Free;
+    if opened {
+        fileclose(old);
+    }
+}
+
 // the owner ship should move to here directly
 // do not clone the Arc pointer
 pub fn fileclose(file: OpenFile) {
     let _ftable = lock_table();
+    // the table holds one reference and file another,
+    // any more are handles still open on the entry
     assert!(Arc::strong_count(&file.0) > 1);
-    if Arc::strong_count(&file.0) >= 2 {
+    if Arc::strong_count(&file.0) > 2 {
         return;
     }
     // clear attribute
     // ty = FileType::Free;
-    let file_ptr = file.0.as_ptr();
-    unsafe { (*file_ptr).ty = FDType::Free };
+    let mut inner = file.0.lock().unwrap();
+    inner.ty = FDType::Free;
     log_begin();
     // the drop of inode will free the inode and put it into inode table
-    unsafe { (*file_ptr).ip = None };
-    unsafe { (*file_ptr).dev = None };
-    unsafe { (*file_ptr).offset = 0 };
+    inner.ip = None;
+    inner.handle = None;
+    inner.dev = None;
+    inner.offset = 0;
     log_end();
+    // the last end of a fifo lets its readers see the end of it
+    inner.pipe = None;
 }
 
-pub fn filestat(file: &OpenFile) -> Stat {
-    let file = file.0.borrow();
-    let ip = file.ip.as_ref().unwrap();
+// the inode of an open file, StaleHandle if the one it was opened on is gone
+// and its inum holds another, as after the image was changed under the file
+fn file_inode(file: &FileInner) -> Result<InodePtr, FsError> {
+    inode_from_handle(file.dev.clone().unwrap(), file.handle.unwrap())
+}
+
+pub fn filestat(file: &OpenFile) -> Result<Stat, FsError> {
+    let file = file.0.lock().unwrap();
     log_begin();
+    let ip = match file_inode(&file) {
+        Ok(ip) => ip,
+        Err(e) => {
+            log_end();
+            return Err(e);
+        }
+    };
     let ret = ip.read_disk_inode(|diskinode| Stat {
         dev: 0,
         ino: ip.0.inum,
+        generation: diskinode.generation,
         ty: match diskinode.ftype {
             0 => FileType::Free,
             1 => FileType::File,
             2 => FileType::Dir,
+            3 => FileType::Device,
+            4 => FileType::Fifo,
+            5 => FileType::Symlink,
             _ => panic!("unknown file type"),
         },
+        major: match diskinode.ftype {
+            3 => device_number(diskinode).0,
+            _ => 0,
+        },
+        minor: match diskinode.ftype {
+            3 => device_number(diskinode).1,
+            _ => 0,
+        },
         nlink: diskinode.nlink as u32,
         size: diskinode.size,
     });
+    // the last reference to an unlinked inode frees it, in the transaction
+    drop(ip);
     log_end();
-    ret
+    Ok(ret)
+}
+
+pub fn fileread(file: &OpenFile, dst: &mut [u8]) -> Result<usize, FsError> {
+    let Some(seek) = seek_lock(file) else {
+        return filepread(file, dst, 0);
+    };
+    let _seek = seek.lock().unwrap();
+    let offset = file.0.lock().unwrap().offset;
+    let n = filepread(file, dst, offset)?;
+    file.0.lock().unwrap().offset = offset + n as u64;
+    Ok(n)
+}
+
+// the lock to hold across a transfer at the offset of the entry. a fifo has
+// no offset and no lock, a read waiting on it would hold up its writers
+fn seek_lock(file: &OpenFile) -> Option<Arc<Mutex<()>>> {
+    let inner = file.0.lock().unwrap();
+    (inner.ty != FDType::Pipe).then(|| inner.seek.clone())
 }
 
-pub fn fileread(file: &OpenFile, dst: &mut [u8]) -> usize {
-    let mut file_ptr = file.0.as_ptr();
+// read at off and leave the file offset alone,
+// so threads sharing a file can read different parts of it at once.
+// a fifo has no offsets, off is ignored there
+pub fn filepread(file: &OpenFile, dst: &mut [u8], off: u64) -> Result<usize, FsError> {
+    // the entry is only locked to look at it, the transfer runs on what it
+    // refers to, so a read waiting on a fifo does not hold up the entry
+    let inner = file.0.lock().unwrap();
+    if !inner.readable {
+        return Err(FsError::BadFileDescriptor);
+    }
+    if dst.is_empty() {
+        return Ok(0);
+    }
+    if inner.ty == FDType::Device {
+        let handler = device_handler(&inner);
+        drop(inner);
+        return Ok(handler.map_or(0, |(handler, minor)| handler.read(minor, dst, off)));
+    }
+    if inner.ty == FDType::Pipe {
+        let pipe = inner.pipe.clone().unwrap();
+        drop(inner);
+        return Ok(pipe.read(dst));
+    }
+    let (ip, direct) = (file_inode(&inner), inner.direct);
+    drop(inner);
+    let mut ip = ip?;
     log_begin();
-    let n = rinode(
-        unsafe { (*file_ptr).ip.as_mut().unwrap() },
-        dst,
-        unsafe { (*file_ptr).offset } as usize,
-        dst.len(),
-    );
+    let n = rinode_with(&mut ip, dst, off as usize, dst.len(), direct);
+    // the last reference to an unlinked inode frees it, in the transaction
+    drop(ip);
     log_end();
-    unsafe { (*file_ptr).offset += n as u32 };
     n
 }
 
-pub fn filewrite(file: &OpenFile, src: &[u8]) -> usize {
-    let mut file_ptr = file.0.as_ptr();
+// read from the offset to the end of the file
+pub fn file_read_to_end(file: &OpenFile) -> Result<Vec<u8>, FsError> {
+    let mut data = vec![];
+    let mut buf = [0u8; BLOCK_SIZE as usize];
+    loop {
+        let n = fileread(file, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        data.extend_from_slice(&buf[..n]);
+    }
+    Ok(data)
+}
+
+// the used entries of an open directory, read one at a time,
+// so a large directory is never held in memory. it keeps its own offset,
+// opening the directory again while iterating resets the file offset
+pub struct ReadDir<'a> {
+    file: &'a OpenFile,
+    off: u64,
+}
+
+impl Iterator for ReadDir<'_> {
+    type Item = DirEntry;
+
+    fn next(&mut self) -> Option<DirEntry> {
+        let mut buf = [0u8; std::mem::size_of::<DirEntry>()];
+        while filepread(self.file, &mut buf, self.off) == Ok(buf.len()) {
+            self.off += buf.len() as u64;
+            let entry = unsafe {
+                std::mem::transmute::<[u8; std::mem::size_of::<DirEntry>()], DirEntry>(buf)
+            }
+            .to_le();
+            if entry.inum != 0 && entry_in_range(&entry) {
+                return Some(entry);
+            }
+        }
+        None
+    }
+}
+
+pub fn readdir(file: &OpenFile) -> ReadDir<'_> {
+    ReadDir { file, off: 0 }
+}
+
+// the SHA-256 of the whole file, streamed a block at a time.
+// reads from the start and leaves the offset alone
+pub fn filehash(file: &OpenFile) -> Result<[u8; 32], FsError> {
+    let mut sha = Sha256::new();
+    let mut buf = [0u8; BLOCK_SIZE as usize];
+    let mut off = 0;
+    loop {
+        let n = filepread(file, &mut buf, off)?;
+        if n == 0 {
+            break;
+        }
+        sha.update(&buf[..n]);
+        off += n as u64;
+    }
+    Ok(sha.finalize())
+}
+
+// fill the whole buf, fileread may return less than asked for
+#[allow(unused)]
+pub fn file_read_exact(file: &OpenFile, buf: &mut [u8]) -> Result<(), FsError> {
+    let mut tot = 0;
+    while tot < buf.len() {
+        let n = fileread(file, &mut buf[tot..])?;
+        if n == 0 {
+            return Err(FsError::UnexpectedEof);
+        }
+        tot += n;
+    }
+    Ok(())
+}
+
+pub fn filewrite(file: &OpenFile, src: &[u8]) -> Result<usize, FsError> {
+    let Some(seek) = seek_lock(file) else {
+        return filepwrite(file, src, 0);
+    };
+    let _seek = seek.lock().unwrap();
+    let offset = file.0.lock().unwrap().offset;
+    let n = filepwrite(file, src, offset)?;
+    file.0.lock().unwrap().offset = offset + n as u64;
+    Ok(n)
+}
+
+// write all of src a block at a time, so no transaction outgrows the log
+pub fn filewrite_all(file: &OpenFile, src: &[u8]) -> Result<(), FsError> {
+    for chunk in src.chunks(BLOCK_SIZE as usize) {
+        if filewrite(file, chunk)? < chunk.len() {
+            return Err(short_write(file));
+        }
+    }
+    Ok(())
+}
+
+// why a write stopped short: the file reached MAXFILE blocks, or the device is full
+fn short_write(file: &OpenFile) -> FsError {
+    if file.0.lock().unwrap().offset >= (MAXFILE * BLOCK_SIZE) as u64 {
+        FsError::FileTooBig
+    } else {
+        FsError::NoSpace
+    }
+}
+
+// gathers small writes into whole blocks, so a run of small writes costs a
+// transaction per block instead of a read-modify-write of the same block per
+// write. the buffer ends on a block boundary of the file, the tail is written
+// by flush or on drop
+pub struct FileWriter {
+    file: OpenFile,
+    buf: Vec<u8>,
+}
+
+impl FileWriter {
+    pub fn new(file: OpenFile) -> Self {
+        Self {
+            file,
+            buf: Vec::with_capacity(BLOCK_SIZE as usize),
+        }
+    }
+
+    // write the tail and hand the file back, to be closed.
+    // whether the tail made it is for a flush before to tell
+    pub fn into_inner(mut self) -> OpenFile {
+        let _ = self.write_buf();
+        std::mem::take(&mut self.file)
+    }
+
+    // the bytes the buffer holds when it reaches the next block boundary
+    fn block_end(&self) -> usize {
+        let offset = self.file.0.lock().unwrap().offset;
+        (BLOCK_SIZE as u64 - offset % BLOCK_SIZE as u64) as usize
+    }
+
+    // the bytes not written stay in the buffer
+    fn write_buf(&mut self) -> Result<(), FsError> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let n = filewrite(&self.file, &self.buf)?;
+        self.buf.drain(..n);
+        if !self.buf.is_empty() {
+            return Err(short_write(&self.file));
+        }
+        Ok(())
+    }
+}
+
+impl Write for FileWriter {
+    // takes at most what fills the buffer to the block boundary
+    fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+        let n = src.len().min(self.block_end() - self.buf.len());
+        self.buf.extend_from_slice(&src[..n]);
+        if self.buf.len() == self.block_end() {
+            self.write_buf().map_err(io::Error::other)?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_buf().map_err(io::Error::other)
+    }
+}
+
+impl Drop for FileWriter {
+    fn drop(&mut self) {
+        let _ = self.write_buf();
+    }
+}
+
+// write at off and leave the file offset alone, like filepread
+pub fn filepwrite(file: &OpenFile, src: &[u8], off: u64) -> Result<usize, FsError> {
+    let inner = file.0.lock().unwrap();
+    if !inner.writable {
+        return Err(FsError::BadFileDescriptor);
+    }
+    if src.is_empty() {
+        return Ok(0);
+    }
+    if inner.ty == FDType::Device {
+        let handler = device_handler(&inner);
+        drop(inner);
+        return Ok(handler.map_or(0, |(handler, minor)| handler.write(minor, src, off)));
+    }
+    if inner.ty == FDType::Pipe {
+        let pipe = inner.pipe.clone().unwrap();
+        drop(inner);
+        return Ok(pipe.write(src));
+    }
+    let (ip, direct) = (file_inode(&inner), inner.direct);
+    let (dev, nobarrier_write, sync) = (inner.dev.clone().unwrap(), inner.nobarrier, inner.sync);
+    drop(inner);
+    let mut ip = ip?;
+    if nobarrier_write {
+        // the inode is let go inside too, like the write it skips the log
+        return nobarrier(move || {
+            let n = winode_with(&mut ip, src, off as usize, src.len(), direct);
+            audit_inode("write", &ip);
+            n
+        });
+    }
     log_begin();
-    let n = winode(
-        unsafe { (*file_ptr).ip.as_mut().unwrap() },
-        src,
-        unsafe { (*file_ptr).offset } as usize,
-        src.len(),
-    );
-    log_end();
-    unsafe { (*file_ptr).offset += n as u32 };
+    let n = winode_with(&mut ip, src, off as usize, src.len(), direct);
+    audit_inode("write", &ip);
+    drop(ip);
+    if sync {
+        log_end_sync();
+        dev.flush();
+    } else {
+        log_end();
+    }
     n
 }
 
-pub fn fileseek(file: &mut OpenFile, offset: usize, whence: usize) -> Result<(), String> {
-    let mut file_ptr = file.0.as_ptr();
+// like lseek(SEEK_DATA): the first offset at or after off inside a mapped block,
+// None if off is past the end of the file or only holes follow
+pub fn file_next_data(file: &OpenFile, off: u64) -> Result<Option<u64>, FsError> {
+    let file = file.0.lock().unwrap();
+    let ip = file.ip.as_ref().unwrap();
+    let dev = file.dev.as_ref().unwrap();
+    ip.read_disk_inode(|diskinode| {
+        let size = diskinode.size as u64;
+        if off >= size {
+            return Ok(None);
+        }
+        // inline bytes have no holes
+        if is_inline(diskinode) {
+            return Ok(Some(off));
+        }
+        for bn in off / BLOCK_SIZE as u64..size.div_ceil(BLOCK_SIZE as u64) {
+            if block_lookup(diskinode, dev.clone(), bn as u32)? != 0 {
+                return Ok(Some(off.max(bn * BLOCK_SIZE as u64)));
+            }
+        }
+        Ok(None)
+    })
+}
+
+// like lseek(SEEK_HOLE): the first offset at or after off inside a hole,
+// the end of the file counts as a hole, None if off is past it
+pub fn file_next_hole(file: &OpenFile, off: u64) -> Result<Option<u64>, FsError> {
+    let file = file.0.lock().unwrap();
+    let ip = file.ip.as_ref().unwrap();
+    let dev = file.dev.as_ref().unwrap();
+    ip.read_disk_inode(|diskinode| {
+        let size = diskinode.size as u64;
+        if off >= size {
+            return Ok(None);
+        }
+        if is_inline(diskinode) {
+            return Ok(Some(size));
+        }
+        for bn in off / BLOCK_SIZE as u64..size.div_ceil(BLOCK_SIZE as u64) {
+            if block_lookup(diskinode, dev.clone(), bn as u32)? == 0 {
+                return Ok(Some(off.max(bn * BLOCK_SIZE as u64)));
+            }
+        }
+        Ok(Some(size))
+    })
+}
+
+// the disk block behind each block of the file, None for a hole, for tools
+// that copy or dedup what a file holds without reading it through the file.
+// only looks the blocks up, nothing is allocated. an inline file has its
+// bytes in the inode and no blocks
+#[allow(unused)]
+pub fn file_block_map(file: &OpenFile) -> Result<Vec<Option<u32>>, FsError> {
+    let file = file.0.lock().unwrap();
+    let ip = file.ip.as_ref().unwrap();
+    let dev = file.dev.as_ref().unwrap();
+    ip.read_disk_inode(|diskinode| {
+        if is_inline(diskinode) {
+            return Ok(vec![]);
+        }
+        (0..diskinode.size.div_ceil(BLOCK_SIZE))
+            .map(|bn| {
+                block_lookup(diskinode, dev.clone(), bn)
+                    .map(|addr| Some(addr).filter(|&addr| addr != 0))
+            })
+            .collect()
+    })
+}
+
+pub fn fileseek(file: &mut OpenFile, offset: u64, whence: usize) -> Result<(), String> {
+    // waits out a transfer through a dup'ed handle, it would move the offset after
+    let seek = seek_lock(file);
+    let _seek = seek.as_ref().map(|seek| seek.lock().unwrap());
+    let mut inner = file.0.lock().unwrap();
     match whence {
         0 => {
-            unsafe { (*file_ptr).offset = offset as u32 };
+            inner.offset = offset;
         }
         1 => {
-            unsafe { (*file_ptr).offset += offset as u32 };
+            inner.offset += offset;
         }
         2 => {
-            unsafe {
-                (*file_ptr).offset = (*file_ptr)
-                    .ip
-                    .as_ref()
-                    .unwrap()
-                    .read_disk_inode(|diskinode| diskinode.size)
-                    - offset as u32
+            let size = inner
+                .ip
+                .as_ref()
+                .unwrap()
+                .read_disk_inode(|diskinode| diskinode.size);
+            let Some(offset) = (size as u64).checked_sub(offset) else {
+                return Err("filelseek: offset before the start of the file".to_string());
             };
+            inner.offset = offset;
+        }
+        // SEEK_DATA and SEEK_HOLE, the next data or hole at or after offset
+        3 | 4 => {
+            drop(inner);
+            let next = if whence == 3 {
+                file_next_data(file, offset)
+            } else {
+                file_next_hole(file, offset)
+            };
+            match next {
+                Ok(Some(next)) => file.0.lock().unwrap().offset = next,
+                Ok(None) => return Err("filelseek: offset past the end of the file".to_string()),
+                Err(e) => return Err(format!("filelseek: {}", e)),
+            }
         }
         _ => {
             return Err("filelseek: invalid whence".to_string());
@@ -255,13 +903,17 @@ pub fn fileseek(file: &mut OpenFile, offset: usize, whence: usize) -> Result<(),
 }
 
 pub fn fileunlink(dev: Arc<dyn BlockDevice>, path: &PathBuf) -> Result<(), String> {
+    if read_only() {
+        return Err(format!("fileunlink: {}", FsError::ReadOnly));
+    }
     log_begin();
     let dp = find_parent_inode(dev.clone(), path);
     if dp.is_none() {
         return Err("fileunlink: cannot find parent inode".to_string());
     }
     let mut dp = dp.unwrap();
-    let ip = find_inode(dev, path);
+    // a symlink is removed itself, not its target
+    let ip = inode::resolve_nofollow(dev, path).ok();
     if ip.is_none() {
         return Err("fileunlink: cannot find inode".to_string());
     }
@@ -270,12 +922,1630 @@ pub fn fileunlink(dev: Arc<dyn BlockDevice>, path: &PathBuf) -> Result<(), Strin
         .unwrap()
         .read_disk_inode(|diskinode| diskinode.ftype);
     let name = path.file_name().unwrap().to_str().unwrap();
-    dirunlink(&mut dp, name)?;
-    if ty == FileType::Dir as u16 {
+    if let Err(e) = dirunlink(&mut dp, name) {
+        log_end();
+        return Err(format!("fileunlink: {}", e));
+    }
+    let ip = ip.unwrap();
+    // a corrupt nlink lower than the links going stops at 0, fsck reports it
+    if ty == FileType::Dir as u8 {
+        // the entry and "." of ip go, and so does the ".." linking dp
+        ip.modify_disk_inode(|diskinode| {
+            diskinode.nlink = diskinode.nlink.saturating_sub(DIR_NLINK);
+        });
         dp.modify_disk_inode(|diskinode| {
-            diskinode.nlink -= 1;
+            diskinode.nlink = diskinode.nlink.saturating_sub(1);
         });
-    };
+    } else {
+        ip.modify_disk_inode(|diskinode| {
+            diskinode.nlink = diskinode.nlink.saturating_sub(1);
+        });
+    }
+    audit_inode("unlink", &ip);
+    // the last reference frees the inode, that must be part of the transaction
+    drop(ip);
     log_end();
     Ok(())
 }
+
+// an entry removing a path unlinks
+#[derive(Debug, Clone, PartialEq)]
+pub struct Removal {
+    pub path: PathBuf,
+    pub inum: u32,
+    // the blocks freed with the inode, None while another link keeps it
+    pub freed: Option<u32>,
+}
+
+// what removing path does, without changing anything: the entries unlinked,
+// those under a directory before it, and the inodes and blocks that go with
+// them. a non-empty directory is only removed with recursive. a block shared
+// by a reflink is not counted, it stays with its other owners
+pub fn removal_plan(
+    dev: Arc<dyn BlockDevice>,
+    path: &Path,
+    recursive: bool,
+) -> Result<Vec<Removal>, FsError> {
+    let mut plan = vec![];
+    // the links left to each inode, for hard links within the tree
+    let mut links = HashMap::new();
+    plan_removal(dev, path, recursive, &mut links, &mut plan)?;
+    Ok(plan)
+}
+
+fn plan_removal(
+    dev: Arc<dyn BlockDevice>,
+    path: &Path,
+    recursive: bool,
+    links: &mut HashMap<u32, u16>,
+    plan: &mut Vec<Removal>,
+) -> Result<(), FsError> {
+    if path.parent().is_none() {
+        return Err(FsError::InvalidName);
+    }
+    let ip = inode::resolve_nofollow(dev.clone(), path)?;
+    let dinode = ip.read_disk_inode(|diskinode| *diskinode);
+    let last = if dinode.ftype == FileType::Dir as u8 {
+        let entries = dir_entries(dev.clone(), &dinode)?;
+        if !entries.is_empty() && !recursive {
+            return Err(FsError::NotEmpty);
+        }
+        for entry in entries.iter() {
+            let child = path.join(entry_name(entry));
+            plan_removal(dev.clone(), &child, recursive, links, plan)?;
+        }
+        // its subdirectories and their ".." went first
+        true
+    } else {
+        let left = links.entry(ip.0.inum).or_insert(dinode.nlink);
+        *left = left.saturating_sub(1);
+        *left == 0
+    };
+    let freed = last.then(|| {
+        inode_blocks(dev.clone(), &sb(), &dinode)
+            .into_iter()
+            .filter(|&b| block_refs(dev.clone(), b) == 0)
+            .count() as u32
+            + (xattr_block(dev.clone(), ip.0.inum) != 0) as u32
+    });
+    plan.push(Removal {
+        path: path.to_path_buf(),
+        inum: ip.0.inum,
+        freed,
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::fs::{
+        buffer::{buffer_cached, sync_all, wait_buffer_block},
+        fs::{BPB, MAXFILE, NDIRECT, ROOTINO},
+        fsck::{fsck, rebuild_bitmap, Problem},
+        log::log_stats,
+        pipe::PIPESIZE,
+        superblock::{mark_in_use, sb, write_superblock, RO_COMPAT_REFLINK},
+        testutil::{mount_on, CrashDisk, TestImage},
+    };
+
+    #[test]
+    fn test_next_data_and_hole() {
+        let image = TestImage::new("file_holes");
+        let dev = image.mount();
+        let mut file = fileopen(dev.clone(), &PathBuf::from("/sparse"), OpenMode::OCreate).unwrap();
+        let block = [0xa5u8; BLOCK_SIZE as usize];
+        // data in block 0, a hole in blocks 1..4, data in block 4,
+        // then a hole running into the indirect blocks up to block 20
+        filewrite(&file, &block).unwrap();
+        fileseek(&mut file, 4 * BLOCK_SIZE as u64, 0).unwrap();
+        filewrite(&file, &block).unwrap();
+        fileseek(&mut file, 20 * BLOCK_SIZE as u64 + 10, 0).unwrap();
+        filewrite(&file, &[1u8; 10]).unwrap();
+        let bs = BLOCK_SIZE as u64;
+        let size = 20 * bs + 20;
+
+        assert_eq!(file_next_data(&file, 0).unwrap(), Some(0));
+        assert_eq!(file_next_hole(&file, 0).unwrap(), Some(bs));
+        assert_eq!(file_next_hole(&file, 100).unwrap(), Some(bs));
+        assert_eq!(file_next_data(&file, bs).unwrap(), Some(4 * bs));
+        assert_eq!(file_next_hole(&file, 2 * bs + 7).unwrap(), Some(2 * bs + 7));
+        assert_eq!(file_next_data(&file, 4 * bs + 3).unwrap(), Some(4 * bs + 3));
+        assert_eq!(file_next_hole(&file, 4 * bs).unwrap(), Some(5 * bs));
+        assert_eq!(file_next_data(&file, 5 * bs).unwrap(), Some(20 * bs));
+        // the end of the file is a hole
+        assert_eq!(file_next_hole(&file, 20 * bs).unwrap(), Some(size));
+        assert_eq!(file_next_data(&file, size).unwrap(), None);
+        assert_eq!(file_next_hole(&file, size).unwrap(), None);
+        // an offset past what a u32 holds is past the end, not wrapped into the file
+        assert_eq!(file_next_data(&file, u32::MAX as u64 + 1).unwrap(), None);
+        assert_eq!(file_next_hole(&file, 1 << 32).unwrap(), None);
+        // fileseek moves the offset there, as lseek with SEEK_DATA and SEEK_HOLE
+        fileseek(&mut file, bs, 3).unwrap();
+        assert_eq!(file.0.lock().unwrap().offset, 4 * bs);
+        fileseek(&mut file, 4 * bs, 4).unwrap();
+        assert_eq!(file.0.lock().unwrap().offset, 5 * bs);
+        assert!(fileseek(&mut file, size, 3).is_err());
+        assert_eq!(file.0.lock().unwrap().offset, 5 * bs);
+
+        // reading the hole gives zeros and leaves it unallocated
+        let mut buf = [0xffu8; BLOCK_SIZE as usize];
+        fileseek(&mut file, 2 * BLOCK_SIZE as u64, 0).unwrap();
+        assert_eq!(fileread(&file, &mut buf), Ok(BLOCK_SIZE as usize));
+        assert!(buf.iter().all(|b| *b == 0));
+        assert_eq!(file_next_hole(&file, bs).unwrap(), Some(bs));
+        fileclose(file);
+    }
+
+    #[test]
+    fn test_file_block_map() {
+        let image = TestImage::new("file_block_map");
+        let dev = image.mount();
+        let file = fileopen(dev.clone(), &PathBuf::from("/sparse"), OpenMode::OCreate).unwrap();
+        // blocks 0 and 4 and the first indirect one written, holes between
+        let written = [0, 4, NDIRECT];
+        for bn in written {
+            filepwrite(
+                &file,
+                &[bn as u8 + 1; BLOCK_SIZE as usize],
+                (bn * BLOCK_SIZE) as u64,
+            )
+            .unwrap();
+        }
+        let map = file_block_map(&file).unwrap();
+        assert_eq!(map.len(), NDIRECT as usize + 1);
+        for (bn, addr) in map.iter().enumerate() {
+            assert_eq!(
+                addr.is_some(),
+                written.contains(&(bn as u32)),
+                "block {}",
+                bn
+            );
+        }
+        // each block on the disk holds what was written there
+        sync_all();
+        for bn in written {
+            let mut buf = [0u8; BLOCK_SIZE as usize];
+            dev.read_block(map[bn as usize].unwrap(), &mut buf);
+            assert!(buf.iter().all(|&b| b == bn as u8 + 1), "block {}", bn);
+        }
+        // looking did not fill the holes
+        assert_eq!(file_next_hole(&file, 0).unwrap(), Some(BLOCK_SIZE as u64));
+        fileclose(file);
+
+        let file = fileopen(dev.clone(), &PathBuf::from("/small"), OpenMode::OCreate).unwrap();
+        filewrite_all(&file, b"small").unwrap();
+        assert_eq!(file_block_map(&file).unwrap(), []);
+        fileclose(file);
+    }
+
+    #[test]
+    fn test_reflink() {
+        let image = TestImage::new("file_reflink");
+        let dev = image.mount();
+        let (src, dst) = (PathBuf::from("/src"), PathBuf::from("/dst"));
+        // 14 blocks, so the copy also goes through the indirect block
+        let nblocks = NDIRECT + 2;
+        let data = (0..nblocks * BLOCK_SIZE)
+            .map(|i| (i / BLOCK_SIZE) as u8)
+            .collect::<Vec<_>>();
+        let file = fileopen(dev.clone(), &src, OpenMode::OCreate).unwrap();
+        filewrite(&file, &data).unwrap();
+        fileclose(file);
+        filereflink(dev.clone(), &src, &dst).unwrap();
+        assert_eq!(
+            filereflink(dev.clone(), &src, &dst),
+            Err(FsError::AlreadyExists)
+        );
+        let blocks = |path: &PathBuf| {
+            resolve(dev.clone(), path)
+                .unwrap()
+                .read_disk_inode(|diskinode| {
+                    (0..nblocks)
+                        .map(|bn| block_lookup(diskinode, dev.clone(), bn).unwrap())
+                        .collect::<Vec<_>>()
+                })
+        };
+        let shared = blocks(&src);
+        assert_eq!(blocks(&dst), shared);
+        assert!(shared.iter().all(|b| block_refs(dev.clone(), *b) == 1));
+
+        // overwrite one direct and one indirect block of the copy
+        let mut file = fileopen(dev.clone(), &dst, OpenMode::OWronly).unwrap();
+        for bn in [3, NDIRECT + 1] {
+            fileseek(&mut file, (bn * BLOCK_SIZE) as u64, 0).unwrap();
+            filewrite(&file, &[0xffu8; BLOCK_SIZE as usize]).unwrap();
+        }
+        fileclose(file);
+        let copied = blocks(&dst);
+        for bn in 0..nblocks as usize {
+            if bn == 3 || bn == NDIRECT as usize + 1 {
+                assert_ne!(copied[bn], shared[bn]);
+                assert_eq!(block_refs(dev.clone(), shared[bn]), 0);
+            } else {
+                assert_eq!(copied[bn], shared[bn]);
+            }
+        }
+        assert_eq!(blocks(&src), shared);
+
+        // the source still reads the original data
+        let read = |path: &PathBuf| {
+            let file = fileopen(dev.clone(), path, OpenMode::ORdonly).unwrap();
+            let mut buf = vec![0u8; data.len()];
+            assert_eq!(fileread(&file, &mut buf), Ok(data.len()));
+            fileclose(file);
+            buf
+        };
+        assert_eq!(read(&src), data);
+        let mut expected = data.clone();
+        for bn in [3, NDIRECT + 1] {
+            let off = (bn * BLOCK_SIZE) as usize;
+            expected[off..off + BLOCK_SIZE as usize].fill(0xff);
+        }
+        assert_eq!(read(&dst), expected);
+    }
+
+    #[test]
+    fn test_reflink_without_map() {
+        let image = TestImage::new("file_reflink_without_map");
+        let dev = image.mount();
+        let mut old = sb();
+        old.feature_ro_compat &= !RO_COMPAT_REFLINK;
+        write_superblock(dev.clone(), &old);
+        let (src, dst) = (PathBuf::from("/src"), PathBuf::from("/dst"));
+        let file = fileopen(dev.clone(), &src, OpenMode::OCreate).unwrap();
+        filewrite_all(&file, &[1u8; 2 * BLOCK_SIZE as usize]).unwrap();
+        fileclose(file);
+        assert_eq!(
+            filereflink(dev.clone(), &src, &dst),
+            Err(FsError::NotSupported)
+        );
+        assert_eq!(resolve(dev.clone(), &dst).err(), Some(FsError::NotFound));
+        // without the map no block counts as shared, the blocks go on unlink
+        let block = resolve(dev.clone(), &src)
+            .unwrap()
+            .read_disk_inode(|diskinode| diskinode.addrs[0]);
+        assert_eq!(block_refs(dev.clone(), block), 0);
+        fileunlink(dev.clone(), &src).unwrap();
+        sync_all();
+        assert_eq!(fsck(image.disk()), []);
+    }
+
+    #[test]
+    fn test_unshare_no_space() {
+        let image = TestImage::new("file_unshare_no_space");
+        let dev = image.mount();
+        let (src, dst) = (PathBuf::from("/src"), PathBuf::from("/dst"));
+        let data = [1u8; 2 * BLOCK_SIZE as usize];
+        let file = fileopen(dev.clone(), &src, OpenMode::OCreate).unwrap();
+        filewrite_all(&file, &data).unwrap();
+        fileclose(file);
+        filereflink(dev.clone(), &src, &dst).unwrap();
+        let small = PathBuf::from("/small");
+        let file = fileopen(dev.clone(), &small, OpenMode::OCreate).unwrap();
+        filewrite_all(&file, b"hi").unwrap();
+        fileclose(file);
+        // a file with an indirect block, its reflink needs a block for its own
+        // copy of it. the name of the copy gets a free slot in / to go in
+        let (big, copy) = (PathBuf::from("/big"), PathBuf::from("/copy"));
+        let file = fileopen(dev.clone(), &big, OpenMode::OCreate).unwrap();
+        filewrite_all(&file, &[5u8; ((NDIRECT + 1) * BLOCK_SIZE) as usize]).unwrap();
+        fileclose(file);
+        fileclose(fileopen(dev.clone(), &copy, OpenMode::OCreate).unwrap());
+        // take every free block
+        for i in 0.. {
+            let path = PathBuf::from(format!("/fill{}", i));
+            let file = fileopen(dev.clone(), &path, OpenMode::OCreate).unwrap();
+            let ret = filewrite_all(&file, &[2; (MAXFILE * BLOCK_SIZE) as usize]);
+            fileclose(file);
+            if ret == Err(FsError::NoSpace) {
+                break;
+            }
+            ret.unwrap();
+        }
+        // the reflink fails and takes its new name with it
+        fileunlink(dev.clone(), &copy).unwrap();
+        assert_eq!(filereflink(dev.clone(), &big, &copy), Err(FsError::NoSpace));
+        assert_eq!(resolve(dev.clone(), &copy).err(), Some(FsError::NotFound));
+        // the shared block has no copy to go to, the write stops short
+        let file = fileopen(dev.clone(), &dst, OpenMode::ORdwr).unwrap();
+        assert_eq!(filewrite(&file, &[3; 10]), Ok(0));
+        assert_eq!(filewrite_all(&file, &[3; 10]), Err(FsError::NoSpace));
+        fileclose(file);
+        for path in [&src, &dst] {
+            let file = fileopen(dev.clone(), path, OpenMode::ORdonly).unwrap();
+            assert_eq!(file_read_to_end(&file).unwrap(), data);
+            fileclose(file);
+        }
+        // neither can an inline file move its bytes to a block, they stay inline
+        let mut file = fileopen(dev.clone(), &small, OpenMode::ORdwr).unwrap();
+        let long = [4u8; BLOCK_SIZE as usize];
+        assert_eq!(filewrite_all(&file, &long), Err(FsError::NoSpace));
+        fileseek(&mut file, 0, 0).unwrap();
+        assert_eq!(file_read_to_end(&file).unwrap(), b"hi");
+        fileclose(file);
+        sync_all();
+        assert_eq!(fsck(image.disk()), []);
+    }
+
+    #[test]
+    fn test_nobarrier_write() {
+        let image = TestImage::new("file_nobarrier");
+        let dev = image.mount();
+        let path = PathBuf::from("/bulk");
+        // more blocks than the buffer cache holds, so some are evicted before the sync
+        let data = (0..(NDIRECT + 100) * BLOCK_SIZE)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let file = fileopen_nobarrier(dev.clone(), &path, OpenMode::OCreate).unwrap();
+        for chunk in data.chunks(BLOCK_SIZE as usize * 4) {
+            assert_eq!(filewrite(&file, chunk), Ok(chunk.len()));
+        }
+        fileclose(file);
+        sync_all();
+
+        // a fresh device reads everything from the image
+        let dev = image.mount();
+        let file = fileopen(dev.clone(), &path, OpenMode::ORdonly).unwrap();
+        assert_eq!(filestat(&file).unwrap().size as usize, data.len());
+        let mut buf = vec![0u8; data.len()];
+        assert_eq!(fileread(&file, &mut buf), Ok(data.len()));
+        assert!(buf == data);
+        fileclose(file);
+    }
+
+    #[test]
+    fn test_read_to_end_and_exact() {
+        let image = TestImage::new("file_read_to_end");
+        let dev = image.mount();
+        let path = PathBuf::from("/unaligned");
+        let data = (0..3 * BLOCK_SIZE + 77)
+            .map(|i| (i % 253) as u8)
+            .collect::<Vec<_>>();
+        let mut file = fileopen(dev.clone(), &path, OpenMode::OCreate).unwrap();
+        filewrite(&file, &data).unwrap();
+
+        fileseek(&mut file, 0, 0).unwrap();
+        assert!(file_read_to_end(&file).unwrap() == data);
+        // at the end there is nothing left
+        assert!(file_read_to_end(&file).unwrap().is_empty());
+        fileseek(&mut file, 100, 0).unwrap();
+        assert!(file_read_to_end(&file).unwrap() == data[100..]);
+
+        let mut buf = vec![0u8; BLOCK_SIZE as usize + 10];
+        fileseek(&mut file, 5, 0).unwrap();
+        file_read_exact(&file, &mut buf).unwrap();
+        assert!(buf == data[5..5 + buf.len()]);
+        // a read running past the end fails
+        fileseek(&mut file, data.len() as u64 - 10, 0).unwrap();
+        let mut buf = [0u8; 11];
+        assert_eq!(
+            file_read_exact(&file, &mut buf),
+            Err(FsError::UnexpectedEof)
+        );
+        fileclose(file);
+    }
+
+    // reads fill the buffer with the minor number, writes are collected
+    struct TestDevice(Mutex<Vec<u8>>);
+
+    impl DeviceHandler for TestDevice {
+        fn read(&self, minor: u16, dst: &mut [u8], _offset: u64) -> usize {
+            dst.fill(minor as u8);
+            dst.len()
+        }
+
+        fn write(&self, _minor: u16, src: &[u8], _offset: u64) -> usize {
+            self.0.lock().unwrap().extend_from_slice(src);
+            src.len()
+        }
+    }
+
+    #[test]
+    fn test_device_inode() {
+        let image = TestImage::new("file_device_inode");
+        let dev = image.mount();
+        let handler = Arc::new(TestDevice(Mutex::new(vec![])));
+        register_device(7, handler.clone());
+        let path = PathBuf::from("/tty");
+        mknod(dev.clone(), &path, 7, 3).unwrap();
+        assert_eq!(mknod(dev.clone(), &path, 7, 3), Err(FsError::AlreadyExists));
+
+        // truncating keeps the device number
+        let file = fileopen(dev.clone(), &path, OpenMode::OTrunc).unwrap();
+        let stat = filestat(&file).unwrap();
+        assert!(stat.ty == FileType::Device);
+        assert_eq!((stat.major, stat.minor), (7, 3));
+        assert_eq!(stat.size, 0);
+
+        let mut buf = [0u8; 16];
+        assert_eq!(fileread(&file, &mut buf), Ok(buf.len()));
+        assert_eq!(buf, [3u8; 16]);
+        assert_eq!(filewrite(&file, b"hello"), Ok(5));
+        assert_eq!(*handler.0.lock().unwrap(), b"hello");
+        // nothing went to the data blocks
+        assert_eq!(filestat(&file).unwrap().size, 0);
+        fileclose(file);
+
+        // without a handler the device reads and writes nothing
+        let path = PathBuf::from("/unregistered");
+        mknod(dev.clone(), &path, 8, 0).unwrap();
+        let file = fileopen(dev.clone(), &path, OpenMode::ORdwr).unwrap();
+        assert_eq!(fileread(&file, &mut buf), Ok(0));
+        assert_eq!(filewrite(&file, b"lost"), Ok(0));
+        fileclose(file);
+    }
+
+    #[test]
+    fn test_shared_offset() {
+        let image = TestImage::new("file_shared_offset");
+        let dev = image.mount();
+        let file = fileopen(dev.clone(), &PathBuf::from("/log"), OpenMode::OCreate).unwrap();
+        // the holders of one entry append records side by side, none lands on another
+        let (threads, records, len) = (4u8, 100, 24);
+        std::thread::scope(|scope| {
+            for t in 0..threads {
+                let file = filedup(&file);
+                scope.spawn(move || {
+                    for _ in 0..records {
+                        assert_eq!(filewrite(&file, &vec![t + 1; len]), Ok(len));
+                    }
+                });
+            }
+        });
+        let total = threads as usize * records * len;
+        assert_eq!(filestat(&file).unwrap().size as usize, total);
+
+        // and read them back the same way, each record whole and read once
+        fileseek(&mut file.clone(), 0, 0).unwrap();
+        let counts = std::thread::scope(|scope| {
+            let readers = (0..threads)
+                .map(|_| {
+                    let file = filedup(&file);
+                    scope.spawn(move || {
+                        let mut counts = vec![0; threads as usize];
+                        let mut buf = vec![0u8; len];
+                        while fileread(&file, &mut buf) == Ok(len) {
+                            assert!(buf.iter().all(|&b| b == buf[0]));
+                            counts[buf[0] as usize - 1] += 1;
+                        }
+                        counts
+                    })
+                })
+                .collect::<Vec<_>>();
+            readers
+                .into_iter()
+                .map(|reader| reader.join().unwrap())
+                .fold(vec![0; threads as usize], |mut tot, counts| {
+                    tot.iter_mut().zip(counts).for_each(|(tot, n)| *tot += n);
+                    tot
+                })
+        });
+        assert_eq!(counts, vec![records; threads as usize]);
+        fileclose(file);
+    }
+
+    #[test]
+    fn test_stale_file() {
+        let image = TestImage::new("file_stale");
+        let dev = image.mount();
+        let file = fileopen(dev.clone(), &PathBuf::from("/a"), OpenMode::OCreate).unwrap();
+        assert_eq!(filewrite(&file, b"old"), Ok(3));
+        let inum = filestat(&file).unwrap().ino;
+
+        // the inum now holds another inode, as after the image was changed under the file
+        let ip = get_inode(dev.clone(), inum);
+        log_begin();
+        ip.modify_disk_inode(|diskinode| diskinode.generation += 1);
+        log_end();
+        drop(ip);
+        let mut buf = [0u8; 3];
+        assert_eq!(filepread(&file, &mut buf, 0), Err(FsError::StaleHandle));
+        assert_eq!(filewrite(&file, b"new"), Err(FsError::StaleHandle));
+        assert_eq!(filestat(&file).err(), Some(FsError::StaleHandle));
+        fileclose(file);
+
+        // opened again it is the new inode
+        let file = fileopen(dev.clone(), &PathBuf::from("/a"), OpenMode::ORdonly).unwrap();
+        assert_eq!(fileread(&file, &mut buf), Ok(3));
+        assert_eq!(&buf, b"old");
+        fileclose(file);
+    }
+
+    #[test]
+    fn test_fifo() {
+        let image = TestImage::new("file_fifo");
+        let dev = image.mount();
+        let path = PathBuf::from("/fifo");
+        mkfifo(dev.clone(), &path).unwrap();
+        // more than the pipe holds, so the writer has to wait for the reader
+        let data = (0..8 * PIPESIZE)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let (opened, wait) = std::sync::mpsc::channel();
+        let writer = {
+            let (dev, path, data) = (dev.clone(), path.clone(), data.clone());
+            std::thread::spawn(move || {
+                let file = fileopen(dev, &path, OpenMode::OWronly).unwrap();
+                opened.send(()).unwrap();
+                for chunk in data.chunks(100) {
+                    assert_eq!(filewrite(&file, chunk), Ok(chunk.len()));
+                }
+                fileclose(file);
+            })
+        };
+        // a reader with no writer would see the end of the fifo at once
+        wait.recv().unwrap();
+        let file = fileopen(dev.clone(), &path, OpenMode::ORdonly).unwrap();
+        let mut buf = vec![0u8; data.len()];
+        file_read_exact(&file, &mut buf).unwrap();
+        writer.join().unwrap();
+        assert!(buf == data);
+        // the writer is gone, the fifo is drained
+        assert_eq!(fileread(&file, &mut buf), Ok(0));
+        let stat = filestat(&file).unwrap();
+        assert!(stat.ty == FileType::Fifo);
+        assert_eq!(stat.size, 0);
+        fileclose(file);
+    }
+
+    #[test]
+    fn test_fifo_end() {
+        let image = TestImage::new("file_fifo_end");
+        let dev = image.mount();
+        let path = PathBuf::from("/fifo");
+        mkfifo(dev.clone(), &path).unwrap();
+        let mut buf = [0u8; 8];
+        // no writer, nothing to wait for
+        let reader = fileopen(dev.clone(), &path, OpenMode::ORdonly).unwrap();
+        assert_eq!(fileread(&reader, &mut buf), Ok(0));
+        // what a writer leaves is read after it closes, then the end
+        let writer = fileopen(dev.clone(), &path, OpenMode::OWronly).unwrap();
+        let second = filedup(&writer);
+        filewrite(&writer, b"abc").unwrap();
+        fileclose(writer);
+        assert_eq!(fileread(&reader, &mut buf), Ok(3));
+        filewrite(&second, b"de").unwrap();
+        fileclose(second);
+        assert_eq!(fileread(&reader, &mut buf), Ok(2));
+        assert_eq!(fileread(&reader, &mut buf), Ok(0));
+        fileclose(reader);
+
+        // the buffer goes with the last end, what no reader took is dropped
+        let writer = fileopen(dev.clone(), &path, OpenMode::OWronly).unwrap();
+        filewrite(&writer, b"lost").unwrap();
+        fileclose(writer);
+        let reader = fileopen(dev.clone(), &path, OpenMode::ORdonly).unwrap();
+        assert_eq!(fileread(&reader, &mut buf), Ok(0));
+        fileclose(reader);
+    }
+
+    #[test]
+    fn test_shared_fifo_entry() {
+        let image = TestImage::new("file_shared_fifo");
+        let dev = image.mount();
+        let path = PathBuf::from("/fifo");
+        mkfifo(dev.clone(), &path).unwrap();
+        // one entry, read on another thread and written through a dup of it.
+        // the waiting read must not keep the write out of the entry
+        let file = fileopen(dev.clone(), &path, OpenMode::ORdwr).unwrap();
+        let reader = filedup(&file);
+        let read = std::thread::spawn(move || {
+            let mut buf = [0u8; 8];
+            let n = fileread(&reader, &mut buf).unwrap();
+            fileclose(reader);
+            buf[..n].to_vec()
+        });
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        filewrite(&file, b"ping").unwrap();
+        assert_eq!(read.join().unwrap(), b"ping");
+        fileclose(file);
+    }
+
+    #[test]
+    fn test_lsof() {
+        let image = TestImage::new("file_lsof");
+        let dev = image.mount();
+        let (r, w) = (PathBuf::from("/lsof_r"), PathBuf::from("/lsof_w"));
+        for path in [&r, &w] {
+            log_begin();
+            drop(inode::create(dev.clone(), path, FileType::File).unwrap());
+            log_end();
+        }
+        let rf = fileopen(dev.clone(), &r, OpenMode::ORdonly).unwrap();
+        let wf = fileopen(dev.clone(), &w, OpenMode::OWronly).unwrap();
+        filewrite(&wf, b"abc").unwrap();
+        let find = |path: &PathBuf| {
+            let infos = lsof().into_iter().filter(|info| info.path == *path);
+            infos.collect::<Vec<_>>()
+        };
+        let infos = find(&r);
+        assert_eq!(infos.len(), 1);
+        assert_eq!(
+            (infos[0].mode(), infos[0].offset, infos[0].refs),
+            ("r", 0, 1)
+        );
+        let infos = find(&w);
+        assert_eq!(infos.len(), 1);
+        assert_eq!(
+            (infos[0].mode(), infos[0].offset, infos[0].refs),
+            ("w", 3, 1)
+        );
+        fileclose(rf);
+        fileclose(wf);
+    }
+
+    #[test]
+    fn test_pread_concurrent() {
+        let image = TestImage::new("file_pread");
+        let dev = image.mount();
+        let path = PathBuf::from("/pread");
+        let data = (0..16 * BLOCK_SIZE)
+            .map(|i| (i % 241) as u8)
+            .collect::<Vec<_>>();
+        let file = fileopen(dev.clone(), &path, OpenMode::OCreate).unwrap();
+        assert_eq!(filepwrite(&file, &data, 0), Ok(data.len()));
+        // the explicit offset does not move the file offset
+        assert_eq!(fileread(&file, &mut [0u8; 4]), Ok(4));
+        assert_eq!(filepwrite(&file, &data[..10], 100), Ok(10));
+        let mut buf = [0u8; 4];
+        assert_eq!(filepread(&file, &mut buf, 100), Ok(4));
+        assert_eq!(buf, data[..4]);
+        assert_eq!(fileread(&file, &mut buf), Ok(4));
+        assert_eq!(buf, data[4..8]);
+        fileclose(file);
+
+        let threads = (0..4u32)
+            .map(|t| {
+                let (dev, path, data) = (dev.clone(), path.clone(), data.clone());
+                std::thread::spawn(move || {
+                    // every thread gets an entry of its own
+                    let file = fileopen(dev, &path, OpenMode::ORdonly).unwrap();
+                    for round in 0..64 {
+                        let off = (t * 4 + round % 4) * BLOCK_SIZE + 300 + round;
+                        let mut buf = [0u8; 100];
+                        assert_eq!(filepread(&file, &mut buf, off as u64), Ok(buf.len()));
+                        assert!(buf == data[off as usize..off as usize + buf.len()]);
+                    }
+                    fileclose(file);
+                })
+            })
+            .collect::<Vec<_>>();
+        threads.into_iter().for_each(|t| t.join().unwrap());
+    }
+
+    #[test]
+    fn test_access_mode() {
+        let image = TestImage::new("file_access_mode");
+        let dev = image.mount();
+        let path = PathBuf::from("/f");
+        let file = fileopen(dev.clone(), &path, OpenMode::OCreate).unwrap();
+        assert_eq!(filewrite(&file, b"data"), Ok(4));
+        assert_eq!(filepread(&file, &mut [0u8; 4], 0), Ok(4));
+        fileclose(file);
+
+        let mut buf = [0u8; 4];
+        let file = fileopen(dev.clone(), &path, OpenMode::ORdonly).unwrap();
+        assert_eq!(filewrite(&file, b"lost"), Err(FsError::BadFileDescriptor));
+        assert_eq!(
+            filepwrite(&file, b"lost", 0),
+            Err(FsError::BadFileDescriptor)
+        );
+        // even an empty write is refused, and the offset stays
+        assert_eq!(filewrite(&file, &[]), Err(FsError::BadFileDescriptor));
+        assert_eq!(fileread(&file, &mut buf), Ok(4));
+        assert_eq!(&buf, b"data");
+        fileclose(file);
+
+        let file = fileopen(dev.clone(), &path, OpenMode::OWronly).unwrap();
+        assert_eq!(fileread(&file, &mut buf), Err(FsError::BadFileDescriptor));
+        assert_eq!(
+            filepread(&file, &mut buf, 0),
+            Err(FsError::BadFileDescriptor)
+        );
+        assert_eq!(file_read_to_end(&file), Err(FsError::BadFileDescriptor));
+        assert_eq!(filewrite(&file, b"more"), Ok(4));
+        fileclose(file);
+
+        // a new open does not take the mode of the last one
+        let file = fileopen(dev.clone(), &path, OpenMode::ORdonly).unwrap();
+        assert_eq!(file_read_to_end(&file).unwrap(), b"more");
+        fileclose(file);
+    }
+
+    #[test]
+    fn test_reclaim_dropped_files() {
+        let image = TestImage::new("file_reclaim");
+        let dev = image.mount();
+        mkdir(dev.clone(), &PathBuf::from("/many")).unwrap();
+        // every handle is dropped without fileclose
+        for i in 0..2 * NFILE {
+            let path = PathBuf::from(format!("/many/f{}", i));
+            drop(fileopen(dev.clone(), &path, OpenMode::OCreate).unwrap());
+        }
+        let path = PathBuf::from("/many/f0");
+        let file = fileopen(dev.clone(), &path, OpenMode::ORdonly).unwrap();
+        // only the open file is left in the table
+        let infos = lsof();
+        let many = infos.iter().filter(|info| info.path.starts_with("/many"));
+        assert_eq!(many.map(|info| info.refs).collect::<Vec<_>>(), vec![1]);
+        drop(file);
+        // a reclaimed entry does not hand its old mode to the next open
+        let file = fileopen(dev.clone(), &path, OpenMode::OWronly).unwrap();
+        let inner = file.0.lock().unwrap();
+        assert!(inner.writable && !inner.readable);
+        drop(inner);
+        assert_eq!(filewrite(&file, b"data"), Ok(4));
+        fileclose(file);
+    }
+
+    #[test]
+    fn test_dir_nlink() {
+        let image = TestImage::new("file_dir_nlink");
+        let dev = image.mount();
+        let nlink = |path: &str| {
+            find_inode(dev.clone(), &PathBuf::from(path))
+                .unwrap()
+                .read_disk_inode(|diskinode| diskinode.nlink)
+        };
+        assert_eq!(nlink("/"), DIR_NLINK);
+        mkdir(dev.clone(), &PathBuf::from("/a")).unwrap();
+        assert_eq!(nlink("/a"), DIR_NLINK);
+        assert_eq!(nlink("/"), DIR_NLINK + 1);
+        // files do not link their directory
+        fileclose(fileopen(dev.clone(), &PathBuf::from("/a/f"), OpenMode::OCreate).unwrap());
+        mkdir(dev.clone(), &PathBuf::from("/a/b")).unwrap();
+        assert_eq!(nlink("/a"), DIR_NLINK + 1);
+        sync_all();
+        assert_eq!(fsck(image.disk()), vec![]);
+        fileunlink(dev.clone(), &PathBuf::from("/a/b")).unwrap();
+        assert_eq!(nlink("/a"), DIR_NLINK);
+        assert_eq!(nlink("/"), DIR_NLINK + 1);
+        sync_all();
+        assert_eq!(fsck(image.disk()), vec![]);
+
+        // a corrupt nlink lower than the links going away stops at 0
+        let corrupt = |path: &str| {
+            log_begin();
+            find_inode(dev.clone(), &PathBuf::from(path))
+                .unwrap()
+                .modify_disk_inode(|diskinode| diskinode.nlink = 1);
+            log_end();
+        };
+        for path in ["/c", "/d", "/e"] {
+            mkdir(dev.clone(), &PathBuf::from(path)).unwrap();
+        }
+        corrupt("/c");
+        fileunlink(dev.clone(), &PathBuf::from("/c")).unwrap();
+        corrupt("/e");
+        filerename(dev.clone(), &PathBuf::from("/d"), &PathBuf::from("/e")).unwrap();
+        assert_eq!(nlink("/"), DIR_NLINK + 2);
+        sync_all();
+        assert_eq!(fsck(image.disk()), vec![]);
+    }
+
+    #[test]
+    fn test_open_directory() {
+        let image = TestImage::new("file_open_directory");
+        let dev = image.mount();
+        mkdir(dev.clone(), &PathBuf::from("/dir")).unwrap();
+        fileclose(fileopen(dev.clone(), &PathBuf::from("/f"), OpenMode::OCreate).unwrap());
+
+        assert_eq!(
+            fileopen(dev.clone(), &PathBuf::from("/f"), OpenMode::ODirectory).err(),
+            Some(FsError::NotDirectory)
+        );
+        let dir = fileopen(dev.clone(), &PathBuf::from("/dir"), OpenMode::ODirectory).unwrap();
+        assert_eq!(filestat(&dir).unwrap().ty, FileType::Dir);
+        // a file already open is checked too
+        let file = fileopen(dev.clone(), &PathBuf::from("/f"), OpenMode::ORdonly).unwrap();
+        assert_eq!(
+            fileopen(dev.clone(), &PathBuf::from("/f"), OpenMode::ODirectory).err(),
+            Some(FsError::NotDirectory)
+        );
+        fileclose(file);
+        fileclose(dir);
+        // a directory is not opened for writing
+        assert_eq!(
+            fileopen(dev.clone(), &PathBuf::from("/dir"), OpenMode::ORdwr).err(),
+            Some(FsError::IsDirectory)
+        );
+    }
+
+    #[test]
+    fn test_relative_symlink() {
+        let image = TestImage::new("file_relative_symlink");
+        let dev = image.mount();
+        let inum = |path: &str| resolve(dev.clone(), &PathBuf::from(path)).map(|ip| ip.0.inum);
+        mkdir(dev.clone(), &PathBuf::from("/a")).unwrap();
+        mkdir(dev.clone(), &PathBuf::from("/b")).unwrap();
+        fileclose(fileopen(dev.clone(), &PathBuf::from("/b/file"), OpenMode::OCreate).unwrap());
+
+        // relative to /a, where the link is, not to the root
+        symlink(dev.clone(), "../b/file", &PathBuf::from("/a/link")).unwrap();
+        assert_eq!(inum("/a/link"), inum("/b/file"));
+        // a link in the middle of a path, to a directory
+        symlink(dev.clone(), "./../b", &PathBuf::from("/a/dir")).unwrap();
+        assert_eq!(inum("/a/dir/file"), inum("/b/file"));
+        assert_eq!(inum("/a/dir/../a/link"), inum("/b/file"));
+        symlink(dev.clone(), "/b/file", &PathBuf::from("/a/abs")).unwrap();
+        assert_eq!(inum("/a/abs"), inum("/b/file"));
+
+        let mut link = resolve_nofollow(dev.clone(), &PathBuf::from("/a/link")).unwrap();
+        assert_eq!(readlink(&mut link).unwrap(), "../b/file");
+        drop(link);
+        symlink(dev.clone(), "loop", &PathBuf::from("/a/loop")).unwrap();
+        assert_eq!(inum("/a/loop").err(), Some(FsError::TooManyLinks));
+
+        // unlink removes the link, the target stays
+        fileunlink(dev.clone(), &PathBuf::from("/a/link")).unwrap();
+        assert_eq!(inum("/a/link").err(), Some(FsError::NotFound));
+        assert!(inum("/b/file").is_ok());
+    }
+
+    #[test]
+    fn test_zero_length_io() {
+        let image = TestImage::new("file_zero_length");
+        let dev = image.mount();
+        let path = PathBuf::from("/f");
+        let file = fileopen(dev.clone(), &path, OpenMode::OCreate).unwrap();
+        let dinode = || {
+            find_inode(dev.clone(), &path)
+                .unwrap()
+                .read_disk_inode(|diskinode| *diskinode)
+        };
+
+        // on an empty file, at its start and past its end
+        assert_eq!(filewrite(&file, &[]), Ok(0));
+        assert_eq!(filepwrite(&file, &[], 1000), Ok(0));
+        assert_eq!(dinode().size, 0);
+        assert!(is_inline(&dinode()));
+        assert_eq!(fileread(&file, &mut []), Ok(0));
+
+        // past the end of a file in blocks, no block is mapped and the size stays
+        filewrite(&file, &[1u8; 2 * BLOCK_SIZE as usize]).unwrap();
+        let before = dinode();
+        assert_eq!(filepwrite(&file, &[], 20 * BLOCK_SIZE as u64), Ok(0));
+        assert_eq!(dinode(), before);
+
+        // a read at the end and past it
+        let mut buf = [0u8; 8];
+        assert_eq!(filepread(&file, &mut buf, 2 * BLOCK_SIZE as u64), Ok(0));
+        assert_eq!(filepread(&file, &mut buf, 3 * BLOCK_SIZE as u64), Ok(0));
+        assert_eq!(filepread(&file, &mut [], 0), Ok(0));
+        fileclose(file);
+    }
+
+    #[test]
+    fn test_create_name_taken_by_other_type() {
+        let image = TestImage::new("file_create_other_type");
+        let dev = image.mount();
+        fileclose(fileopen(dev.clone(), &PathBuf::from("/x"), OpenMode::OCreate).unwrap());
+        mkdir(dev.clone(), &PathBuf::from("/d")).unwrap();
+
+        assert_eq!(
+            mkdir(dev.clone(), &PathBuf::from("/x")),
+            Err(FsError::AlreadyExists)
+        );
+        assert_eq!(
+            fileopen(dev.clone(), &PathBuf::from("/d"), OpenMode::OCreate).err(),
+            Some(FsError::AlreadyExists)
+        );
+        assert_eq!(
+            mkfifo(dev.clone(), &PathBuf::from("/x")),
+            Err(FsError::AlreadyExists)
+        );
+        // each name is still there once, with its first type
+        let root = find_inode(dev.clone(), &PathBuf::from("/")).unwrap();
+        let names = root.read_disk_inode(|diskinode| dir_entries(dev.clone(), diskinode).unwrap());
+        let names: Vec<String> = names.iter().map(entry_name).collect();
+        assert_eq!(names.iter().filter(|name| *name == "x").count(), 1);
+        assert_eq!(names.iter().filter(|name| *name == "d").count(), 1);
+        let ftype = |path: &str| {
+            find_inode(dev.clone(), &PathBuf::from(path))
+                .unwrap()
+                .read_disk_inode(|diskinode| diskinode.ftype)
+        };
+        assert_eq!(ftype("/x"), FileType::File as u8);
+        assert_eq!(ftype("/d"), FileType::Dir as u8);
+    }
+
+    #[test]
+    fn test_rename_over_existing() {
+        let image = TestImage::new("file_rename_over");
+        let dev = image.mount();
+        let (src, dst) = (PathBuf::from("/src"), PathBuf::from("/dst"));
+        let file = fileopen(dev.clone(), &src, OpenMode::OCreate).unwrap();
+        filewrite(&file, b"new").unwrap();
+        fileclose(file);
+        let file = fileopen(dev.clone(), &dst, OpenMode::OCreate).unwrap();
+        filewrite(&file, &[7u8; 2 * BLOCK_SIZE as usize]).unwrap();
+        fileclose(file);
+        let old = find_inode(dev.clone(), &dst).unwrap();
+        let (old_inum, old_block) = (old.0.inum, old.read_disk_inode(|d| d.addrs[0]));
+        drop(old);
+        // an open reclaims the entry still holding the old dst
+        fileclose(fileopen(dev.clone(), &PathBuf::from("/"), OpenMode::ORdonly).unwrap());
+
+        filerename(dev.clone(), &src, &dst).unwrap();
+        assert!(find_inode(dev.clone(), &src).is_none());
+        let file = fileopen(dev.clone(), &dst, OpenMode::ORdonly).unwrap();
+        assert_eq!(file_read_to_end(&file).unwrap(), b"new");
+        fileclose(file);
+        // the old dst is freed with its blocks
+        let freed = get_inode(dev.clone(), old_inum).read_disk_inode(|d| *d);
+        assert_eq!(freed.ftype, FileType::Free as u8);
+        let bmapstart = sb().bmapstart;
+        let used = wait_buffer_block(bmapstart + old_block / BPB, dev.clone())
+            .read()
+            .unwrap()
+            .read((old_block % BPB) as usize / 8, |byte: &u8| {
+                byte & (1 << (old_block % 8)) != 0
+            });
+        assert!(!used);
+
+        // an empty directory is replaced by a directory from elsewhere
+        mkdir(dev.clone(), &PathBuf::from("/a")).unwrap();
+        mkdir(dev.clone(), &PathBuf::from("/a/d")).unwrap();
+        mkdir(dev.clone(), &PathBuf::from("/e")).unwrap();
+        filerename(dev.clone(), &PathBuf::from("/a/d"), &PathBuf::from("/e")).unwrap();
+        let e = find_inode(dev.clone(), &PathBuf::from("/e")).unwrap();
+        let parent = find_child(&e, "..").unwrap();
+        assert_eq!(parent.unwrap().0.inum, ROOTINO);
+        drop(e);
+        sync_all();
+        assert_eq!(fsck(image.disk()), vec![]);
+    }
+
+    #[test]
+    fn test_rename_type_mismatch() {
+        let image = TestImage::new("file_rename_mismatch");
+        let dev = image.mount();
+        let path = |p: &str| PathBuf::from(p);
+        fileclose(fileopen(dev.clone(), &path("/f"), OpenMode::OCreate).unwrap());
+        mkdir(dev.clone(), &path("/d")).unwrap();
+        mkdir(dev.clone(), &path("/full")).unwrap();
+        fileclose(fileopen(dev.clone(), &path("/full/x"), OpenMode::OCreate).unwrap());
+
+        assert_eq!(
+            filerename(dev.clone(), &path("/f"), &path("/d")),
+            Err(FsError::IsDirectory)
+        );
+        assert_eq!(
+            filerename(dev.clone(), &path("/d"), &path("/f")),
+            Err(FsError::NotDirectory)
+        );
+        assert_eq!(
+            filerename(dev.clone(), &path("/d"), &path("/full")),
+            Err(FsError::NotEmpty)
+        );
+        // not under itself
+        assert_eq!(
+            filerename(dev.clone(), &path("/full"), &path("/full/sub")),
+            Err(FsError::InvalidName)
+        );
+        // nothing moved
+        for p in ["/f", "/d", "/full/x"] {
+            assert!(find_inode(dev.clone(), &path(p)).is_some());
+        }
+        sync_all();
+        assert_eq!(fsck(image.disk()), vec![]);
+    }
+
+    #[test]
+    fn test_readdir_types() {
+        let image = TestImage::new("file_readdir_types");
+        let dev = image.mount();
+        let path = |p: &str| PathBuf::from(p);
+        mkdir(dev.clone(), &path("/d")).unwrap();
+        mkdir(dev.clone(), &path("/d/sub")).unwrap();
+        fileclose(fileopen(dev.clone(), &path("/d/f"), OpenMode::OCreate).unwrap());
+        mkfifo(dev.clone(), &path("/d/p")).unwrap();
+        symlink(dev.clone(), "f", &path("/d/l")).unwrap();
+        filerename(dev.clone(), &path("/d/sub"), &path("/d/moved")).unwrap();
+        // an open reclaims the entries of the files opened above
+        let dir = fileopen(dev.clone(), &path("/d"), OpenMode::ODirectory).unwrap();
+
+        let types = readdir(&dir)
+            .map(|entry| (entry_name(&entry), FileType::from_u8(entry.ftype).unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            vec![
+                (".".to_string(), FileType::Dir),
+                ("..".to_string(), FileType::Dir),
+                ("f".to_string(), FileType::File),
+                ("p".to_string(), FileType::Fifo),
+                ("l".to_string(), FileType::Symlink),
+                // the rename linked the new name before freeing the old
+                ("moved".to_string(), FileType::Dir),
+            ]
+        );
+        // only the directory itself was opened
+        let open = lsof().into_iter().map(|info| info.path).collect::<Vec<_>>();
+        assert_eq!(open, vec![path("/d")]);
+        fileclose(dir);
+    }
+
+    #[test]
+    fn test_corrupt_dirent() {
+        let image = TestImage::new("file_corrupt_dirent");
+        let dev = image.mount();
+        let path = |p: &str| PathBuf::from(p);
+        mkdir(dev.clone(), &path("/d")).unwrap();
+        fileclose(fileopen(dev.clone(), &path("/d/f"), OpenMode::OCreate).unwrap());
+        // an entry for an inode past the end of the inode blocks
+        let bad = sb().ninodes + 5;
+        let mut dp = find_inode(dev.clone(), &path("/d")).unwrap();
+        log_begin();
+        dirlink(&mut dp, "bad", bad, FileType::File as u8).unwrap();
+        log_end();
+        drop(dp);
+        sync_all();
+        // a fresh mount, so the name index is read from the disk
+        let dev = image.mount();
+
+        assert_eq!(
+            find_inode(dev.clone(), &path("/d/bad")).map(|ip| ip.0.inum),
+            None
+        );
+        assert!(find_inode(dev.clone(), &path("/d/f")).is_some());
+        let dir = fileopen(dev.clone(), &path("/d"), OpenMode::ODirectory).unwrap();
+        let names = readdir(&dir)
+            .map(|entry| entry_name(&entry))
+            .collect::<Vec<_>>();
+        assert_eq!(names, [".", "..", "f"]);
+        fileclose(dir);
+        let d = find_inode(dev.clone(), &path("/d")).unwrap().0.inum;
+        assert_eq!(fsck(dev), vec![Problem::BadEntry { dir: d, inum: bad }]);
+    }
+
+    #[test]
+    fn test_sync_handle() {
+        let image = TestImage::new("file_sync");
+        let dev = image.mount();
+        let path = PathBuf::from("/db");
+        fileclose(fileopen(dev.clone(), &path, OpenMode::OCreate).unwrap());
+        let data = (0..3 * BLOCK_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+
+        // a transaction of another thread holds back the commit,
+        // a sync write waits for it instead of leaving its blocks in the log
+        let (tx, rx) = std::sync::mpsc::channel();
+        let other = std::thread::spawn(move || {
+            log_begin();
+            tx.send(()).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            log_end();
+        });
+        rx.recv().unwrap();
+        let file = fileopen(dev.clone(), &path, OpenMode::OSync).unwrap();
+        assert_eq!(filewrite(&file, &data), Ok(data.len()));
+        fileclose(file);
+
+        // what a crash right now leaves: the image as a fresh device sees it
+        let disk = image.disk();
+        mount_on(disk.clone());
+        let file = fileopen(disk, &path, OpenMode::ORdonly).unwrap();
+        assert_eq!(file_read_to_end(&file).unwrap(), data);
+        fileclose(file);
+        other.join().unwrap();
+    }
+
+    #[test]
+    fn test_filehash() {
+        let image = TestImage::new("file_hash");
+        let dev = image.mount();
+        // not a multiple of the block size, the last read is short
+        let data = (0..7000u32)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect::<Vec<_>>();
+        let path = PathBuf::from("/data");
+        let file = fileopen(dev.clone(), &path, OpenMode::OCreate).unwrap();
+        assert_eq!(filewrite(&file, &data), Ok(data.len()));
+        // the offset is at the end, filehash still reads the whole file
+        assert_eq!(filehash(&file).unwrap(), Sha256::digest(&data));
+        fileclose(file);
+    }
+
+    #[test]
+    fn test_mkdir_all() {
+        let image = TestImage::new("file_mkdir_all");
+        let dev = image.mount();
+        mkdir_all(dev.clone(), &PathBuf::from("/a/b/c")).unwrap();
+        assert!(find_inode(dev.clone(), &PathBuf::from("/a/b/c")).is_some());
+        // the parts already there are kept, with what they hold
+        let file = fileopen(dev.clone(), &PathBuf::from("/a/b/f"), OpenMode::OCreate).unwrap();
+        fileclose(file);
+        mkdir_all(dev.clone(), &PathBuf::from("/a/b/d/../c/e")).unwrap();
+        assert!(find_inode(dev.clone(), &PathBuf::from("/a/b/f")).is_some());
+        assert!(find_inode(dev.clone(), &PathBuf::from("/a/b/c/e")).is_some());
+        assert!(find_inode(dev.clone(), &PathBuf::from("/a/b/d")).is_none());
+        assert_eq!(
+            mkdir_all(dev.clone(), &PathBuf::from("/a/b/f/g")),
+            Err(FsError::NotDirectory)
+        );
+        mkdir_all(dev.clone(), &PathBuf::from("/")).unwrap();
+    }
+
+    #[test]
+    fn test_open_modes_per_handle() {
+        let image = TestImage::new("file_open_modes");
+        let dev = image.mount();
+        let path = PathBuf::from("/f");
+        fileclose(fileopen(dev.clone(), &path, OpenMode::OCreate).unwrap());
+        let writer = fileopen(dev.clone(), &path, OpenMode::OWronly).unwrap();
+        let reader = fileopen(dev.clone(), &path, OpenMode::ORdonly).unwrap();
+        // each open has its own mode
+        assert_eq!(filewrite(&writer, b"abcdef"), Ok(6));
+        assert_eq!(filewrite(&reader, b"x"), Err(FsError::BadFileDescriptor));
+        assert_eq!(
+            fileread(&writer, &mut [0u8; 1]),
+            Err(FsError::BadFileDescriptor)
+        );
+        // and its own offset, the write did not move the reader
+        let mut buf = [0u8; 3];
+        assert_eq!(fileread(&reader, &mut buf), Ok(3));
+        assert_eq!(&buf, b"abc");
+        assert_eq!(filewrite(&writer, b"gh"), Ok(2));
+        // a dup shares the offset and the mode of its entry
+        let dup = filedup(&reader);
+        assert_eq!(fileread(&dup, &mut buf), Ok(3));
+        assert_eq!(&buf, b"def");
+        assert_eq!(fileread(&reader, &mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"gh");
+        assert_eq!(filewrite(&dup, b"x"), Err(FsError::BadFileDescriptor));
+        let modes = lsof()
+            .iter()
+            .filter(|info| info.path == path)
+            .map(|info| (info.mode(), info.refs))
+            .collect::<Vec<_>>();
+        assert_eq!(modes, [("w", 1), ("r", 2)]);
+        fileclose(dup);
+        fileclose(reader);
+        fileclose(writer);
+    }
+
+    #[test]
+    fn test_link_limit() {
+        let image = TestImage::new("file_link_limit");
+        let dev = image.mount();
+        let path = |p: &str| PathBuf::from(p);
+        let file = fileopen(dev.clone(), &path("/f"), OpenMode::OCreate).unwrap();
+        filewrite(&file, b"linked").unwrap();
+        fileclose(file);
+        filelink(dev.clone(), &path("/f"), &path("/g")).unwrap();
+        let ip = find_inode(dev.clone(), &path("/g")).unwrap();
+        assert_eq!(
+            ip.0.inum,
+            find_inode(dev.clone(), &path("/f")).unwrap().0.inum
+        );
+        assert_eq!(ip.read_disk_inode(|diskinode| diskinode.nlink), 2);
+        fileunlink(dev.clone(), &path("/f")).unwrap();
+        let file = fileopen(dev.clone(), &path("/g"), OpenMode::ORdonly).unwrap();
+        assert_eq!(file_read_to_end(&file).unwrap(), b"linked");
+        fileclose(file);
+        assert_eq!(
+            filelink(dev.clone(), &path("/"), &path("/root")),
+            Err(FsError::IsDirectory)
+        );
+
+        // one short of the limit, the next link takes nlink to it
+        let set_nlink = |ip: &InodePtr, nlink: u16| {
+            log_begin();
+            ip.modify_disk_inode(|diskinode| diskinode.nlink = nlink);
+            log_end();
+        };
+        set_nlink(&ip, MAXNLINK - 1);
+        filelink(dev.clone(), &path("/g"), &path("/h")).unwrap();
+        assert_eq!(ip.read_disk_inode(|diskinode| diskinode.nlink), MAXNLINK);
+        assert_eq!(
+            filelink(dev.clone(), &path("/g"), &path("/i")),
+            Err(FsError::MaxLinks)
+        );
+        assert!(find_inode(dev.clone(), &path("/i")).is_none());
+        assert_eq!(ip.read_disk_inode(|diskinode| diskinode.nlink), MAXNLINK);
+
+        // the ".." of a subdirectory links its parent too
+        mkdir(dev.clone(), &path("/d")).unwrap();
+        mkdir(dev.clone(), &path("/e")).unwrap();
+        let d = find_inode(dev.clone(), &path("/d")).unwrap();
+        set_nlink(&d, MAXNLINK);
+        assert_eq!(mkdir(dev.clone(), &path("/d/sub")), Err(FsError::MaxLinks));
+        assert_eq!(
+            filerename(dev.clone(), &path("/e"), &path("/d/e")),
+            Err(FsError::MaxLinks)
+        );
+        assert!(find_inode(dev.clone(), &path("/d/sub")).is_none());
+        assert!(find_inode(dev.clone(), &path("/e")).is_some());
+        // a file is no link to the directory
+        fileclose(fileopen(dev.clone(), &path("/d/file"), OpenMode::OCreate).unwrap());
+        assert_eq!(d.read_disk_inode(|diskinode| diskinode.nlink), MAXNLINK);
+    }
+
+    #[test]
+    fn test_globals_from_threads() {
+        let image = TestImage::new("file_globals_threads");
+        let dev = image.mount();
+        let size = sb().size;
+        std::thread::scope(|scope| {
+            // the file table, the inode cache, the log and the buffer cache
+            for t in 0..8 {
+                let dev = dev.clone();
+                scope.spawn(move || {
+                    let dir = PathBuf::from(format!("/t{}", t));
+                    mkdir(dev.clone(), &dir).unwrap();
+                    for i in 0..16 {
+                        let path = dir.join(format!("f{}", i));
+                        let data = vec![(t * 16 + i) as u8; 700 + i * 97];
+                        let file = fileopen(dev.clone(), &path, OpenMode::OCreate).unwrap();
+                        filewrite_all(&file, &data).unwrap();
+                        fileclose(file);
+                        let file = fileopen(dev.clone(), &path, OpenMode::ORdonly).unwrap();
+                        assert_eq!(file_read_to_end(&file).unwrap(), data);
+                        fileclose(file);
+                        if i % 4 == 0 {
+                            sync_all();
+                        }
+                        assert_eq!(sb().size, size);
+                    }
+                });
+            }
+            // the superblock, written while the others read it
+            let dev = dev.clone();
+            scope.spawn(move || {
+                for i in 0..64 {
+                    mark_in_use(dev.clone(), i % 2 == 0);
+                    assert_eq!(sb().size, size);
+                }
+            });
+        });
+        sync_all();
+        assert_eq!(fsck(image.mount()), []);
+    }
+
+    #[test]
+    fn test_file_writer() {
+        let image = TestImage::new("file_writer");
+        let dev = image.mount();
+        let data: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        let write_bytes = |path: &str, buffered: bool| {
+            let before = log_stats().blocks_logged;
+            let file = fileopen(dev.clone(), &PathBuf::from(path), OpenMode::OCreate).unwrap();
+            if buffered {
+                let mut writer = FileWriter::new(file);
+                for byte in &data {
+                    writer.write_all(std::slice::from_ref(byte)).unwrap();
+                }
+                writer.flush().unwrap();
+                fileclose(writer.into_inner());
+            } else {
+                for byte in &data {
+                    assert_eq!(filewrite(&file, std::slice::from_ref(byte)), Ok(1));
+                }
+                fileclose(file);
+            }
+            let file = fileopen(dev.clone(), &PathBuf::from(path), OpenMode::ORdonly).unwrap();
+            assert_eq!(file_read_to_end(&file).unwrap(), data);
+            fileclose(file);
+            log_stats().blocks_logged - before
+        };
+        // each byte rewrites its block and the inode, through the log
+        let plain = write_bytes("/plain", false);
+        assert!(plain >= data.len() as u64);
+        // a block is written once it is full
+        let buffered = write_bytes("/buffered", true);
+        assert!(
+            buffered * 100 < plain,
+            "{} blocks logged buffered, {} plain",
+            buffered,
+            plain
+        );
+
+        // after an unaligned start the buffer ends on the block boundary
+        let file = fileopen(dev.clone(), &PathBuf::from("/plain"), OpenMode::ORdwr).unwrap();
+        file.0.lock().unwrap().offset = 100;
+        let mut writer = FileWriter::new(file);
+        assert_eq!(writer.write(&data).unwrap(), BLOCK_SIZE as usize - 100);
+        assert_eq!(writer.file.0.lock().unwrap().offset, BLOCK_SIZE as u64);
+        // the tail is written on drop
+        assert_eq!(writer.write(&[7; 3]).unwrap(), 3);
+        let file = filedup(&writer.file);
+        drop(writer);
+        assert_eq!(file.0.lock().unwrap().offset, BLOCK_SIZE as u64 + 3);
+        let mut buf = [0; 3];
+        assert_eq!(filepread(&file, &mut buf, BLOCK_SIZE as u64), Ok(3));
+        assert_eq!(buf, [7; 3]);
+        fileclose(file);
+    }
+
+    #[test]
+    fn test_create_race() {
+        const ROUNDS: usize = 100;
+        let image = TestImage::new("file_create_race");
+        let dev = image.mount();
+        for round in 0..ROUNDS {
+            let path = PathBuf::from(format!("/race{}", round));
+            // the threads start at once, so their lookups overlap
+            let barrier = std::sync::Barrier::new(8);
+            let results = std::thread::scope(|scope| {
+                let threads = (0..8)
+                    .map(|_| {
+                        scope.spawn(|| {
+                            barrier.wait();
+                            fileopen(dev.clone(), &path, OpenMode::OCreate).map(|file| {
+                                let ino = filestat(&file).unwrap().ino;
+                                fileclose(file);
+                                ino
+                            })
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                threads
+                    .into_iter()
+                    .map(|thread| thread.join().unwrap())
+                    .collect::<Vec<_>>()
+            });
+            // one thread creates the file, the others find it there
+            let created = results.iter().filter(|result| result.is_ok()).count();
+            assert_eq!(created, 1, "{:?}", results);
+            assert!(results
+                .iter()
+                .all(|result| result.is_ok() || *result == Err(FsError::AlreadyExists)));
+        }
+        // one entry for each name
+        let root = fileopen(dev.clone(), &PathBuf::from("/"), OpenMode::ODirectory).unwrap();
+        let mut names = readdir(&root)
+            .map(|entry| String::from_utf8(entry.name.to_vec()).unwrap())
+            .map(|name| name.trim_matches(char::from(0)).to_string())
+            .filter(|name| name.starts_with("race"))
+            .collect::<Vec<_>>();
+        fileclose(root);
+        names.sort();
+        let mut expected = (0..ROUNDS)
+            .map(|round| format!("race{}", round))
+            .collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(names, expected);
+        sync_all();
+        assert_eq!(fsck(image.mount()), []);
+    }
+
+    #[test]
+    fn test_rename_race() {
+        const ROUNDS: usize = 50;
+        let image = TestImage::new("file_rename_race");
+        let dev = image.mount();
+        for round in 0..ROUNDS {
+            let dst = PathBuf::from(format!("/dst{}", round));
+            let srcs = (0..4)
+                .map(|i| PathBuf::from(format!("/src{}_{}", round, i)))
+                .collect::<Vec<_>>();
+            for src in srcs.iter() {
+                fileclose(fileopen(dev.clone(), src, OpenMode::OCreate).unwrap());
+            }
+            // the renames look dst up at once, each must see the one before
+            let barrier = std::sync::Barrier::new(srcs.len());
+            std::thread::scope(|scope| {
+                for src in srcs.iter() {
+                    let (dev, dst, barrier) = (dev.clone(), &dst, &barrier);
+                    scope.spawn(move || {
+                        barrier.wait();
+                        filerename(dev, src, dst).unwrap();
+                    });
+                }
+            });
+        }
+        // one entry for each dst, the files it replaced are gone
+        let root = fileopen(dev.clone(), &PathBuf::from("/"), OpenMode::ODirectory).unwrap();
+        let mut names = readdir(&root)
+            .map(|entry| entry_name(&entry))
+            .filter(|name| name.starts_with("dst") || name.starts_with("src"))
+            .collect::<Vec<_>>();
+        fileclose(root);
+        names.sort();
+        let mut expected = (0..ROUNDS)
+            .map(|round| format!("dst{}", round))
+            .collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(names, expected);
+        sync_all();
+        assert_eq!(fsck(image.mount()), []);
+    }
+
+    #[test]
+    fn test_max_file() {
+        let image = TestImage::new("file_max");
+        let dev = image.mount();
+        let max = (MAXFILE * BLOCK_SIZE) as usize;
+        let boot = wait_buffer_block(0, dev.clone())
+            .read()
+            .unwrap()
+            .read(0, |buf: &[u8; BLOCK_SIZE as usize]| *buf);
+        // every block a different pattern, through the direct and the indirect blocks
+        let data = (0..max)
+            .map(|i| (i / BLOCK_SIZE as usize + i) as u8)
+            .collect::<Vec<_>>();
+        let path = PathBuf::from("/max");
+        let file = fileopen(dev.clone(), &path, OpenMode::OCreate).unwrap();
+        filewrite_all(&file, &data[..max - 100]).unwrap();
+        // a write over the end keeps what fits
+        assert_eq!(filewrite(&file, &[0xee; 200]), Ok(100));
+        assert_eq!(filewrite(&file, &[0xee; 1]), Ok(0));
+        assert_eq!(filewrite_all(&file, &[0xee; 1]), Err(FsError::FileTooBig));
+        fileclose(file);
+        // the last 100 bytes are the ones that fit
+        let mut expected = data.clone();
+        expected[max - 100..].fill(0xee);
+        let file = fileopen(dev.clone(), &path, OpenMode::ORdonly).unwrap();
+        assert_eq!(filestat(&file).unwrap().size, max as u32);
+        assert_eq!(file_read_to_end(&file).unwrap(), expected);
+        fileclose(file);
+        // nothing went to block 0 for a block past the end
+        let after = wait_buffer_block(0, dev.clone())
+            .read()
+            .unwrap()
+            .read(0, |buf: &[u8; BLOCK_SIZE as usize]| *buf);
+        assert_eq!(after, boot);
+        sync_all();
+        assert_eq!(fsck(image.mount()), []);
+    }
+
+    #[test]
+    fn test_seek_past_4g() {
+        let image = TestImage::new("file_seek_4g");
+        let dev = image.mount();
+        let far = (1u64 << 32) + 100;
+        let path = PathBuf::from("/far");
+        let mut file = fileopen(dev.clone(), &path, OpenMode::OCreate).unwrap();
+        filewrite_all(&file, &[1; 200]).unwrap();
+        // the offset is kept whole instead of wrapping to 100
+        fileseek(&mut file, far, 0).unwrap();
+        assert_eq!(file.0.lock().unwrap().offset, far);
+        assert_eq!(filewrite(&file, &[2; 10]), Ok(0));
+        assert_eq!(filewrite_all(&file, &[2; 10]), Err(FsError::FileTooBig));
+        assert_eq!(filepwrite(&file, &[2; 10], far), Ok(0));
+        let mut buf = [0; 10];
+        assert_eq!(filepread(&file, &mut buf, far), Ok(0));
+        assert_eq!(file.0.lock().unwrap().offset, far);
+        // relative seeks carry past 4GiB too
+        fileseek(&mut file, 1 << 32, 1).unwrap();
+        assert_eq!(file.0.lock().unwrap().offset, far + (1 << 32));
+        fileseek(&mut file, 50, 2).unwrap();
+        assert_eq!(file.0.lock().unwrap().offset, 150);
+        assert!(fileseek(&mut file, 201, 2).is_err());
+        fileclose(file);
+        let file = fileopen(dev.clone(), &path, OpenMode::ORdonly).unwrap();
+        assert_eq!(file_read_to_end(&file).unwrap(), [1; 200]);
+        fileclose(file);
+        sync_all();
+        assert_eq!(fsck(image.mount()), []);
+    }
+
+    // a write from the last direct block into the first indirect one allocates
+    // the indirect block and the data block it points at in the transaction of
+    // the write, a handful of blocks well within MAXOPBLOCKS. so a crash at any
+    // point of its commit leaves the write whole or not at all, and never an
+    // indirect block allocated but not in the inode
+    #[test]
+    fn test_write_across_indirect_crash() {
+        let old = [1u8; BLOCK_SIZE as usize];
+        let new = [2u8; BLOCK_SIZE as usize];
+        let boundary = (NDIRECT * BLOCK_SIZE) as usize;
+        // half in the last direct block, half in the first indirect one
+        let off = boundary - BLOCK_SIZE as usize / 2;
+        let before = vec![1u8; boundary];
+        let mut after = before[..off].to_vec();
+        after.extend_from_slice(&new);
+        let path = PathBuf::from("/f");
+        for k in 0.. {
+            let image = TestImage::new("file_indirect_crash");
+            let crash = Arc::new(CrashDisk::new(image.disk()));
+            mount_on(crash.clone());
+            let file = fileopen(crash.clone(), &path, OpenMode::OCreate).unwrap();
+            for bn in 0..NDIRECT as u64 {
+                filepwrite(&file, &old, bn * BLOCK_SIZE as u64).unwrap();
+            }
+            crash.crash_after(k);
+            assert_eq!(filepwrite(&file, &new, off as u64), Ok(new.len()));
+            fileclose(file);
+
+            // reboot, replaying whatever the log holds
+            let dev = image.mount();
+            let file = fileopen(dev.clone(), &path, OpenMode::ORdonly).unwrap();
+            let data = file_read_to_end(&file).unwrap();
+            fileclose(file);
+            assert!(data == before || data == after, "crash after {}", k);
+            assert_eq!(fsck(dev.clone()), [], "crash after {}", k);
+            // every block the bitmap has is one some inode uses
+            assert_eq!(rebuild_bitmap(dev.clone()), Ok(0), "crash after {}", k);
+            if crash.lost() == 0 {
+                // the whole commit went through
+                assert_eq!(data, after);
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn test_unlink_open_file() {
+        let image = TestImage::new("file_unlink_open");
+        let dev = image.mount();
+        let path = PathBuf::from("/doomed");
+        let data = (0..3 * BLOCK_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+        let mut file = fileopen(dev.clone(), &path, OpenMode::OCreate).unwrap();
+        filewrite_all(&file, &data).unwrap();
+        let inum = filestat(&file).unwrap().ino;
+        let reader = filedup(&file);
+        fileunlink(dev.clone(), &path).unwrap();
+        assert_eq!(
+            fileopen(dev.clone(), &path, OpenMode::ORdonly).err(),
+            Some(FsError::NotFound)
+        );
+
+        // the name is gone, the data stays while a handle is open
+        fileseek(&mut file, 0, 0).unwrap();
+        assert_eq!(file_read_to_end(&reader).unwrap(), data);
+        let ftype = || get_inode(dev.clone(), inum).read_disk_inode(|diskinode| diskinode.ftype);
+        assert_eq!(ftype(), FileType::File as u8);
+        fileclose(reader);
+        assert_eq!(ftype(), FileType::File as u8);
+
+        // and goes with the last close, no later open needed
+        fileclose(file);
+        assert_eq!(ftype(), FileType::Free as u8);
+        sync_all();
+        assert_eq!(rebuild_bitmap(image.disk()), Ok(0));
+        assert_eq!(fsck(image.disk()), []);
+    }
+
+    #[test]
+    fn test_direct_io() {
+        let image = TestImage::new("file_direct");
+        let dev = image.mount();
+        let hot = PathBuf::from("/hot");
+        let file = fileopen(dev.clone(), &hot, OpenMode::OCreate).unwrap();
+        filewrite_all(&file, &[7; BLOCK_SIZE as usize]).unwrap();
+        let hot_block = file_block_map(&file).unwrap()[0].unwrap();
+        fileclose(file);
+        assert!(buffer_cached(hot_block, dev.clone()));
+
+        // more blocks than the buffer cache holds, with an unaligned head and tail
+        let path = PathBuf::from("/big");
+        let len = ((NDIRECT + 100) * BLOCK_SIZE + 37) as usize;
+        assert!(len as u64 >= DIRECT_MIN);
+        let data = (0..len).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let file = fileopen_direct(dev.clone(), &path, OpenMode::OCreate).unwrap();
+        filewrite_all(&file, &data[..100]).unwrap();
+        filewrite_all(&file, &data[100..BLOCK_SIZE as usize]).unwrap();
+        filewrite_all(&file, &data[BLOCK_SIZE as usize..]).unwrap();
+        let blocks = file_block_map(&file)
+            .unwrap()
+            .into_iter()
+            .map(Option::unwrap)
+            .collect::<Vec<_>>();
+        fileclose(file);
+        let (head, tail) = (blocks[0], blocks[blocks.len() - 1]);
+        let middle = &blocks[1..blocks.len() - 1];
+        assert!(buffer_cached(head, dev.clone()));
+        assert!(buffer_cached(tail, dev.clone()));
+        assert!(middle.iter().all(|&b| !buffer_cached(b, dev.clone())));
+        assert!(buffer_cached(hot_block, dev.clone()));
+
+        // a direct read leaves the cache alone too
+        let file = fileopen_direct(dev.clone(), &path, OpenMode::ORdonly).unwrap();
+        assert_eq!(file_read_to_end(&file).unwrap(), data);
+        fileclose(file);
+        assert!(middle.iter().all(|&b| !buffer_cached(b, dev.clone())));
+        assert!(buffer_cached(hot_block, dev.clone()));
+
+        // and the data is on the image
+        sync_all();
+        let dev = image.mount();
+        let file = fileopen(dev.clone(), &path, OpenMode::ORdonly).unwrap();
+        assert_eq!(file_read_to_end(&file).unwrap(), data);
+        fileclose(file);
+        assert_eq!(fsck(image.disk()), []);
+    }
+}
@@ -1,6 +1,10 @@
+use super::error::FsError;
 use super::fs::{BlockDevice, BLOCK_SIZE};
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom, Write};
+use log::warn;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
 use std::sync::Mutex;
 
 pub struct FileDisk(Mutex<File>);
@@ -17,7 +21,15 @@ impl BlockDevice for FileDisk {
         file.seek(SeekFrom::Start((block_id * BLOCK_SIZE) as u64))
             .unwrap();
         // TODO: async read
-        file.read_exact(buf).unwrap();
+        let mut n = 0;
+        while n < buf.len() {
+            match file.read(&mut buf[n..]).unwrap() {
+                0 => break,
+                m => n += m,
+            }
+        }
+        // blocks beyond the end of the image read as zeros
+        buf[n..].fill(0);
     }
 
     fn write_block(&self, block_id: u32, buf: &[u8]) {
@@ -27,6 +39,52 @@ impl BlockDevice for FileDisk {
         // TODO: async write
         file.write_all(buf).unwrap();
     }
+
+    fn block_count(&self) -> Option<u32> {
+        let len = self.0.lock().unwrap().metadata().unwrap().len();
+        Some((len / BLOCK_SIZE as u64) as u32)
+    }
+
+    fn flush(&self) {
+        self.0.lock().unwrap().sync_data().unwrap();
+    }
+
+    // punch a hole, the host gives back the space of the whole host blocks in it
+    #[cfg(target_os = "linux")]
+    fn discard_blocks(&self, start: u32, n: u32) {
+        let file = self.0.lock().unwrap();
+        let ret = unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                start as i64 * BLOCK_SIZE as i64,
+                n as i64 * BLOCK_SIZE as i64,
+            )
+        };
+        if ret != 0 {
+            warn!(
+                "FileDisk: discard of blocks {}..{}: {}",
+                start,
+                start + n,
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+// take an exclusive flock on the image, so a second mount or a mkfs refuses it.
+// the lock lasts as long as the returned file stays open. an image that can not
+// be opened gives the error with the path in front, one locked already InUse
+pub fn lock_image(path: &Path) -> io::Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        return Err(io::Error::other(FsError::InUse));
+    }
+    Ok(file)
 }
 
 #[allow(unused_imports)]
@@ -53,4 +111,47 @@ mod test {
         file_disk.read_block(1, &mut buf);
         assert_eq!(buf, [0; 512]);
     }
+
+    #[test]
+    fn test_read_beyond_eof() {
+        let path = std::env::temp_dir().join(format!("fatpigeorz_eof_{}.img", std::process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        // one and a half blocks
+        file.set_len(768).unwrap();
+        let file_disk = FileDisk::new(file);
+        file_disk.write_block(0, &[1; 512]);
+        assert_eq!(file_disk.block_count(), Some(1));
+        let mut buf = [1; 512];
+        file_disk.read_block(1, &mut buf);
+        assert_eq!(buf, [0; 512]);
+        file_disk.read_block(8, &mut buf);
+        assert_eq!(buf, [0; 512]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_lock_image() {
+        let path = std::env::temp_dir().join(format!("fatpigeorz_lock_{}.img", std::process::id()));
+        File::create(&path).unwrap();
+        let lock = lock_image(&path).unwrap();
+        // a second open of the image, even from this process, is refused
+        let e = lock_image(&path).err().unwrap();
+        assert_eq!(
+            e.get_ref().and_then(|e| e.downcast_ref::<FsError>()),
+            Some(&FsError::InUse)
+        );
+        drop(lock);
+        assert!(lock_image(&path).is_ok());
+        std::fs::remove_file(&path).unwrap();
+        // a missing image is an error, not a panic
+        let e = lock_image(&path).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert!(e.to_string().starts_with(&format!("{}: ", path.display())));
+    }
 }
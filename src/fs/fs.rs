@@ -1,19 +1,31 @@
+use std::sync::Arc;
+
 // Disk layout:
-// [ boot block | super block | log | inode blocks |  bit freemap | data blocks]
+// [ boot block | super block | log | inode blocks |  bit freemap | refcount map | xattr map | data blocks]
 pub const SB_BLOCK: u32 = 1;
 // Bitmap bits per block
 pub const BPB: u32 = BLOCK_SIZE * 8;
+// Refcounts per block, a u16 for each block
+// NINODES is below u16::MAX, so a block can not have more owners than that
+pub const RPB: u32 = BLOCK_SIZE / std::mem::size_of::<u16>() as u32;
+// Xattr block numbers per block, a u32 for each inode
+pub const XPB: u32 = BLOCK_SIZE / std::mem::size_of::<u32>() as u32;
 
-pub const FATPIGEORZMAGIC: u32 = 0x14451100;
+pub const FATPIGEORZMAGIC: u32 = 0x14451101;
 pub const ROOTINO: u32 = 1;
 pub const NDIRECT: u32 = 12; // make full use of the 64 bytes of DiskInode
-pub const NAMESIZE: u32 = 28;
+pub const NAMESIZE: u32 = 27; // a dirent is 32 bytes, the last one holds the file type
 pub const NINDIRECT: u32 = BLOCK_SIZE / std::mem::size_of::<u32>() as u32;
-pub const MAXFILE: u32 = NDIRECT + NINDIRECT + NINDIRECT * NINDIRECT;
+// the direct blocks and the ones of the single indirect block, the inode has no room for more
+pub const MAXFILE: u32 = NDIRECT + NINDIRECT;
 
 pub const BLOCK_SIZE: u32 = 512;
 pub const BLOCK_NUM: u32 = MAXOPBLOCKS * 4;
 pub const SHARD_NUM: u32 = 4;
+// Buffers of the shard the metadata blocks are pinned to, a full log of them fits
+pub const META_BLOCK_NUM: u32 = LOGSIZE - 1;
+// Share of each buffer shard kept for blocks hit more than once
+pub const PROTECTED_PERCENT: u32 = 75;
 
 // Maxinum of blocks an FS op can write
 pub const MAXOPBLOCKS: u32 = 16;
@@ -28,11 +40,39 @@ pub const IPB: u32 = BLOCK_SIZE / (std::mem::size_of::<DiskInode>() as u32);
 
 pub const NFILE: u32 = 100;
 
+// integers on disk are little-endian, so an image moves between hosts.
+// converting from and to little-endian is the same swap, so to_le does both,
+// on a little-endian host it is free
+pub trait LittleEndian: Copy {
+    fn to_le(self) -> Self;
+}
+
+impl LittleEndian for u16 {
+    fn to_le(self) -> Self {
+        u16::from_le(self)
+    }
+}
+
+impl LittleEndian for u32 {
+    fn to_le(self) -> Self {
+        u32::from_le(self)
+    }
+}
+
+impl<T: LittleEndian, const N: usize> LittleEndian for [T; N] {
+    fn to_le(self) -> Self {
+        self.map(T::to_le)
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum FileType {
     Free = 0,
     File = 1,
     Dir = 2,
+    Device = 3,
+    Fifo = 4,
+    Symlink = 5,
 }
 
 // Display
@@ -42,6 +82,24 @@ impl std::fmt::Display for FileType {
             FileType::Free => write!(f, "Free"),
             FileType::File => write!(f, "File"),
             FileType::Dir => write!(f, "Dir"),
+            FileType::Device => write!(f, "Device"),
+            FileType::Fifo => write!(f, "Fifo"),
+            FileType::Symlink => write!(f, "Symlink"),
+        }
+    }
+}
+
+impl FileType {
+    // None for a value no FileType has
+    pub fn from_u8(ftype: u8) -> Option<FileType> {
+        match ftype {
+            0 => Some(FileType::Free),
+            1 => Some(FileType::File),
+            2 => Some(FileType::Dir),
+            3 => Some(FileType::Device),
+            4 => Some(FileType::Fifo),
+            5 => Some(FileType::Symlink),
+            _ => None,
         }
     }
 }
@@ -55,4 +113,23 @@ impl Default for FileType {
 pub trait BlockDevice: Send + Sync {
     fn read_block(&self, block_id: u32, buf: &mut [u8]);
     fn write_block(&self, block_id: u32, buf: &[u8]);
+    // number of blocks the device holds, None if unknown
+    fn block_count(&self) -> Option<u32> {
+        None
+    }
+    // wait until the blocks written so far are on stable storage
+    fn flush(&self) {}
+    // the n blocks from start hold nothing, the device may drop them and
+    // read them as zeros. a hint, a device that cannot keeps them
+    fn discard_blocks(&self, _start: u32, _n: u32) {}
+    // a device that cannot take writes, the image on it is mounted read-only
+    fn read_only(&self) -> bool {
+        false
+    }
+}
+
+// identify a device by the address of its data,
+// so caches never mix up blocks of different images
+pub fn device_id(dev: &Arc<dyn BlockDevice>) -> usize {
+    Arc::as_ptr(dev) as *const u8 as usize
 }
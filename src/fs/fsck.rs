@@ -0,0 +1,806 @@
+// offline consistency checks of an image,
+// the problems found are reported, only the bitmap can be rebuilt here
+use std::{
+    ops::Range,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use super::{
+    buffer::wait_buffer_block,
+    error::FsError,
+    fs::{
+        BlockDevice, FileType, LittleEndian, BLOCK_SIZE, BPB, IPB, NDIRECT, NINDIRECT, ROOTINO,
+        SB_BLOCK, XPB,
+    },
+    inode::{all_entries, entry_name, is_inline, DiskInode, DIR_NLINK},
+    log::logged_blocks,
+    superblock::{backup_block, read_superblock, SuperBlock, RO_COMPAT_XATTR},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Problem {
+    // the superblock kept at this block has no valid magic number
+    BadSuperBlock {
+        block: u32,
+    },
+    // the backup superblock does not agree with the primary
+    SuperBlockMismatch,
+    // the image is mounted, or was not unmounted cleanly
+    InUse,
+    // a directory entry naming an inode the image does not have
+    BadEntry {
+        dir: u32,
+        inum: u32,
+    },
+    // a directory whose nlink does not count its links
+    BadNlink {
+        inum: u32,
+        nlink: u16,
+        expected: u16,
+    },
+    // an inode other than a directory named by more or fewer entries than its nlink
+    BadLinkCount {
+        inum: u32,
+        nlink: u16,
+        entries: u32,
+    },
+    // an inode whose type is none the image knows
+    BadType {
+        inum: u32,
+        ftype: u8,
+    },
+    // an inode pointing at a block outside the data blocks
+    BadAddr {
+        inum: u32,
+        addr: u32,
+    },
+    // a directory whose entries could not be read, so its links are not counted
+    Unreadable {
+        inum: u32,
+        error: FsError,
+    },
+}
+
+// Display
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Problem::BadSuperBlock { block } => {
+                write!(f, "bad superblock at block {}", block)
+            }
+            Problem::SuperBlockMismatch => {
+                write!(f, "the backup superblock differs from the primary")
+            }
+            Problem::InUse => {
+                write!(f, "the image is in use or was not unmounted cleanly")
+            }
+            Problem::BadEntry { dir, inum } => {
+                write!(f, "directory {} has an entry for inode {}", dir, inum)
+            }
+            Problem::BadNlink {
+                inum,
+                nlink,
+                expected,
+            } => {
+                write!(
+                    f,
+                    "directory {} has nlink {}, but {} links",
+                    inum, nlink, expected
+                )
+            }
+            Problem::BadLinkCount {
+                inum,
+                nlink,
+                entries,
+            } => {
+                write!(
+                    f,
+                    "inode {} has nlink {}, but {} entries name it",
+                    inum, nlink, entries
+                )
+            }
+            Problem::BadType { inum, ftype } => {
+                write!(f, "inode {} has unknown type {}", inum, ftype)
+            }
+            Problem::BadAddr { inum, addr } => {
+                write!(
+                    f,
+                    "inode {} points at block {}, outside the data blocks",
+                    inum, addr
+                )
+            }
+            Problem::Unreadable { inum, error } => {
+                write!(f, "directory {} can not be read: {}", inum, error)
+            }
+        }
+    }
+}
+
+// the passes of fsck, in the order they run. the inode pass checks each
+// directory and its nlink along with the inode, and counts the entries naming
+// each inode, held up against the nlink of the others once it is done
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Superblock,
+    Inodes,
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Phase::Superblock => write!(f, "superblock"),
+            Phase::Inodes => write!(f, "inodes"),
+        }
+    }
+}
+
+// told the phase and how far into it fsck is, as (done, total): once at the
+// start of a phase, about every percent and at its end. the workers of the
+// inode pass call it from their own threads
+pub type Progress<'a> = &'a (dyn Fn(Phase, u32, u32) + Sync);
+
+pub fn fsck(dev: Arc<dyn BlockDevice>) -> Vec<Problem> {
+    fsck_parallel(dev, 1)
+}
+
+// fsck with the inode table split across jobs threads. each inode, and each
+// directory with the inodes its entries name, is checked on its own, so the
+// parts need nothing from each other. the problems come back in the order
+// a single thread finds them
+pub fn fsck_parallel(dev: Arc<dyn BlockDevice>, jobs: usize) -> Vec<Problem> {
+    fsck_progress(dev, jobs, &|_, _, _| {})
+}
+
+// fsck_parallel, reporting how far it got to progress
+pub fn fsck_progress(dev: Arc<dyn BlockDevice>, jobs: usize, progress: Progress) -> Vec<Problem> {
+    let mut problems = vec![];
+    progress(Phase::Superblock, 0, 1);
+    check_superblock(dev.clone(), &mut problems);
+    let sb = read_superblock(dev.clone(), SB_BLOCK);
+    progress(Phase::Superblock, 1, 1);
+    if !sb.valid() {
+        return problems;
+    }
+    let inodes = sb.ninodes.saturating_sub(ROOTINO);
+    let checked = Checked {
+        done: AtomicU32::new(0),
+        total: inodes,
+        progress,
+        reported: Mutex::new(0),
+        links: (0..sb.ninodes).map(|_| AtomicU32::new(0)).collect(),
+    };
+    progress(Phase::Inodes, 0, inodes);
+    let per_job = inodes.div_ceil(jobs.max(1) as u32).max(1);
+    let parts = (ROOTINO..sb.ninodes)
+        .step_by(per_job as usize)
+        .map(|start| start..sb.ninodes.min(start + per_job))
+        .collect::<Vec<_>>();
+    std::thread::scope(|scope| {
+        let workers = parts
+            .into_iter()
+            .map(|part| {
+                let dev = dev.clone();
+                let (sb, checked) = (&sb, &checked);
+                scope.spawn(move || check_inodes(dev, sb, part, checked))
+            })
+            .collect::<Vec<_>>();
+        for worker in workers {
+            problems.extend(worker.join().unwrap());
+        }
+    });
+    problems.extend(check_links(dev, &sb, &checked.links));
+    problems
+}
+
+// whether the image was unmounted cleanly, read without mounting it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Status {
+    pub in_use: bool, // the in-use flag is still set
+    pub logged: u32,  // blocks of a commit the next mount installs
+}
+
+impl Status {
+    pub fn clean(&self) -> bool {
+        !self.in_use && self.logged == 0
+    }
+}
+
+pub fn status(dev: Arc<dyn BlockDevice>) -> Result<Status, Problem> {
+    let sb = read_superblock(dev.clone(), SB_BLOCK);
+    if !sb.valid() {
+        return Err(Problem::BadSuperBlock { block: SB_BLOCK });
+    }
+    Ok(Status {
+        in_use: sb.in_use != 0,
+        logged: logged_blocks(dev, &sb),
+    })
+}
+
+// clear the in-use flag an unclean shutdown left, once fsck found nothing else.
+// the image must not be mounted while this runs
+pub fn clear_in_use(dev: Arc<dyn BlockDevice>) -> Result<(), Problem> {
+    let mut sb = read_superblock(dev.clone(), SB_BLOCK);
+    if !sb.valid() {
+        return Err(Problem::BadSuperBlock { block: SB_BLOCK });
+    }
+    sb.mark_in_use(dev, false);
+    Ok(())
+}
+
+// cross-check the primary superblock with its backup
+fn check_superblock(dev: Arc<dyn BlockDevice>, problems: &mut Vec<Problem>) {
+    let mut primary = read_superblock(dev.clone(), SB_BLOCK);
+    if !primary.valid() {
+        problems.push(Problem::BadSuperBlock { block: SB_BLOCK });
+    } else if primary.in_use != 0 {
+        problems.push(Problem::InUse);
+    }
+    // the flag is only kept up to date in the primary
+    primary.in_use = 0;
+    let block = match backup_block(&primary, &dev) {
+        Some(block) => block,
+        None => return,
+    };
+    let backup = read_superblock(dev, block);
+    if !backup.valid() {
+        problems.push(Problem::BadSuperBlock { block });
+    } else if primary.valid() && primary != backup {
+        problems.push(Problem::SuperBlockMismatch);
+    }
+}
+
+// the block holding the attributes of inum, 0 for none
+// or on an image without the xattr map
+pub(super) fn read_xattr_block(dev: Arc<dyn BlockDevice>, sb: &SuperBlock, inum: u32) -> u32 {
+    if sb.feature_ro_compat & RO_COMPAT_XATTR == 0 {
+        return 0;
+    }
+    let off = inum % XPB * std::mem::size_of::<u32>() as u32;
+    wait_buffer_block(sb.xattrstart + inum / XPB, dev)
+        .read()
+        .unwrap()
+        .read(off as usize, |b: &u32| b.to_le())
+}
+
+pub(super) fn read_inode(dev: Arc<dyn BlockDevice>, sb: &SuperBlock, inum: u32) -> DiskInode {
+    let off = inum % IPB * std::mem::size_of::<DiskInode>() as u32;
+    wait_buffer_block(sb.inodestart + inum / IPB, dev)
+        .read()
+        .unwrap()
+        .read(off as usize, |dinode: &DiskInode| dinode.to_le())
+}
+
+// the inodes the workers of the inode pass have checked between them
+struct Checked<'a> {
+    done: AtomicU32,
+    total: u32,
+    progress: Progress<'a>,
+    // the count last reported. the workers report one at a time and never
+    // less than the last, so the display does not step back
+    reported: Mutex<u32>,
+    // the entries naming each inode, "." and ".." left out
+    links: Vec<AtomicU32>,
+}
+
+impl Checked<'_> {
+    fn one_more(&self) {
+        let done = self.done.fetch_add(1, Ordering::SeqCst) + 1;
+        if done == self.total || done.is_multiple_of(self.total.div_ceil(100).max(1)) {
+            let mut reported = self.reported.lock().unwrap();
+            let done = self.done.load(Ordering::SeqCst);
+            if done > *reported {
+                *reported = done;
+                (self.progress)(Phase::Inodes, done, self.total);
+            }
+        }
+    }
+}
+
+fn check_inodes(
+    dev: Arc<dyn BlockDevice>,
+    sb: &SuperBlock,
+    inums: Range<u32>,
+    checked: &Checked,
+) -> Vec<Problem> {
+    let mut problems = vec![];
+    for inum in inums {
+        check_inode(dev.clone(), sb, inum, &checked.links, &mut problems);
+        checked.one_more();
+    }
+    problems
+}
+
+fn check_inode(
+    dev: Arc<dyn BlockDevice>,
+    sb: &SuperBlock,
+    inum: u32,
+    links: &[AtomicU32],
+    problems: &mut Vec<Problem>,
+) {
+    let dinode = read_inode(dev.clone(), sb, inum);
+    if FileType::from_u8(dinode.ftype).is_none() {
+        problems.push(Problem::BadType {
+            inum,
+            ftype: dinode.ftype,
+        });
+        return;
+    }
+    problems.extend(
+        wild_addrs(dev.clone(), sb, inum, &dinode)
+            .into_iter()
+            .map(|addr| Problem::BadAddr { inum, addr }),
+    );
+    if dinode.ftype == FileType::Dir as u8 {
+        check_dir(dev, sb, inum, &dinode, links, problems);
+    }
+}
+
+// the addresses of an inode that are not data blocks
+fn wild_addrs(
+    dev: Arc<dyn BlockDevice>,
+    sb: &SuperBlock,
+    inum: u32,
+    dinode: &DiskInode,
+) -> Vec<u32> {
+    let data = sb.data_start()..sb.size - 1;
+    let has_blocks = dinode.ftype == FileType::File as u8
+        || dinode.ftype == FileType::Dir as u8
+        || dinode.ftype == FileType::Symlink as u8;
+    // any inode can have an attribute block
+    let mut addrs = vec![read_xattr_block(dev.clone(), sb, inum)];
+    if has_blocks && !is_inline(dinode) {
+        addrs.extend_from_slice(&dinode.addrs);
+        let indirect = dinode.addrs[NDIRECT as usize];
+        if data.contains(&indirect) {
+            addrs.extend(
+                wait_buffer_block(indirect, dev)
+                    .read()
+                    .unwrap()
+                    .read(0, |addrs: &[u32; NINDIRECT as usize]| addrs.to_le()),
+            );
+        }
+    }
+    addrs.retain(|&addr| addr != 0 && !data.contains(&addr));
+    addrs
+}
+
+// every directory has DIR_NLINK links plus one per subdirectory,
+// and its entries name inodes in [ROOTINO, ninodes). they are counted in links
+fn check_dir(
+    dev: Arc<dyn BlockDevice>,
+    sb: &SuperBlock,
+    inum: u32,
+    dinode: &DiskInode,
+    links: &[AtomicU32],
+    problems: &mut Vec<Problem>,
+) {
+    let entries = match all_entries(dev.clone(), dinode) {
+        Ok(entries) => entries,
+        Err(error) => {
+            problems.push(Problem::Unreadable { inum, error });
+            return;
+        }
+    };
+    let (entries, bad): (Vec<_>, Vec<_>) = entries
+        .into_iter()
+        .partition(|entry| (ROOTINO..sb.ninodes).contains(&entry.inum));
+    problems.extend(bad.iter().map(|entry| Problem::BadEntry {
+        dir: inum,
+        inum: entry.inum,
+    }));
+    let named = entries
+        .iter()
+        .filter(|entry| !matches!(entry_name(entry).as_str(), "." | ".."))
+        .collect::<Vec<_>>();
+    for entry in &named {
+        links[entry.inum as usize].fetch_add(1, Ordering::SeqCst);
+    }
+    let subdirs = named
+        .iter()
+        .filter(|entry| read_inode(dev.clone(), sb, entry.inum).ftype == FileType::Dir as u8)
+        .count();
+    let expected = DIR_NLINK + subdirs as u16;
+    if dinode.nlink != expected {
+        problems.push(Problem::BadNlink {
+            inum,
+            nlink: dinode.nlink,
+            expected,
+        });
+    }
+}
+
+// every inode but a directory, whose nlink check_dir checks, has an nlink of
+// the entries naming it. a free one has none, an entry naming it is left over
+fn check_links(dev: Arc<dyn BlockDevice>, sb: &SuperBlock, links: &[AtomicU32]) -> Vec<Problem> {
+    let mut problems = vec![];
+    for inum in ROOTINO..sb.ninodes {
+        let dinode = read_inode(dev.clone(), sb, inum);
+        match FileType::from_u8(dinode.ftype) {
+            // an unknown type is reported already
+            Some(FileType::Dir) | None => continue,
+            Some(_) => {}
+        }
+        let entries = links[inum as usize].load(Ordering::SeqCst);
+        if entries != dinode.nlink as u32 {
+            problems.push(Problem::BadLinkCount {
+                inum,
+                nlink: dinode.nlink,
+                entries,
+            });
+        }
+    }
+    problems
+}
+
+// the blocks in use: the metadata, the backup superblock
+// and every block a file or directory points at
+fn used_blocks(dev: Arc<dyn BlockDevice>, sb: &SuperBlock) -> Vec<bool> {
+    let mut used = vec![false; sb.size as usize];
+    used[..sb.data_start() as usize].fill(true);
+    used[sb.size as usize - 1] = true;
+    for inum in ROOTINO..sb.ninodes {
+        let dinode = read_inode(dev.clone(), sb, inum);
+        for b in inode_blocks(dev.clone(), sb, &dinode) {
+            used[b as usize] = true;
+        }
+        let xattr = read_xattr_block(dev.clone(), sb, inum);
+        if xattr != 0 && xattr < sb.size {
+            used[xattr as usize] = true;
+        }
+    }
+    used
+}
+
+// the data and indirect blocks of an inode.
+// 0 is a hole, a wild address is left for the checks to report
+pub(super) fn inode_blocks(
+    dev: Arc<dyn BlockDevice>,
+    sb: &SuperBlock,
+    dinode: &DiskInode,
+) -> Vec<u32> {
+    // devices keep their numbers in addrs, fifos keep nothing on disk
+    let has_blocks = dinode.ftype == FileType::File as u8
+        || dinode.ftype == FileType::Dir as u8
+        || dinode.ftype == FileType::Symlink as u8;
+    if !has_blocks || is_inline(dinode) {
+        return vec![];
+    }
+    let mut blocks = dinode.addrs[..NDIRECT as usize].to_vec();
+    let indirect = dinode.addrs[NDIRECT as usize];
+    if indirect != 0 && indirect < sb.size {
+        blocks.push(indirect);
+        blocks.extend(
+            wait_buffer_block(indirect, dev)
+                .read()
+                .unwrap()
+                .read(0, |addrs: &[u32; NINDIRECT as usize]| addrs.to_le()),
+        );
+    }
+    blocks.retain(|&b| b != 0 && b < sb.size);
+    blocks
+}
+
+// rewrite the whole bitmap from the blocks in use, freeing every block nothing
+// points at. the image must not be mounted while this runs.
+// returns the number of bits that changed
+pub fn rebuild_bitmap(dev: Arc<dyn BlockDevice>) -> Result<u32, Problem> {
+    let sb = read_superblock(dev.clone(), SB_BLOCK);
+    if !sb.valid() {
+        return Err(Problem::BadSuperBlock { block: SB_BLOCK });
+    }
+    if sb.in_use != 0 {
+        return Err(Problem::InUse);
+    }
+    let used = used_blocks(dev.clone(), &sb);
+    let mut changed = 0;
+    for (i, bits) in used.chunks(BPB as usize).enumerate() {
+        let mut buf = [0u8; BLOCK_SIZE as usize];
+        for (j, &inuse) in bits.iter().enumerate() {
+            if inuse {
+                buf[j / 8] |= 1 << (j % 8);
+            }
+        }
+        let blk = wait_buffer_block(sb.bmapstart + i as u32, dev.clone());
+        let mut guard = blk.write().unwrap();
+        guard.sync_write(0, |data: &mut [u8; BLOCK_SIZE as usize]| {
+            changed += data
+                .iter()
+                .zip(buf.iter())
+                .map(|(old, new)| (old ^ new).count_ones())
+                .sum::<u32>();
+            *data = buf;
+        });
+    }
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs::OpenOptions, os::unix::prelude::FileExt, path::PathBuf, sync::Arc};
+
+    use super::*;
+    use crate::fs::{
+        buffer::sync_all,
+        file::{fileclose, filelink, fileopen, filestat, filewrite, mkdir, OpenMode},
+        filedisk::FileDisk,
+        superblock::mark_in_use,
+        testutil::{TestImage, TEST_IMAGE_SIZE},
+    };
+
+    fn open(image: &TestImage) -> Arc<dyn BlockDevice> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&image.path)
+            .unwrap();
+        Arc::new(FileDisk::new(file))
+    }
+
+    fn write_block(image: &TestImage, block: u32, buf: &[u8]) {
+        let file = OpenOptions::new().write(true).open(&image.path).unwrap();
+        file.write_all_at(buf, (block * BLOCK_SIZE) as u64).unwrap();
+    }
+
+    #[test]
+    fn test_superblock_cross_check() {
+        let last = TEST_IMAGE_SIZE / BLOCK_SIZE - 1;
+        let image = TestImage::new("fsck_superblock");
+        assert_eq!(fsck(open(&image)), vec![]);
+
+        // a backup that disagrees with the primary
+        let mut sb = read_superblock(open(&image), last);
+        sb.nblocks += 1;
+        let sb = sb.to_le();
+        let buf = unsafe {
+            std::slice::from_raw_parts(&sb as *const _ as *const u8, std::mem::size_of_val(&sb))
+        };
+        write_block(&image, last, buf);
+        assert_eq!(fsck(open(&image)), vec![Problem::SuperBlockMismatch]);
+
+        // a lost backup
+        write_block(&image, last, &[0u8; BLOCK_SIZE as usize]);
+        assert_eq!(
+            fsck(open(&image)),
+            vec![Problem::BadSuperBlock { block: last }]
+        );
+
+        // a lost primary, the backup is then found from the image size
+        drop(image);
+        let image = TestImage::new("fsck_superblock");
+        write_block(&image, SB_BLOCK, &[0u8; BLOCK_SIZE as usize]);
+        assert_eq!(
+            fsck(open(&image)),
+            vec![Problem::BadSuperBlock { block: SB_BLOCK }]
+        );
+    }
+
+    #[test]
+    fn test_in_use_flag() {
+        let image = TestImage::new("fsck_in_use");
+        let dev = image.mount();
+        mark_in_use(dev.clone(), true);
+        // the flag lives in the primary only, the backup still matches
+        assert_eq!(fsck(open(&image)), vec![Problem::InUse]);
+        mark_in_use(dev.clone(), false);
+        assert_eq!(fsck(open(&image)), vec![]);
+    }
+
+    #[test]
+    fn test_status() {
+        let image = TestImage::new("fsck_status");
+        let clean = Status {
+            in_use: false,
+            logged: 0,
+        };
+        assert_eq!(status(open(&image)), Ok(clean));
+        assert!(clean.clean());
+
+        // what a shell that did not exit leaves
+        let mut sb = read_superblock(open(&image), SB_BLOCK);
+        sb.mark_in_use(open(&image), true);
+        let dirty = status(open(&image)).unwrap();
+        assert!(dirty.in_use);
+        assert!(!dirty.clean());
+        // reading the status leaves the flag alone, fsck clears it
+        assert_eq!(status(open(&image)), Ok(dirty));
+        clear_in_use(open(&image)).unwrap();
+        assert_eq!(status(open(&image)), Ok(clean));
+
+        // a commit that was not installed yet
+        write_block(&image, sb.logstart, &2u32.to_le_bytes());
+        let logged = status(open(&image)).unwrap();
+        assert_eq!(logged.logged, 2);
+        assert!(!logged.clean());
+    }
+
+    #[test]
+    fn test_rebuild_bitmap() {
+        let image = TestImage::new("fsck_rebuild_bitmap");
+        let dev = image.mount();
+        // enough blocks to need the indirect block
+        let file = fileopen(dev.clone(), &PathBuf::from("/big"), OpenMode::OCreate).unwrap();
+        filewrite(
+            &file,
+            &vec![7u8; (NDIRECT as usize + 4) * BLOCK_SIZE as usize],
+        )
+        .unwrap();
+        fileclose(file);
+        mkdir(dev.clone(), &PathBuf::from("/dir")).unwrap();
+        sync_all();
+        drop(dev);
+
+        let bmapstart = read_superblock(open(&image), SB_BLOCK).bmapstart;
+        let disk = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&image.path)
+            .unwrap();
+        let mut bitmap = [0u8; BLOCK_SIZE as usize];
+        disk.read_exact_at(&mut bitmap, (bmapstart * BLOCK_SIZE) as u64)
+            .unwrap();
+        // nothing to change on a consistent image
+        assert_eq!(rebuild_bitmap(open(&image)), Ok(0));
+
+        let mut corrupt = bitmap;
+        corrupt[200] = 0xff;
+        corrupt[201] |= 0x10;
+        write_block(&image, bmapstart, &corrupt);
+        assert_eq!(rebuild_bitmap(open(&image)), Ok(9));
+        let mut rebuilt = [0u8; BLOCK_SIZE as usize];
+        disk.read_exact_at(&mut rebuilt, (bmapstart * BLOCK_SIZE) as u64)
+            .unwrap();
+        assert_eq!(rebuilt, bitmap);
+        assert_eq!(fsck(open(&image)), vec![]);
+    }
+
+    #[test]
+    fn test_fsck_parallel() {
+        let image = TestImage::new("fsck_parallel");
+        let dev = image.mount();
+        // a tree spread over the inode table, some files past the direct blocks
+        let mut files = vec![];
+        let mut dirs = vec![];
+        for d in 0..12 {
+            let dir = PathBuf::from(format!("/d{}", d));
+            mkdir(dev.clone(), &dir).unwrap();
+            for f in 0..6 {
+                let path = dir.join(format!("f{}", f));
+                let file = fileopen(dev.clone(), &path, OpenMode::OCreate).unwrap();
+                let blocks = if f == 0 { NDIRECT as usize + 2 } else { 2 };
+                filewrite(&file, &vec![f as u8; blocks * BLOCK_SIZE as usize]).unwrap();
+                files.push(filestat(&file).unwrap().ino);
+                fileclose(file);
+            }
+            let file = fileopen(dev.clone(), &dir, OpenMode::ODirectory).unwrap();
+            dirs.push(filestat(&file).unwrap().ino);
+            fileclose(file);
+        }
+        sync_all();
+        drop(dev);
+        assert_eq!(fsck(open(&image)), vec![]);
+
+        let inodestart = read_superblock(open(&image), SB_BLOCK).inodestart;
+        let patch = |inum: u32, off: usize, bytes: &[u8]| {
+            let disk = OpenOptions::new().write(true).open(&image.path).unwrap();
+            let at = inodestart * BLOCK_SIZE + inum * std::mem::size_of::<DiskInode>() as u32;
+            disk.write_all_at(bytes, (at as usize + off) as u64)
+                .unwrap();
+        };
+        // a type no inode has, a block in the log, a directory one link short
+        let (bad_type, bad_addr, bad_dir) = (files[3], files[40], dirs[9]);
+        patch(bad_type, 4, &[9]);
+        patch(bad_addr, 12, &3u32.to_le_bytes());
+        patch(bad_dir, 6, &(DIR_NLINK - 1).to_le_bytes());
+        let serial = fsck(open(&image));
+        assert_eq!(
+            serial,
+            vec![
+                Problem::BadType {
+                    inum: bad_type,
+                    ftype: 9
+                },
+                Problem::BadAddr {
+                    inum: bad_addr,
+                    addr: 3
+                },
+                Problem::BadNlink {
+                    inum: bad_dir,
+                    nlink: DIR_NLINK - 1,
+                    expected: DIR_NLINK
+                },
+            ]
+        );
+        for jobs in [2, 3, 8, 1000] {
+            assert_eq!(fsck_parallel(open(&image), jobs), serial, "{} jobs", jobs);
+        }
+    }
+
+    #[test]
+    fn test_fsck_progress() {
+        let image = TestImage::new("fsck_progress");
+        let dev = image.mount();
+        mkdir(dev.clone(), &PathBuf::from("/d")).unwrap();
+        sync_all();
+        drop(dev);
+        let inodes = read_superblock(open(&image), SB_BLOCK).ninodes - ROOTINO;
+        for jobs in [1, 4] {
+            let seen = Mutex::new(vec![]);
+            let progress = |phase, done, total| seen.lock().unwrap().push((phase, done, total));
+            assert_eq!(fsck_progress(open(&image), jobs, &progress), []);
+            let seen = seen.into_inner().unwrap();
+            // each phase is announced before it runs
+            assert_eq!(
+                seen[..3],
+                [
+                    (Phase::Superblock, 0, 1),
+                    (Phase::Superblock, 1, 1),
+                    (Phase::Inodes, 0, inodes)
+                ]
+            );
+            let inode_pass = seen.iter().filter(|(phase, _, _)| *phase == Phase::Inodes);
+            assert!(inode_pass.clone().all(|&(_, _, total)| total == inodes));
+            // about once a percent, and the end of the pass
+            assert!(inode_pass.clone().count() <= 102);
+            // however many workers, the count only goes up and ends the pass once
+            assert_eq!(seen.last(), Some(&(Phase::Inodes, inodes, inodes)));
+            assert!(inode_pass.clone().is_sorted_by_key(|&(_, done, _)| done));
+            assert_eq!(
+                inode_pass.filter(|&&(_, done, _)| done == inodes).count(),
+                1
+            );
+        }
+    }
+
+    #[test]
+    fn test_link_count() {
+        let image = TestImage::new("fsck_link_count");
+        let dev = image.mount();
+        mkdir(dev.clone(), &PathBuf::from("/d")).unwrap();
+        let mut inums = vec![];
+        for path in ["/a", "/c"] {
+            let file = fileopen(dev.clone(), &PathBuf::from(path), OpenMode::OCreate).unwrap();
+            inums.push(filestat(&file).unwrap().ino);
+            fileclose(file);
+        }
+        filelink(dev.clone(), &PathBuf::from("/a"), &PathBuf::from("/d/b")).unwrap();
+        sync_all();
+        drop(dev);
+        assert_eq!(fsck(open(&image)), vec![]);
+
+        // /a is named twice and counts one link, /c is named once and counts three
+        let (linked, single) = (inums[0], inums[1]);
+        let inodestart = read_superblock(open(&image), SB_BLOCK).inodestart;
+        let patch_nlink = |inum: u32, nlink: u16| {
+            let at = inodestart * BLOCK_SIZE + inum * std::mem::size_of::<DiskInode>() as u32;
+            let disk = OpenOptions::new().write(true).open(&image.path).unwrap();
+            disk.write_all_at(&nlink.to_le_bytes(), at as u64 + 6)
+                .unwrap();
+        };
+        patch_nlink(linked, 1);
+        patch_nlink(single, 3);
+        let problems = fsck(open(&image));
+        assert_eq!(
+            problems,
+            vec![
+                Problem::BadLinkCount {
+                    inum: linked,
+                    nlink: 1,
+                    entries: 2
+                },
+                Problem::BadLinkCount {
+                    inum: single,
+                    nlink: 3,
+                    entries: 1
+                },
+            ]
+        );
+        assert_eq!(
+            problems[0].to_string(),
+            format!("inode {} has nlink 1, but 2 entries name it", linked)
+        );
+        assert_eq!(fsck_parallel(open(&image), 4), problems);
+    }
+}
@@ -0,0 +1,127 @@
+// a gzip compressed image, mounted read-only without unpacking it to disk.
+// the archive is inflated once when it is opened and the blocks are served
+// from memory, so nothing can be written back. there is no index to inflate
+// a block from the nearest point before it, the whole image is held in
+// memory: an image larger than the memory at hand has to be unpacked with
+// gunzip and mounted as it is
+use std::fs::File;
+use std::io::{self, Read};
+
+use super::fs::{BlockDevice, BLOCK_SIZE};
+use super::inflate::gunzip;
+
+pub struct GzRamDisk(Vec<u8>);
+
+impl GzRamDisk {
+    pub fn new(mut file: File) -> io::Result<Self> {
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+        gunzip(&data).map(Self).map_err(io::Error::other)
+    }
+}
+
+impl BlockDevice for GzRamDisk {
+    fn read_block(&self, block_id: u32, buf: &mut [u8]) {
+        let start = block_id as usize * BLOCK_SIZE as usize;
+        let data = self.0.get(start..).unwrap_or_default();
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        // blocks beyond the end of the image read as zeros, like FileDisk
+        buf[n..].fill(0);
+    }
+
+    fn write_block(&self, block_id: u32, _buf: &[u8]) {
+        panic!(
+            "GzRamDisk: write to block {} of a read-only image",
+            block_id
+        );
+    }
+
+    fn block_count(&self) -> Option<u32> {
+        Some((self.0.len() / BLOCK_SIZE as usize) as u32)
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{path::PathBuf, sync::Arc};
+
+    use super::*;
+    use crate::fs::{
+        buffer::sync_all,
+        error::FsError,
+        file::{file_read_to_end, fileopen, filewrite, mkdir, readdir, OpenMode},
+        inflate::crc32,
+        inode::entry_name,
+        superblock::read_only,
+        testutil::{mount_on, TestImage},
+    };
+
+    // a gzip member of stored deflate blocks, which gunzip reads like any other
+    fn gzip_stored(data: &[u8]) -> Vec<u8> {
+        let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+        let mut chunks = data.chunks(u16::MAX as usize).peekable();
+        while let Some(chunk) = chunks.next() {
+            // BFINAL on the last block, BTYPE 0, then the block starts on a byte
+            out.push(chunks.peek().is_none() as u8);
+            let len = chunk.len() as u16;
+            out.extend_from_slice(&len.to_le_bytes());
+            out.extend_from_slice(&(!len).to_le_bytes());
+            out.extend_from_slice(chunk);
+        }
+        out.extend_from_slice(&crc32(data).to_le_bytes());
+        out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        out
+    }
+
+    #[test]
+    fn test_gzip_image() {
+        let image = TestImage::new("gzdisk");
+        let dev = image.mount();
+        mkdir(dev.clone(), &PathBuf::from("/dir")).unwrap();
+        let file = fileopen(dev.clone(), &PathBuf::from("/file"), OpenMode::OCreate).unwrap();
+        filewrite(&file, &[7u8; 3 * BLOCK_SIZE as usize]).unwrap();
+        drop(file);
+        sync_all();
+        drop(dev);
+
+        let gz = image.path.with_extension("img.gz");
+        std::fs::write(&gz, gzip_stored(&std::fs::read(&image.path).unwrap())).unwrap();
+        let disk = GzRamDisk::new(File::open(&gz).unwrap());
+        std::fs::remove_file(&gz).unwrap();
+        let dev: Arc<dyn BlockDevice> = Arc::new(disk.unwrap());
+        mount_on(dev.clone());
+        assert!(read_only());
+
+        let root = fileopen(dev.clone(), &PathBuf::from("/"), OpenMode::ODirectory).unwrap();
+        let mut names = readdir(&root)
+            .map(|entry| entry_name(&entry))
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, [".", "..", "dir", "file"]);
+        let file = fileopen(dev.clone(), &PathBuf::from("/file"), OpenMode::ORdonly).unwrap();
+        assert_eq!(
+            file_read_to_end(&file).unwrap(),
+            vec![7u8; 3 * BLOCK_SIZE as usize]
+        );
+        assert_eq!(
+            fileopen(dev.clone(), &PathBuf::from("/new"), OpenMode::OCreate).err(),
+            Some(FsError::ReadOnly)
+        );
+
+        // a damaged archive and a file that can not be read are errors, not panics
+        let mut bad = gzip_stored(b"not an image");
+        bad[12] ^= 1;
+        std::fs::write(&gz, bad).unwrap();
+        let err = GzRamDisk::new(File::open(&gz).unwrap()).err().unwrap();
+        std::fs::remove_file(&gz).unwrap();
+        assert_eq!(err.to_string(), FsError::BadArchive.to_string());
+        let dir = File::open(image.path.parent().unwrap()).unwrap();
+        let err = GzRamDisk::new(dir).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EISDIR));
+    }
+}
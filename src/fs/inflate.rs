@@ -0,0 +1,364 @@
+// gzip (RFC 1952) and the deflate data inside it (RFC 1951), decoding only.
+// the codes are decoded a bit at a time, canonically, which is slow but small
+use super::error::FsError;
+
+// the base and extra bits of the length symbols 257..285
+const LBASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LEXT: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+// and of the distance symbols 0..29
+const DBASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DEXT: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+// the order the code length code lengths come in
+const CLORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+const MAXBITS: usize = 15;
+
+// the bits of the input, least significant first
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buf: u32,
+    count: u32, // bits left in buf, always less than 8 between calls
+}
+
+impl<'a> Bits<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Self {
+            data,
+            pos,
+            buf: 0,
+            count: 0,
+        }
+    }
+
+    fn bits(&mut self, n: u32) -> Result<u32, FsError> {
+        while self.count < n {
+            let byte = *self.data.get(self.pos).ok_or(FsError::BadArchive)?;
+            self.buf |= (byte as u32) << self.count;
+            self.pos += 1;
+            self.count += 8;
+        }
+        let v = self.buf & ((1u32 << n) - 1);
+        self.buf >>= n;
+        self.count -= n;
+        Ok(v)
+    }
+
+    // drop the rest of the byte, stored blocks start on a byte boundary
+    fn align(&mut self) {
+        self.buf = 0;
+        self.count = 0;
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], FsError> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + n)
+            .ok_or(FsError::BadArchive)?;
+        self.pos += n;
+        Ok(bytes)
+    }
+}
+
+// a canonical huffman code: how many codes each length has,
+// and the symbols ordered by code
+struct Huffman {
+    counts: [u16; MAXBITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self, FsError> {
+        let mut counts = [0u16; MAXBITS + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        // more codes than the lengths leave room for. fewer is fine,
+        // a single distance code is allowed
+        let mut left: i32 = 1;
+        for &count in counts.iter().skip(1) {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(FsError::BadArchive);
+            }
+        }
+        let mut offs = [0u16; MAXBITS + 1];
+        for len in 1..MAXBITS {
+            offs[len + 1] = offs[len] + counts[len];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offs[len as usize] as usize] = symbol as u16;
+                offs[len as usize] += 1;
+            }
+        }
+        Ok(Self { counts, symbols })
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u16, FsError> {
+        let mut code: i32 = 0; // the bits read so far
+        let mut first: i32 = 0; // the first code of this length
+        let mut index: i32 = 0; // the index of that code in symbols
+        for len in 1..=MAXBITS {
+            code |= bits.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(FsError::BadArchive)
+    }
+}
+
+fn fixed() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    let lencode = Huffman::new(&lengths).unwrap();
+    let distcode = Huffman::new(&[5; 30]).unwrap();
+    (lencode, distcode)
+}
+
+fn dynamic(bits: &mut Bits) -> Result<(Huffman, Huffman), FsError> {
+    let nlen = bits.bits(5)? as usize + 257;
+    let ndist = bits.bits(5)? as usize + 1;
+    let ncode = bits.bits(4)? as usize + 4;
+    if nlen > 286 || ndist > 30 {
+        return Err(FsError::BadArchive);
+    }
+    let mut lengths = [0u8; 19];
+    for &i in CLORDER.iter().take(ncode) {
+        lengths[i] = bits.bits(3)? as u8;
+    }
+    let lencode = Huffman::new(&lengths)?;
+    // the literal/length and distance code lengths, run length coded
+    let mut lengths = vec![];
+    while lengths.len() < nlen + ndist {
+        let symbol = lencode.decode(bits)?;
+        let (len, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => (
+                *lengths.last().ok_or(FsError::BadArchive)?,
+                3 + bits.bits(2)?,
+            ),
+            17 => (0, 3 + bits.bits(3)?),
+            _ => (0, 11 + bits.bits(7)?),
+        };
+        if lengths.len() + repeat as usize > nlen + ndist {
+            return Err(FsError::BadArchive);
+        }
+        lengths.extend(std::iter::repeat_n(len, repeat as usize));
+    }
+    // a block without an end code could never finish
+    if lengths[256] == 0 {
+        return Err(FsError::BadArchive);
+    }
+    Ok((
+        Huffman::new(&lengths[..nlen])?,
+        Huffman::new(&lengths[nlen..])?,
+    ))
+}
+
+fn codes(
+    bits: &mut Bits,
+    out: &mut Vec<u8>,
+    lencode: &Huffman,
+    distcode: &Huffman,
+) -> Result<(), FsError> {
+    loop {
+        let symbol = lencode.decode(bits)? as usize;
+        if symbol < 256 {
+            out.push(symbol as u8);
+            continue;
+        }
+        if symbol == 256 {
+            return Ok(());
+        }
+        let symbol = symbol - 257;
+        if symbol >= LBASE.len() {
+            return Err(FsError::BadArchive);
+        }
+        let len = LBASE[symbol] as usize + bits.bits(LEXT[symbol] as u32)? as usize;
+        let symbol = distcode.decode(bits)? as usize;
+        if symbol >= DBASE.len() {
+            return Err(FsError::BadArchive);
+        }
+        let dist = DBASE[symbol] as usize + bits.bits(DEXT[symbol] as u32)? as usize;
+        if dist > out.len() {
+            return Err(FsError::BadArchive);
+        }
+        // the copy may overlap what it appends, so one byte at a time
+        for _ in 0..len {
+            out.push(out[out.len() - dist]);
+        }
+    }
+}
+
+// inflate the deflate stream at pos, returns where it ended
+fn inflate(data: &[u8], pos: usize, out: &mut Vec<u8>) -> Result<usize, FsError> {
+    let mut bits = Bits::new(data, pos);
+    loop {
+        let last = bits.bits(1)?;
+        match bits.bits(2)? {
+            0 => {
+                bits.align();
+                let header = bits.bytes(4)?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                let nlen = u16::from_le_bytes([header[2], header[3]]);
+                if len != !nlen {
+                    return Err(FsError::BadArchive);
+                }
+                out.extend_from_slice(bits.bytes(len as usize)?);
+            }
+            1 => {
+                let (lencode, distcode) = fixed();
+                codes(&mut bits, out, &lencode, &distcode)?;
+            }
+            2 => {
+                let (lencode, distcode) = dynamic(&mut bits)?;
+                codes(&mut bits, out, &lencode, &distcode)?;
+            }
+            _ => return Err(FsError::BadArchive),
+        }
+        if last == 1 {
+            return Ok(bits.pos);
+        }
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+    for (n, entry) in table.iter_mut().enumerate() {
+        let mut c = n as u32;
+        for _ in 0..8 {
+            c = if c & 1 != 0 {
+                0xedb88320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+        }
+        *entry = c;
+    }
+    !data.iter().fold(!0u32, |c, &byte| {
+        table[((c ^ byte as u32) & 0xff) as usize] ^ (c >> 8)
+    })
+}
+
+const FHCRC: u8 = 1 << 1;
+const FEXTRA: u8 = 1 << 2;
+const FNAME: u8 = 1 << 3;
+const FCOMMENT: u8 = 1 << 4;
+
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&[0x1f, 0x8b])
+}
+
+// the data of every member of a gzip file, checked against their crc
+pub fn gunzip(data: &[u8]) -> Result<Vec<u8>, FsError> {
+    let mut out = vec![];
+    let mut pos = 0;
+    while pos < data.len() {
+        let header = data.get(pos..pos + 10).ok_or(FsError::BadArchive)?;
+        // only deflate is defined
+        if !is_gzip(header) || header[2] != 8 {
+            return Err(FsError::BadArchive);
+        }
+        let flags = header[3];
+        pos += 10;
+        if flags & FEXTRA != 0 {
+            let xlen = data.get(pos..pos + 2).ok_or(FsError::BadArchive)?;
+            pos += 2 + u16::from_le_bytes([xlen[0], xlen[1]]) as usize;
+        }
+        // the original name and a comment, both NUL terminated
+        for flag in [FNAME, FCOMMENT] {
+            if flags & flag != 0 {
+                let end = data
+                    .get(pos..)
+                    .and_then(|rest| rest.iter().position(|&b| b == 0))
+                    .ok_or(FsError::BadArchive)?;
+                pos += end + 1;
+            }
+        }
+        if flags & FHCRC != 0 {
+            pos += 2;
+        }
+        let start = out.len();
+        pos = inflate(data, pos, &mut out)?;
+        let trailer = data.get(pos..pos + 8).ok_or(FsError::BadArchive)?;
+        let crc = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+        let size = u32::from_le_bytes(trailer[4..].try_into().unwrap());
+        if crc != crc32(&out[start..]) || size != (out.len() - start) as u32 {
+            return Err(FsError::BadArchive);
+        }
+        pos += 8;
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_gunzip() {
+        // one block of each type, as python's gzip writes them
+        let fixed =
+            hex("1f8b08000000000002ffcb48cdc9c957c840903a0a6989250599e9a9f94555002854bf641d000000");
+        assert_eq!(
+            gunzip(&fixed),
+            Ok(b"hello hello hello, fatpigeorz".to_vec())
+        );
+        let stored = hex("1f8b08000000000000ff010600f9ff73746f7265640bf9435606000000");
+        assert_eq!(gunzip(&stored), Ok(b"stored".to_vec()));
+        let dynamic = hex(concat!(
+            "1f8b08000000000002ffc5ce8b1504110c40d15a1164884f9021aadfdd2af616f0ce33c658070113b5",
+            "21ea62ee72038de3699a8715f9a6055593c4e919184658b8c970dc0584e0ad4147f63a0bbab33aa1b7",
+            "ba65726ff5a7759eb2d57aa4be8ec332d5e7a1a1be4002654736b47185f12db39f51925658e9322a3f",
+            "66923f83c2959ea353198d120670d6fcfce1fa03a31c35802c010000",
+        ));
+        let expected = (0..300u32)
+            .map(|i| (i * i / 7 % 26) as u8 + b'a')
+            .collect::<Vec<_>>();
+        assert_eq!(gunzip(&dynamic), Ok(expected));
+
+        // members one after another make one stream
+        let both = [fixed.clone(), stored.clone()].concat();
+        assert_eq!(
+            gunzip(&both),
+            Ok(b"hello hello hello, fatpigeorzstored".to_vec())
+        );
+
+        // a flipped bit is caught by the crc, a cut one by the missing trailer
+        let mut bad = stored.clone();
+        bad[15] ^= 1;
+        assert_eq!(gunzip(&bad), Err(FsError::BadArchive));
+        assert_eq!(gunzip(&fixed[..fixed.len() - 4]), Err(FsError::BadArchive));
+        assert_eq!(gunzip(b"not gzip at all"), Err(FsError::BadArchive));
+    }
+}
@@ -1,61 +1,173 @@
 use core::panic;
-use std::ffi::OsString;
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
 
-use log::info;
+use log::{info, log_enabled, trace, warn, Level};
 use once_cell::sync::Lazy;
 
 use crate::fs::fs::BLOCK_SIZE;
 
+use super::alloc::{alloc_blocks, free_blocks};
+use super::error::FsError;
 use super::fs::{NINDIRECT, NINODES, ROOTINO};
 use super::log::log_write;
 use super::{
-    buffer::get_buffer_block,
-    fs::{BlockDevice, FileType, BPB, IPB, NAMESIZE, NDIRECT},
-    superblock::SB,
+    buffer::{buffer_bypass, get_buffer_block, get_buffer_block_with, wait_buffer_block},
+    fs::{
+        device_id, BlockDevice, FileType, LittleEndian, BPB, IPB, MAXFILE, NAMESIZE, NDIRECT, RPB,
+        XPB,
+    },
+    superblock::{
+        read_only, sb, INCOMPAT_DIRENT_FTYPE, INCOMPAT_INLINE_DATA, RO_COMPAT_REFLINK,
+        RO_COMPAT_XATTR,
+    },
 };
 
 // Disk Struct
 #[repr(C)]
 #[derive(Debug, Default, PartialEq, Clone, Copy)]
 pub struct DiskInode {
-    pub dev: u32,                           // Device number, always 0
-    pub ftype: u16,                         // File type
-    pub nlink: u16,                         // Number of links to file
+    pub generation: u32,                    // Bumped each time the inode is allocated
+    pub ftype: u8,                          // File type
+    pub flags: u8,                          // INLINE_DATA
+    pub nlink: u16,                         // Number of links to file, see DIR_NLINK
     pub size: u32,                          // Size of file (bytes)
     pub addrs: [u32; NDIRECT as usize + 1], // Pointers to blocks
 }
 
+const _: () = assert!(std::mem::size_of::<DiskInode>() == 64);
+
+// the links of a new directory: its entry in the parent and its own ".".
+// each subdirectory adds one more with its "..", root has no entry in a
+// parent, its ".." stands in for it
+pub const DIR_NLINK: u16 = 2;
+
+// the most links an inode can have, nlink is a u16
+pub const MAXNLINK: u16 = u16::MAX;
+
 // directory contains a sequence of entry
 #[repr(C)]
 #[derive(Debug, Default, Clone, Copy)]
 pub struct DirEntry {
     pub inum: u32,
     pub name: [u8; NAMESIZE as usize],
+    // the FileType of inum, so readdir does not load the inode.
+    // Free on images made before INCOMPAT_DIRENT_FTYPE
+    pub ftype: u8,
 }
 
-pub fn namecmp(s: &[u8], t: &String) -> bool {
-    let mut i = 0;
-    for c in t.chars() {
-        if i >= s.len() {
-            return false;
+// NAMESIZE leaves room for ftype, so a block still holds 16 dirents
+const _: () = assert!(std::mem::size_of::<DirEntry>() == 32);
+
+impl LittleEndian for DiskInode {
+    fn to_le(self) -> Self {
+        Self {
+            generation: self.generation.to_le(),
+            ftype: self.ftype,
+            flags: self.flags,
+            nlink: self.nlink.to_le(),
+            size: self.size.to_le(),
+            addrs: self.addrs.to_le(),
         }
-        if s[i] != c as u8 {
-            return false;
+    }
+}
+
+impl LittleEndian for DirEntry {
+    fn to_le(self) -> Self {
+        Self {
+            inum: self.inum.to_le(),
+            name: self.name,
+            ftype: self.ftype,
         }
-        i += 1;
     }
-    true
+}
+
+// a device inode keeps major << 16 | minor in addrs[0]
+pub fn device_number(diskinode: &DiskInode) -> (u16, u16) {
+    ((diskinode.addrs[0] >> 16) as u16, diskinode.addrs[0] as u16)
+}
+
+// a small file keeps its bytes in addrs instead of a data block,
+// until a write reaches past INLINE_SIZE and they move to a block
+pub const INLINE_DATA: u8 = 1;
+pub const INLINE_SIZE: usize = (NDIRECT as usize + 1) * std::mem::size_of::<u32>();
+
+pub fn is_inline(diskinode: &DiskInode) -> bool {
+    diskinode.flags & INLINE_DATA != 0
+}
+
+// images made before inline data keep every file in blocks
+fn inline_enabled() -> bool {
+    let incompat = sb().feature_incompat;
+    incompat & INCOMPAT_INLINE_DATA != 0
+}
+
+// without INCOMPAT_DIRENT_FTYPE the byte after the name is the last byte of
+// a name one longer, it is neither written nor read as a type
+fn dirent_ftype_enabled() -> bool {
+    let incompat = sb().feature_incompat;
+    incompat & INCOMPAT_DIRENT_FTYPE != 0
+}
+
+// the inline bytes, in disk order whatever the host byte order
+fn inline_bytes(diskinode: &DiskInode) -> [u8; INLINE_SIZE] {
+    let mut bytes = [0u8; INLINE_SIZE];
+    for (b, addr) in bytes.chunks_mut(4).zip(diskinode.addrs.iter()) {
+        b.copy_from_slice(&addr.to_le_bytes());
+    }
+    bytes
+}
+
+fn set_inline_bytes(diskinode: &mut DiskInode, bytes: &[u8; INLINE_SIZE]) {
+    for (addr, b) in diskinode.addrs.iter_mut().zip(bytes.chunks(4)) {
+        *addr = u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+    }
+}
+
+// move the inline bytes to a data block, the inode then maps blocks as usual.
+// with no block for them the inode is left inline
+fn inline_spill(diskinode: &mut DiskInode, dev: Arc<dyn BlockDevice>) -> Result<(), FsError> {
+    let bytes = inline_bytes(diskinode);
+    diskinode.addrs = [0; NDIRECT as usize + 1];
+    diskinode.flags &= !INLINE_DATA;
+    if diskinode.size > 0 {
+        let blk = block_map(diskinode, dev.clone(), 0).and_then(|b| {
+            get_buffer_block(b, dev.clone()).inspect_err(|_| block_free(dev.clone(), b))
+        });
+        let blk = match blk {
+            Ok(blk) => blk,
+            Err(e) => {
+                set_inline_bytes(diskinode, &bytes);
+                diskinode.flags |= INLINE_DATA;
+                return Err(e);
+            }
+        };
+        let mut guard = blk.write().unwrap();
+        guard.write(0, |data: &mut [u8; INLINE_SIZE]| *data = bytes);
+        log_write(guard);
+    }
+    Ok(())
+}
+
+pub fn namecmp(s: &[u8], t: &String) -> bool {
+    let t = t.as_bytes();
+    if t.len() > s.len() || s[..t.len()] != *t {
+        return false;
+    }
+    // the stored name must end here too, so "a" does not match "ab"
+    t.len() == s.len() || s[t.len()] == 0
 }
 
 pub fn nameassign(s: &mut [u8], t: &String) {
     let mut i = 0;
-    for c in t.chars() {
+    for c in t.bytes() {
         if i >= s.len() {
             panic!("nameassign: name too long");
         }
-        s[i] = c as u8;
+        s[i] = c;
         i += 1;
     }
     while i < s.len() {
@@ -67,56 +179,127 @@ pub fn nameassign(s: &mut [u8], t: &String) {
 // get the (block,offset) of inum
 fn addr_of_inode(inum: u32) -> (u32, u32) {
     (
-        inum / IPB + unsafe { SB.inodestart },
+        inum / IPB + sb().inodestart,
         inum % IPB * std::mem::size_of::<DiskInode>() as u32,
     )
 }
 
 // get the block containing the bitmap
+#[allow(unused)]
 fn block_of_bitmap(block: u32) -> u32 {
-    block / BPB + unsafe { SB.bmapstart }
+    block / BPB + sb().bmapstart
 }
 
-fn block_alloc(dev: Arc<dyn BlockDevice>) -> Option<u32> {
-    for b in (0..unsafe { SB.size }).step_by(BPB as usize) {
-        let bno = block_of_bitmap(b);
-        let blk = get_buffer_block(bno, dev.clone());
-        let mut guard = blk.write().unwrap();
-        let mut buf = guard.read(0, |buf: &[u8; BLOCK_SIZE as usize]| *buf);
-        for (i, byte) in buf.iter_mut().enumerate() {
-            if *byte == 0xff {
-                continue;
-            }
-            for j in 0..8 {
-                let m = 1 << j;
-                if *byte & m == 0 {
-                    *byte |= m;
-                    guard.write(i, |data: &mut u8| {
-                        *data = *byte;
-                    });
-                    let buf = get_buffer_block(b + i as u32 * 8 + j, dev.clone());
-                    let mut guard = buf.write().unwrap();
-                    guard.write(0, |data: &mut [u8; BLOCK_SIZE as usize]| {
-                        data.fill(0);
-                    });
-                    log_write(guard);
-                    return Some(b + i as u32 * 8 + j);
-                }
-            }
-        }
+// a zeroed block from the allocation policy in use,
+// NoSpace if there is none and NoBuffer if it can not be cached
+pub(super) fn block_alloc(dev: Arc<dyn BlockDevice>) -> Result<u32, FsError> {
+    let b = alloc_blocks(dev.clone(), 1).ok_or(FsError::NoSpace)?;
+    let buf = get_buffer_block(b, dev.clone()).inspect_err(|_| free_blocks(dev, b, 1))?;
+    let mut guard = buf.write().unwrap();
+    guard.write(0, |data: &mut [u8; BLOCK_SIZE as usize]| {
+        data.fill(0);
+    });
+    log_write(guard);
+    Ok(b)
+}
+
+pub(super) fn block_free(dev: Arc<dyn BlockDevice>, b: u32) {
+    // a shared block only loses one owner
+    if block_refs(dev.clone(), b) > 0 {
+        modify_block_refs(dev, b, |refs| *refs -= 1);
+        return;
     }
-    None
+    // logged, so the block is not free on disk while a committed inode still uses it
+    free_blocks(dev, b, 1);
 }
 
-fn block_free(dev: Arc<dyn BlockDevice>, b: u32) {
-    let bno = block_of_bitmap(b);
-    let bi = b % BPB;
-    get_buffer_block(bno, dev.clone())
-        .write()
+// the refcount map keeps, for each block, the number of owners beyond the first,
+// so only blocks shared by a reflink have a non-zero count
+fn addr_of_refs(block: u32) -> (u32, u32) {
+    (
+        block / RPB + sb().refstart,
+        block % RPB * std::mem::size_of::<u16>() as u32,
+    )
+}
+
+// images made without RO_COMPAT_REFLINK have no refcount map, and no block
+// is shared there
+pub fn reflink_enabled() -> bool {
+    let ro_compat = sb().feature_ro_compat;
+    ro_compat & RO_COMPAT_REFLINK != 0
+}
+
+pub fn block_refs(dev: Arc<dyn BlockDevice>, b: u32) -> u16 {
+    if !reflink_enabled() {
+        return 0;
+    }
+    let (bno, off) = addr_of_refs(b);
+    wait_buffer_block(bno, dev)
+        .read()
         .unwrap()
-        .sync_write(bi as usize / 8, |data: &mut u8| {
-            *data &= !(1 << (bi % 8));
-        });
+        .read(off as usize, |refs: &u16| refs.to_le())
+}
+
+fn modify_block_refs(dev: Arc<dyn BlockDevice>, b: u32, f: impl FnOnce(&mut u16)) {
+    let (bno, off) = addr_of_refs(b);
+    let blk = wait_buffer_block(bno, dev);
+    let mut guard = blk.write().unwrap();
+    guard.write(off as usize, |refs: &mut u16| {
+        let mut host = refs.to_le();
+        f(&mut host);
+        *refs = host.to_le();
+    });
+    log_write(guard);
+}
+
+// the xattr map keeps, for each inode, the block holding its extended
+// attributes, 0 for none. images made without RO_COMPAT_XATTR have no map
+pub fn xattr_enabled() -> bool {
+    let ro_compat = sb().feature_ro_compat;
+    ro_compat & RO_COMPAT_XATTR != 0
+}
+
+fn addr_of_xattr(inum: u32) -> (u32, u32) {
+    (
+        inum / XPB + sb().xattrstart,
+        inum % XPB * std::mem::size_of::<u32>() as u32,
+    )
+}
+
+pub fn xattr_block(dev: Arc<dyn BlockDevice>, inum: u32) -> u32 {
+    if !xattr_enabled() {
+        return 0;
+    }
+    let (bno, off) = addr_of_xattr(inum);
+    wait_buffer_block(bno, dev)
+        .read()
+        .unwrap()
+        .read(off as usize, |b: &u32| b.to_le())
+}
+
+pub(super) fn set_xattr_block(dev: Arc<dyn BlockDevice>, inum: u32, b: u32) {
+    let (bno, off) = addr_of_xattr(inum);
+    let blk = wait_buffer_block(bno, dev);
+    let mut guard = blk.write().unwrap();
+    guard.write(off as usize, |addr: &mut u32| *addr = b.to_le());
+    log_write(guard);
+}
+
+// give the writer its own copy of a shared block
+fn block_unshare(dev: Arc<dyn BlockDevice>, b: u32) -> Result<u32, FsError> {
+    let data = get_buffer_block(b, dev.clone())?
+        .read()
+        .unwrap()
+        .read(0, |data: &[u8; BLOCK_SIZE as usize]| *data);
+    let copy = block_alloc(dev.clone())?;
+    let blk = get_buffer_block(copy, dev.clone()).inspect_err(|_| block_free(dev.clone(), copy))?;
+    let mut guard = blk.write().unwrap();
+    guard.write(0, |dst: &mut [u8; BLOCK_SIZE as usize]| {
+        *dst = data;
+    });
+    log_write(guard);
+    block_free(dev, b);
+    Ok(copy)
 }
 
 pub struct Inode {
@@ -129,6 +312,9 @@ pub struct Inode {
     // the dinode will set to None while drop
     // if nlink == 0 and no other inode point to it(Arc::strong_count == 2(table and the drop routine))
     pub dinode: Mutex<Option<DiskInode>>, // inode copy
+    // held from the lookup of a name in this directory to the dirlink that
+    // adds it, so two threads adding the same name can not both miss it
+    pub entries: Mutex<()>,
 }
 
 impl Inode {
@@ -137,6 +323,7 @@ impl Inode {
             dev: None,
             inum: 0,
             dinode: Mutex::new(None),
+            entries: Mutex::new(()),
         }
     }
 }
@@ -144,22 +331,37 @@ impl Inode {
 impl Inode {
     fn read_disk_inode<V>(&self, f: impl FnOnce(&DiskInode) -> V) -> V {
         let (blk, off) = addr_of_inode(self.inum);
-        get_buffer_block(blk, self.dev.as_ref().unwrap().clone())
+        let dinode = wait_buffer_block(blk, self.dev.as_ref().unwrap().clone())
             .read()
             .unwrap()
-            .read(off as usize, f)
+            .read(off as usize, |dinode: &DiskInode| dinode.to_le());
+        f(&dinode)
     }
 
     fn modify_disk_inode<V>(&self, f: impl FnOnce(&mut DiskInode) -> V) -> V {
         let (blk, off) = addr_of_inode(self.inum);
-        let binding = get_buffer_block(blk, self.dev.as_ref().unwrap().clone());
+        let binding = wait_buffer_block(blk, self.dev.as_ref().unwrap().clone());
         let mut guard = binding.write().unwrap();
-        let ret = guard.write(off as usize, f);
+        let ret = guard.write(off as usize, |disk: &mut DiskInode| {
+            let mut dinode = disk.to_le();
+            let ret = f(&mut dinode);
+            *disk = dinode.to_le();
+            ret
+        });
         log_write(guard);
         ret
     }
 
     pub fn truncate(dev: Arc<dyn BlockDevice>, dinode: &mut DiskInode) {
+        if dinode.ftype == FileType::Device as u8 {
+            // a device owns no blocks, addrs[0] holds its number
+            dinode.addrs[0] = 0;
+            return;
+        }
+        if is_inline(dinode) {
+            dinode.addrs = [0; NDIRECT as usize + 1];
+            return;
+        }
         // free the data blocks
         dinode
             .addrs
@@ -172,18 +374,28 @@ impl Inode {
             });
         if dinode.addrs[NDIRECT as usize] > 0 {
             // read the indirect block
-            let addrs = get_buffer_block(dinode.addrs[NDIRECT as usize], dev.clone())
+            let addrs = wait_buffer_block(dinode.addrs[NDIRECT as usize], dev.clone())
                 .read()
                 .unwrap()
-                .read(0, |addrs: &[u32; NINDIRECT as usize]| *addrs);
+                .read(0, |addrs: &[u32; NINDIRECT as usize]| addrs.to_le());
             addrs
                 .iter()
                 .take(NINDIRECT as usize)
                 .filter(|i| **i != 0)
                 .for_each(|i| block_free(dev.clone(), *i));
+            // clear the indirect block in the same transaction,
+            // so it holds no stale addresses when it is allocated again
+            let blk = wait_buffer_block(dinode.addrs[NDIRECT as usize], dev.clone());
+            let mut guard = blk.write().unwrap();
+            guard.write(0, |data: &mut [u32; NINDIRECT as usize]| data.fill(0));
+            log_write(guard);
             block_free(dev.clone(), dinode.addrs[NDIRECT as usize]);
             dinode.addrs[NDIRECT as usize] = 0;
         }
+        // an emptied file is small again
+        if dinode.ftype == FileType::File as u8 && inline_enabled() {
+            dinode.flags |= INLINE_DATA;
+        }
     }
 }
 
@@ -223,6 +435,128 @@ impl InodePtr {
     }
 }
 
+// names an inode across lookups, like the file handles nfs or fuse hand out,
+// the generation tells the inode apart from a later one reusing the inum
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InodeHandle {
+    pub inum: u32,
+    pub generation: u32,
+}
+
+impl InodePtr {
+    pub fn handle(&self) -> InodeHandle {
+        InodeHandle {
+            inum: self.0.inum,
+            generation: self.read_disk_inode(|diskinode| diskinode.generation),
+        }
+    }
+}
+
+// the inode a handle names, StaleHandle if it was freed since
+pub fn inode_from_handle(
+    dev: Arc<dyn BlockDevice>,
+    handle: InodeHandle,
+) -> Result<InodePtr, FsError> {
+    if handle.inum < ROOTINO || handle.inum >= sb().ninodes {
+        return Err(FsError::StaleHandle);
+    }
+    let ip = get_inode(dev, handle.inum);
+    let live = ip.read_disk_inode(|diskinode| {
+        diskinode.ftype != FileType::Free as u8 && diskinode.generation == handle.generation
+    });
+    if live {
+        Ok(ip)
+    } else {
+        Err(FsError::StaleHandle)
+    }
+}
+
+// the lowest inum that may be free on the device, where the scan of
+// inode_alloc starts, so the inodes in use below it are not read again for
+// every allocation. a hint that is off only makes the scan longer, the inums
+// below it are tried last. the device is held, as in DIR_INDEX
+type FreeInodeHint = Option<(Arc<dyn BlockDevice>, u32)>;
+static FREE_INODE_HINT: Mutex<FreeInodeHint> = Mutex::new(None);
+
+fn free_inode_hint(dev: &Arc<dyn BlockDevice>) -> u32 {
+    hint_on(&FREE_INODE_HINT.lock().unwrap(), dev)
+}
+
+fn hint_on(hint: &FreeInodeHint, dev: &Arc<dyn BlockDevice>) -> u32 {
+    match hint {
+        Some((hinted, inum)) if device_id(hinted) == device_id(dev) => *inum,
+        _ => ROOTINO,
+    }
+}
+
+// replace the hint on dev by f of it, the hint of another device is dropped
+fn set_free_inode_hint(dev: &Arc<dyn BlockDevice>, f: impl FnOnce(u32) -> u32) {
+    let mut hint = FREE_INODE_HINT.lock().unwrap();
+    let inum = f(hint_on(&hint, dev));
+    *hint = Some((dev.clone(), inum));
+}
+
+// mark inode i allocated as ftype if it is free, through the log.
+// returns the free inode as it was on disk
+fn claim_inode(dev: &Arc<dyn BlockDevice>, i: u32, ftype: FileType) -> Option<DiskInode> {
+    let (bno, off) = addr_of_inode(i);
+    let blk = wait_buffer_block(bno, dev.clone());
+    let mut blk_guard = blk.write().unwrap();
+    let free = blk_guard.read(off as usize, |dinode: &DiskInode| dinode.to_le());
+    if free.ftype != FileType::Free as u8 {
+        return None;
+    }
+    let mut dinode = free;
+    dinode.ftype = ftype as u8;
+    dinode.flags = 0;
+    dinode.generation = dinode.generation.wrapping_add(1);
+    blk_guard.write(off as usize, |diskinode: &mut DiskInode| {
+        *diskinode = dinode.to_le();
+    });
+    log_write(blk_guard);
+    Some(free)
+}
+
+// put back an inode claim_inode took, in the same transaction
+fn unclaim_inode(dev: &Arc<dyn BlockDevice>, i: u32, free: DiskInode) {
+    let (bno, off) = addr_of_inode(i);
+    let blk = wait_buffer_block(bno, dev.clone());
+    let mut blk_guard = blk.write().unwrap();
+    blk_guard.write(off as usize, |diskinode: &mut DiskInode| {
+        *diskinode = free.to_le();
+    });
+    log_write(blk_guard);
+}
+
+// mark n free inodes allocated in the caller's transaction, all of them or
+// none: if fewer are free, the ones taken are put back before it commits.
+// they are files without links, the caller gives them their type and links
+// before the transaction ends
+#[allow(unused)]
+pub fn reserve_inodes(dev: Arc<dyn BlockDevice>, n: u32) -> Option<Vec<u32>> {
+    let ninodes = sb().ninodes;
+    let start = free_inode_hint(&dev).clamp(ROOTINO, ninodes);
+    let mut taken = vec![];
+    for i in (start..ninodes).chain(ROOTINO..start) {
+        if taken.len() == n as usize {
+            break;
+        }
+        if let Some(free) = claim_inode(&dev, i, FileType::File) {
+            taken.push((i, free));
+        }
+    }
+    if taken.len() < n as usize {
+        for (i, free) in taken {
+            unclaim_inode(&dev, i, free);
+        }
+        return None;
+    }
+    if let Some(&(last, _)) = taken.last() {
+        set_free_inode_hint(&dev, |_| last + 1);
+    }
+    Some(taken.into_iter().map(|(i, _)| i).collect())
+}
+
 pub struct InodePtrManager(Mutex<Vec<InodePtr>>);
 
 impl InodePtrManager {
@@ -233,17 +567,11 @@ impl InodePtrManager {
     // mark an inode allocated in disk
     // and return an InodePtr with NonePtr
     pub fn inode_alloc(&self, dev: Arc<dyn BlockDevice>, ftype: FileType) -> Option<InodePtr> {
-        for i in ROOTINO..unsafe { SB.ninodes } {
-            let (bno, off) = addr_of_inode(i);
-            let blk = get_buffer_block(bno, dev.clone());
-            let mut blk_guard = blk.write().unwrap();
-            let mut dinode = blk_guard.read(off as usize, |dinode: &DiskInode| *dinode);
-            if dinode.ftype == FileType::Free as u16 {
-                dinode.ftype = ftype as u16;
-                blk_guard.write(off as usize, |diskinode: &mut DiskInode| {
-                    *diskinode = dinode;
-                });
-                log_write(blk_guard);
+        let ninodes = sb().ninodes;
+        let start = free_inode_hint(&dev).clamp(ROOTINO, ninodes);
+        for i in (start..ninodes).chain(ROOTINO..start) {
+            if claim_inode(&dev, i, ftype).is_some() {
+                set_free_inode_hint(&dev, |_| i + 1);
                 return Some(self.get_inode(dev.clone(), i));
             }
         }
@@ -254,7 +582,10 @@ impl InodePtrManager {
         let mut guard = self.0.lock().unwrap();
         let mut empty = 0;
         for (i, inode) in guard.iter().enumerate() {
-            if Arc::strong_count(&inode.0) > 1 && inode.0.inum == inum {
+            if Arc::strong_count(&inode.0) > 1
+                && inode.0.inum == inum
+                && inode.0.dev.as_ref().map(device_id) == Some(device_id(&dev))
+            {
                 return InodePtr(Arc::clone(&inode.0));
             }
             if empty == 0 && Arc::strong_count(&inode.0) == 1 {
@@ -269,9 +600,10 @@ impl InodePtrManager {
             dev: Some(dev.clone()),
             inum,
             dinode: Mutex::new(None),
+            entries: Mutex::new(()),
         }));
         info!("InodePtrManager::get_inode: get inode {}", inum);
-        return InodePtr(Arc::clone(&guard[i].0));
+        InodePtr(Arc::clone(&guard[i].0))
     }
 }
 
@@ -282,53 +614,207 @@ impl Drop for InodePtr {
         if Arc::strong_count(&self.0) == 2 {
             // lock the table
             info!("InodePtr::drop: drop inode {}", self.0.inum);
-            let table_guard = unsafe { INODE_CACHE.0.lock().unwrap() };
+            let table_guard = INODE_CACHE.0.lock().unwrap();
             let mut dinode = self.0.dinode.lock().unwrap();
             if dinode.is_some() {
                 let dinode = dinode.as_mut().unwrap();
-                if dinode.nlink == 0 {
+                if dinode.nlink == 0 && dinode.ftype != FileType::Free as u8 {
                     // truncate the inode
                     drop(table_guard);
                     Inode::truncate(self.0.dev.as_ref().unwrap().clone(), dinode);
                     info!("InodePtr::drop: truncate inode {}", self.0.inum);
-                    // update on disk
-                    self.0.modify_disk_inode(|dinode| {
-                        dinode.ftype = FileType::Free as u16;
-                        dinode.size = 0;
-                    });
+                    // the attributes go with the inode, not with its data
+                    let dev = self.0.dev.as_ref().unwrap().clone();
+                    let xattr = xattr_block(dev.clone(), self.0.inum);
+                    if xattr != 0 {
+                        block_free(dev.clone(), xattr);
+                        set_xattr_block(dev, self.0.inum, 0);
+                    }
+                    // update on disk, with the cleared addrs,
+                    // so the next inode_alloc does not inherit the freed blocks
+                    dinode.ftype = FileType::Free as u8;
+                    dinode.flags = 0;
+                    dinode.size = 0;
+                    let freed = *dinode;
+                    self.0.modify_disk_inode(|dinode| *dinode = freed);
+                    // the next scan starts at the freed inode at the latest
+                    let inum = self.0.inum;
+                    set_free_inode_hint(self.0.dev.as_ref().unwrap(), |hint| hint.min(inum));
                 }
             }
         }
     }
 }
 
-static mut INODE_CACHE: Lazy<InodePtrManager> = Lazy::new(|| InodePtrManager::new());
+static INODE_CACHE: Lazy<InodePtrManager> = Lazy::new(InodePtrManager::new);
 
 pub fn get_inode(dev: Arc<dyn BlockDevice>, inum: u32) -> InodePtr {
-    unsafe { INODE_CACHE.get_inode(dev, inum) }
+    INODE_CACHE.get_inode(dev, inum)
 }
 
 pub fn inode_alloc(dev: Arc<dyn BlockDevice>, ftype: FileType) -> Option<InodePtr> {
-    unsafe { INODE_CACHE.inode_alloc(dev, ftype) }
+    INODE_CACHE.inode_alloc(dev, ftype)
+}
+
+// name -> inum of every entry in a directory, built on the first lookup
+// and kept up to date by dirlink and dirunlink, so lookups in a large directory
+// do not scan all its blocks. keyed by device, inum and generation, a freed and
+// reallocated directory starts over
+type DirIndexKey = (usize, u32, u32);
+
+struct DirIndex {
+    // held, so its address is not reused by another image while the index is cached
+    dev: Arc<dyn BlockDevice>,
+    entries: HashMap<String, u32>,
+    // the lookup that last used it, the index used longest ago is dropped first
+    used: u64,
+}
+
+#[derive(Default)]
+struct DirIndexes {
+    dirs: HashMap<DirIndexKey, DirIndex>,
+    lookups: u64,
 }
 
-pub fn find_child(
+// the directories whose index is kept
+const DIR_INDEX_MAX: usize = 64;
+
+static DIR_INDEX: Lazy<Mutex<DirIndexes>> = Lazy::new(|| Mutex::new(DirIndexes::default()));
+
+// the directory scans of the current thread that built an index, tests
+// check these to tell a lookup in the index from one that read the blocks
+#[cfg(test)]
+thread_local! {
+    pub static DIR_SCANS: std::cell::Cell<u32> = const { std::cell::Cell::new(0) };
+}
+
+fn dir_index_key(dev: &Arc<dyn BlockDevice>, dir: u32, diskinode: &DiskInode) -> DirIndexKey {
+    (device_id(dev), dir, diskinode.generation)
+}
+
+// apply f to the index of dp, if it has been built
+fn dir_index_update(dp: &InodePtr, f: impl FnOnce(&mut HashMap<String, u32>)) {
+    let dev = dp.0.dev.clone().unwrap();
+    let key =
+        dp.0.read_disk_inode(|diskinode| dir_index_key(&dev, dp.0.inum, diskinode));
+    if let Some(dir) = DIR_INDEX.lock().unwrap().dirs.get_mut(&key) {
+        f(&mut dir.entries);
+    }
+}
+
+// drop the indexes of the directories on dev, and the hold on it, at unmount
+pub fn dir_index_release(dev: &Arc<dyn BlockDevice>) {
+    DIR_INDEX
+        .lock()
+        .unwrap()
+        .dirs
+        .retain(|_, dir| device_id(&dir.dev) != device_id(dev));
+}
+
+// the inode named name in the directory dp
+pub fn find_child(dp: &InodePtr, name: &str) -> Result<Option<InodePtr>, FsError> {
+    let dev = dp.0.dev.clone().unwrap();
+    let inum = {
+        let mut index = DIR_INDEX.lock().unwrap();
+        index.lookups += 1;
+        let used = index.lookups;
+        let key = dp.read_disk_inode(|diskinode| dir_index_key(&dev, dp.0.inum, diskinode));
+        if let Some(dir) = index.dirs.get_mut(&key) {
+            dir.used = used;
+            dir.entries.get(name).copied()
+        } else {
+            // the directory is read under its lock, so a dirlink running now
+            // lands either before the scan or in the index after it
+            let (key, entries) = dp.read_disk_inode(|diskinode| {
+                let entries = all_entries(dev.clone(), diskinode)?;
+                Ok::<_, FsError>((dir_index_key(&dev, dp.0.inum, diskinode), entries))
+            })?;
+            #[cfg(test)]
+            DIR_SCANS.with(|scans| scans.set(scans.get() + 1));
+            // "." and ".." are looked up like any other name
+            let entries = entries
+                .iter()
+                .filter(|entry| entry_in_range(entry))
+                .map(|entry| (entry_name(entry), entry.inum))
+                .collect::<HashMap<_, _>>();
+            let inum = entries.get(name).copied();
+            if index.dirs.len() >= DIR_INDEX_MAX {
+                let oldest = index
+                    .dirs
+                    .iter()
+                    .min_by_key(|(_, dir)| dir.used)
+                    .map(|(key, _)| *key)
+                    .unwrap();
+                index.dirs.remove(&oldest);
+            }
+            let dev = dev.clone();
+            index.dirs.insert(key, DirIndex { dev, entries, used });
+            inum
+        }
+    };
+    Ok(inum.map(|inum| get_inode(dev.clone(), inum)))
+}
+
+// the name of a dirent, up to the first NUL
+pub fn entry_name(entry: &DirEntry) -> String {
+    let len = entry
+        .name
+        .iter()
+        .position(|c| *c == 0)
+        .unwrap_or(entry.name.len());
+    let mut name = entry.name[..len].to_vec();
+    if len == entry.name.len() && !dirent_ftype_enabled() && entry.ftype != 0 {
+        name.push(entry.ftype);
+    }
+    String::from_utf8_lossy(&name).into_owned()
+}
+
+// the FileType a dirent keeps, Free when the image's dirents keep none
+pub fn entry_ftype(entry: &DirEntry) -> u8 {
+    stored_ftype(entry.ftype)
+}
+
+// a used dirent naming an inode the image does not have is corrupt.
+// lookups and listings skip it rather than read past the inode blocks,
+// fsck reports it
+pub(super) fn entry_in_range(entry: &DirEntry) -> bool {
+    let ninodes = sb().ninodes;
+    let valid = (ROOTINO..ninodes).contains(&entry.inum);
+    if !valid {
+        warn!(
+            "dirent {:?}: inode {} out of range, skipped",
+            entry_name(entry),
+            entry.inum
+        );
+    }
+    valid
+}
+
+// the used entries of a directory, except "." and "..", wherever they are
+pub(super) fn dir_entries(
     dev: Arc<dyn BlockDevice>,
-    diskinode: DiskInode,
-    name: &str,
-) -> Option<InodePtr> {
+    diskinode: &DiskInode,
+) -> Result<Vec<DirEntry>, FsError> {
+    let mut entries = all_entries(dev, diskinode)?;
+    entries.retain(|entry| !matches!(entry_name(entry).as_str(), "." | ".."));
+    Ok(entries)
+}
+
+// every used entry of a directory
+pub(super) fn all_entries(
+    dev: Arc<dyn BlockDevice>,
+    diskinode: &DiskInode,
+) -> Result<Vec<DirEntry>, FsError> {
     let mut entries = Vec::new();
     for i in 0..NDIRECT {
         if diskinode.addrs[i as usize] != 0 {
             // read entries
-            for j in (0 * std::mem::size_of::<DirEntry>()..BLOCK_SIZE as usize)
-                .step_by(std::mem::size_of::<DirEntry>())
-            {
-                let entry = get_buffer_block(diskinode.addrs[i as usize], dev.clone())
+            for j in (0..BLOCK_SIZE as usize).step_by(std::mem::size_of::<DirEntry>()) {
+                let entry = get_buffer_block(diskinode.addrs[i as usize], dev.clone())?
                     .read()
                     .unwrap()
-                    .read(j, |entry: &DirEntry| *entry);
-                if entry.inum != 0 && !(i == 0 && j <= 2) {
+                    .read(j, |entry: &DirEntry| entry.to_le());
+                if entry.inum != 0 {
                     entries.push(entry);
                 }
             }
@@ -336,18 +822,18 @@ pub fn find_child(
     }
     // read indirect block
     if diskinode.addrs[NDIRECT as usize] != 0 {
-        let addrs = get_buffer_block(diskinode.addrs[NDIRECT as usize], dev.clone())
+        let addrs = get_buffer_block(diskinode.addrs[NDIRECT as usize], dev.clone())?
             .read()
             .unwrap()
-            .read(0, |addrs: &[u32; NINDIRECT as usize]| *addrs);
+            .read(0, |addrs: &[u32; NINDIRECT as usize]| addrs.to_le());
         for i in 0..NINDIRECT as usize {
             if addrs[i] != 0 {
                 // read entries
                 for j in (0..BLOCK_SIZE).step_by(std::mem::size_of::<DirEntry>()) {
-                    let entry = get_buffer_block(addrs[i], dev.clone())
+                    let entry = get_buffer_block(addrs[i], dev.clone())?
                         .read()
                         .unwrap()
-                        .read(j as usize, |entry: &DirEntry| *entry);
+                        .read(j as usize, |entry: &DirEntry| entry.to_le());
                     if entry.inum != 0 {
                         entries.push(entry);
                     }
@@ -355,30 +841,227 @@ pub fn find_child(
             }
         }
     }
-    for entry in entries {
-        if namecmp(&entry.name, &name.to_string()) {
-            return Some(get_inode(dev.clone(), entry.inum));
-        }
+    Ok(entries)
+}
+
+// check a path component before it is looked up or stored in a dirent
+fn check_name(name: &OsStr) -> Result<&str, FsError> {
+    let name = name.to_str().ok_or(FsError::InvalidName)?;
+    if name.is_empty() || name.contains('\0') {
+        return Err(FsError::InvalidName);
     }
-    None
+    if name.len() > NAMESIZE as usize {
+        return Err(FsError::NameTooLong);
+    }
+    Ok(name)
+}
+
+// the directory "/" names, ROOTINO unless a subtree is mounted.
+// ".." in it stays there, so lookups cannot leave the subtree
+static ROOT: AtomicU32 = AtomicU32::new(ROOTINO);
+
+pub fn set_root(inum: u32) {
+    ROOT.store(inum, Ordering::SeqCst);
+}
+
+pub fn root_inum() -> u32 {
+    ROOT.load(Ordering::SeqCst)
+}
+
+// the most symlinks one lookup follows, so a loop of links ends
+pub const MAXSYMLINKS: u32 = 8;
+
+/// path should be absolute path,
+/// repeated separators and `.` are skipped, `..` is looked up in the directory.
+/// symlinks are followed, the last component included
+pub fn resolve(dev: Arc<dyn BlockDevice>, path: &Path) -> Result<InodePtr, FsError> {
+    namei(dev, path, true)
+}
+
+// like resolve, but a symlink in the last component is returned itself
+pub fn resolve_nofollow(dev: Arc<dyn BlockDevice>, path: &Path) -> Result<InodePtr, FsError> {
+    namei(dev, path, false)
+}
+
+// the result of walking a path up to the first symlink to follow
+enum Walk {
+    Found(InodePtr),
+    // the path to walk again, with the symlink replaced by its target
+    Link(PathBuf),
 }
 
-pub fn find_inode(dev: Arc<dyn BlockDevice>, path: &PathBuf) -> Option<InodePtr> {
-    let mut inode = get_inode(dev.clone(), ROOTINO);
-    if *path == PathBuf::from("/") {
-        return Some(inode);
+fn namei(dev: Arc<dyn BlockDevice>, path: &Path, follow: bool) -> Result<InodePtr, FsError> {
+    let mut path = path.to_path_buf();
+    for _ in 0..=MAXSYMLINKS {
+        match walk(dev.clone(), &path, follow)? {
+            Walk::Found(inode) => return Ok(inode),
+            Walk::Link(next) => path = next,
+        }
     }
-    if path.iter().next() != Some(&OsString::from("/")) {
-        return None;
+    Err(FsError::TooManyLinks)
+}
+
+fn walk(dev: Arc<dyn BlockDevice>, path: &Path, follow: bool) -> Result<Walk, FsError> {
+    let mut components = path.components();
+    if components.next() != Some(Component::RootDir) {
+        return Err(FsError::InvalidName);
     }
-    for name in path.iter().skip(1) {
+    let root = root_inum();
+    let mut inode = get_inode(dev.clone(), root);
+    // the path of the directory being searched, a relative symlink starts there
+    let mut dir_path = PathBuf::from("/");
+    while let Some(component) = components.next() {
+        let name = match component {
+            Component::Normal(name) => check_name(name)?,
+            Component::ParentDir if inode.0.inum == root => continue,
+            Component::ParentDir => "..",
+            Component::CurDir => continue,
+            _ => return Err(FsError::InvalidName),
+        };
         let dinode = inode.0.read_disk_inode(|diskinode| *diskinode);
-        inode = match find_child(dev.clone(), dinode, name.to_str().unwrap()) {
-            Some(inode) => inode,
-            None => return None,
+        if dinode.ftype != FileType::Dir as u8 {
+            namei_trace(|| format!("{:?} in inum {}: not a directory", name, inode.0.inum));
+            return Err(FsError::NotDirectory);
+        }
+        let dir = inode.0.inum;
+        inode = match find_child(&inode, name)? {
+            Some(child) => child,
+            None => {
+                namei_trace(|| format!("{:?} in dir {}: not found", name, dir));
+                return Err(FsError::NotFound);
+            }
+        };
+        namei_trace(|| format!("{:?} in dir {}: inum {}", name, dir, inode.0.inum));
+        let rest = components.as_path();
+        let ftype = inode.0.read_disk_inode(|diskinode| diskinode.ftype);
+        if ftype == FileType::Symlink as u8 && (follow || rest.components().next().is_some()) {
+            let target = PathBuf::from(readlink(&mut inode)?);
+            // an absolute target replaces dir_path, a relative one is joined to it
+            return Ok(Walk::Link(canonicalize(&dir_path.join(target).join(rest))?));
+        }
+        if name == ".." {
+            dir_path.pop();
+        } else {
+            dir_path.push(name);
+        }
+    }
+    Ok(Walk::Found(inode))
+}
+
+// the target a symlink holds
+pub fn readlink(ip: &mut InodePtr) -> Result<String, FsError> {
+    let size = ip.read_disk_inode(|diskinode| diskinode.size as usize);
+    let mut buf = vec![0u8; size];
+    rinode(ip, &mut buf, 0, size)?;
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+// the most names a canonical path holds, deeper paths are refused
+// before they grow an unbounded PathBuf
+pub const MAXPATHDEPTH: usize = 256;
+
+// drop "." and apply ".." to a path without looking anything up. a relative
+// path is taken from "/" and ".." at "/" stays there, as in a lookup
+pub fn canonicalize(path: &Path) -> Result<PathBuf, FsError> {
+    let mut names = vec![];
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                names.pop();
+            }
+            Component::Normal(name) => {
+                if name.len() > NAMESIZE as usize || names.len() == MAXPATHDEPTH {
+                    return Err(FsError::NameTooLong);
+                }
+                names.push(name);
+            }
+            _ => {}
         }
     }
-    Some(inode)
+    Ok(names
+        .iter()
+        .fold(PathBuf::from("/"), |path, name| path.join(name)))
+}
+
+// the steps of a path lookup, logged at trace level under the "namei" target
+// so `shell --trace` can show where a lookup stops
+fn namei_trace(step: impl FnOnce() -> String) {
+    if !cfg!(test) && !log_enabled!(target: "namei", Level::Trace) {
+        return;
+    }
+    let step = step();
+    trace!(target: "namei", "{}", step);
+    #[cfg(test)]
+    NAMEI_TRACE.with(|trace| trace.borrow_mut().push(step));
+}
+
+// the lookups of the current thread, tests check these
+// rather than installing a logger for the whole process
+#[cfg(test)]
+thread_local! {
+    pub static NAMEI_TRACE: std::cell::RefCell<Vec<String>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+// resolve without the error. ".." at "/" stays there, so "/.." and "/a/.."
+// name the root just as they do in the shell's canonicalize
+pub fn find_inode(dev: Arc<dyn BlockDevice>, path: &Path) -> Option<InodePtr> {
+    resolve(dev, path).ok()
+}
+
+// a path naming inum, the reverse of resolve. a directory finds its parent
+// through "..", anything else is looked for in every directory. with several
+// hard links the first one found is used
+#[allow(unused)]
+pub fn inode_to_path(dev: Arc<dyn BlockDevice>, inum: u32) -> Option<PathBuf> {
+    let ninodes = sb().ninodes;
+    if inum < ROOTINO || inum >= ninodes {
+        return None;
+    }
+    let root = root_inum();
+    let mut names = vec![];
+    let mut child = inum;
+    // a damaged tree may loop, no path is longer than the number of inodes
+    while child != root && names.len() < ninodes as usize {
+        let dinode = get_inode(dev.clone(), child)
+            .0
+            .read_disk_inode(|dinode| *dinode);
+        let (parent, name) = if dinode.ftype == FileType::Dir as u8 {
+            let dir = get_inode(dev.clone(), child);
+            let parent = find_child(&dir, "..").ok()??.0.inum;
+            (parent, name_in(dev.clone(), parent, child)?)
+        } else if dinode.ftype == FileType::Free as u8 {
+            return None;
+        } else {
+            (ROOTINO..ninodes).find_map(|dir| Some((dir, name_in(dev.clone(), dir, child)?)))?
+        };
+        names.push(name);
+        child = parent;
+    }
+    // outside the mounted subtree
+    if child != root {
+        return None;
+    }
+    Some(
+        names
+            .iter()
+            .rev()
+            .fold(PathBuf::from("/"), |path, name| path.join(name)),
+    )
+}
+
+// the name of the first entry of dir that refers to inum
+fn name_in(dev: Arc<dyn BlockDevice>, dir: u32, inum: u32) -> Option<String> {
+    let dinode = get_inode(dev.clone(), dir)
+        .0
+        .read_disk_inode(|dinode| *dinode);
+    if dinode.ftype != FileType::Dir as u8 {
+        return None;
+    }
+    dir_entries(dev, &dinode)
+        .ok()?
+        .iter()
+        .find(|entry| entry.inum == inum)
+        .map(entry_name)
 }
 
 pub fn find_parent_inode(dev: Arc<dyn BlockDevice>, path: &PathBuf) -> Option<InodePtr> {
@@ -386,16 +1069,26 @@ pub fn find_parent_inode(dev: Arc<dyn BlockDevice>, path: &PathBuf) -> Option<In
     find_inode(dev, &parent)
 }
 
-pub fn dirlink(dp: &mut InodePtr, name: &str, inum: u32) {
+// the type byte a new dirent gets, nothing on an image whose dirents keep none
+fn stored_ftype(ftype: u8) -> u8 {
+    if dirent_ftype_enabled() {
+        ftype
+    } else {
+        FileType::Free as u8
+    }
+}
+
+pub fn dirlink(dp: &mut InodePtr, name: &str, inum: u32, ftype: u8) -> Result<(), FsError> {
     // look for an empty dirent
     let mut de = DirEntry::default();
     let size = dp.0.read_disk_inode(|diskinode| diskinode.size as usize);
     let mut offset = 0;
     for off in (0..size).step_by(std::mem::size_of::<DirEntry>()) {
         let mut buf = [0u8; std::mem::size_of::<DirEntry>()];
-        rinode(dp, &mut buf, off, std::mem::size_of::<DirEntry>());
+        rinode(dp, &mut buf, off, std::mem::size_of::<DirEntry>())?;
         let entry =
-            unsafe { std::mem::transmute::<[u8; std::mem::size_of::<DirEntry>()], DirEntry>(buf) };
+            unsafe { std::mem::transmute::<[u8; std::mem::size_of::<DirEntry>()], DirEntry>(buf) }
+                .to_le();
         if entry.inum == 0 {
             de = entry;
             offset = off;
@@ -405,21 +1098,97 @@ pub fn dirlink(dp: &mut InodePtr, name: &str, inum: u32) {
     }
 
     de.inum = inum;
+    de.ftype = stored_ftype(ftype);
     nameassign(&mut de.name, &name.to_string());
 
-    let src = unsafe { std::mem::transmute::<DirEntry, [u8; std::mem::size_of::<DirEntry>()]>(de) };
-    winode(dp, &src, offset, src.len());
+    let src = unsafe {
+        std::mem::transmute::<DirEntry, [u8; std::mem::size_of::<DirEntry>()]>(de.to_le())
+    };
+    winode(dp, &src, offset, src.len())?;
+    dir_index_update(dp, |entries| {
+        entries.insert(name.to_string(), inum);
+    });
+    Ok(())
+}
+
+// whether dirlink has somewhere to put one more entry: a free slot, or room
+// to grow. a directory at MAXFILE blocks has neither once every slot is used,
+// and dirlink would drop the entry, so create, link and rename ask first
+pub(super) fn dir_has_room(
+    dev: Arc<dyn BlockDevice>,
+    diskinode: &DiskInode,
+) -> Result<bool, FsError> {
+    let slots = diskinode.size as usize / std::mem::size_of::<DirEntry>();
+    Ok(
+        diskinode.size as usize + std::mem::size_of::<DirEntry>()
+            <= (MAXFILE * BLOCK_SIZE) as usize
+            || all_entries(dev, diskinode)?.len() < slots,
+    )
+}
+
+// dirlink for many entries at once: the directory is read once, the entries
+// fill its free slots and then its end, and each block they land in is
+// written once. like dirlink it only log_writes, the caller's transaction
+// must have room for every block touched, and the names must not exist yet
+#[allow(unused)]
+pub fn dir_add_many(dp: &mut InodePtr, entries: &[(&str, u32, u8)]) -> Result<(), FsError> {
+    const DESIZE: usize = std::mem::size_of::<DirEntry>();
+    // the free slots are read and filled under the lock, as in create
+    let dir = InodePtr(dp.0.clone());
+    let _entries = dir.0.entries.lock().unwrap();
+    let size = dp.0.read_disk_inode(|diskinode| diskinode.size as usize);
+    let mut buf = vec![0u8; size];
+    rinode(dp, &mut buf, 0, size)?;
+    let mut free = (0..size)
+        .step_by(DESIZE)
+        .filter(|&off| {
+            let raw: [u8; DESIZE] = buf[off..off + DESIZE].try_into().unwrap();
+            unsafe { std::mem::transmute::<[u8; DESIZE], DirEntry>(raw) }
+                .to_le()
+                .inum
+                == 0
+        })
+        .collect::<Vec<_>>()
+        .into_iter();
+    let mut dirty = std::collections::BTreeSet::new();
+    for &(name, inum, ftype) in entries {
+        let off = free.next().unwrap_or_else(|| {
+            buf.resize(buf.len() + DESIZE, 0);
+            buf.len() - DESIZE
+        });
+        let mut de = DirEntry {
+            inum,
+            ftype: stored_ftype(ftype),
+            ..Default::default()
+        };
+        nameassign(&mut de.name, &name.to_string());
+        let src = unsafe { std::mem::transmute::<DirEntry, [u8; DESIZE]>(de.to_le()) };
+        buf[off..off + DESIZE].copy_from_slice(&src);
+        dirty.insert(off / BLOCK_SIZE as usize);
+    }
+    for b in dirty {
+        let start = b * BLOCK_SIZE as usize;
+        let end = buf.len().min(start + BLOCK_SIZE as usize);
+        winode(dp, &buf[start..end], start, end - start)?;
+    }
+    dir_index_update(dp, |index| {
+        for &(name, inum, _) in entries {
+            index.insert(name.to_string(), inum);
+        }
+    });
+    Ok(())
 }
 
-pub fn dirunlink(dp: &mut InodePtr, name: &str) -> Result<(), String> {
+pub fn dirunlink(dp: &mut InodePtr, name: &str) -> Result<(), FsError> {
     let mut de = DirEntry::default();
     let size = dp.0.read_disk_inode(|diskinode| diskinode.size as usize);
     let mut offset = 0;
     for off in (0..size).step_by(std::mem::size_of::<DirEntry>()) {
         let mut buf = [0u8; std::mem::size_of::<DirEntry>()];
-        rinode(dp, &mut buf, off, std::mem::size_of::<DirEntry>());
+        rinode(dp, &mut buf, off, std::mem::size_of::<DirEntry>())?;
         let entry =
-            unsafe { std::mem::transmute::<[u8; std::mem::size_of::<DirEntry>()], DirEntry>(buf) };
+            unsafe { std::mem::transmute::<[u8; std::mem::size_of::<DirEntry>()], DirEntry>(buf) }
+                .to_le();
         if namecmp(&entry.name, &name.to_string()) {
             de = entry;
             offset = off;
@@ -428,149 +1197,633 @@ pub fn dirunlink(dp: &mut InodePtr, name: &str) -> Result<(), String> {
         offset += std::mem::size_of::<DirEntry>();
     }
     if de.inum == 0 {
-        return Err("dirunlink: no entry".to_string());
+        return Err(FsError::NotFound);
     }
     de.inum = 0;
+    de.ftype = FileType::Free as u8;
     nameassign(&mut de.name, &"".to_string());
-    let src = unsafe { std::mem::transmute::<DirEntry, [u8; std::mem::size_of::<DirEntry>()]>(de) };
-    winode(dp, &src, offset, src.len());
+    let src = unsafe {
+        std::mem::transmute::<DirEntry, [u8; std::mem::size_of::<DirEntry>()]>(de.to_le())
+    };
+    winode(dp, &src, offset, src.len())?;
+    dir_index_update(dp, |entries| {
+        entries.remove(name);
+    });
     // decrease dp's size
     Ok(())
 }
 
-pub fn create(dev: Arc<dyn BlockDevice>, path: &PathBuf, filetype: FileType) -> Result<InodePtr, String> {
-    let parent_dir = find_parent_inode(dev.clone(), path);
-    if parent_dir.is_none() {
-        return Err("create: no parent dir".to_string());
+pub fn create(dev: Arc<dyn BlockDevice>, path: &Path, filetype: FileType) -> Result<InodePtr, FsError> {
+    if read_only() {
+        return Err(FsError::ReadOnly);
     }
-    let mut dp = parent_dir.unwrap();
+    let name = match path.file_name() {
+        Some(name) => check_name(name)?,
+        None => return Err(FsError::InvalidName),
+    };
+    let mut dp = resolve(dev.clone(), path.parent().unwrap())?;
+    // a racer creating the same name waits here and then finds it.
+    // dirlink takes dp mutably, so the lock is held through a second handle
+    let dir = InodePtr(dp.0.clone());
+    let _entries = dir.0.entries.lock().unwrap();
     let dp_dinode = dp.0.read_disk_inode(|diskinode| *diskinode);
+    if dp_dinode.ftype != FileType::Dir as u8 {
+        return Err(FsError::NotDirectory);
+    }
     // alloc
-    let dp_guard = dp.0.dinode.lock().unwrap();
-    let ip = find_child(
-        dev.clone(),
-        dp_dinode,
-        path.file_name().unwrap().to_str().unwrap(),
-    );
-    if let Some(inode) = ip {
-        if inode.0.read_disk_inode(|diskinode| diskinode.ftype) == filetype as u16 {
-            return Err("create: file exists".to_string());
-        }
+    // inode_alloc and the dirlinks below only log_write, so they land in the
+    // caller's transaction and commit together
+    // a name is taken whatever the type behind it, a directory never holds it twice
+    if find_child(&dp, name)?.is_some() {
+        return Err(FsError::AlreadyExists);
+    }
+    // the ".." of a new directory is one more link to dp
+    if filetype == FileType::Dir && dp_dinode.nlink == MAXNLINK {
+        return Err(FsError::MaxLinks);
+    }
+    if !dir_has_room(dev.clone(), &dp_dinode)? {
+        return Err(FsError::FileTooBig);
     }
     if let Some(mut ip) = inode_alloc(dev.clone(), filetype) {
         // init
         ip.modify_disk_inode(|diskinode| {
-            diskinode.nlink = 1;
+            diskinode.nlink = if filetype == FileType::Dir {
+                DIR_NLINK
+            } else {
+                1
+            };
             diskinode.size = 0;
+            if matches!(filetype, FileType::File | FileType::Symlink) && inline_enabled() {
+                diskinode.flags = INLINE_DATA;
+            }
         });
         // the inode ptr will not be dropped, so it's safe to lock stagely
+        let linked = (|| -> Result<(), FsError> {
+            if filetype == FileType::Dir {
+                // create . and ..
+                let ip_inum = ip.0.inum;
+                dirlink(&mut ip, ".", ip_inum, FileType::Dir as u8)?;
+                dirlink(&mut ip, "..", dp.0.inum, FileType::Dir as u8)?;
+            }
+            dirlink(&mut dp, name, ip.0.inum, filetype as u8)
+        })();
+        // an inode no entry names is freed with its last reference
+        if let Err(e) = linked {
+            ip.modify_disk_inode(|diskinode| diskinode.nlink = 0);
+            return Err(e);
+        }
         if filetype == FileType::Dir {
-            // create . and ..
-            let ip_inum = ip.0.inum;
-            dirlink(&mut ip, ".", ip_inum);
-            dirlink(&mut ip, "..", dp.0.inum);
-        }
-        let name = path.file_name().unwrap().to_str().unwrap();
-        drop(dp_guard);
-        dirlink(&mut dp, name, ip.0.inum);
-        if filetype == FileType::Dir {
-            // update parent dir size
+            // the ".." of ip links dp
             dp.modify_disk_inode(|diskinode| diskinode.nlink += 1);
         }
         Ok(ip)
     } else {
-        Err("no free inode".to_string())
+        Err(FsError::NoSpace)
     }
 }
 
-// get the bn'th block of inode
-pub fn block_map(diskinode: &mut DiskInode, dev: Arc<dyn BlockDevice>, mut offset_bn: u32) -> u32 {
-    let mut addr;
-    if offset_bn < NDIRECT {
-        if diskinode.addrs[offset_bn as usize] == 0 {
-            addr = block_alloc(dev.clone());
-            diskinode.addrs[offset_bn as usize] = addr.unwrap();
-        } else {
-            addr = Some(diskinode.addrs[offset_bn as usize]);
-        }
-        return addr.unwrap();
+// give the inode at src the second name dst, like link(2). a symlink is
+// linked itself, and a directory keeps the single name its ".." relies on
+pub fn link(dev: Arc<dyn BlockDevice>, src: &Path, dst: &Path) -> Result<(), FsError> {
+    if read_only() {
+        return Err(FsError::ReadOnly);
     }
-    offset_bn -= NDIRECT;
-    if offset_bn < NINDIRECT {
-        if diskinode.addrs[NDIRECT as usize] == 0 {
-            addr = block_alloc(dev.clone());
-            diskinode.addrs[NDIRECT as usize] = addr.unwrap();
-        }
-        let mut addrs = get_buffer_block(diskinode.addrs[NDIRECT as usize], dev.clone())
-            .read()
-            .unwrap()
-            .read(0, |addrs: &[u32; NINDIRECT as usize]| *addrs);
-        if addrs[offset_bn as usize] == 0 {
-            addr = block_alloc(dev.clone());
-            addrs[offset_bn as usize] = addr.unwrap();
-            let blk = get_buffer_block(diskinode.addrs[NDIRECT as usize], dev.clone());
+    let name = match dst.file_name() {
+        Some(name) => check_name(name)?,
+        None => return Err(FsError::InvalidName),
+    };
+    let ip = resolve_nofollow(dev.clone(), src)?;
+    let ftype = ip.read_disk_inode(|diskinode| diskinode.ftype);
+    if ftype == FileType::Dir as u8 {
+        return Err(FsError::IsDirectory);
+    }
+    let mut dp = resolve(dev.clone(), dst.parent().unwrap())?;
+    // as in create, dst is looked up and linked under the lock
+    let dir = InodePtr(dp.0.clone());
+    let _entries = dir.0.entries.lock().unwrap();
+    let dp_dinode = dp.0.read_disk_inode(|diskinode| *diskinode);
+    if dp_dinode.ftype != FileType::Dir as u8 {
+        return Err(FsError::NotDirectory);
+    }
+    if find_child(&dp, name)?.is_some() {
+        return Err(FsError::AlreadyExists);
+    }
+    if !dir_has_room(dev.clone(), &dp_dinode)? {
+        return Err(FsError::FileTooBig);
+    }
+    // the check and the increment are one step, so two links can not both
+    // pass the check
+    let mut linked = false;
+    ip.modify_disk_inode(|diskinode| {
+        if diskinode.nlink < MAXNLINK {
+            diskinode.nlink += 1;
+            linked = true;
+        }
+    });
+    if !linked {
+        return Err(FsError::MaxLinks);
+    }
+    dirlink(&mut dp, name, ip.0.inum, ftype)
+        .inspect_err(|_| ip.modify_disk_inode(|diskinode| diskinode.nlink -= 1))
+}
+
+// create dst sharing the data blocks of src,
+// a block is copied when either file writes to it
+pub fn reflink(dev: Arc<dyn BlockDevice>, src: &Path, dst: &Path) -> Result<InodePtr, FsError> {
+    // an image made without the refcount map can not count a second owner
+    if !reflink_enabled() {
+        return Err(FsError::NotSupported);
+    }
+    let src = resolve(dev.clone(), src)?.read_disk_inode(|diskinode| *diskinode);
+    if src.ftype != FileType::File as u8 {
+        return Err(FsError::IsDirectory);
+    }
+    let ip = create(dev.clone(), dst, FileType::File)?;
+    if let Err(e) = share_blocks(dev.clone(), &src, &ip) {
+        // an empty dst would pass for a copy, it goes again and the inode
+        // no entry names is freed with its last reference
+        let name = dst.file_name().unwrap().to_str().unwrap();
+        let unlinked =
+            resolve(dev, dst.parent().unwrap()).and_then(|mut dp| dirunlink(&mut dp, name));
+        if unlinked.is_ok() {
+            ip.modify_disk_inode(|diskinode| diskinode.nlink = 0);
+        }
+        return Err(e);
+    }
+    Ok(ip)
+}
+
+// give the new file ip the blocks of src, each one with one more owner.
+// the counts only change once nothing else can fail
+fn share_blocks(dev: Arc<dyn BlockDevice>, src: &DiskInode, ip: &InodePtr) -> Result<(), FsError> {
+    if is_inline(src) {
+        // the bytes are copied with the inode, there is no block to share
+        ip.modify_disk_inode(|diskinode| {
+            diskinode.size = src.size;
+            diskinode.addrs = src.addrs;
+        });
+        return Ok(());
+    }
+    let mut addrs = src.addrs;
+    let mut shared = src.addrs[..NDIRECT as usize].to_vec();
+    if src.addrs[NDIRECT as usize] != 0 {
+        // the indirect block is not shared, each file gets its own copy
+        let indirect = get_buffer_block(src.addrs[NDIRECT as usize], dev.clone())?
+            .read()
+            .unwrap()
+            .read(0, |addrs: &[u32; NINDIRECT as usize]| addrs.to_le());
+        let copy = block_alloc(dev.clone())?;
+        let blk =
+            get_buffer_block(copy, dev.clone()).inspect_err(|_| block_free(dev.clone(), copy))?;
+        let mut guard = blk.write().unwrap();
+        guard.write(0, |data: &mut [u32; NINDIRECT as usize]| {
+            *data = indirect.to_le();
+        });
+        log_write(guard);
+        addrs[NDIRECT as usize] = copy;
+        shared.extend(indirect);
+    }
+    shared
+        .iter()
+        .filter(|b| **b != 0)
+        .for_each(|b| modify_block_refs(dev.clone(), *b, |refs| *refs += 1));
+    ip.modify_disk_inode(|diskinode| {
+        diskinode.size = src.size;
+        diskinode.flags = src.flags;
+        diskinode.addrs = addrs;
+    });
+    Ok(())
+}
+
+// move the entry src to dst. an existing dst is replaced: it loses the
+// link and is freed with its last one. everything only log_writes, so the
+// caller's transaction commits the move at once and a reader sees either
+// the old dst or src
+pub fn rename(dev: Arc<dyn BlockDevice>, src: &Path, dst: &Path) -> Result<(), FsError> {
+    if read_only() {
+        return Err(FsError::ReadOnly);
+    }
+    let (sname, dname) = match (src.file_name(), dst.file_name()) {
+        (Some(sname), Some(dname)) => (check_name(sname)?, check_name(dname)?),
+        _ => return Err(FsError::InvalidName),
+    };
+    let mut sdp = resolve(dev.clone(), src.parent().unwrap())?;
+    let mut ddp = resolve(dev.clone(), dst.parent().unwrap())?;
+    // as in create, dst is looked up and linked under the lock of ddp
+    let dir = InodePtr(ddp.0.clone());
+    let _entries = dir.0.entries.lock().unwrap();
+    let sdp_dinode = sdp.0.read_disk_inode(|diskinode| *diskinode);
+    let ddp_dinode = ddp.0.read_disk_inode(|diskinode| *diskinode);
+    if sdp_dinode.ftype != FileType::Dir as u8 || ddp_dinode.ftype != FileType::Dir as u8 {
+        return Err(FsError::NotDirectory);
+    }
+    let mut ip = find_child(&sdp, sname)?.ok_or(FsError::NotFound)?;
+    let ftype = ip.read_disk_inode(|diskinode| diskinode.ftype);
+    let is_dir = ftype == FileType::Dir as u8;
+    if is_dir {
+        // a directory can not move under itself, walk up from ddp to the root
+        let mut dir = ddp.0.inum;
+        while dir != root_inum() && dir != ROOTINO {
+            if dir == ip.0.inum {
+                return Err(FsError::InvalidName);
+            }
+            dir = find_child(&get_inode(dev.clone(), dir), "..")?
+                .ok_or(FsError::NotFound)?
+                .0
+                .inum;
+        }
+    }
+    let old = find_child(&ddp, dname)?;
+    if let Some(old) = &old {
+        // a second name of the same inode, nothing moves
+        if old.0.inum == ip.0.inum {
+            return Ok(());
+        }
+        let old_dinode = old.read_disk_inode(|diskinode| *diskinode);
+        match (is_dir, old_dinode.ftype == FileType::Dir as u8) {
+            (true, false) => return Err(FsError::NotDirectory),
+            (false, true) => return Err(FsError::IsDirectory),
+            // only ".." is left in an empty directory
+            (true, true) if !dir_entries(dev.clone(), &old_dinode)?.is_empty() => {
+                return Err(FsError::NotEmpty)
+            }
+            _ => {}
+        }
+    }
+    // the ".." of a directory moving in is one more link to ddp,
+    // unless it replaces a directory
+    let replaces_dir = match &old {
+        Some(old) => old.read_disk_inode(|diskinode| diskinode.ftype) == FileType::Dir as u8,
+        None => false,
+    };
+    if is_dir && sdp.0.inum != ddp.0.inum && !replaces_dir && ddp_dinode.nlink == MAXNLINK {
+        return Err(FsError::MaxLinks);
+    }
+    // a replaced dst gives its slot to src
+    if old.is_none() && !dir_has_room(dev.clone(), &ddp_dinode)? {
+        return Err(FsError::FileTooBig);
+    }
+    // as in fileunlink, a corrupt nlink stops at 0
+    if let Some(old) = old {
+        dirunlink(&mut ddp, dname)?;
+        if is_dir {
+            // as fileunlink, the ".." of old linked ddp
+            old.modify_disk_inode(|diskinode| {
+                diskinode.nlink = diskinode.nlink.saturating_sub(DIR_NLINK)
+            });
+            ddp.modify_disk_inode(|diskinode| diskinode.nlink = diskinode.nlink.saturating_sub(1));
+        } else {
+            old.modify_disk_inode(|diskinode| diskinode.nlink = diskinode.nlink.saturating_sub(1));
+        }
+        // the last reference truncates old, inside the transaction
+        drop(old);
+    }
+    dirlink(&mut ddp, dname, ip.0.inum, ftype)?;
+    dirunlink(&mut sdp, sname)?;
+    if is_dir && sdp.0.inum != ddp.0.inum {
+        dirunlink(&mut ip, "..")?;
+        dirlink(&mut ip, "..", ddp.0.inum, FileType::Dir as u8)?;
+        sdp.modify_disk_inode(|diskinode| diskinode.nlink = diskinode.nlink.saturating_sub(1));
+        ddp.modify_disk_inode(|diskinode| diskinode.nlink += 1);
+    }
+    Ok(())
+}
+
+// get the bn'th block of inode without allocating, 0 for a hole
+pub fn block_lookup(
+    diskinode: &DiskInode,
+    dev: Arc<dyn BlockDevice>,
+    mut offset_bn: u32,
+) -> Result<u32, FsError> {
+    if offset_bn < NDIRECT {
+        return Ok(diskinode.addrs[offset_bn as usize]);
+    }
+    offset_bn -= NDIRECT;
+    if offset_bn < NINDIRECT && diskinode.addrs[NDIRECT as usize] != 0 {
+        return Ok(get_buffer_block(diskinode.addrs[NDIRECT as usize], dev)?
+            .read()
+            .unwrap()
+            .read(0, |addrs: &[u32; NINDIRECT as usize]| {
+                addrs[offset_bn as usize].to_le()
+            }));
+    }
+    Ok(0)
+}
+
+// how the blocks of files lie on the disk. a run is a stretch of blocks that
+// follow each other on the disk in the order the file reads them
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Fragmentation {
+    // the files with at least one block
+    pub files: u32,
+    pub blocks: u32,
+    pub runs: u32,
+}
+
+impl Fragmentation {
+    // the share of the possible breaks between runs that are there:
+    // 0 when every file is one run, 1 when no two blocks of a file are adjacent
+    pub fn score(&self) -> f64 {
+        match self.blocks - self.files {
+            0 => 0.0,
+            breaks => (self.runs - self.files) as f64 / breaks as f64,
+        }
+    }
+
+    fn add(&mut self, other: Fragmentation) {
+        self.files += other.files;
+        self.blocks += other.blocks;
+        self.runs += other.runs;
+    }
+}
+
+// walk the block map of an inode in logical order, the holes are skipped.
+// only the data blocks count, not the indirect block
+pub fn fragmentation(
+    diskinode: &DiskInode,
+    dev: Arc<dyn BlockDevice>,
+) -> Result<Fragmentation, FsError> {
+    let mut frag = Fragmentation::default();
+    // devices keep their numbers in addrs, fifos keep nothing on disk
+    let has_blocks = diskinode.ftype == FileType::File as u8
+        || diskinode.ftype == FileType::Dir as u8
+        || diskinode.ftype == FileType::Symlink as u8;
+    if !has_blocks || is_inline(diskinode) {
+        return Ok(frag);
+    }
+    let mut prev = None;
+    for bn in 0..diskinode.size.div_ceil(BLOCK_SIZE) {
+        let addr = block_lookup(diskinode, dev.clone(), bn)?;
+        if addr == 0 {
+            continue;
+        }
+        if prev != Some(addr - 1) {
+            frag.runs += 1;
+        }
+        frag.blocks += 1;
+        prev = Some(addr);
+    }
+    frag.files = (frag.blocks > 0) as u32;
+    Ok(frag)
+}
+
+// the fragmentation of every inode in use, added up
+pub fn image_fragmentation(dev: Arc<dyn BlockDevice>) -> Result<Fragmentation, FsError> {
+    let mut frag = Fragmentation::default();
+    for inum in ROOTINO..sb().ninodes {
+        let (bno, off) = addr_of_inode(inum);
+        let dinode = wait_buffer_block(bno, dev.clone())
+            .read()
+            .unwrap()
+            .read(off as usize, |dinode: &DiskInode| dinode.to_le());
+        frag.add(fragmentation(&dinode, dev.clone())?);
+    }
+    Ok(frag)
+}
+
+// get the bn'th block of inode, NoSpace if a block it needs can not be allocated
+pub fn block_map(
+    diskinode: &mut DiskInode,
+    dev: Arc<dyn BlockDevice>,
+    offset_bn: u32,
+) -> Result<u32, FsError> {
+    block_map_with(diskinode, dev, offset_bn, block_alloc)
+}
+
+// block_map, a missing data block coming from data_alloc
+fn block_map_with(
+    diskinode: &mut DiskInode,
+    dev: Arc<dyn BlockDevice>,
+    mut offset_bn: u32,
+    data_alloc: fn(Arc<dyn BlockDevice>) -> Result<u32, FsError>,
+) -> Result<u32, FsError> {
+    let addr;
+    if offset_bn < NDIRECT {
+        if diskinode.addrs[offset_bn as usize] == 0 {
+            addr = data_alloc(dev.clone())?;
+            diskinode.addrs[offset_bn as usize] = addr;
+        } else {
+            let old = diskinode.addrs[offset_bn as usize];
+            // a block shared by a reflink is copied before it is written
+            if block_refs(dev.clone(), old) > 0 {
+                addr = block_unshare(dev.clone(), old)?;
+                diskinode.addrs[offset_bn as usize] = addr;
+            } else {
+                addr = old;
+            }
+        }
+        return Ok(addr);
+    }
+    offset_bn -= NDIRECT;
+    if offset_bn < NINDIRECT {
+        if diskinode.addrs[NDIRECT as usize] == 0 {
+            diskinode.addrs[NDIRECT as usize] = block_alloc(dev.clone())?;
+        }
+        // held until the new address is in it, so it is not given up in between
+        let blk = get_buffer_block(diskinode.addrs[NDIRECT as usize], dev.clone())?;
+        let mut addrs = blk
+            .read()
+            .unwrap()
+            .read(0, |addrs: &[u32; NINDIRECT as usize]| addrs.to_le());
+        let old = addrs[offset_bn as usize];
+        if old == 0 || block_refs(dev.clone(), old) > 0 {
+            addr = if old == 0 {
+                data_alloc(dev.clone())?
+            } else {
+                block_unshare(dev.clone(), old)?
+            };
+            addrs[offset_bn as usize] = addr;
             let mut guard = blk.write().unwrap();
             guard.write(0, |data: &mut [u32; NINDIRECT as usize]| {
-                    *data = addrs;
+                    *data = addrs.to_le();
                 });
             log_write(guard);
         } else {
-            addr = Some(addrs[offset_bn as usize]);
+            addr = addrs[offset_bn as usize];
         }
-        return addr.unwrap();
+        return Ok(addr);
     }
-    0
+    // winode stops at MAXFILE blocks, so this is not reached
+    Err(FsError::FileTooBig)
 }
 
-pub fn rinode(ip: &mut InodePtr, dst: &mut [u8], mut off: usize, mut n: usize) -> usize {
-    ip.modify_disk_inode(|diskinode| {
+// reads at most dst.len() bytes, whatever n asks for
+pub fn rinode(ip: &mut InodePtr, dst: &mut [u8], off: usize, n: usize) -> Result<usize, FsError> {
+    rinode_with(ip, dst, off, n, false)
+}
+
+// what a transfer stopped by err returns: the bytes it moved, the error
+// itself when there are none. a full device or MAXFILE only end a write
+// short, filewrite_all tells which from that
+fn transferred(tot: usize, err: Option<FsError>) -> Result<usize, FsError> {
+    match err {
+        Some(e) if tot == 0 && !matches!(e, FsError::NoSpace | FsError::FileTooBig) => Err(e),
+        _ => Ok(tot),
+    }
+}
+
+// rinode, a direct read taking the whole blocks the buffer cache does not
+// hold from the device, so they are not cached. the rest goes through it
+pub fn rinode_with(
+    ip: &mut InodePtr,
+    dst: &mut [u8],
+    mut off: usize,
+    mut n: usize,
+    direct: bool,
+) -> Result<usize, FsError> {
+    n = n.min(dst.len());
+    if n == 0 {
+        return Ok(0);
+    }
+    ip.read_disk_inode(|diskinode| {
         let size = diskinode.size as usize;
         if off > size {
-            return 0;
+            return Ok(0);
         }
         if off + n > size {
             n = size - off;
         }
+        if is_inline(diskinode) {
+            dst[..n].copy_from_slice(&inline_bytes(diskinode)[off..off + n]);
+            return Ok(n);
+        }
         let mut tot = 0;
+        let mut err = None;
         while tot < n {
-            let bp = get_buffer_block(
-                block_map(
-                    diskinode,
-                    ip.0.dev.as_ref().unwrap().clone(),
-                    off as u32 / BLOCK_SIZE,
-                ),
+            let addr = match block_lookup(
+                diskinode,
                 ip.0.dev.as_ref().unwrap().clone(),
-            );
-            let guard = bp.read().unwrap();
-            let buf = guard.read(0, |buf: &[u8; BLOCK_SIZE as usize]| *buf);
+                (off / BLOCK_SIZE as usize) as u32,
+            ) {
+                Ok(addr) => addr,
+                Err(e) => {
+                    err = Some(e);
+                    break;
+                }
+            };
             let m = std::cmp::min(n - tot, BLOCK_SIZE as usize - off % BLOCK_SIZE as usize);
+            let dev = ip.0.dev.as_ref().unwrap().clone();
+            // holes read as zeros and stay unallocated
+            let mut buf = [0u8; BLOCK_SIZE as usize];
+            let whole = direct && m == BLOCK_SIZE as usize;
+            let bypassed = addr != 0
+                && whole
+                && buffer_bypass(addr, dev.clone(), || dev.read_block(addr, &mut buf)).is_some();
+            if addr != 0 && !bypassed {
+                let bp = match get_buffer_block(addr, dev) {
+                    Ok(bp) => bp,
+                    Err(e) => {
+                        err = Some(e);
+                        break;
+                    }
+                };
+                buf = bp
+                    .read()
+                    .unwrap()
+                    .read(0, |buf: &[u8; BLOCK_SIZE as usize]| *buf);
+            }
+            debug_assert!(tot + m <= dst.len());
             dst[tot..tot + m]
                 .copy_from_slice(&buf[off % BLOCK_SIZE as usize..off % BLOCK_SIZE as usize + m]);
             tot += m;
             off += m;
         }
-        tot
+        transferred(tot, err)
     })
 }
 
-pub fn winode(ip: &mut InodePtr, src: &[u8], mut off: usize, n: usize) -> usize {
+// writes at most src.len() bytes, whatever n asks for
+pub fn winode(ip: &mut InodePtr, src: &[u8], off: usize, n: usize) -> Result<usize, FsError> {
+    winode_with(ip, src, off, n, false)
+}
+
+// winode, a direct write putting the whole blocks the buffer cache does not
+// hold on the device at once, so they are neither cached nor logged. they are
+// there before the transaction that maps them commits. the rest goes through it
+pub fn winode_with(
+    ip: &mut InodePtr,
+    src: &[u8],
+    mut off: usize,
+    n: usize,
+    direct: bool,
+) -> Result<usize, FsError> {
+    // past MAXFILE blocks block_map has no block to give, what would go there is not written
+    let n = n
+        .min(src.len())
+        .min(((MAXFILE * BLOCK_SIZE) as usize).saturating_sub(off));
     info!("winode: inum {} off {}, n {}", ip.0.inum, off, n);
+    // nothing to write, so nothing to spill, allocate or grow, even past the end
+    if n == 0 {
+        return Ok(0);
+    }
     ip.modify_disk_inode(|diskinode| {
+        if is_inline(diskinode) {
+            if off + n <= INLINE_SIZE {
+                let mut bytes = inline_bytes(diskinode);
+                bytes[off..off + n].copy_from_slice(&src[..n]);
+                set_inline_bytes(diskinode, &bytes);
+                diskinode.size = diskinode.size.max((off + n) as u32);
+                return Ok(n);
+            }
+            if let Err(e) = inline_spill(diskinode, ip.0.dev.as_ref().unwrap().clone()) {
+                return transferred(0, Some(e));
+            }
+        }
         let mut tot = 0;
+        let mut err = None;
         while tot < n {
-            let bp = get_buffer_block(
-                block_map(
-                    diskinode,
-                    ip.0.dev.as_ref().unwrap().clone(),
-                    off as u32 / BLOCK_SIZE,
-                ),
-                ip.0.dev.as_ref().unwrap().clone(),
-            );
-            let mut guard = bp.write().unwrap();
-            let mut buf = guard.read(0, |buf: &[u8; BLOCK_SIZE as usize]| *buf);
             let m = std::cmp::min(n - tot, BLOCK_SIZE as usize - off % BLOCK_SIZE as usize);
-            buf[off % BLOCK_SIZE as usize..off % BLOCK_SIZE as usize + m]
-                .copy_from_slice(&src[tot..tot + m]);
+            let dev = ip.0.dev.as_ref().unwrap().clone();
+            let bn = (off / BLOCK_SIZE as usize) as u32;
+            debug_assert!(tot + m <= src.len());
+            if direct && m == BLOCK_SIZE as usize {
+                // a new block is overwritten whole, zeroing it would only cache it
+                let block = block_map_with(diskinode, dev.clone(), bn, |dev| {
+                    alloc_blocks(dev, 1).ok_or(FsError::NoSpace)
+                });
+                let block = match block {
+                    Ok(block) => block,
+                    Err(e) => {
+                        err = Some(e);
+                        break;
+                    }
+                };
+                let write = || dev.write_block(block, &src[tot..tot + m]);
+                if buffer_bypass(block, dev.clone(), write).is_some() {
+                    tot += m;
+                    off += m;
+                    continue;
+                }
+            }
+            let block = match block_map(diskinode, dev, bn) {
+                Ok(block) => block,
+                Err(e) => {
+                    err = Some(e);
+                    break;
+                }
+            };
+            let mut buf = [0u8; BLOCK_SIZE as usize];
+            // a write of the whole block needs nothing of what was there,
+            // so the block is not read from the device first
+            let bp = if m == BLOCK_SIZE as usize {
+                buf.copy_from_slice(&src[tot..tot + m]);
+                get_buffer_block_with(block, ip.0.dev.as_ref().unwrap().clone(), &buf)
+            } else {
+                get_buffer_block(block, ip.0.dev.as_ref().unwrap().clone())
+            };
+            let bp = match bp {
+                Ok(bp) => bp,
+                Err(e) => {
+                    err = Some(e);
+                    break;
+                }
+            };
+            let mut guard = bp.write().unwrap();
+            if m < BLOCK_SIZE as usize {
+                buf = guard.read(0, |buf: &[u8; BLOCK_SIZE as usize]| *buf);
+                buf[off % BLOCK_SIZE as usize..off % BLOCK_SIZE as usize + m]
+                    .copy_from_slice(&src[tot..tot + m]);
+            }
             guard.write(0, |data: &mut [u8; BLOCK_SIZE as usize]| {
                 *data = buf;
             });
@@ -585,7 +1838,7 @@ pub fn winode(ip: &mut InodePtr, src: &[u8], mut off: usize, n: usize) -> usize
                 ip.0.inum, diskinode.size
             );
         }
-        tot
+        transferred(tot, err)
     })
 }
 
@@ -611,15 +1864,24 @@ mod test {
     use env_logger::{Builder, Target};
 
     use crate::fs::{
-        buffer::{get_buffer_block, sync_all},
+        buffer::{sync_all, wait_buffer_block},
         filedisk::FileDisk,
         fs::{FileType, BLOCK_SIZE, ROOTINO},
         inode::DirEntry,
         log::{LOG_MANAGER, log_begin, log_end},
-        superblock::SB,
+        superblock::{init_superblock, sb, unmount},
     };
 
-    use super::{create, winode, InodePtrManager};
+    use super::{
+        addr_of_inode, all_entries, block_lookup, block_of_bitmap, canonicalize, create, device_id,
+        dir_add_many, dir_entries, dirlink, dirunlink, entry_name, find_child, find_inode,
+        fragmentation, free_inode_hint, get_inode, image_fragmentation, inode_alloc,
+        inode_from_handle, inode_to_path, is_inline, link, rename, reserve_inodes, resolve,
+        set_root, winode, BlockDevice, DiskInode, Fragmentation, FsError, Inode, InodePtr,
+        InodePtrManager, BPB, DIR_INDEX, DIR_INDEX_MAX, DIR_SCANS, FREE_INODE_HINT, MAXFILE,
+        MAXPATHDEPTH, NAMEI_TRACE, NAMESIZE, NDIRECT, NINDIRECT,
+    };
+    use crate::fs::testutil::{mount_on, CrashDisk, ReadLogDisk, TestImage};
     #[test]
     fn test_get_inode() {
         let file: File = OpenOptions::new()
@@ -632,7 +1894,7 @@ mod test {
         let manager = InodePtrManager::new();
         let inode = manager.get_inode(filedisk.clone(), ROOTINO);
         // sb init
-        unsafe { SB.init(filedisk.clone()) };
+        init_superblock(filedisk.clone()).unwrap();
         // ls root
         let entries = inode.read_disk_inode(|diskinode| {
             let mut entries = Vec::new();
@@ -640,10 +1902,11 @@ mod test {
                 if diskinode.addrs[i as usize] != 0 {
                     // read entries
                     for j in (0..BLOCK_SIZE).step_by(std::mem::size_of::<DirEntry>()) {
-                        let entry = get_buffer_block(diskinode.addrs[i as usize], filedisk.clone())
-                            .read()
-                            .unwrap()
-                            .read(j as usize, |entry: &DirEntry| *entry);
+                        let entry =
+                            wait_buffer_block(diskinode.addrs[i as usize], filedisk.clone())
+                                .read()
+                                .unwrap()
+                                .read(j as usize, |entry: &DirEntry| *entry);
                         if entry.inum != 0 {
                             entries.push(entry);
                         }
@@ -672,8 +1935,8 @@ mod test {
             .open("./test.img")
             .unwrap();
         let filedisk = Arc::new(FileDisk::new(file));
-        unsafe { SB.init(filedisk.clone()) };
-        unsafe { LOG_MANAGER.init(&SB, filedisk.clone()) };
+        init_superblock(filedisk.clone()).unwrap();
+        LOG_MANAGER.init(&sb(), filedisk.clone());
         let manager = InodePtrManager::new();
         let inode = manager.inode_alloc(filedisk.clone(), FileType::File);
         log_begin();
@@ -700,8 +1963,8 @@ mod test {
             .open("./test.img")
             .unwrap();
         let filedisk = Arc::new(FileDisk::new(file));
-        unsafe { SB.init(filedisk.clone()) };
-        unsafe { LOG_MANAGER.init(&SB, filedisk.clone()) };
+        init_superblock(filedisk.clone()).unwrap();
+        LOG_MANAGER.init(&sb(), filedisk.clone());
         let manager = InodePtrManager::new();
         let inode = manager.inode_alloc(filedisk.clone(), FileType::File);
         let addr = inode.unwrap().modify_disk_inode(|diskinode| {
@@ -709,7 +1972,7 @@ mod test {
             diskinode.size = 0;
             super::block_map(diskinode, filedisk, 0)
         });
-        assert_eq!(addr, 197);
+        assert_eq!(addr, Ok(197));
     }
 
     #[test]
@@ -726,21 +1989,21 @@ mod test {
             .open("./test.img")
             .unwrap();
         let filedisk = Arc::new(FileDisk::new(file));
-        unsafe { SB.init(filedisk.clone()) };
-        unsafe { LOG_MANAGER.init(&SB, filedisk.clone()) };
+        init_superblock(filedisk.clone()).unwrap();
+        LOG_MANAGER.init(&sb(), filedisk.clone());
         // create
         let path = PathBuf::from("/test");
         log_begin();
         let mut testi = create(filedisk.clone(), &path, FileType::File).unwrap();
-        winode(&mut testi, &[1, 2, 3, 4, 5, 6], 0, 6);
+        winode(&mut testi, &[1, 2, 3, 4, 5, 6], 0, 6).unwrap();
         let mut buf = [0; 6];
-        super::rinode(&mut testi, &mut buf, 0, 6);
+        super::rinode(&mut testi, &mut buf, 0, 6).unwrap();
         assert_eq!(buf, [1, 2, 3, 4, 5, 6]);
         // test big file
         let mut buf = ['1' as u8;512 * 13 + 1];
-        winode(&mut testi, &mut buf, 0, 512 * 13 + 1);
+        winode(&mut testi, &mut buf, 0, 512 * 13 + 1).unwrap();
         let mut buf = [0; 512 * 13 + 1];
-        super::rinode(&mut testi, &mut buf, 0, 512 * 13 + 1);
+        super::rinode(&mut testi, &mut buf, 0, 512 * 13 + 1).unwrap();
         assert_eq!(buf, ['1' as u8; 512 * 13 + 1]);
         log_end();
         sync_all();
@@ -760,8 +2023,8 @@ mod test {
             .open("./test.img")
             .unwrap();
         let filedisk = Arc::new(FileDisk::new(file));
-        unsafe { SB.init(filedisk.clone()) };
-        unsafe { LOG_MANAGER.init(&SB, filedisk.clone()) };
+        init_superblock(filedisk.clone()).unwrap();
+        LOG_MANAGER.init(&sb(), filedisk.clone());
         // create
         let path = PathBuf::from("/test/");
         let _ = create(filedisk.clone(), &path, FileType::Dir).unwrap();
@@ -769,4 +2032,986 @@ mod test {
         let _ = create(filedisk.clone(), &path, FileType::File).unwrap();
         sync_all();
     }
+
+    #[test]
+    fn test_pathological_paths() {
+        let image = TestImage::new("inode_paths");
+        let dev = image.mount();
+        log_begin();
+        create(dev.clone(), &PathBuf::from("/a"), FileType::Dir).unwrap();
+        let b = create(dev.clone(), &PathBuf::from("/a/b"), FileType::File).unwrap();
+        create(dev.clone(), &PathBuf::from("/a/bb"), FileType::File).unwrap();
+        // redundant separators and `.` are normalized, `..` walks up
+        for path in ["/a/b", "/a//b", "//a/./b/", "/a/../a/b", "/../a/b"] {
+            let ip = resolve(dev.clone(), &PathBuf::from(path)).unwrap();
+            assert_eq!(ip.0.inum, b.0.inum, "{}", path);
+        }
+        // "b" is a prefix of "bb", but must not match it
+        assert_eq!(
+            resolve(dev.clone(), &PathBuf::from("/a/c")).err(),
+            Some(FsError::NotFound)
+        );
+        assert_eq!(
+            resolve(dev.clone(), &PathBuf::from("/a/b/c")).err(),
+            Some(FsError::NotDirectory)
+        );
+        // invalid components are rejected
+        for path in ["/a/\0/b", "/a/b\0", "a/b", ""] {
+            assert_eq!(
+                resolve(dev.clone(), &PathBuf::from(path)).err(),
+                Some(FsError::InvalidName),
+                "{:?}",
+                path
+            );
+        }
+        let cases = [
+            ("/a/x\0y", FsError::InvalidName),
+            ("/a/\0/c", FsError::InvalidName),
+            ("/a/..", FsError::InvalidName),
+            ("/", FsError::InvalidName),
+            ("/a/bb", FsError::AlreadyExists),
+        ];
+        for (path, err) in cases {
+            assert_eq!(
+                create(dev.clone(), &PathBuf::from(path), FileType::File).err(),
+                Some(err),
+                "{:?}",
+                path
+            );
+        }
+        let long = format!("/a/{}", "x".repeat(NAMESIZE as usize + 1));
+        assert_eq!(
+            create(dev.clone(), &PathBuf::from(long), FileType::File).err(),
+            Some(FsError::NameTooLong)
+        );
+        // a name filling the whole dirent has no terminator
+        let full = format!("/a/{}", "x".repeat(NAMESIZE as usize));
+        let ip = create(dev.clone(), &PathBuf::from(&full), FileType::File).unwrap();
+        assert_eq!(
+            resolve(dev.clone(), &PathBuf::from(&full)).unwrap().0.inum,
+            ip.0.inum
+        );
+        log_end();
+    }
+
+    #[test]
+    fn test_find_inode_parent() {
+        let image = TestImage::new("inode_parent");
+        let dev = image.mount();
+        log_begin();
+        create(dev.clone(), &PathBuf::from("/a"), FileType::Dir).unwrap();
+        create(dev.clone(), &PathBuf::from("/a/b"), FileType::Dir).unwrap();
+        create(dev.clone(), &PathBuf::from("/a/b/c"), FileType::File).unwrap();
+        log_end();
+        let inum = |path: &std::path::Path| find_inode(dev.clone(), path).map(|ip| ip.0.inum);
+        // the lookup lands where the shell's cd would
+        for path in [
+            "/..",
+            "/../..",
+            "/a/..",
+            "/a/b/../..",
+            "/a/b/../../..",
+            "/../a/b/../b/c",
+            "/a/./b/../../a/b",
+        ] {
+            let path = PathBuf::from(path);
+            let canonical = canonicalize(&path).unwrap();
+            assert_eq!(inum(&path), inum(&canonical), "{:?}", path);
+        }
+        assert_eq!(inum(&PathBuf::from("/..")), Some(ROOTINO));
+        assert_eq!(inum(&PathBuf::from("/a/b/../..")), Some(ROOTINO));
+    }
+
+    #[test]
+    fn test_canonicalize_bounds() {
+        let canon = |path: &str| canonicalize(&PathBuf::from(path));
+        let ok = |path: &str| Ok(PathBuf::from(path));
+        // ".." at the root stays there
+        assert_eq!(canon("/../../x"), ok("/x"));
+        assert_eq!(canon("/a/b/../../../../c/./d"), ok("/c/d"));
+        assert_eq!(canon("a/../.."), ok("/"));
+        // trailing and repeated slashes
+        assert_eq!(canon("/a/b/"), ok("/a/b"));
+        assert_eq!(canon("//a///b//"), ok("/a/b"));
+        assert_eq!(canon("/a/b/../"), ok("/a"));
+
+        // the depth is bounded, not the length
+        let deep = "a/".repeat(MAXPATHDEPTH);
+        assert!(canon(&deep).is_ok());
+        assert_eq!(canon(&format!("{}b", deep)), Err(FsError::NameTooLong));
+        assert_eq!(canon(&"a/../".repeat(10000)), ok("/"));
+        let long = "x".repeat(NAMESIZE as usize + 1);
+        assert_eq!(canon(&long), Err(FsError::NameTooLong));
+    }
+
+    #[test]
+    fn test_namei_trace() {
+        let image = TestImage::new("inode_namei_trace");
+        let dev = image.mount();
+        log_begin();
+        let mut path = PathBuf::from("/");
+        for name in ["a", "b", "c"] {
+            path.push(name);
+            create(dev.clone(), &path, FileType::Dir).unwrap();
+        }
+        let c = resolve(dev.clone(), &path).unwrap();
+        let b = resolve(dev.clone(), &PathBuf::from("/a/b")).unwrap();
+        let a = resolve(dev.clone(), &PathBuf::from("/a")).unwrap();
+        log_end();
+
+        NAMEI_TRACE.with(|trace| trace.borrow_mut().clear());
+        assert!(resolve(dev.clone(), &PathBuf::from("/a/b/c/file")).is_err());
+        let trace = NAMEI_TRACE.with(|trace| trace.take());
+        assert_eq!(
+            trace,
+            vec![
+                format!("\"a\" in dir {}: inum {}", ROOTINO, a.0.inum),
+                format!("\"b\" in dir {}: inum {}", a.0.inum, b.0.inum),
+                format!("\"c\" in dir {}: inum {}", b.0.inum, c.0.inum),
+                format!("\"file\" in dir {}: not found", c.0.inum),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stale_handle() {
+        let image = TestImage::new("inode_stale_handle");
+        let dev = image.mount();
+        log_begin();
+        let ip = inode_alloc(dev.clone(), FileType::File).unwrap();
+        let old = ip.handle();
+        assert_eq!(
+            inode_from_handle(dev.clone(), old).unwrap().0.inum,
+            old.inum
+        );
+        // nlink is still 0, so dropping the last reference frees the inode
+        drop(ip);
+        assert_eq!(
+            inode_from_handle(dev.clone(), old).err(),
+            Some(FsError::StaleHandle)
+        );
+        let ip = inode_alloc(dev.clone(), FileType::File).unwrap();
+        let new = ip.handle();
+        assert_eq!(new.inum, old.inum);
+        assert_eq!(new.generation, old.generation + 1);
+        assert_eq!(
+            inode_from_handle(dev.clone(), old).err(),
+            Some(FsError::StaleHandle)
+        );
+        assert!(inode_from_handle(dev.clone(), new).is_ok());
+        drop(ip);
+        log_end();
+    }
+
+    // lookups go through the index, so they should not slow down as the directory grows
+    #[test]
+    fn test_dir_index_lookup() {
+        let image = TestImage::new("inode_dir_index");
+        let dev = image.mount();
+        // the inode table caps a directory well below 5000 entries
+        let populate = |dir: &str, n: usize| {
+            log_begin();
+            drop(create(dev.clone(), &PathBuf::from(dir), FileType::Dir).unwrap());
+            log_end();
+            for i in 0..n {
+                log_begin();
+                let path = PathBuf::from(format!("{}/f{}", dir, i));
+                drop(create(dev.clone(), &path, FileType::File).unwrap());
+                log_end();
+            }
+        };
+        populate("/big", 900);
+        let scans = || DIR_SCANS.with(|scans| scans.get());
+        // the first lookup reads the directory, the rest only the index
+        let before = scans();
+        for round in 0..2000 {
+            let path = PathBuf::from(format!("/big/f{}", round % 900));
+            assert!(resolve(dev.clone(), &path).is_ok());
+        }
+        assert!(scans() - before <= 2, "{} scans", scans() - before);
+        let before = scans();
+        assert!(resolve(dev.clone(), &PathBuf::from("/big/f1")).is_ok());
+        assert_eq!(scans(), before);
+
+        // unlinking and relinking keeps the index in sync with the directory
+        let mut dp = resolve(dev.clone(), &PathBuf::from("/big")).unwrap();
+        log_begin();
+        dirunlink(&mut dp, "f7").unwrap();
+        log_end();
+        assert_eq!(
+            resolve(dev.clone(), &PathBuf::from("/big/f7")).err(),
+            Some(FsError::NotFound)
+        );
+        log_begin();
+        dirlink(&mut dp, "g7", 2, FileType::File as u8).unwrap();
+        log_end();
+        let ip = resolve(dev.clone(), &PathBuf::from("/big/g7")).unwrap();
+        assert_eq!(ip.0.inum, 2);
+        assert_eq!(scans(), before);
+    }
+
+    #[test]
+    fn test_dir_index_bound() {
+        let image = TestImage::new("inode_dir_index_bound");
+        let dev = image.mount();
+        let scans = || DIR_SCANS.with(|scans| scans.get());
+        for i in 0..DIR_INDEX_MAX {
+            log_begin();
+            let path = PathBuf::from(format!("/d{}", i));
+            drop(create(dev.clone(), &path, FileType::Dir).unwrap());
+            log_end();
+        }
+        let lookup = |i: usize| {
+            let before = scans();
+            let path = PathBuf::from(format!("/d{}/f", i));
+            assert_eq!(resolve(dev.clone(), &path).err(), Some(FsError::NotFound));
+            scans() - before
+        };
+        (0..DIR_INDEX_MAX).for_each(|i| {
+            lookup(i);
+        });
+        // the root and the directories do not all fit, the least recently used go
+        assert!(DIR_INDEX.lock().unwrap().dirs.len() <= DIR_INDEX_MAX);
+        assert_eq!(lookup(DIR_INDEX_MAX - 1), 0);
+        assert_eq!(lookup(0), 1);
+
+        // unmount lets go of the device and its indexes
+        unmount(dev.clone());
+        let held = |dev: &Arc<dyn BlockDevice>| {
+            DIR_INDEX
+                .lock()
+                .unwrap()
+                .dirs
+                .values()
+                .any(|dir| device_id(&dir.dev) == device_id(dev))
+        };
+        assert!(!held(&dev));
+    }
+
+    // the allocated inode and its directory entry commit together,
+    // so a crash at any point of the commit leaves no orphan inode
+    #[test]
+    fn test_create_crash_leaves_no_orphan() {
+        for k in 0.. {
+            let image = TestImage::new("inode_create_crash");
+            let crash = Arc::new(CrashDisk::new(image.disk()));
+            mount_on(crash.clone());
+            crash.crash_after(k);
+            log_begin();
+            let path = PathBuf::from("/a");
+            drop(create(crash.clone(), &path, FileType::File).unwrap());
+            log_end();
+
+            // reboot, replaying whatever the log holds
+            let dev = image.mount();
+            let allocated = (ROOTINO..sb().ninodes)
+                .filter(|inum| {
+                    let (bno, off) = addr_of_inode(*inum);
+                    wait_buffer_block(bno, dev.clone())
+                        .read()
+                        .unwrap()
+                        .read(off as usize, |dinode: &DiskInode| dinode.ftype)
+                        != FileType::Free as u8
+                })
+                .collect::<Vec<_>>();
+            match resolve(dev.clone(), &path) {
+                Ok(ip) => assert_eq!(allocated, vec![ROOTINO, ip.0.inum], "crash after {}", k),
+                Err(e) => {
+                    assert_eq!(e, FsError::NotFound);
+                    assert_eq!(allocated, vec![ROOTINO], "crash after {}", k);
+                }
+            }
+            if crash.lost() == 0 {
+                // the whole commit went through
+                assert!(resolve(dev.clone(), &path).is_ok());
+                break;
+            }
+        }
+    }
+
+    // the data and indirect blocks of every allocated inode
+    fn used_blocks(dev: Arc<dyn BlockDevice>) -> Vec<u32> {
+        let mut used = vec![];
+        for inum in ROOTINO..sb().ninodes {
+            let dinode = get_inode(dev.clone(), inum).read_disk_inode(|dinode| *dinode);
+            if dinode.ftype != FileType::File as u8 && dinode.ftype != FileType::Dir as u8
+                || is_inline(&dinode)
+            {
+                continue;
+            }
+            used.extend(
+                (0..NDIRECT + NINDIRECT)
+                    .map(|bn| block_lookup(&dinode, dev.clone(), bn).unwrap())
+                    .filter(|b| *b != 0),
+            );
+            if dinode.addrs[NDIRECT as usize] != 0 {
+                used.push(dinode.addrs[NDIRECT as usize]);
+            }
+        }
+        used
+    }
+
+    fn check_blocks(dev: Arc<dyn BlockDevice>, k: usize) {
+        let mut used = used_blocks(dev.clone());
+        for b in used.iter() {
+            let byte = wait_buffer_block(block_of_bitmap(*b), dev.clone())
+                .read()
+                .unwrap()
+                .read((b % BPB) as usize / 8, |byte: &u8| *byte);
+            assert!(
+                byte & (1 << (b % 8)) != 0,
+                "crash after {}: {} is free",
+                k,
+                b
+            );
+        }
+        let n = used.len();
+        used.sort();
+        used.dedup();
+        assert_eq!(used.len(), n, "crash after {}: a block is used twice", k);
+    }
+
+    // truncate frees blocks through the log, so after a crash at any point of
+    // its commit no block is both free and still used by the inode
+    #[test]
+    fn test_truncate_crash_no_double_allocation() {
+        let data = [7u8; BLOCK_SIZE as usize];
+        let write = |dev: Arc<dyn BlockDevice>, path: &str| {
+            log_begin();
+            let mut ip = create(dev.clone(), &PathBuf::from(path), FileType::File).unwrap();
+            log_end();
+            // on through the indirect block
+            for bn in 0..NDIRECT as usize + 4 {
+                log_begin();
+                winode(&mut ip, &data, bn * data.len(), data.len()).unwrap();
+                log_end();
+            }
+            ip
+        };
+        for k in 0.. {
+            let image = TestImage::new("inode_truncate_crash");
+            let crash = Arc::new(CrashDisk::new(image.disk()));
+            mount_on(crash.clone());
+            let ip = write(crash.clone(), "/big");
+            crash.crash_after(k);
+            log_begin();
+            ip.modify_disk_inode(|dinode| {
+                dinode.size = 0;
+                Inode::truncate(crash.clone(), dinode);
+            });
+            log_end();
+            drop(ip);
+
+            // reboot, then allocate blocks again
+            let dev = image.mount();
+            check_blocks(dev.clone(), k);
+            drop(write(dev.clone(), "/again"));
+            check_blocks(dev.clone(), k);
+            if crash.lost() == 0 {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn test_inode_to_path() {
+        let image = TestImage::new("inode_to_path");
+        let dev = image.mount();
+        log_begin();
+        drop(create(dev.clone(), &PathBuf::from("/a"), FileType::Dir).unwrap());
+        drop(create(dev.clone(), &PathBuf::from("/a/b"), FileType::Dir).unwrap());
+        let file = create(dev.clone(), &PathBuf::from("/a/b/file"), FileType::File).unwrap();
+        log_end();
+        let b = resolve(dev.clone(), &PathBuf::from("/a/b")).unwrap();
+        assert_eq!(
+            inode_to_path(dev.clone(), file.0.inum),
+            Some(PathBuf::from("/a/b/file"))
+        );
+        assert_eq!(
+            inode_to_path(dev.clone(), b.0.inum),
+            Some(PathBuf::from("/a/b"))
+        );
+        assert_eq!(
+            inode_to_path(dev.clone(), ROOTINO),
+            Some(PathBuf::from("/"))
+        );
+        // a free inode has no path
+        assert_eq!(inode_to_path(dev.clone(), file.0.inum + 1), None);
+    }
+
+    #[test]
+    fn test_subroot() {
+        let image = TestImage::new("inode_subroot");
+        let dev = image.mount();
+        log_begin();
+        drop(create(dev.clone(), &PathBuf::from("/home"), FileType::Dir).unwrap());
+        drop(create(dev.clone(), &PathBuf::from("/home/texts"), FileType::Dir).unwrap());
+        drop(create(dev.clone(), &PathBuf::from("/top"), FileType::File).unwrap());
+        log_end();
+        let inum = |path: &str| resolve(dev.clone(), &PathBuf::from(path)).map(|ip| ip.0.inum);
+        let home = inum("/home").unwrap();
+        let texts = inum("/home/texts").unwrap();
+
+        set_root(home);
+        assert_eq!(inum("/"), Ok(home));
+        assert_eq!(inum("/texts"), Ok(texts));
+        // ".." does not climb out of the subtree
+        assert_eq!(inum("/texts/../.."), Ok(home));
+        assert_eq!(inum("/../top"), Err(FsError::NotFound));
+        assert_eq!(
+            inode_to_path(dev.clone(), texts),
+            Some(PathBuf::from("/texts"))
+        );
+        set_root(ROOTINO);
+        assert!(inum("/top").is_ok());
+    }
+
+    #[test]
+    fn test_inline_data() {
+        let image = TestImage::new("inode_inline");
+        let dev = image.mount();
+        let path = PathBuf::from("/small");
+        log_begin();
+        let mut ip = create(dev.clone(), &path, FileType::File).unwrap();
+        log_end();
+        let read = |ip: &mut InodePtr| {
+            let size = ip.read_disk_inode(|dinode| dinode.size) as usize;
+            let mut buf = vec![0u8; size];
+            assert_eq!(super::rinode(ip, &mut buf, 0, size).unwrap(), size);
+            buf
+        };
+        // a write past the end leaves zeros between, still inline
+        let mut expected = vec![0u8; 50];
+        expected[..20].fill(1);
+        expected[40..].fill(2);
+        log_begin();
+        winode(&mut ip, &[1; 20], 0, 20).unwrap();
+        winode(&mut ip, &[2; 10], 40, 10).unwrap();
+        log_end();
+        assert!(ip.read_disk_inode(is_inline));
+        assert_eq!(read(&mut ip), expected);
+
+        // growing past INLINE_SIZE moves the bytes to a block
+        expected.extend([3u8; 30]);
+        log_begin();
+        winode(&mut ip, &[3; 30], 50, 30).unwrap();
+        log_end();
+        let dinode = ip.read_disk_inode(|dinode| *dinode);
+        assert!(!is_inline(&dinode));
+        assert_ne!(dinode.addrs[0], 0);
+        assert_eq!(read(&mut ip), expected);
+
+        // emptied, the file is inline again and its block is free
+        log_begin();
+        ip.modify_disk_inode(|dinode| {
+            dinode.size = 0;
+            Inode::truncate(dev.clone(), dinode);
+        });
+        winode(&mut ip, b"tiny", 0, 4).unwrap();
+        log_end();
+        assert!(ip.read_disk_inode(is_inline));
+        let b = dinode.addrs[0];
+        let byte = wait_buffer_block(block_of_bitmap(b), dev.clone())
+            .read()
+            .unwrap()
+            .read((b % BPB) as usize / 8, |byte: &u8| *byte);
+        assert_eq!(byte & (1 << (b % 8)), 0);
+        drop(ip);
+
+        // the bytes live in the inode on disk
+        sync_all();
+        drop(dev);
+        let dev = image.mount();
+        let mut ip = resolve(dev.clone(), &path).unwrap();
+        assert_eq!(read(&mut ip), b"tiny");
+    }
+
+    #[test]
+    fn test_short_buffers() {
+        let image = TestImage::new("inode_short_buffers");
+        let dev = image.mount();
+        log_begin();
+        let mut ip = create(dev.clone(), &PathBuf::from("/short"), FileType::File).unwrap();
+        // n past the end of src writes only what src holds, inline and in blocks
+        assert_eq!(winode(&mut ip, &[1; 10], 0, 20).unwrap(), 10);
+        assert_eq!(ip.read_disk_inode(|dinode| dinode.size), 10);
+        let data = vec![2u8; 3 * BLOCK_SIZE as usize];
+        assert_eq!(
+            winode(&mut ip, &data, 10, 4 * BLOCK_SIZE as usize).unwrap(),
+            data.len()
+        );
+        log_end();
+        let size = 10 + data.len();
+        assert_eq!(ip.read_disk_inode(|dinode| dinode.size) as usize, size);
+
+        // n past the end of dst reads only what dst holds
+        let mut small = [0u8; 16];
+        assert_eq!(
+            super::rinode(&mut ip, &mut small, 0, size).unwrap(),
+            small.len()
+        );
+        assert_eq!(small[..10], [1; 10]);
+        assert_eq!(small[10..], [2; 6]);
+        let mut buf = vec![0u8; BLOCK_SIZE as usize + 7];
+        assert_eq!(
+            super::rinode(&mut ip, &mut buf, 5, size).unwrap(),
+            buf.len()
+        );
+        assert_eq!(buf[..5], [1; 5]);
+        assert!(buf[5..].iter().all(|&b| b == 2));
+    }
+
+    #[test]
+    fn test_full_block_write_no_read() {
+        const BS: usize = BLOCK_SIZE as usize;
+        let image = TestImage::new("inode_full_block_write");
+        let dev = image.mount();
+        let path = PathBuf::from("/f");
+        log_begin();
+        let mut ip = create(dev.clone(), &path, FileType::File).unwrap();
+        winode(&mut ip, &[1; 2 * BS], 0, 2 * BS).unwrap();
+        log_end();
+        drop(ip);
+        sync_all();
+
+        // a device of its own, so none of its blocks are cached
+        let disk = Arc::new(ReadLogDisk::new(image.disk()));
+        let dev: Arc<dyn BlockDevice> = disk.clone();
+        mount_on(dev.clone());
+        let mut ip = find_inode(dev.clone(), &path).unwrap();
+        let (b0, b1) = ip.read_disk_inode(|diskinode| (diskinode.addrs[0], diskinode.addrs[1]));
+        log_begin();
+        assert_eq!(winode(&mut ip, &[2; BS], 0, BS).unwrap(), BS);
+        assert_eq!(winode(&mut ip, &[3; 10], BS + 5, 10).unwrap(), 10);
+        log_end();
+        let reads = disk.reads();
+        assert!(!reads.contains(&b0));
+        // a part of a block still needs the rest of it
+        assert!(reads.contains(&b1));
+        drop(ip);
+        sync_all();
+
+        let dev = image.mount();
+        let mut ip = find_inode(dev.clone(), &path).unwrap();
+        let mut buf = vec![0u8; 2 * BS];
+        assert_eq!(super::rinode(&mut ip, &mut buf, 0, 2 * BS).unwrap(), 2 * BS);
+        assert!(buf[..BS].iter().all(|&b| b == 2));
+        assert!(buf[BS..BS + 5].iter().all(|&b| b == 1));
+        assert!(buf[BS + 5..BS + 15].iter().all(|&b| b == 3));
+        assert!(buf[BS + 15..].iter().all(|&b| b == 1));
+    }
+
+    #[test]
+    fn test_dirent_ftype_feature() {
+        use super::{entry_ftype, INCOMPAT_DIRENT_FTYPE};
+        use crate::fs::superblock::write_superblock;
+        let image = TestImage::new("inode_dirent_ftype");
+        let dev = image.mount();
+        let mut old = sb();
+        old.feature_incompat &= !INCOMPAT_DIRENT_FTYPE;
+        write_superblock(dev.clone(), &old);
+        // without the feature a new dirent keeps no type, the byte is the name's
+        log_begin();
+        let mut dp = create(dev.clone(), &PathBuf::from("/d"), FileType::Dir).unwrap();
+        let ip = inode_alloc(dev.clone(), FileType::File).unwrap();
+        ip.modify_disk_inode(|diskinode| diskinode.nlink = 1);
+        dir_add_many(&mut dp, &[("f", ip.0.inum, FileType::File as u8)]).unwrap();
+        log_end();
+        let root = get_inode(dev.clone(), ROOTINO).read_disk_inode(|diskinode| *diskinode);
+        let d = dp.read_disk_inode(|diskinode| *diskinode);
+        let mut entries = dir_entries(dev.clone(), &root).unwrap();
+        entries.extend(dir_entries(dev.clone(), &d).unwrap());
+        assert!(entries.iter().all(|entry| entry.ftype == 0));
+        // a name of an old image fills the byte
+        let long = "x".repeat(NAMESIZE as usize + 1);
+        let mut entry = DirEntry::default();
+        let name = &long.as_bytes()[..NAMESIZE as usize];
+        entry.name.copy_from_slice(name);
+        entry.ftype = b'x';
+        assert_eq!(entry_name(&entry), long);
+        assert_eq!(entry_ftype(&entry), FileType::Free as u8);
+
+        old.feature_incompat |= INCOMPAT_DIRENT_FTYPE;
+        write_superblock(dev.clone(), &old);
+        entry.ftype = FileType::File as u8;
+        assert_eq!(entry_name(&entry), long[..NAMESIZE as usize]);
+        assert_eq!(entry_ftype(&entry), FileType::File as u8);
+    }
+
+    #[test]
+    fn test_dir_add_many() {
+        let image = TestImage::new("inode_dir_add_many");
+        let dev = image.mount();
+        let dir = PathBuf::from("/many");
+        log_begin();
+        let mut dp = create(dev.clone(), &dir, FileType::Dir).unwrap();
+        log_end();
+        let mut inums = vec![];
+        for _ in 0..10 {
+            log_begin();
+            for _ in 0..10 {
+                let ip = inode_alloc(dev.clone(), FileType::File).unwrap();
+                ip.modify_disk_inode(|diskinode| diskinode.nlink = 1);
+                inums.push(ip.0.inum);
+            }
+            log_end();
+        }
+        let names = (0..inums.len())
+            .map(|i| format!("f{}", i))
+            .collect::<Vec<_>>();
+        let entries = names
+            .iter()
+            .zip(&inums)
+            .map(|(name, &inum)| (name.as_str(), inum, FileType::File as u8))
+            .collect::<Vec<_>>();
+
+        // a hole left by an unlink is filled before the directory grows
+        log_begin();
+        dirlink(&mut dp, "gone", inums[0], FileType::File as u8).unwrap();
+        dirunlink(&mut dp, "gone").unwrap();
+        dir_add_many(&mut dp, &entries).unwrap();
+        log_end();
+        let size = dp.read_disk_inode(|diskinode| diskinode.size);
+        assert_eq!(
+            size as usize,
+            (entries.len() + 2) * std::mem::size_of::<DirEntry>()
+        );
+
+        let check = |dev: Arc<dyn BlockDevice>| {
+            for (name, &inum) in names.iter().zip(&inums) {
+                let ip = find_inode(dev.clone(), &dir.join(name)).unwrap();
+                assert_eq!(ip.0.inum, inum);
+            }
+        };
+        // through the index, and read back from the disk
+        check(dev.clone());
+        drop(dp);
+        sync_all();
+        check(image.mount());
+    }
+
+    #[test]
+    fn test_dir_indirect() {
+        let image = TestImage::new("inode_dir_indirect");
+        let dev = image.mount();
+        let dir = PathBuf::from("/big");
+        log_begin();
+        let dp = create(dev.clone(), &dir, FileType::Dir).unwrap();
+        log_end();
+        // 16 dirents a block, 1000 of them run well past the direct blocks
+        let mut inums = vec![];
+        for i in 0..1000 {
+            log_begin();
+            let ip = create(dev.clone(), &dir.join(format!("f{}", i)), FileType::File).unwrap();
+            log_end();
+            inums.push(ip.0.inum);
+        }
+        let dinode = dp.read_disk_inode(|diskinode| *diskinode);
+        assert!(dinode.size > NDIRECT * BLOCK_SIZE);
+        assert_ne!(dinode.addrs[NDIRECT as usize], 0);
+        assert_eq!(dir_entries(dev.clone(), &dinode).unwrap().len(), 1000);
+        let last = find_inode(dev.clone(), &dir.join("f999")).unwrap();
+        assert_eq!(last.0.inum, inums[999]);
+
+        // a fresh mount reads the names from the indirect blocks
+        sync_all();
+        let dev = image.mount();
+        let last = find_inode(dev.clone(), &dir.join("f999")).unwrap();
+        assert_eq!(last.0.inum, inums[999]);
+        assert_eq!(crate::fs::fsck::fsck(dev.clone()), []);
+
+        // fill every slot MAXFILE blocks hold, a few blocks a transaction
+        const DESIZE: u32 = std::mem::size_of::<DirEntry>() as u32;
+        let mut dp = find_inode(dev.clone(), &dir).unwrap();
+        let used = dp.read_disk_inode(|diskinode| diskinode.size) / DESIZE;
+        let names = (used..MAXFILE * BLOCK_SIZE / DESIZE)
+            .map(|i| format!("g{}", i))
+            .collect::<Vec<_>>();
+        for chunk in names.chunks(8 * (BLOCK_SIZE / DESIZE) as usize) {
+            let entries = chunk
+                .iter()
+                .map(|name| (name.as_str(), inums[0], FileType::File as u8))
+                .collect::<Vec<_>>();
+            log_begin();
+            dir_add_many(&mut dp, &entries).unwrap();
+            log_end();
+        }
+        assert_eq!(
+            dp.read_disk_inode(|diskinode| diskinode.size),
+            MAXFILE * BLOCK_SIZE
+        );
+        let inum = |path: PathBuf| find_inode(dev.clone(), &path).unwrap().0.inum;
+        let last = format!("g{}", MAXFILE * BLOCK_SIZE / DESIZE - 1);
+        assert_eq!(inum(dir.join(&last)), inums[0]);
+
+        // a full directory refuses a new name instead of dropping it
+        let path = |name: &str| dir.join(name);
+        log_begin();
+        let out = create(dev.clone(), &PathBuf::from("/out"), FileType::File).unwrap();
+        let created = create(dev.clone(), &path("more"), FileType::File).map(|_| ());
+        let linked = link(dev.clone(), &path("f0"), &path("more"));
+        let moved = rename(dev.clone(), &PathBuf::from("/out"), &path("more"));
+        // a replaced name frees its slot for the next one
+        let replaced = rename(dev.clone(), &path("f1"), &path("f2"));
+        let more = create(dev.clone(), &path("more"), FileType::File).unwrap();
+        log_end();
+        assert_eq!(created, Err(FsError::FileTooBig));
+        assert_eq!(linked, Err(FsError::FileTooBig));
+        assert_eq!(moved, Err(FsError::FileTooBig));
+        assert_eq!(replaced, Ok(()));
+        assert_eq!(inum(path("f2")), inums[1]);
+        assert_eq!(inum(path("more")), more.0.inum);
+        assert_eq!(inum(PathBuf::from("/out")), out.0.inum);
+    }
+
+    #[test]
+    fn test_dot_entries_anywhere() {
+        let image = TestImage::new("inode_dot_entries");
+        let dev = image.mount();
+        log_begin();
+        let mut dp = create(dev.clone(), &PathBuf::from("/d"), FileType::Dir).unwrap();
+        let sub = create(dev.clone(), &PathBuf::from("/d/sub"), FileType::Dir).unwrap();
+        let f = create(dev.clone(), &PathBuf::from("/d/f"), FileType::File).unwrap();
+        // move "." and ".." behind the other names, so slots 0 and 1 hold files
+        let d = dp.0.inum;
+        dirunlink(&mut dp, ".").unwrap();
+        dirunlink(&mut dp, "..").unwrap();
+        dirlink(&mut dp, "a", f.0.inum, FileType::File as u8).unwrap();
+        dirlink(&mut dp, "b", f.0.inum, FileType::File as u8).unwrap();
+        dirlink(&mut dp, ".", d, FileType::Dir as u8).unwrap();
+        dirlink(&mut dp, "..", ROOTINO, FileType::Dir as u8).unwrap();
+        log_end();
+        drop(dp);
+        sync_all();
+
+        // a fresh mount, so the names come from the disk
+        let dev = image.mount();
+        let dp = find_inode(dev.clone(), &PathBuf::from("/d")).unwrap();
+        let dinode = dp.read_disk_inode(|diskinode| *diskinode);
+        let slots = all_entries(dev.clone(), &dinode)
+            .unwrap()
+            .iter()
+            .map(entry_name)
+            .collect::<Vec<_>>();
+        assert_eq!(slots, ["a", "b", "sub", "f", ".", ".."]);
+        let mut names = dir_entries(dev.clone(), &dinode)
+            .unwrap()
+            .iter()
+            .map(entry_name)
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["a", "b", "f", "sub"]);
+
+        let child = |name: &str| find_child(&dp, name).unwrap().map(|ip| ip.0.inum);
+        assert_eq!(child("a"), Some(f.0.inum));
+        assert_eq!(child("b"), Some(f.0.inum));
+        assert_eq!(child("."), Some(d));
+        assert_eq!(child(".."), Some(ROOTINO));
+        let path = |p: &str| find_inode(dev.clone(), &PathBuf::from(p)).map(|ip| ip.0.inum);
+        assert_eq!(path("/d/sub/../a"), Some(f.0.inum));
+        assert_eq!(path("/d/sub"), Some(sub.0.inum));
+        assert_eq!(
+            inode_to_path(dev.clone(), sub.0.inum),
+            Some(PathBuf::from("/d/sub"))
+        );
+    }
+
+    #[test]
+    fn test_inode_alloc_hint() {
+        let image = TestImage::new("inode_alloc_hint");
+        let dev = image.mount();
+        let ninodes = sb().ninodes;
+        // the root and nothing else is in use on a fresh image
+        let mut ips = vec![];
+        for _ in 0..40 {
+            log_begin();
+            for _ in 0..8 {
+                let ip = inode_alloc(dev.clone(), FileType::File).unwrap();
+                ip.modify_disk_inode(|diskinode| diskinode.nlink = 1);
+                // each scan starts right at the free inode
+                assert_eq!(free_inode_hint(&dev), ip.0.inum + 1);
+                ips.push(ip);
+            }
+            log_end();
+        }
+        let inums = ips.iter().map(|ip| ip.0.inum).collect::<Vec<_>>();
+        assert_eq!(inums, (ROOTINO + 1..ROOTINO + 321).collect::<Vec<_>>());
+
+        // a freed inode is the next one handed out
+        let freed = ips.remove(100);
+        log_begin();
+        freed.modify_disk_inode(|diskinode| diskinode.nlink = 0);
+        let inum = freed.0.inum;
+        drop(freed);
+        log_end();
+        assert_eq!(free_inode_hint(&dev), inum);
+        log_begin();
+        let ip = inode_alloc(dev.clone(), FileType::File).unwrap();
+        ip.modify_disk_inode(|diskinode| diskinode.nlink = 1);
+        log_end();
+        assert_eq!(ip.0.inum, inum);
+        assert_eq!(free_inode_hint(&dev), inum + 1);
+        ips.push(ip);
+
+        // a hint past the free inodes wraps around to them
+        *FREE_INODE_HINT.lock().unwrap() = Some((dev.clone(), ninodes));
+        log_begin();
+        let ip = inode_alloc(dev.clone(), FileType::File).unwrap();
+        ip.modify_disk_inode(|diskinode| diskinode.nlink = 1);
+        log_end();
+        assert_eq!(ip.0.inum, ROOTINO + 321);
+        ips.push(ip);
+        // the hint of another device is not used
+        *FREE_INODE_HINT.lock().unwrap() = Some((image.disk(), ninodes));
+        assert_eq!(free_inode_hint(&dev), ROOTINO);
+
+        // free them again, a few in each transaction
+        while !ips.is_empty() {
+            log_begin();
+            let n = ips.len().min(8);
+            for ip in ips.drain(..n) {
+                ip.modify_disk_inode(|diskinode| diskinode.nlink = 0);
+            }
+            log_end();
+        }
+    }
+
+    #[test]
+    fn test_fragmentation() {
+        let image = TestImage::new("inode_fragmentation");
+        let dev = image.mount();
+        let data = [3u8; BLOCK_SIZE as usize];
+        let create_file = |path: &str| {
+            log_begin();
+            let ip = create(dev.clone(), &PathBuf::from(path), FileType::File).unwrap();
+            log_end();
+            ip
+        };
+        let write = |ip: &mut InodePtr, bn: usize| {
+            log_begin();
+            winode(ip, &data, bn * data.len(), data.len()).unwrap();
+            log_end();
+        };
+        let frag_of = |ip: &InodePtr| {
+            ip.read_disk_inode(|dinode| fragmentation(dinode, dev.clone()).unwrap())
+        };
+
+        // two files written in turns take every other block
+        let mut a = create_file("/a");
+        let mut b = create_file("/b");
+        for bn in 0..6 {
+            write(&mut a, bn);
+            write(&mut b, bn);
+        }
+        let scattered = Fragmentation {
+            files: 1,
+            blocks: 6,
+            runs: 6,
+        };
+        assert_eq!(frag_of(&a), scattered);
+        assert_eq!(frag_of(&b), scattered);
+        assert_eq!(scattered.score(), 1.0);
+
+        // written alone, the blocks follow each other
+        let mut packed = create_file("/packed");
+        for bn in 0..4 {
+            write(&mut packed, bn);
+        }
+        let contiguous = Fragmentation {
+            files: 1,
+            blocks: 4,
+            runs: 1,
+        };
+        assert_eq!(frag_of(&packed), contiguous);
+        assert_eq!(contiguous.score(), 0.0);
+
+        // a hole does not break a run
+        let mut sparse = create_file("/sparse");
+        write(&mut sparse, 0);
+        write(&mut sparse, 2);
+        assert_eq!(
+            frag_of(&sparse),
+            Fragmentation {
+                files: 1,
+                blocks: 2,
+                runs: 1
+            }
+        );
+        assert_eq!(frag_of(&create_file("/empty")), Fragmentation::default());
+
+        // the image adds up every inode, the root directory is one block
+        let root = frag_of(&get_inode(dev.clone(), ROOTINO));
+        assert_eq!(
+            root,
+            Fragmentation {
+                files: 1,
+                blocks: 1,
+                runs: 1
+            }
+        );
+        let image_frag = image_fragmentation(dev.clone()).unwrap();
+        assert_eq!(
+            image_frag,
+            Fragmentation {
+                files: 5,
+                blocks: 19,
+                runs: 15
+            }
+        );
+        assert_eq!(image_frag.score(), 10.0 / 14.0);
+    }
+
+    #[test]
+    fn test_reserve_inodes() {
+        let image = TestImage::new("inode_reserve");
+        let dev = image.mount();
+        // read from the block, an InodePtr of an inode without links frees it on drop
+        let type_and_links = |i: u32| {
+            let (bno, off) = addr_of_inode(i);
+            wait_buffer_block(bno, dev.clone())
+                .read()
+                .unwrap()
+                .read(off as usize, |dinode: &DiskInode| {
+                    (dinode.ftype, u16::from_le(dinode.nlink))
+                })
+        };
+        let free_inodes = || {
+            (ROOTINO..sb().ninodes)
+                .filter(|&i| type_and_links(i).0 == FileType::Free as u8)
+                .collect::<Vec<_>>()
+        };
+        // everything but the root is free, take all of it but two
+        let mut left = sb().ninodes - ROOTINO - 1;
+        assert_eq!(free_inodes().len() as u32, left);
+        while left > 2 {
+            let n = (left - 2).min(8);
+            log_begin();
+            let inums = reserve_inodes(dev.clone(), n).unwrap();
+            log_end();
+            assert_eq!(inums.len() as u32, n);
+            left -= n;
+        }
+        let free = free_inodes();
+        assert_eq!(free.len(), 2);
+        let hint = free_inode_hint(&dev);
+
+        // three do not fit, and the two free ones are left as they were
+        log_begin();
+        assert_eq!(reserve_inodes(dev.clone(), 3), None);
+        log_end();
+        assert_eq!(free_inodes(), free);
+        assert_eq!(free_inode_hint(&dev), hint);
+
+        // two do
+        log_begin();
+        assert_eq!(reserve_inodes(dev.clone(), 2), Some(free.clone()));
+        log_end();
+        assert_eq!(free_inodes(), []);
+        for inum in free {
+            assert_eq!(type_and_links(inum), (FileType::File as u8, 0));
+        }
+        log_begin();
+        assert_eq!(reserve_inodes(dev.clone(), 1), None);
+        log_end();
+    }
 }
@@ -1,9 +1,13 @@
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, RwLockWriteGuard};
+use std::thread::ThreadId;
+use std::time::Instant;
 
-use log::{debug, info};
+use log::{debug, info, warn};
 use once_cell::sync::Lazy;
 
-use super::buffer::{get_buffer_block, BufferBlock};
+use super::buffer::{wait_buffer_block, BufferBlock};
 use super::fs::*;
 use super::superblock::SuperBlock;
 
@@ -25,13 +29,48 @@ impl LogHeader {
     }
 }
 
+impl LittleEndian for LogHeader {
+    fn to_le(self) -> Self {
+        Self {
+            n: self.n.to_le(),
+            block: self.block.to_le(),
+        }
+    }
+}
+
+// counters to tune LOGSIZE and MAXOPBLOCKS with, since the program started
+static COMMITTED: AtomicU64 = AtomicU64::new(0);
+static BLOCKS_LOGGED: AtomicU64 = AtomicU64::new(0);
+static MAX_LOGGED: AtomicU32 = AtomicU32::new(0);
+static BEGIN_SLEEPS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LogStats {
+    pub committed: u64,     // transactions committed
+    pub blocks_logged: u64, // blocks written to the log
+    pub max_logged: u32,    // the most blocks one commit held
+    pub begin_sleeps: u64,  // times log_begin waited for a commit or for space
+}
+
+pub fn log_stats() -> LogStats {
+    LogStats {
+        committed: COMMITTED.load(Ordering::SeqCst),
+        blocks_logged: BLOCKS_LOGGED.load(Ordering::SeqCst),
+        max_logged: MAX_LOGGED.load(Ordering::SeqCst),
+        begin_sleeps: BEGIN_SLEEPS.load(Ordering::SeqCst),
+    }
+}
+
 // the log manager in memory
 pub struct Log {
     dev: Option<Arc<dyn BlockDevice>>,
     head: u32, // head block
     size: u32, // log max size
     outstanding: u32,
+    ended: u32,   // transactions ended since the last commit
+    commits: u64, // commits finished, log_end_sync waits for the next one
     committing: bool,
+    active: Vec<(ThreadId, Instant)>, // who began each open transaction, and when
     buffer_outstanding: Vec<Arc<RwLock<BufferBlock>>>, // for performance, the log buffer should in memory
     lh: LogHeader,                                     // log header
 }
@@ -43,7 +82,10 @@ impl Log {
             head: 0,
             size: 0,
             outstanding: 0,
+            ended: 0,
+            commits: 0,
             committing: false,
+            active: Vec::new(),
             buffer_outstanding: Vec::new(),
             lh: LogHeader::new(),
         }
@@ -52,35 +94,45 @@ impl Log {
         self.dev = Some(dev.clone());
         self.head = sb.logstart;
         self.size = sb.nlog;
-        self.recover();
+        // the replay writes, a read-only mount shows the image as the
+        // last installed commit left it
+        if dev.read_only() {
+            self.read_head();
+            if self.lh.n > 0 {
+                warn!("Log::init: {} blocks in the log, not replayed", self.lh.n);
+            }
+            self.lh.n = 0;
+            return;
+        }
+        self.recover(sb);
     }
 
     fn read_head(&mut self) {
-        let b = get_buffer_block(self.head, self.dev.as_ref().unwrap().clone());
+        let b = wait_buffer_block(self.head, self.dev.as_ref().unwrap().clone());
         b.read().unwrap().read(0, |lh: &LogHeader| {
-            self.lh = *lh;
+            self.lh = lh.to_le();
         });
     }
 
     fn write_head(&mut self) {
         info!("{:?} write head", std::thread::current().id());
-        get_buffer_block(self.head, self.dev.as_ref().unwrap().clone())
+        wait_buffer_block(self.head, self.dev.as_ref().unwrap().clone())
             .write()
             .unwrap()
             .sync_write(0, |lh: &mut LogHeader| {
-                *lh = self.lh;
+                *lh = self.lh.to_le();
             });
     }
 
     fn write_log(&self) {
         (0..self.lh.n).for_each(|i| {
             assert_ne!(self.lh.block[i as usize], self.head + i + 1);
-            get_buffer_block(self.head + i + 1, self.dev.as_ref().unwrap().clone())
+            wait_buffer_block(self.head + i + 1, self.dev.as_ref().unwrap().clone())
                 .write()
                 .unwrap()
                 .sync_write(0, |buf: &mut [u8; BLOCK_SIZE as usize]| {
                     buf.copy_from_slice(
-                        &get_buffer_block(
+                        &wait_buffer_block(
                             self.lh.block[i as usize],
                             self.dev.as_ref().unwrap().clone(),
                         )
@@ -95,7 +147,7 @@ impl Log {
     fn install_commit(&mut self) {
         (0..self.lh.n).for_each(|i| {
             assert_ne!(self.lh.block[i as usize], self.head + i + 1);
-            get_buffer_block(
+            wait_buffer_block(
                 self.lh.block[i as usize],
                 self.dev.as_ref().unwrap().clone(),
             )
@@ -103,7 +155,7 @@ impl Log {
             .unwrap()
             .sync_write(0, |buf: &mut [u8; BLOCK_SIZE as usize]| {
                 buf.copy_from_slice(
-                    &get_buffer_block(self.head + i + 1, self.dev.as_ref().unwrap().clone())
+                    &wait_buffer_block(self.head + i + 1, self.dev.as_ref().unwrap().clone())
                         .read()
                         .unwrap()
                         .read(0, |f: &[u8; BLOCK_SIZE as usize]| f.clone()),
@@ -113,10 +165,31 @@ impl Log {
         self.buffer_outstanding.clear();
     }
 
-    fn recover(&mut self) {
+    // a header that holds more blocks than the log, or sends one to a block
+    // no transaction writes: the log itself, the superblocks, past the end.
+    // installing it would copy log blocks over whatever those are
+    fn valid_head(&self, sb: &SuperBlock) -> bool {
+        let log = self.head..self.head + self.size;
+        let homes = sb.inodestart..sb.size.saturating_sub(1);
+        self.lh.n < self.size
+            && self.lh.block[..self.lh.n as usize]
+                .iter()
+                .all(|b| homes.contains(b) && !log.contains(b))
+    }
+
+    fn recover(&mut self, sb: &SuperBlock) {
         info!("{:?} recover", std::thread::current().id());
         self.read_head();
-        self.install_commit();
+        // a torn header is taken as no commit, the transaction in it is lost
+        // but the blocks it would have named are left alone
+        if self.valid_head(sb) {
+            self.install_commit();
+        } else {
+            warn!(
+                "Log::recover: corrupt log header with {} blocks, discarded",
+                self.lh.n
+            );
+        }
         self.lh.n = 0;
         self.write_head();
     }
@@ -124,6 +197,8 @@ impl Log {
     fn commit(&mut self) {
         if self.lh.n > 0 {
             debug!("{:?} commit", std::thread::current().id());
+            BLOCKS_LOGGED.fetch_add(self.lh.n as u64, Ordering::SeqCst);
+            MAX_LOGGED.fetch_max(self.lh.n, Ordering::SeqCst);
             // write commit record to disk
             self.write_log(); // write cached block to log block
             self.write_head(); // write log header to disk
@@ -135,22 +210,45 @@ impl Log {
     }
 }
 
+// the blocks a commit left in the log, read from the header of an unmounted image.
+// they are installed by the next mount
+pub fn logged_blocks(dev: Arc<dyn BlockDevice>, sb: &SuperBlock) -> u32 {
+    wait_buffer_block(sb.logstart, dev)
+        .read()
+        .unwrap()
+        .read(0, |lh: &LogHeader| lh.to_le().n)
+}
+
+// the open transactions: the thread that began each one, and for how many
+// milliseconds it has been open. a thread in a nested transaction shows up
+// once for each, a transaction that stays open holds up every commit
+pub fn log_inspect() -> Vec<(ThreadId, usize)> {
+    LOG_MANAGER
+        .0
+        .lock()
+        .unwrap()
+        .active
+        .iter()
+        .map(|(id, since)| (*id, since.elapsed().as_millis() as usize))
+        .collect()
+}
+
 pub struct LogManager(Mutex<Log>);
 
-pub static mut LOG_MANAGER: Lazy<LogManager> = Lazy::new(|| LogManager(Mutex::new(Log::new())));
+pub static LOG_MANAGER: Lazy<LogManager> = Lazy::new(|| LogManager(Mutex::new(Log::new())));
 
-pub static mut COND: Condvar = Condvar::new();
+pub static COND: Condvar = Condvar::new();
 
 fn sleep<T>(guard: MutexGuard<T>) -> MutexGuard<T> {
-    unsafe { COND.wait(guard).unwrap() }
+    COND.wait(guard).unwrap()
 }
 
 fn wakeup() {
-    unsafe { COND.notify_all() }
+    COND.notify_all()
 }
 
 impl LogManager {
-    pub fn init(&mut self, sb: &SuperBlock, dev: Arc<dyn BlockDevice>) {
+    pub fn init(&self, sb: &SuperBlock, dev: Arc<dyn BlockDevice>) {
         self.0.lock().unwrap().init(sb, dev);
     }
 
@@ -158,13 +256,18 @@ impl LogManager {
         let mut log_guard = self.0.lock().unwrap();
         loop {
             if log_guard.committing {
+                BEGIN_SLEEPS.fetch_add(1, Ordering::SeqCst);
                 log_guard = sleep(log_guard);
             } else if (log_guard.lh.n + (log_guard.outstanding + 1) * MAXOPBLOCKS) > log_guard.size
             {
                 // this transaction might exhaust log space;
+                BEGIN_SLEEPS.fetch_add(1, Ordering::SeqCst);
                 log_guard = sleep(log_guard);
             } else {
                 log_guard.outstanding += 1;
+                log_guard
+                    .active
+                    .push((std::thread::current().id(), Instant::now()));
                 debug!(
                     "{:?} log_begin, outstanding = {}",
                     std::thread::current().id(),
@@ -175,11 +278,20 @@ impl LogManager {
         }
     }
 
-    fn log_end(&self) {
+    // with sync, return once a commit holding the transaction is on disk. no
+    // commit starts while it is outstanding, so that is the next one to finish
+    fn end(&self, sync: bool) {
         let mut log_guard = self.0.lock().unwrap();
         let mut log_ptr: *mut Log = std::ptr::null_mut();
+        let seq = log_guard.commits;
         assert!(log_guard.outstanding > 0);
         log_guard.outstanding -= 1;
+        log_guard.ended += 1;
+        // a nested transaction ends before the one it is in
+        let me = std::thread::current().id();
+        if let Some(i) = log_guard.active.iter().rposition(|(id, _)| *id == me) {
+            log_guard.active.remove(i);
+        }
         debug!(
             "{:?} log_end, outstanding={}",
             std::thread::current().id(),
@@ -187,6 +299,9 @@ impl LogManager {
         );
         assert_ne!(log_guard.committing, true);
         if log_guard.outstanding == 0 {
+            // the commit below takes every transaction ended since the last one
+            COMMITTED.fetch_add(log_guard.ended as u64, Ordering::SeqCst);
+            log_guard.ended = 0;
             log_guard.committing = true;
             log_ptr = &mut *log_guard;
         } else {
@@ -199,12 +314,19 @@ impl LogManager {
                 (*log_ptr).commit();
             }
             let mut log_guard = self.0.lock().unwrap();
+            log_guard.commits += 1;
             log_guard.committing = false;
             wakeup();
         }
+        if sync {
+            let mut log_guard = self.0.lock().unwrap();
+            while log_guard.commits == seq {
+                log_guard = sleep(log_guard);
+            }
+        }
     }
 
-    fn log_write(&mut self, buffer: RwLockWriteGuard<BufferBlock>) {
+    fn log_write(&self, buffer: RwLockWriteGuard<BufferBlock>) {
         let mut log_guard = self.0.lock().unwrap();
         assert!(log_guard.lh.n < LOGSIZE as u32);
         assert!(log_guard.outstanding > 0);
@@ -223,27 +345,63 @@ impl LogManager {
             let dev = log_guard.dev.as_ref().unwrap().clone();
             log_guard
                 .buffer_outstanding
-                .push(get_buffer_block(buffer.id(), dev));
+                .push(wait_buffer_block(buffer.id(), dev));
         }
     }
 }
 
+thread_local! {
+    // set while this thread runs an unlogged operation
+    static NOBARRIER: Cell<bool> = const { Cell::new(false) };
+}
+
+// run f without the log, for bulk writes where crash consistency does not matter.
+// its writes stay dirty in the buffer cache until they are evicted or sync_all,
+// so a crash can leave them half on disk, f must not call log_begin/log_end
+pub fn nobarrier<V>(f: impl FnOnce() -> V) -> V {
+    NOBARRIER.with(|nobarrier| nobarrier.set(true));
+    let ret = f();
+    NOBARRIER.with(|nobarrier| nobarrier.set(false));
+    ret
+}
+
 pub fn log_write(buffer: RwLockWriteGuard<BufferBlock>) {
-    unsafe {
-        LOG_MANAGER.log_write(buffer);
+    if NOBARRIER.with(|nobarrier| nobarrier.get()) {
+        return;
     }
+    LOG_MANAGER.log_write(buffer);
+}
+
+thread_local! {
+    // the transactions this thread has open
+    static TRANSACTIONS: Cell<u32> = const { Cell::new(0) };
+}
+
+// whether this thread is between log_begin and log_end
+pub fn in_transaction() -> bool {
+    TRANSACTIONS.with(|n| n.get() > 0)
 }
 
 pub fn log_begin() {
-    unsafe {
-        LOG_MANAGER.log_begin();
-    }
+    LOG_MANAGER.log_begin();
+    TRANSACTIONS.with(|n| n.set(n.get() + 1));
 }
 
 pub fn log_end() {
-    unsafe {
-        LOG_MANAGER.log_end();
-    }
+    end_transaction(false);
+}
+
+// log_end, returning once the transaction is committed, even when other
+// threads still have theirs open. nested in another transaction of this
+// thread it can only commit with the outer one, so it just ends
+pub fn log_end_sync() {
+    end_transaction(true);
+}
+
+fn end_transaction(sync: bool) {
+    TRANSACTIONS.with(|n| n.set(n.get() - 1));
+    let sync = sync && !in_transaction();
+    LOG_MANAGER.end(sync);
 }
 
 #[cfg(test)]
@@ -257,7 +415,17 @@ mod test {
 
     use env_logger::{Builder, Target};
 
-    use super::super::filedisk::FileDisk;
+    use std::{os::unix::fs::FileExt, path::PathBuf};
+
+    use super::super::{
+        buffer::sync_all,
+        file::mkdir,
+        filedisk::FileDisk,
+        fsck::fsck,
+        inode::resolve,
+        superblock::{read_superblock, sb},
+        testutil::{mount_on, CrashDisk, TestImage},
+    };
     use super::*;
 
     #[test]
@@ -313,19 +481,19 @@ mod test {
         lh.n = 0;
         log.lh = lh;
         log.write_head();
-        unsafe { LOG_MANAGER.init(&sb, filedisk.clone()) };
+        LOG_MANAGER.init(&sb, filedisk.clone());
         let mut handles = Vec::new();
         for i in 0..100 as u8 {
             let filedisk = filedisk.clone();
-            let handle = thread::spawn(move || unsafe {
+            let handle = thread::spawn(move || {
                 LOG_MANAGER.log_begin();
-                get_buffer_block(i as u32 + 3 + LOGSIZE, filedisk.clone())
+                wait_buffer_block(i as u32 + 3 + LOGSIZE, filedisk.clone())
                     .write()
                     .unwrap()
                     .write(0, |b: &mut u8| {
                         *b = i;
                     });
-                LOG_MANAGER.log_end();
+                LOG_MANAGER.end(false);
             });
             handles.push(handle);
         }
@@ -335,7 +503,7 @@ mod test {
             .for_each(|handle| handle.join().unwrap());
 
         for i in 0..100u8 {
-            let _ = get_buffer_block(i as u32 + 3 + LOGSIZE, filedisk.clone())
+            let _ = wait_buffer_block(i as u32 + 3 + LOGSIZE, filedisk.clone())
                 .read()
                 .unwrap()
                 .read(0, |b: &u8| {
@@ -343,4 +511,112 @@ mod test {
                 });
         }
     }
+
+    #[test]
+    fn test_log_stats() {
+        let image = TestImage::new("log_stats");
+        let dev = image.mount();
+        let before = log_stats();
+        for i in 0..5u32 {
+            log_begin();
+            // each transaction logs i + 1 of the free blocks at the end
+            for b in 0..=i {
+                let blk = wait_buffer_block(sb().size - 2 - b, dev.clone());
+                let mut guard = blk.write().unwrap();
+                guard.write(0, |byte: &mut u8| *byte = i as u8);
+                log_write(guard);
+            }
+            log_end();
+        }
+        let after = log_stats();
+        assert_eq!(after.committed - before.committed, 5);
+        assert_eq!(
+            after.blocks_logged - before.blocks_logged,
+            1 + 2 + 3 + 4 + 5
+        );
+        assert!(after.max_logged >= 5);
+    }
+
+    // a commit that reached the log but not its home blocks, with the header
+    // then damaged, is dropped at mount instead of being installed
+    #[test]
+    fn test_corrupt_header() {
+        let image = TestImage::new("log_corrupt_header");
+        let head_at = |image: &TestImage| {
+            let sb = read_superblock(image.disk(), SB_BLOCK);
+            (sb.logstart * BLOCK_SIZE) as u64
+        };
+        let corruptions: [(u32, &[u32]); 3] = [
+            // more blocks than the log holds
+            (u32::MAX, &[]),
+            // onto the superblock
+            (1, &[SB_BLOCK]),
+            // onto a log block and past the end of the image
+            (2, &[2, u32::MAX]),
+        ];
+        for (n, blocks) in corruptions {
+            mkdir(image.mount(), &PathBuf::from("/kept")).ok();
+            sync_all();
+            let sb = sb();
+            // crash once the header of the next commit is written
+            let crash = Arc::new(CrashDisk::new(image.disk()));
+            mount_on(crash.clone());
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&image.path)
+                .unwrap();
+            let mut k = 0;
+            loop {
+                crash.crash_after(k);
+                mkdir(crash.clone(), &PathBuf::from(format!("/lost{}", k))).unwrap();
+                let mut logged = [0u8; 4];
+                file.read_exact_at(&mut logged, head_at(&image)).unwrap();
+                if u32::from_le_bytes(logged) > 0 {
+                    break;
+                }
+                k += 1;
+                crash.crash_after(usize::MAX);
+            }
+            let mut header = n.to_le_bytes().to_vec();
+            header.extend(blocks.iter().flat_map(|b| b.to_le_bytes()));
+            file.write_all_at(&header, head_at(&image)).unwrap();
+
+            let primary = read_superblock(image.disk(), SB_BLOCK);
+            let dev = image.mount();
+            assert_eq!(read_superblock(image.disk(), SB_BLOCK), primary);
+            assert_eq!(logged_blocks(dev.clone(), &sb), 0);
+            assert!(resolve(dev.clone(), &PathBuf::from("/kept")).is_ok());
+            assert_eq!(fsck(image.disk()), []);
+        }
+    }
+
+    #[test]
+    fn test_log_inspect() {
+        let image = TestImage::new("log_inspect");
+        image.mount();
+        let (begun, wait) = std::sync::mpsc::channel();
+        let (release, end) = std::sync::mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            log_begin();
+            log_begin();
+            begun.send(()).unwrap();
+            end.recv().unwrap();
+            log_end();
+            log_end();
+        });
+        wait.recv().unwrap();
+        let id = handle.thread().id();
+        thread::sleep(std::time::Duration::from_millis(20));
+        let open = log_inspect()
+            .into_iter()
+            .filter(|(tid, _)| *tid == id)
+            .collect::<Vec<_>>();
+        // the outer transaction and the one nested in it
+        assert_eq!(open.len(), 2);
+        assert!(open.iter().all(|(_, ms)| *ms >= 20));
+        release.send(()).unwrap();
+        handle.join().unwrap();
+        assert!(log_inspect().iter().all(|(tid, _)| *tid != id));
+    }
 }
@@ -0,0 +1,207 @@
+// raid-1 over two devices: every block is written to both, and read from the
+// first. the copy on the other device is read as well and a difference is
+// warned about, but left alone: with no checksum nothing tells which copy is
+// the good one. a block past the end of one device, as when its file was
+// truncated, is read from the other and written back to it
+use std::sync::Arc;
+
+use log::warn;
+
+use super::fs::BlockDevice;
+
+pub struct MirrorDisk {
+    devs: [Arc<dyn BlockDevice>; 2],
+}
+
+impl MirrorDisk {
+    pub fn new(first: Arc<dyn BlockDevice>, second: Arc<dyn BlockDevice>) -> Self {
+        Self {
+            devs: [first, second],
+        }
+    }
+}
+
+impl BlockDevice for MirrorDisk {
+    fn read_block(&self, block_id: u32, buf: &mut [u8]) {
+        let holds = |dev: &Arc<dyn BlockDevice>| dev.block_count().is_none_or(|n| block_id < n);
+        let (from, to) = if holds(&self.devs[0]) || !holds(&self.devs[1]) {
+            (0, 1)
+        } else {
+            (1, 0)
+        };
+        self.devs[from].read_block(block_id, buf);
+        if !holds(&self.devs[to]) {
+            if !self.devs[to].read_only() {
+                warn!(
+                    "MirrorDisk: block {} missing on device {}, written from device {}",
+                    block_id, to, from
+                );
+                self.devs[to].write_block(block_id, buf);
+            }
+            return;
+        }
+        let mut copy = vec![0u8; buf.len()];
+        self.devs[to].read_block(block_id, &mut copy);
+        if copy != buf {
+            warn!(
+                "MirrorDisk: block {} differs on device {}, read from device {}",
+                block_id, to, from
+            );
+        }
+    }
+
+    fn write_block(&self, block_id: u32, buf: &[u8]) {
+        self.devs
+            .iter()
+            .for_each(|dev| dev.write_block(block_id, buf));
+    }
+
+    // the longer copy, a block the other one lacks is read from it
+    fn block_count(&self) -> Option<u32> {
+        let counts = self.devs.iter().map(|dev| dev.block_count());
+        counts.collect::<Option<Vec<_>>>()?.into_iter().max()
+    }
+
+    fn flush(&self) {
+        self.devs.iter().for_each(|dev| dev.flush());
+    }
+
+    fn discard_blocks(&self, start: u32, n: u32) {
+        self.devs
+            .iter()
+            .for_each(|dev| dev.discard_blocks(start, n));
+    }
+
+    // a write has to reach both copies
+    fn read_only(&self) -> bool {
+        self.devs.iter().any(|dev| dev.read_only())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs::OpenOptions,
+        os::unix::fs::FileExt,
+        path::{Path, PathBuf},
+    };
+
+    use super::*;
+    use crate::fs::{
+        buffer::sync_all,
+        file::{file_block_map, file_read_to_end, fileclose, fileopen, filewrite_all, OpenMode},
+        filedisk::FileDisk,
+        fs::BLOCK_SIZE,
+        fsck::fsck,
+        testutil::{mount_on, TestImage},
+    };
+
+    fn open(path: &Path) -> Arc<dyn BlockDevice> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap();
+        Arc::new(FileDisk::new(file))
+    }
+
+    #[test]
+    fn test_mirror() {
+        let image = TestImage::new("mirror");
+        let copy = image.path.with_extension("mirror");
+        std::fs::copy(&image.path, &copy).unwrap();
+        let mirror: Arc<dyn BlockDevice> =
+            Arc::new(MirrorDisk::new(open(&image.path), open(&copy)));
+        mount_on(mirror.clone());
+        let path = PathBuf::from("/data");
+        let data = (0..4 * BLOCK_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+        let file = fileopen(mirror.clone(), &path, OpenMode::OCreate).unwrap();
+        filewrite_all(&file, &data).unwrap();
+        let first = file_block_map(&file).unwrap()[0].unwrap();
+        fileclose(file);
+        sync_all();
+        // both copies are the same image
+        assert_eq!(
+            std::fs::read(&image.path).unwrap(),
+            std::fs::read(&copy).unwrap()
+        );
+
+        // damage every block of the second copy but the superblocks
+        let len = std::fs::metadata(&copy).unwrap().len();
+        let garbage = vec![0xa5u8; BLOCK_SIZE as usize];
+        let damaged = OpenOptions::new().write(true).open(&copy).unwrap();
+        for block in 2..len / BLOCK_SIZE as u64 - 1 {
+            damaged
+                .write_all_at(&garbage, block * BLOCK_SIZE as u64)
+                .unwrap();
+        }
+        // a fresh mirror, so the blocks come from the disks and not the cache
+        let mirror: Arc<dyn BlockDevice> =
+            Arc::new(MirrorDisk::new(open(&image.path), open(&copy)));
+        mount_on(mirror.clone());
+        let file = fileopen(mirror.clone(), &path, OpenMode::ORdonly).unwrap();
+        assert_eq!(file_read_to_end(&file).unwrap(), data);
+        fileclose(file);
+        assert_eq!(fsck(mirror.clone()), []);
+        // nothing says which copy is damaged, so neither is rewritten
+        let mut buf = [0u8; BLOCK_SIZE as usize];
+        open(&copy).read_block(first, &mut buf);
+        assert_eq!(buf[..], garbage[..]);
+        std::fs::copy(&image.path, &copy).unwrap();
+
+        // a truncated first copy is read from the second, and filled in again
+        let full = std::fs::read(&image.path).unwrap();
+        OpenOptions::new()
+            .write(true)
+            .open(&image.path)
+            .unwrap()
+            .set_len(full.len() as u64 / 2)
+            .unwrap();
+        let mirror = MirrorDisk::new(open(&image.path), open(&copy));
+        assert_eq!(mirror.block_count(), Some(full.len() as u32 / BLOCK_SIZE));
+        let last = full.len() as u32 / BLOCK_SIZE - 1;
+        let mut buf = [0u8; BLOCK_SIZE as usize];
+        mirror.read_block(last, &mut buf);
+        assert_eq!(buf[..], full[(last * BLOCK_SIZE) as usize..]);
+        assert_eq!(open(&image.path).block_count(), Some(last + 1));
+        std::fs::remove_file(copy).unwrap();
+    }
+
+    #[test]
+    fn test_mirror_first_copy_damaged() {
+        let image = TestImage::new("mirror_first");
+        let copy = image.path.with_extension("mirror");
+        std::fs::copy(&image.path, &copy).unwrap();
+        let mirror: Arc<dyn BlockDevice> =
+            Arc::new(MirrorDisk::new(open(&image.path), open(&copy)));
+        mount_on(mirror.clone());
+        let path = PathBuf::from("/data");
+        let data = (0..4 * BLOCK_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+        let file = fileopen(mirror.clone(), &path, OpenMode::OCreate).unwrap();
+        filewrite_all(&file, &data).unwrap();
+        fileclose(file);
+        sync_all();
+
+        // damage every block of the first copy but the superblocks
+        let len = std::fs::metadata(&image.path).unwrap().len();
+        let garbage = vec![0xa5u8; BLOCK_SIZE as usize];
+        let damaged = OpenOptions::new().write(true).open(&image.path).unwrap();
+        for block in 2..len / BLOCK_SIZE as u64 - 1 {
+            damaged
+                .write_all_at(&garbage, block * BLOCK_SIZE as u64)
+                .unwrap();
+        }
+        // reading every block through the mirror leaves the good copy alone
+        let mirror = MirrorDisk::new(open(&image.path), open(&copy));
+        let mut buf = [0u8; BLOCK_SIZE as usize];
+        for block in 0..len as u32 / BLOCK_SIZE {
+            mirror.read_block(block, &mut buf);
+        }
+        assert_eq!(fsck(open(&copy)), []);
+        mount_on(open(&copy));
+        let file = fileopen(open(&copy), &path, OpenMode::ORdonly).unwrap();
+        assert_eq!(file_read_to_end(&file).unwrap(), data);
+        fileclose(file);
+        std::fs::remove_file(copy).unwrap();
+    }
+}
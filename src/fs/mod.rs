@@ -1,7 +1,23 @@
+pub mod alloc;
+pub mod audit;
 pub mod buffer;
+pub mod clock;
+pub mod diff;
+pub mod error;
 pub mod file;
 pub mod filedisk;
 pub mod fs;
+pub mod fsck;
+pub mod gzdisk;
+pub mod inflate;
 pub mod inode;
 pub mod log;
+pub mod mirrordisk;
+pub mod pipe;
+pub mod sha256;
 pub mod superblock;
+pub mod upgrade;
+pub mod xattr;
+
+#[cfg(test)]
+pub mod testutil;
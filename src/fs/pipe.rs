@@ -0,0 +1,158 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    ops::Deref,
+    sync::{Arc, Condvar, Mutex},
+};
+
+use once_cell::sync::Lazy;
+
+use super::fs::{device_id, BlockDevice};
+
+// bytes a fifo holds before writers block
+pub const PIPESIZE: usize = 512;
+
+struct PipeState {
+    buf: VecDeque<u8>,
+    readers: u32,
+    writers: u32,
+}
+
+// the data of a fifo lives in memory only, the inode just names it
+pub struct Pipe {
+    state: Mutex<PipeState>,
+    cond: Condvar,
+}
+
+impl Pipe {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(PipeState {
+                buf: VecDeque::with_capacity(PIPESIZE),
+                readers: 0,
+                writers: 0,
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    // block until there is something to read, then take as much as fits in dst.
+    // 0 once the buffer is empty and no writer has the fifo open
+    pub fn read(&self, dst: &mut [u8]) -> usize {
+        if dst.is_empty() {
+            return 0;
+        }
+        let mut state = self.state.lock().unwrap();
+        while state.buf.is_empty() {
+            if state.writers == 0 {
+                return 0;
+            }
+            state = self.cond.wait(state).unwrap();
+        }
+        let n = dst.len().min(state.buf.len());
+        for (d, s) in dst.iter_mut().zip(state.buf.drain(..n)) {
+            *d = s;
+        }
+        self.cond.notify_all();
+        n
+    }
+
+    // block until all of src is in the buffer
+    pub fn write(&self, src: &[u8]) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let mut n = 0;
+        while n < src.len() {
+            while state.buf.len() == PIPESIZE {
+                state = self.cond.wait(state).unwrap();
+            }
+            let m = (src.len() - n).min(PIPESIZE - state.buf.len());
+            state.buf.extend(&src[n..n + m]);
+            n += m;
+            self.cond.notify_all();
+        }
+        n
+    }
+
+    // count an end in or out, waking the readers waiting for a writer that is
+    // gone. returns whether the fifo is left with no end open
+    fn count(&self, readable: bool, writable: bool, open: bool) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let step = |n: &mut u32, counted: bool| match (counted, open) {
+            (false, _) => {}
+            (true, true) => *n += 1,
+            (true, false) => *n -= 1,
+        };
+        step(&mut state.readers, readable);
+        step(&mut state.writers, writable);
+        self.cond.notify_all();
+        state.readers == 0 && state.writers == 0
+    }
+}
+
+// keyed by device, inum and generation, like the directory index.
+// the device is held, so its address is not reused by another image
+type PipeKey = (usize, u32, u32);
+type PipeEntry = (Arc<dyn BlockDevice>, Arc<Pipe>);
+static PIPES: Lazy<Mutex<HashMap<PipeKey, PipeEntry>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// an open of a fifo, a reader, a writer or both until it is dropped.
+// the buffer goes with the last end, the next open starts an empty one
+pub struct PipeEnd {
+    key: PipeKey,
+    pipe: Arc<Pipe>,
+    readable: bool,
+    writable: bool,
+}
+
+impl Deref for PipeEnd {
+    type Target = Pipe;
+
+    fn deref(&self) -> &Pipe {
+        &self.pipe
+    }
+}
+
+// a copy of an end is one more end
+impl Clone for PipeEnd {
+    fn clone(&self) -> Self {
+        let _pipes = PIPES.lock().unwrap();
+        self.pipe.count(self.readable, self.writable, true);
+        Self {
+            key: self.key,
+            pipe: self.pipe.clone(),
+            readable: self.readable,
+            writable: self.writable,
+        }
+    }
+}
+
+impl Drop for PipeEnd {
+    fn drop(&mut self) {
+        // under the map lock, so no open finds the entry while it goes
+        let mut pipes = PIPES.lock().unwrap();
+        if self.pipe.count(self.readable, self.writable, false) {
+            pipes.remove(&self.key);
+        }
+    }
+}
+
+// open the fifo inode, every open shares its buffer
+pub fn pipe_open(
+    dev: Arc<dyn BlockDevice>,
+    inum: u32,
+    generation: u32,
+    readable: bool,
+    writable: bool,
+) -> PipeEnd {
+    let key = (device_id(&dev), inum, generation);
+    let mut pipes = PIPES.lock().unwrap();
+    let (_, pipe) = pipes
+        .entry(key)
+        .or_insert_with(|| (dev.clone(), Arc::new(Pipe::new())));
+    pipe.count(readable, writable, true);
+    PipeEnd {
+        key,
+        pipe: pipe.clone(),
+        readable,
+        writable,
+    }
+}
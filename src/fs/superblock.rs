@@ -1,9 +1,42 @@
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, RwLock,
+};
 
-use super::buffer::get_buffer_block;
-use super::fs::{BlockDevice, FATPIGEORZMAGIC, SB_BLOCK};
+use super::buffer::{keep_resident, pin_blocks, resident_inodes, sync_all, wait_buffer_block};
+use super::error::FsError;
+use super::fs::{BlockDevice, LittleEndian, FATPIGEORZMAGIC, LOGSIZE, MAXOPBLOCKS, SB_BLOCK};
+use super::inode::dir_index_release;
+use super::log::{log_begin, log_end_sync};
+use log::warn;
 use once_cell::sync::Lazy;
 
+// format features, set by mkfs. an image with an incompat feature this code
+// does not know is refused, one with an unknown ro-compat feature mounts read-only
+pub const INCOMPAT_INLINE_DATA: u32 = 1 << 0; // small files kept in the inode
+pub const INCOMPAT_DIRENT_FTYPE: u32 = 1 << 1; // dirents keep the file type
+pub const INCOMPAT_SUPPORTED: u32 = INCOMPAT_INLINE_DATA | INCOMPAT_DIRENT_FTYPE;
+// the xattr map, see xattrstart. code without it would free an inode and leave
+// its attributes for the next one, it may only read the image
+pub const RO_COMPAT_XATTR: u32 = 1 << 0;
+// the refcount map, see refstart. code without it would free a block a
+// reflink still shares, it may only read the image
+pub const RO_COMPAT_REFLINK: u32 = 1 << 1;
+pub const RO_COMPAT_SUPPORTED: u32 = RO_COMPAT_XATTR | RO_COMPAT_REFLINK;
+
+// the on-disk format. images made before the field have 0 there and are taken
+// as 1: no dirent file types and no inline data. upgrade moves an older image
+// to this one, an image of a later version is refused
+pub const FS_VERSION: u16 = 2;
+
+// set by init when the image has a ro-compat feature we do not know,
+// or the device is read-only
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+pub fn read_only() -> bool {
+    READ_ONLY.load(Ordering::SeqCst)
+}
+
 // the super block of filesystem
 #[repr(C)]
 #[derive(Debug, Default, Clone, Copy, PartialEq)]
@@ -16,6 +49,13 @@ pub struct SuperBlock {
     pub logstart: u32,   // Block number of first log block
     pub inodestart: u32, // Block number of first inode block
     pub bmapstart: u32,  // Block number of first free map block
+    pub refstart: u32,   // Block number of first refcount map block, with RO_COMPAT_REFLINK
+    pub in_use: u32,     // Set while mounted, still set after an unclean shutdown
+    pub feature_incompat: u32,
+    pub feature_ro_compat: u32,
+    pub version: u16, // see version()
+    reserved: u16,
+    pub xattrstart: u32, // Block number of first xattr map block, with RO_COMPAT_XATTR
 }
 
 impl SuperBlock {
@@ -29,27 +69,458 @@ impl SuperBlock {
             logstart: 0,
             inodestart: 0,
             bmapstart: 0,
+            refstart: 0,
+            in_use: 0,
+            feature_incompat: 0,
+            feature_ro_compat: 0,
+            version: FS_VERSION,
+            reserved: 0,
+            xattrstart: 0,
         }
     }
 
-    pub fn init(&mut self, dev: Arc<dyn BlockDevice>) {
-        get_buffer_block(SB_BLOCK, dev.clone())
-            .read()
-            .unwrap()
-            .read(0, |sb: &SuperBlock| {
-                // assert magic eq and panic
-                if self.magic != FATPIGEORZMAGIC {
-                    panic!("SuperBlock::init: invalid magic number");
+    pub fn valid(&self) -> bool {
+        self.magic == FATPIGEORZMAGIC
+    }
+
+    // the format of the image, see FS_VERSION
+    pub fn version(&self) -> u16 {
+        self.version.max(1)
+    }
+
+    // the first data block, the nblocks data blocks end before the backup superblock
+    pub fn data_start(&self) -> u32 {
+        self.size.saturating_sub(self.nblocks + 1)
+    }
+
+    pub fn init(&mut self, dev: Arc<dyn BlockDevice>) -> Result<(), FsError> {
+        let mut sb = read_superblock(dev.clone(), SB_BLOCK);
+        if !sb.valid() {
+            // mount from the backup and restore the primary from it
+            let backup = backup_block(&sb, &dev)
+                .map(|b| read_superblock(dev.clone(), b))
+                .filter(|backup| backup.valid());
+            match backup {
+                Some(backup) => {
+                    warn!("SuperBlock::init: invalid magic number, restore from the backup");
+                    if !dev.read_only() {
+                        wait_buffer_block(SB_BLOCK, dev.clone())
+                            .write()
+                            .unwrap()
+                            .sync_write(0, |primary: &mut SuperBlock| *primary = backup.to_le());
+                    }
+                    sb = backup;
                 }
-                self.size = sb.size;
-                self.nblocks = sb.nblocks;
-                self.ninodes = sb.ninodes;
-                self.nlog = sb.nlog;
-                self.logstart = sb.logstart;
-                self.inodestart = sb.inodestart;
-                self.bmapstart = sb.bmapstart;
+                // mkfs writes the superblock last, so it never finished
+                None => return Err(FsError::NotFormatted),
+            }
+        }
+        if sb.version() > FS_VERSION {
+            return Err(FsError::UnsupportedVersion {
+                version: sb.version(),
             });
+        }
+        let unknown = sb.feature_incompat & !INCOMPAT_SUPPORTED;
+        if unknown != 0 {
+            return Err(FsError::UnsupportedFeatures { incompat: unknown });
+        }
+        // a log ending past the last block number is no superblock mkfs wrote
+        let Some(logend) = sb.logstart.checked_add(sb.nlog) else {
+            return Err(FsError::NotFormatted);
+        };
+        // the header plus the blocks of the largest transaction, at most what
+        // the header can list, and all of it before the inode blocks
+        let fits = sb.nlog > MAXOPBLOCKS
+            && sb.nlog <= LOGSIZE
+            && sb.logstart > SB_BLOCK
+            && logend <= sb.inodestart;
+        if !fits {
+            return Err(FsError::BadLog {
+                logstart: sb.logstart,
+                nlog: sb.nlog,
+            });
+        }
+        let ro = sb.feature_ro_compat & !RO_COMPAT_SUPPORTED;
+        if ro != 0 {
+            warn!(
+                "SuperBlock::init: unknown features {:#x}, mount read-only",
+                ro
+            );
+        }
+        READ_ONLY.store(ro != 0 || dev.read_only(), Ordering::SeqCst);
+        *self = sb;
+        // refuse an image shorter than the superblock claims,
+        // rather than failing on a read deep inside the cache
+        match dev.block_count() {
+            Some(size) if size < self.size => Err(FsError::Truncated {
+                size,
+                expected: self.size,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    // flag the image mounted, or cleanly unmounted, in the primary superblock
+    pub fn mark_in_use(&mut self, dev: Arc<dyn BlockDevice>, in_use: bool) {
+        self.in_use = in_use as u32;
+        let sb = *self;
+        wait_buffer_block(SB_BLOCK, dev)
+            .write()
+            .unwrap()
+            .sync_write(0, |primary: &mut SuperBlock| *primary = sb.to_le());
+    }
+}
+
+impl LittleEndian for SuperBlock {
+    fn to_le(self) -> Self {
+        Self {
+            magic: self.magic.to_le(),
+            size: self.size.to_le(),
+            nblocks: self.nblocks.to_le(),
+            ninodes: self.ninodes.to_le(),
+            nlog: self.nlog.to_le(),
+            logstart: self.logstart.to_le(),
+            inodestart: self.inodestart.to_le(),
+            bmapstart: self.bmapstart.to_le(),
+            refstart: self.refstart.to_le(),
+            in_use: self.in_use.to_le(),
+            feature_incompat: self.feature_incompat.to_le(),
+            feature_ro_compat: self.feature_ro_compat.to_le(),
+            version: self.version.to_le(),
+            reserved: self.reserved.to_le(),
+            xattrstart: self.xattrstart.to_le(),
+        }
     }
 }
 
-pub static mut SB: Lazy<SuperBlock> = Lazy::new(|| SuperBlock::new());
+pub fn read_superblock(dev: Arc<dyn BlockDevice>, block: u32) -> SuperBlock {
+    wait_buffer_block(block, dev)
+        .read()
+        .unwrap()
+        .read(0, |sb: &SuperBlock| sb.to_le())
+}
+
+// mkfs keeps a copy of the superblock in the last block of the image,
+// when the primary is damaged the image size tells where that is
+pub fn backup_block(primary: &SuperBlock, dev: &Arc<dyn BlockDevice>) -> Option<u32> {
+    if primary.valid() {
+        Some(primary.size - 1)
+    } else {
+        dev.block_count().and_then(|size| size.checked_sub(1))
+    }
+}
+
+// the superblock of the mounted image, readers take a copy with sb().
+// it can be replaced while the image is in use, as upgrade does, a reader
+// gets the superblock from before or after, never a mix of the two
+static SB: Lazy<RwLock<SuperBlock>> = Lazy::new(|| RwLock::new(SuperBlock::new()));
+
+pub fn sb() -> SuperBlock {
+    *SB.read().unwrap()
+}
+
+// mount: load the superblock of dev, see SuperBlock::init
+pub fn init_superblock(dev: Arc<dyn BlockDevice>) -> Result<(), FsError> {
+    let mut sb = sb();
+    let ret = sb.init(dev.clone());
+    *SB.write().unwrap() = sb;
+    ret?;
+    // the inode blocks and the maps. the log is written once a commit
+    // and would only churn them
+    pin_blocks(sb.inodestart..sb.data_start())?;
+    if resident_inodes() {
+        keep_resident(sb.inodestart..sb.bmapstart, dev);
+    }
+    Ok(())
+}
+
+// replace the superblock of the mounted image, in both copies
+pub fn write_superblock(dev: Arc<dyn BlockDevice>, sb: &SuperBlock) {
+    let disk = sb.to_le();
+    for block in [SB_BLOCK, sb.size - 1] {
+        wait_buffer_block(block, dev.clone())
+            .write()
+            .unwrap()
+            .sync_write(0, |copy: &mut SuperBlock| *copy = disk);
+    }
+    *SB.write().unwrap() = *sb;
+}
+
+// set or clear the in-use flag of the mounted image, see SuperBlock::mark_in_use
+pub fn mark_in_use(dev: Arc<dyn BlockDevice>, in_use: bool) {
+    let mut sb = sb();
+    sb.mark_in_use(dev, in_use);
+    SB.write().unwrap().in_use = sb.in_use;
+}
+
+// close the mounted image cleanly: drop its directory indexes, commit the
+// log, write the cache back and only then clear the in-use flag, so status
+// and fsck see a clean image
+pub fn unmount(dev: Arc<dyn BlockDevice>) {
+    dir_index_release(&dev);
+    // nothing was written to a read-only image, and the flag was never set
+    if read_only() {
+        return;
+    }
+    // a transaction of our own ends once those still open are committed
+    log_begin();
+    log_end_sync();
+    sync_all();
+    dev.flush();
+    mark_in_use(dev.clone(), false);
+    dev.flush();
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs::OpenOptions, os::unix::prelude::FileExt, path::PathBuf, sync::atomic::AtomicU32,
+    };
+
+    use super::*;
+    use crate::fs::{
+        file::{file_read_to_end, fileclose, fileopen, filewrite_all, mkdir, OpenMode},
+        fs::BLOCK_SIZE,
+        fsck::{fsck, status, Status},
+        inode::find_inode,
+        testutil::{TestImage, TEST_IMAGE_SIZE},
+    };
+
+    #[test]
+    fn test_mount_from_backup() {
+        let image = TestImage::new("sb_backup");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&image.path)
+            .unwrap();
+        let read_block = |block: u32| {
+            let mut buf = [0u8; BLOCK_SIZE as usize];
+            file.read_exact_at(&mut buf, (block * BLOCK_SIZE) as u64)
+                .unwrap();
+            buf
+        };
+        let last = TEST_IMAGE_SIZE / BLOCK_SIZE - 1;
+        let backup = read_block(last);
+        assert_eq!(read_block(SB_BLOCK), backup);
+        file.write_all_at(&[0u8; BLOCK_SIZE as usize], (SB_BLOCK * BLOCK_SIZE) as u64)
+            .unwrap();
+
+        let dev = image.mount();
+        assert!(sb().valid());
+        assert_eq!(sb().size, last + 1);
+        // the primary is rewritten from the backup
+        assert_eq!(read_block(SB_BLOCK), backup);
+        mkdir(dev.clone(), &PathBuf::from("/dir")).unwrap();
+        assert!(find_inode(dev.clone(), &PathBuf::from("/dir")).is_some());
+    }
+
+    #[test]
+    fn test_little_endian_superblock() {
+        let image = TestImage::new("sb_little_endian");
+        let sb = read_superblock(image.disk(), SB_BLOCK);
+        let size = TEST_IMAGE_SIZE / BLOCK_SIZE;
+        assert_eq!(sb.size, size);
+
+        // the same superblock, laid out by hand
+        let fields = [
+            FATPIGEORZMAGIC,
+            size,
+            sb.nblocks,
+            sb.ninodes,
+            sb.nlog,
+            sb.logstart,
+            sb.inodestart,
+            sb.bmapstart,
+            sb.refstart,
+            0,
+            INCOMPAT_INLINE_DATA | INCOMPAT_DIRENT_FTYPE,
+            RO_COMPAT_XATTR | RO_COMPAT_REFLINK,
+            FS_VERSION as u32,
+            sb.xattrstart,
+        ];
+        let mut buf = [0u8; BLOCK_SIZE as usize];
+        for (i, field) in fields.iter().enumerate() {
+            buf[i * 4..i * 4 + 4].copy_from_slice(&field.to_le_bytes());
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&image.path)
+            .unwrap();
+        let mut disk = [0u8; BLOCK_SIZE as usize];
+        file.read_exact_at(&mut disk, (SB_BLOCK * BLOCK_SIZE) as u64)
+            .unwrap();
+        // mkfs wrote exactly these bytes
+        assert_eq!(disk, buf);
+
+        file.write_all_at(&buf, (SB_BLOCK * BLOCK_SIZE) as u64)
+            .unwrap();
+        let mut parsed = SuperBlock::new();
+        parsed.init(image.disk()).unwrap();
+        assert_eq!(parsed, sb);
+        assert_eq!(parsed.size, size);
+    }
+
+    #[test]
+    fn test_unknown_features() {
+        let image = TestImage::new("sb_features");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&image.path)
+            .unwrap();
+        // feature_incompat and feature_ro_compat follow the ten fields before them
+        let set_field = |index: u64, value: u32| {
+            file.write_all_at(
+                &value.to_le_bytes(),
+                (SB_BLOCK * BLOCK_SIZE) as u64 + index * 4,
+            )
+            .unwrap();
+        };
+
+        set_field(10, INCOMPAT_INLINE_DATA | 1 << 31);
+        let mut sb = SuperBlock::new();
+        assert_eq!(
+            sb.init(image.disk()),
+            Err(FsError::UnsupportedFeatures { incompat: 1 << 31 })
+        );
+
+        set_field(10, INCOMPAT_INLINE_DATA);
+        set_field(11, 1 << 31);
+        let dev = image.mount();
+        assert!(read_only());
+        assert_eq!(
+            mkdir(dev.clone(), &PathBuf::from("/dir")).err(),
+            Some(FsError::ReadOnly)
+        );
+
+        set_field(11, 0);
+        let dev = image.mount();
+        assert!(!read_only());
+        mkdir(dev.clone(), &PathBuf::from("/dir")).unwrap();
+    }
+
+    #[test]
+    fn test_log_region() {
+        let image = TestImage::new("sb_log_region");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&image.path)
+            .unwrap();
+        let sb = read_superblock(image.disk(), SB_BLOCK);
+        // nlog and logstart are the fifth and sixth fields
+        let set_log = |nlog: u32, logstart: u32| {
+            let mut buf = [0u8; 8];
+            buf[..4].copy_from_slice(&nlog.to_le_bytes());
+            buf[4..].copy_from_slice(&logstart.to_le_bytes());
+            file.write_all_at(&buf, (SB_BLOCK * BLOCK_SIZE) as u64 + 16)
+                .unwrap();
+        };
+        let init = || SuperBlock::new().init(image.disk());
+
+        // no room for a transaction of MAXOPBLOCKS blocks
+        set_log(MAXOPBLOCKS, sb.logstart);
+        assert_eq!(
+            init(),
+            Err(FsError::BadLog {
+                logstart: sb.logstart,
+                nlog: MAXOPBLOCKS
+            })
+        );
+        // running into the inode blocks
+        set_log(sb.nlog, sb.logstart + 1);
+        assert_eq!(
+            init(),
+            Err(FsError::BadLog {
+                logstart: sb.logstart + 1,
+                nlog: sb.nlog
+            })
+        );
+        // more than the header has slots for
+        set_log(LOGSIZE + 1, sb.logstart);
+        assert!(init().is_err());
+        // an end past u32::MAX
+        set_log(sb.nlog, u32::MAX);
+        assert_eq!(init(), Err(FsError::NotFormatted));
+
+        set_log(sb.nlog, sb.logstart);
+        assert_eq!(init(), Ok(()));
+        // the smallest log that holds one transaction
+        set_log(MAXOPBLOCKS + 1, sb.logstart);
+        assert_eq!(init(), Ok(()));
+    }
+
+    #[test]
+    fn test_unmount() {
+        let image = TestImage::new("sb_unmount");
+        let dev = image.mount();
+        mark_in_use(dev.clone(), true);
+        let path = PathBuf::from("/kept");
+        let file = fileopen(dev.clone(), &path, OpenMode::OCreate).unwrap();
+        filewrite_all(&file, &[9; 3000]).unwrap();
+        fileclose(file);
+        assert!(status(image.disk()).unwrap().in_use);
+        unmount(dev.clone());
+        assert_eq!(sb().in_use, 0);
+        assert_eq!(
+            status(image.disk()),
+            Ok(Status {
+                in_use: false,
+                logged: 0
+            })
+        );
+        // a fresh device reads the file from the disk, not the cache
+        let dev = image.mount();
+        let file = fileopen(dev.clone(), &path, OpenMode::ORdonly).unwrap();
+        assert_eq!(file_read_to_end(&file).unwrap(), [9; 3000]);
+        fileclose(file);
+        assert_eq!(fsck(image.disk()), []);
+    }
+
+    #[test]
+    fn test_replace_while_read() {
+        let image = TestImage::new("sb_replace");
+        let dev = image.mount();
+        let path = PathBuf::from("/data");
+        let file = fileopen(dev.clone(), &path, OpenMode::OCreate).unwrap();
+        filewrite_all(&file, &[5; 3000]).unwrap();
+        fileclose(file);
+        let stop = Arc::new(AtomicBool::new(false));
+        let reads = Arc::new(AtomicU32::new(0));
+        let readers = (0..4)
+            .map(|_| {
+                let (dev, path) = (dev.clone(), path.clone());
+                let (stop, reads) = (stop.clone(), reads.clone());
+                std::thread::spawn(move || {
+                    while !stop.load(Ordering::SeqCst) {
+                        // a copy is one superblock, never half of two
+                        let sb = sb();
+                        assert_eq!(sb.in_use, sb.reserved as u32);
+                        let file = fileopen(dev.clone(), &path, OpenMode::ORdonly).unwrap();
+                        assert_eq!(file_read_to_end(&file).unwrap(), [5; 3000]);
+                        fileclose(file);
+                        reads.fetch_add(1, Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let original = sb();
+        // until the readers have been at it for a while
+        let mut i = 0;
+        while i < 200 || reads.load(Ordering::SeqCst) < 200 {
+            let mut replaced = original;
+            replaced.in_use = i % 2;
+            replaced.reserved = (i % 2) as u16;
+            write_superblock(dev.clone(), &replaced);
+            i += 1;
+        }
+        stop.store(true, Ordering::SeqCst);
+        readers
+            .into_iter()
+            .for_each(|reader| reader.join().unwrap());
+        write_superblock(dev.clone(), &original);
+        assert_eq!(fsck(image.disk()), []);
+    }
+}
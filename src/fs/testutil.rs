@@ -0,0 +1,165 @@
+// helpers for tests that need a freshly formatted image
+use std::{
+    fs::OpenOptions,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+
+use crate::mkfs::mkfs;
+
+use super::{
+    alloc::{set_alloc_policy, AllocPolicy},
+    buffer::sync_all,
+    filedisk::FileDisk,
+    fs::{BlockDevice, ROOTINO},
+    inode::set_root,
+    log::LOG_MANAGER,
+    superblock::{init_superblock, sb},
+};
+
+pub const TEST_IMAGE_SIZE: u32 = 512 * 512 * 8;
+
+// the superblock and the log are global,
+// so tests working on an image must run one at a time
+static FS_LOCK: Mutex<()> = Mutex::new(());
+
+pub struct TestImage {
+    pub path: PathBuf,
+    _guard: MutexGuard<'static, ()>,
+}
+
+impl TestImage {
+    pub fn new(name: &str) -> Self {
+        let guard = FS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let path =
+            std::env::temp_dir().join(format!("fatpigeorz_{}_{}.img", name, std::process::id()));
+        mkfs(path.clone(), TEST_IMAGE_SIZE);
+        Self {
+            path,
+            _guard: guard,
+        }
+    }
+
+    // open the image and init the superblock and log on it
+    pub fn mount(&self) -> Arc<dyn BlockDevice> {
+        let dev = self.disk();
+        mount_on(dev.clone());
+        dev
+    }
+
+    // the image as a device, without mounting it
+    pub fn disk(&self) -> Arc<dyn BlockDevice> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.path)
+            .unwrap();
+        Arc::new(FileDisk::new(file))
+    }
+}
+
+// init the superblock and log on dev, replaying the log
+pub fn mount_on(dev: Arc<dyn BlockDevice>) {
+    init_superblock(dev.clone()).unwrap();
+    // a test that mounted a subtree may have failed before restoring the root
+    set_root(ROOTINO);
+    set_alloc_policy(AllocPolicy::FirstFit);
+    LOG_MANAGER.init(&sb(), dev.clone());
+}
+
+// a device that loses every write once its budget is spent, like a power cut.
+// the cache keeps the lost blocks, so mount the image again to see the disk
+pub struct CrashDisk {
+    inner: Arc<dyn BlockDevice>,
+    budget: AtomicUsize,
+    lost: AtomicUsize,
+}
+
+impl CrashDisk {
+    pub fn new(inner: Arc<dyn BlockDevice>) -> Self {
+        Self {
+            inner,
+            budget: AtomicUsize::new(usize::MAX),
+            lost: AtomicUsize::new(0),
+        }
+    }
+
+    // let n more writes through, then crash
+    pub fn crash_after(&self, n: usize) {
+        self.budget.store(n, Ordering::SeqCst);
+    }
+
+    // the number of writes dropped since the crash
+    pub fn lost(&self) -> usize {
+        self.lost.load(Ordering::SeqCst)
+    }
+}
+
+impl BlockDevice for CrashDisk {
+    fn read_block(&self, block_id: u32, buf: &mut [u8]) {
+        self.inner.read_block(block_id, buf);
+    }
+
+    fn write_block(&self, block_id: u32, buf: &[u8]) {
+        let spent = self
+            .budget
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_err();
+        if spent {
+            self.lost.fetch_add(1, Ordering::SeqCst);
+        } else {
+            self.inner.write_block(block_id, buf);
+        }
+    }
+
+    fn block_count(&self) -> Option<u32> {
+        self.inner.block_count()
+    }
+}
+
+// a device that keeps the ids of the blocks read from it, in order
+pub struct ReadLogDisk {
+    inner: Arc<dyn BlockDevice>,
+    reads: Mutex<Vec<u32>>,
+}
+
+impl ReadLogDisk {
+    pub fn new(inner: Arc<dyn BlockDevice>) -> Self {
+        Self {
+            inner,
+            reads: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn reads(&self) -> Vec<u32> {
+        self.reads.lock().unwrap().clone()
+    }
+}
+
+impl BlockDevice for ReadLogDisk {
+    fn read_block(&self, block_id: u32, buf: &mut [u8]) {
+        self.reads.lock().unwrap().push(block_id);
+        self.inner.read_block(block_id, buf);
+    }
+
+    fn write_block(&self, block_id: u32, buf: &[u8]) {
+        self.inner.write_block(block_id, buf);
+    }
+
+    fn block_count(&self) -> Option<u32> {
+        self.inner.block_count()
+    }
+}
+
+impl Drop for TestImage {
+    fn drop(&mut self) {
+        // a failed test may have poisoned the buffer locks
+        if !std::thread::panicking() {
+            sync_all();
+        }
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
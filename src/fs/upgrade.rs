@@ -0,0 +1,221 @@
+// move an image an older mkfs made to the current format, in place.
+// version 1 has no dirent file types, the byte after the name holds a 28th
+// name byte instead, and keeps every file in blocks. the inodes are laid out
+// as now, so only the dirents are rewritten, then the features and the version
+// are set in both superblocks. the image is only taken as upgraded once they
+// are written, so a crash before leaves a version 1 image to upgrade again
+use std::sync::Arc;
+
+use log::info;
+
+use super::{
+    buffer::{sync_all, wait_buffer_block},
+    error::FsError,
+    fs::{BlockDevice, FileType, LittleEndian, BLOCK_SIZE, NDIRECT, ROOTINO},
+    fsck::{inode_blocks, read_inode},
+    inode::DirEntry,
+    log::{log_begin, log_end, log_end_sync, log_write},
+    superblock::{
+        read_only, sb, write_superblock, SuperBlock, FS_VERSION, INCOMPAT_DIRENT_FTYPE,
+        INCOMPAT_INLINE_DATA,
+    },
+};
+
+const DIRENT_SIZE: usize = std::mem::size_of::<DirEntry>();
+
+// upgrade the mounted image, returning the version it had
+pub fn upgrade(dev: Arc<dyn BlockDevice>) -> Result<u16, FsError> {
+    let mut sb = sb();
+    let from = sb.version();
+    if from >= FS_VERSION {
+        return Ok(from);
+    }
+    if read_only() {
+        return Err(FsError::ReadOnly);
+    }
+    // nothing is written unless every name fits the new dirent
+    let blocks = dirent_blocks(dev.clone(), &sb);
+    for &block in blocks.iter() {
+        let long = read_dirents(dev.clone(), block)
+            .iter()
+            .any(|entry| entry.inum != 0 && FileType::from_u8(entry.ftype).is_none());
+        if long {
+            return Err(FsError::NameTooLong);
+        }
+    }
+    let mut n = 0;
+    for &block in blocks.iter() {
+        n += set_dirent_types(dev.clone(), &sb, block);
+    }
+    // the dirents are home before the superblocks say they are there
+    log_begin();
+    log_end_sync();
+    sync_all();
+    dev.flush();
+    sb.version = FS_VERSION;
+    sb.feature_incompat |= INCOMPAT_DIRENT_FTYPE | INCOMPAT_INLINE_DATA;
+    write_superblock(dev.clone(), &sb);
+    dev.flush();
+    info!("upgrade: version {} to {}, {} dirents", from, FS_VERSION, n);
+    Ok(from)
+}
+
+// the blocks holding the dirents of every directory
+pub(super) fn dirent_blocks(dev: Arc<dyn BlockDevice>, sb: &SuperBlock) -> Vec<u32> {
+    let mut blocks = vec![];
+    for inum in ROOTINO..sb.ninodes {
+        let dinode = read_inode(dev.clone(), sb, inum);
+        if dinode.ftype != FileType::Dir as u8 {
+            continue;
+        }
+        let indirect = dinode.addrs[NDIRECT as usize];
+        let data = inode_blocks(dev.clone(), sb, &dinode);
+        blocks.extend(data.into_iter().filter(|&b| b != indirect));
+    }
+    blocks
+}
+
+fn read_dirents(dev: Arc<dyn BlockDevice>, block: u32) -> Vec<DirEntry> {
+    let buffer = wait_buffer_block(block, dev);
+    let guard = buffer.read().unwrap();
+    (0..BLOCK_SIZE as usize)
+        .step_by(DIRENT_SIZE)
+        .map(|off| guard.read(off, |entry: &DirEntry| entry.to_le()))
+        .collect()
+}
+
+// give the used dirents of a block the type of their inode, in a transaction
+// of its own. returns how many changed
+fn set_dirent_types(dev: Arc<dyn BlockDevice>, sb: &SuperBlock, block: u32) -> u32 {
+    log_begin();
+    let buffer = wait_buffer_block(block, dev.clone());
+    let mut guard = buffer.write().unwrap();
+    let mut n = 0;
+    for off in (0..BLOCK_SIZE as usize).step_by(DIRENT_SIZE) {
+        let entry = guard.read(off, |entry: &DirEntry| entry.to_le());
+        // a wild inum is left for fsck to report
+        if entry.inum == 0 || entry.inum >= sb.ninodes {
+            continue;
+        }
+        let ftype = read_inode(dev.clone(), sb, entry.inum).ftype;
+        if entry.ftype != ftype {
+            guard.write(off, |entry: &mut DirEntry| entry.ftype = ftype);
+            n += 1;
+        }
+    }
+    log_write(guard);
+    log_end();
+    n
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::fs::{
+        file::{file_read_to_end, fileclose, fileopen, filewrite_all, mkdir, readdir, OpenMode},
+        fsck::fsck,
+        inode::{entry_name, is_inline, resolve},
+        testutil::{mount_on, TestImage},
+    };
+
+    #[test]
+    fn test_upgrade() {
+        let image = TestImage::new("upgrade");
+        let dev = image.mount();
+        // a version 1 image: no features, no version, no dirent types,
+        // and files big enough that none is inline
+        let mut old = sb();
+        old.version = 0;
+        old.feature_incompat = 0;
+        write_superblock(dev.clone(), &old);
+        let data = (0..3 * BLOCK_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+        mkdir(dev.clone(), &PathBuf::from("/dir")).unwrap();
+        for path in ["/a", "/dir/b"] {
+            let file = fileopen(dev.clone(), &PathBuf::from(path), OpenMode::OCreate).unwrap();
+            filewrite_all(&file, &data).unwrap();
+            fileclose(file);
+        }
+        for block in dirent_blocks(dev.clone(), &old) {
+            log_begin();
+            let buffer = wait_buffer_block(block, dev.clone());
+            let mut guard = buffer.write().unwrap();
+            for off in (0..BLOCK_SIZE as usize).step_by(DIRENT_SIZE) {
+                guard.write(off, |entry: &mut DirEntry| entry.ftype = 0);
+            }
+            log_write(guard);
+            log_end();
+        }
+        sync_all();
+
+        // mounted again from the disk, as an old image would be
+        let dev = image.disk();
+        mount_on(dev.clone());
+        assert_eq!(sb().version(), 1);
+        assert_eq!(upgrade(dev.clone()), Ok(1));
+        let dev = image.disk();
+        mount_on(dev.clone());
+        assert_eq!(sb().version(), FS_VERSION);
+        assert_eq!(
+            sb().feature_incompat,
+            INCOMPAT_DIRENT_FTYPE | INCOMPAT_INLINE_DATA
+        );
+        assert_eq!(fsck(dev.clone()), []);
+        for path in ["/a", "/dir/b"] {
+            let file = fileopen(dev.clone(), &PathBuf::from(path), OpenMode::ORdonly).unwrap();
+            assert_eq!(file_read_to_end(&file).unwrap(), data, "{}", path);
+            fileclose(file);
+        }
+        let dir = fileopen(dev.clone(), &PathBuf::from("/"), OpenMode::ODirectory).unwrap();
+        let types = readdir(&dir)
+            .map(|entry| (entry_name(&entry), FileType::from_u8(entry.ftype)))
+            .collect::<Vec<_>>();
+        fileclose(dir);
+        for (name, ftype) in [("dir", FileType::Dir), ("a", FileType::File)] {
+            assert!(
+                types.contains(&(name.to_string(), Some(ftype))),
+                "{:?}",
+                types
+            );
+        }
+        // a new small file is inline now
+        let file = fileopen(dev.clone(), &PathBuf::from("/small"), OpenMode::OCreate).unwrap();
+        filewrite_all(&file, b"small").unwrap();
+        fileclose(file);
+        let small = resolve(dev.clone(), &PathBuf::from("/small"))
+            .unwrap()
+            .0
+            .inum;
+        assert!(is_inline(&read_inode(dev.clone(), &sb(), small)));
+        assert_eq!(upgrade(dev.clone()), Ok(FS_VERSION));
+        assert_eq!(fsck(dev.clone()), []);
+    }
+
+    #[test]
+    fn test_upgrade_long_name() {
+        let image = TestImage::new("upgrade_long_name");
+        let dev = image.mount();
+        let mut old = sb();
+        old.version = 0;
+        old.feature_incompat = 0;
+        write_superblock(dev.clone(), &old);
+        mkdir(dev.clone(), &PathBuf::from("/dir")).unwrap();
+        // a version 1 name of 28 bytes runs into the byte the type is kept in
+        let block = dirent_blocks(dev.clone(), &old)[0];
+        log_begin();
+        let buffer = wait_buffer_block(block, dev.clone());
+        let mut guard = buffer.write().unwrap();
+        for off in (0..BLOCK_SIZE as usize).step_by(DIRENT_SIZE) {
+            guard.write(off, |entry: &mut DirEntry| {
+                if entry.inum != 0 {
+                    entry.ftype = b'x';
+                }
+            });
+        }
+        log_write(guard);
+        log_end();
+        assert_eq!(upgrade(dev.clone()), Err(FsError::NameTooLong));
+        assert_eq!(sb().version(), 1);
+    }
+}
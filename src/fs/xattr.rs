@@ -0,0 +1,226 @@
+// extended attributes: name and value pairs of bytes, kept in one block per
+// inode that the xattr map names, so they cost the inode no room.
+// the pairs are packed from the start of the block, each a u8 name length,
+// a u16 value length, the name and the value. a zero name length ends them
+use std::{path::Path, sync::Arc};
+
+use super::{
+    buffer::get_buffer_block,
+    error::FsError,
+    fs::{BlockDevice, BLOCK_SIZE},
+    inode::{block_alloc, resolve, set_xattr_block, xattr_block, xattr_enabled, InodePtr},
+    log::{log_begin, log_end, log_write},
+    superblock::read_only,
+};
+
+// the name length is kept in a byte
+pub const XATTR_NAME_MAX: usize = u8::MAX as usize;
+
+// the lengths before each pair
+const HEADER: usize = 3;
+
+type Attrs = Vec<(String, Vec<u8>)>;
+
+fn parse(block: &[u8; BLOCK_SIZE as usize]) -> Attrs {
+    let mut attrs = vec![];
+    let mut off = 0;
+    while off + HEADER <= block.len() && block[off] != 0 {
+        let nlen = block[off] as usize;
+        let vlen = u16::from_le_bytes([block[off + 1], block[off + 2]]) as usize;
+        let name = off + HEADER;
+        let value = name + nlen;
+        // a pair running past the block is taken as the end
+        if value + vlen > block.len() {
+            break;
+        }
+        attrs.push((
+            String::from_utf8_lossy(&block[name..value]).into_owned(),
+            block[value..value + vlen].to_vec(),
+        ));
+        off = value + vlen;
+    }
+    attrs
+}
+
+fn pack(attrs: &Attrs) -> Result<[u8; BLOCK_SIZE as usize], FsError> {
+    let mut block = [0u8; BLOCK_SIZE as usize];
+    let mut off = 0;
+    for (name, value) in attrs {
+        let end = off + HEADER + name.len() + value.len();
+        if end > block.len() {
+            return Err(FsError::NoSpace);
+        }
+        block[off] = name.len() as u8;
+        block[off + 1..off + HEADER].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        block[off + HEADER..off + HEADER + name.len()].copy_from_slice(name.as_bytes());
+        block[off + HEADER + name.len()..end].copy_from_slice(value);
+        off = end;
+    }
+    Ok(block)
+}
+
+fn read_attrs(dev: Arc<dyn BlockDevice>, ip: &InodePtr) -> Result<Attrs, FsError> {
+    let b = xattr_block(dev.clone(), ip.0.inum);
+    if b == 0 {
+        return Ok(vec![]);
+    }
+    Ok(parse(
+        &get_buffer_block(b, dev)?
+            .read()
+            .unwrap()
+            .read(0, |data: &[u8; BLOCK_SIZE as usize]| *data),
+    ))
+}
+
+// set name to value on the inode path names, replacing the value it had
+pub fn setxattr(
+    dev: Arc<dyn BlockDevice>,
+    path: &Path,
+    name: &str,
+    value: &[u8],
+) -> Result<(), FsError> {
+    if read_only() {
+        return Err(FsError::ReadOnly);
+    }
+    // an image made without the xattr map has nowhere to name the block
+    if !xattr_enabled() {
+        return Err(FsError::NotSupported);
+    }
+    if name.is_empty() || name.contains('\0') {
+        return Err(FsError::InvalidName);
+    }
+    if name.len() > XATTR_NAME_MAX {
+        return Err(FsError::NameTooLong);
+    }
+    log_begin();
+    let ret = resolve(dev.clone(), path).and_then(|ip| {
+        let mut attrs = read_attrs(dev.clone(), &ip)?;
+        match attrs.iter_mut().find(|(n, _)| n == name) {
+            Some((_, v)) => *v = value.to_vec(),
+            None => attrs.push((name.to_string(), value.to_vec())),
+        }
+        let data = pack(&attrs)?;
+        let mut b = xattr_block(dev.clone(), ip.0.inum);
+        if b == 0 {
+            b = block_alloc(dev.clone())?;
+            set_xattr_block(dev.clone(), ip.0.inum, b);
+        }
+        let blk = get_buffer_block(b, dev.clone())?;
+        let mut guard = blk.write().unwrap();
+        guard.write(0, |block: &mut [u8; BLOCK_SIZE as usize]| *block = data);
+        log_write(guard);
+        Ok(())
+    });
+    log_end();
+    ret
+}
+
+// the value of name on the inode path names
+pub fn getxattr(dev: Arc<dyn BlockDevice>, path: &Path, name: &str) -> Result<Vec<u8>, FsError> {
+    log_begin();
+    let ret = resolve(dev.clone(), path).and_then(|ip| {
+        read_attrs(dev.clone(), &ip)?
+            .into_iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value)
+            .ok_or(FsError::NotFound)
+    });
+    log_end();
+    ret
+}
+
+// the names set on the inode path names, in the order they were first set
+pub fn listxattr(dev: Arc<dyn BlockDevice>, path: &Path) -> Result<Vec<String>, FsError> {
+    log_begin();
+    let ret = resolve(dev.clone(), path).and_then(|ip| {
+        Ok(read_attrs(dev.clone(), &ip)?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect())
+    });
+    log_end();
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::fs::{
+        buffer::sync_all,
+        file::{fileclose, fileopen, fileunlink, filewrite_all, OpenMode},
+        fsck::{fsck, rebuild_bitmap},
+        superblock::{sb, write_superblock, RO_COMPAT_XATTR},
+        testutil::TestImage,
+    };
+
+    #[test]
+    fn test_xattr() {
+        let image = TestImage::new("xattr");
+        let dev = image.mount();
+        let path = PathBuf::from("/doc");
+        let file = fileopen(dev.clone(), &path, OpenMode::OCreate).unwrap();
+        filewrite_all(&file, b"hello").unwrap();
+        fileclose(file);
+        assert_eq!(listxattr(dev.clone(), &path), Ok(vec![]));
+        assert_eq!(getxattr(dev.clone(), &path, "mime"), Err(FsError::NotFound));
+        setxattr(dev.clone(), &path, "mime", b"text/plain").unwrap();
+        setxattr(dev.clone(), &path, "tags", b"a,b").unwrap();
+        setxattr(dev.clone(), &path, "mime", b"text/markdown").unwrap();
+        setxattr(dev.clone(), &path, "empty", b"").unwrap();
+        assert_eq!(
+            setxattr(dev.clone(), &path, "", b"x"),
+            Err(FsError::InvalidName)
+        );
+        assert_eq!(
+            setxattr(dev.clone(), &path, "big", &[0; BLOCK_SIZE as usize]),
+            Err(FsError::NoSpace)
+        );
+
+        // read back from the disk
+        sync_all();
+        drop(dev);
+        let dev = image.mount();
+        assert_eq!(
+            listxattr(dev.clone(), &path),
+            Ok(vec![
+                "mime".to_string(),
+                "tags".to_string(),
+                "empty".to_string()
+            ])
+        );
+        assert_eq!(
+            getxattr(dev.clone(), &path, "mime"),
+            Ok(b"text/markdown".to_vec())
+        );
+        assert_eq!(getxattr(dev.clone(), &path, "tags"), Ok(b"a,b".to_vec()));
+        assert_eq!(getxattr(dev.clone(), &path, "empty"), Ok(vec![]));
+        assert_eq!(fsck(dev.clone()), []);
+
+        // the block goes with the inode, the next file in it starts with none
+        fileunlink(dev.clone(), &path).unwrap();
+        let file = fileopen(dev.clone(), &path, OpenMode::OCreate).unwrap();
+        fileclose(file);
+        assert_eq!(listxattr(dev.clone(), &path), Ok(vec![]));
+        sync_all();
+        assert_eq!(rebuild_bitmap(image.disk()), Ok(0));
+    }
+
+    #[test]
+    fn test_xattr_without_map() {
+        let image = TestImage::new("xattr_without_map");
+        let dev = image.mount();
+        let mut old = sb();
+        old.feature_ro_compat &= !RO_COMPAT_XATTR;
+        write_superblock(dev.clone(), &old);
+        let path = PathBuf::from("/doc");
+        let file = fileopen(dev.clone(), &path, OpenMode::OCreate).unwrap();
+        fileclose(file);
+        assert_eq!(
+            setxattr(dev.clone(), &path, "mime", b"text/plain"),
+            Err(FsError::NotSupported)
+        );
+        assert_eq!(listxattr(dev.clone(), &path), Ok(vec![]));
+    }
+}
@@ -1,27 +1,49 @@
+mod bench;
 mod fs;
 mod mkfs;
+mod selftest;
+mod tar;
 
 use clap::{Parser, Subcommand};
-use env_logger::Builder;
+use env_logger::{Builder, Target};
 use fs::{
-    buffer::{sync_all},
-    file::{fileopen, fileread, filewrite, OpenFile, OpenMode, fileseek},
-    filedisk::FileDisk,
+    alloc::{discard_free, free_extents, set_alloc_policy, AllocPolicy},
+    audit::set_audit,
+    buffer::{
+        buffer_misses, set_buffer_retries, set_cache_mode, set_resident_inodes, set_verify_writes,
+        start_writeback, sync_all, CacheMode, Writeback, DEFAULT_BUFFER_RETRIES,
+    },
+    file::{
+        fileopen, fileopen_direct, removal_plan, FDType, FileWriter, OpenFile, OpenMode, DIRECT_MIN,
+    },
+    filedisk::{lock_image, FileDisk},
     fs::BlockDevice,
-    inode::DirEntry,
+    gzdisk::GzRamDisk,
+    inflate::is_gzip,
     log::LOG_MANAGER,
-    superblock::SB,
+    mirrordisk::MirrorDisk,
+    superblock::{init_superblock, mark_in_use, read_only, sb, unmount, FS_VERSION},
+    upgrade::upgrade,
+    xattr::{getxattr, listxattr, setxattr},
 };
 use std::{
     fs::{File, OpenOptions},
-    io::{Read, Write},
-    path::PathBuf,
+    io::{self, BufReader, BufWriter, IsTerminal, Read, Seek, Write},
+    path::{Path, PathBuf},
     sync::Arc,
+    time::{Duration, Instant},
 };
 
 use crate::fs::{
-    file::{fileclose, filestat},
-    fs::FileType,
+    error::FsError,
+    file::{file_read_to_end, fileclose, filedup, filedup2, filehash, filestat, lsof, readdir},
+    fs::{FileType, LOGSIZE, ROOTINO},
+    inode::{
+        canonicalize, entry_ftype, entry_name, fragmentation, get_inode, image_fragmentation,
+        resolve, set_root,
+    },
+    log::{log_inspect, log_stats},
+    sha256::to_hex,
 };
 
 #[derive(Parser, Debug)]
@@ -33,81 +55,231 @@ struct CLI {
     // subcommands
     #[command(subcommand)]
     commands: Commands,
+    // print what mkfs, shrink and rm in the shell would do, and change nothing
+    #[arg(long, global = true)]
+    dry_run: bool,
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
     Mkfs {
+        // the image path, a second one formats a mirror of the image
+        #[arg(long, short, value_name = "IMAGE_PATH", default_value = "./myDisk.img", num_args = 1..=2)]
+        path: Vec<PathBuf>,
+        // image size
+        #[arg(long, short, value_name = "IMAGE_SIZE", default_value = "2097152")]
+        size: u32,
+        // format the image even if another process has it open
+        #[arg(long)]
+        force: bool,
+    },
+    Shell {
+        // the image path, with a second one every block is mirrored to both
+        #[arg(long, short, value_name = "IMAGE_PATH", default_value = "./myDisk.img", num_args = 1..=2)]
+        path: Vec<PathBuf>,
+        // sync blocks dirty for longer than this many milliseconds in the background
+        #[arg(long, value_name = "MILLIS")]
+        writeback_interval: Option<u64>,
+        // the most blocks a second the background writeback syncs
+        #[arg(long, value_name = "BLOCKS")]
+        writeback_rate: Option<u32>,
+        // write-through puts every block modified outside the log on disk at once
+        #[arg(long, value_enum, default_value = "write-back")]
+        cache_mode: CacheMode,
+        // how often a block waits for a buffer in a full cache shard before it fails
+        #[arg(long, value_name = "RETRIES", default_value_t = DEFAULT_BUFFER_RETRIES)]
+        buffer_retries: u32,
+        // keep the inode blocks cached from mount on, as many as half the
+        // metadata buffers hold, so inode lookups do not wait for the disk
+        #[arg(long)]
+        resident_inodes: bool,
+        // read every block back after writing it, failing on a mismatch
+        #[arg(long)]
+        verify_writes: bool,
+        // how free blocks are picked: the lowest ones, or spread over the image
+        #[arg(long, value_enum, default_value = "first-fit")]
+        alloc_policy: AllocPolicy,
+        // log every step of the path lookups to stderr
+        #[arg(long)]
+        trace: bool,
+        // mount the image even if another process has it open
+        #[arg(long)]
+        force: bool,
+        // the directory to show as "/", only the subtree under it is reachable
+        #[arg(long, value_name = "PATH", default_value = "/")]
+        subroot: PathBuf,
+        // append a line for every create, write, rename and unlink to this host file
+        #[arg(long, value_name = "AUDIT_PATH")]
+        audit: Option<PathBuf>,
+        // append every command entered to this host file, for replay
+        #[arg(long, value_name = "SCRIPT_PATH")]
+        record: Option<PathBuf>,
+    },
+    Replay {
+        // the image path
+        #[arg(long, short, value_name = "IMAGE_PATH", default_value = "./myDisk.img")]
+        path: PathBuf,
+        // the commands a shell recorded with --record, run in order
+        #[arg(long, short, value_name = "SCRIPT_PATH")]
+        script: PathBuf,
+    },
+    Fsck {
+        // the image path
+        #[arg(long, short, value_name = "IMAGE_PATH", default_value = "./myDisk.img")]
+        path: PathBuf,
+        // recompute the bitmap from the blocks in use before checking
+        #[arg(long)]
+        rebuild_bitmap: bool,
+        // clear the in-use flag an unclean shutdown left, when nothing else is wrong
+        #[arg(long)]
+        clear_in_use: bool,
+        // threads checking the inode table
+        #[arg(long, default_value_t = 1)]
+        jobs: usize,
+    },
+    Status {
+        // the image path
+        #[arg(long, short, value_name = "IMAGE_PATH", default_value = "./myDisk.img")]
+        path: PathBuf,
+    },
+    Diff {
+        // the older copy of the image
+        #[arg(long, value_name = "IMAGE_PATH")]
+        base: PathBuf,
+        // the image as it is now
+        #[arg(long, value_name = "IMAGE_PATH")]
+        current: PathBuf,
+    },
+    Export {
         // the image path
         #[arg(long, short, value_name = "IMAGE_PATH", default_value = "./myDisk.img")]
         path: PathBuf,
+        // the tar archive to write on the host
+        #[arg(long, short, value_name = "TAR_PATH")]
+        tar: PathBuf,
+    },
+    Import {
+        // the tar archive to read on the host
+        #[arg(long, short, value_name = "TAR_PATH")]
+        tar: PathBuf,
+        // the image to format and fill
+        #[arg(long, short, value_name = "IMAGE_PATH", default_value = "./myDisk.img")]
+        path: PathBuf,
         // image size
         #[arg(long, short, value_name = "IMAGE_SIZE", default_value = "2097152")]
         size: u32,
     },
-    Shell {
+    Shrink {
         // the image path
         #[arg(long, short, value_name = "IMAGE_PATH", default_value = "./myDisk.img")]
         path: PathBuf,
     },
+    Upgrade {
+        // the image path, copied to IMAGE_PATH.bak before it is changed
+        #[arg(long, short, value_name = "IMAGE_PATH", default_value = "./myDisk.img")]
+        path: PathBuf,
+    },
+    Selftest {
+        // a scratch image, formatted for the run and removed after it.
+        // it must not exist yet
+        #[arg(long, short, value_name = "IMAGE_PATH", default_value = "./self.img")]
+        path: PathBuf,
+    },
+    Bench {
+        // the image path, formatted before the run
+        #[arg(long, short, value_name = "IMAGE_PATH", default_value = "./bench.img")]
+        path: PathBuf,
+        // the workload to run
+        #[arg(long, short, value_enum, default_value = "all")]
+        workload: bench::Workload,
+        // how many times each workload is repeated
+        #[arg(long, short, default_value = "8")]
+        rounds: u32,
+    },
+}
+
+// the slot of the shell's file table that output goes to,
+// slot 0 holds the root directory
+const STDOUT_FD: usize = 1;
+
+// why a command did not run: an argument is missing, or the file system refused
+enum CmdError {
+    Usage(&'static str),
+    Fs(FsError),
 }
 
+impl From<FsError> for CmdError {
+    fn from(e: FsError) -> Self {
+        CmdError::Fs(e)
+    }
+}
+
+impl std::fmt::Display for CmdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CmdError::Usage(usage) => write!(f, "usage: {}", usage),
+            CmdError::Fs(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+// the next argument of a command, the usage of the command if there is none
+fn need<'a>(
+    args: &mut std::vec::IntoIter<&'a str>,
+    usage: &'static str,
+) -> Result<&'a str, CmdError> {
+    args.next().ok_or(CmdError::Usage(usage))
+}
+
+// writes into a file in the image, for output redirected there
 struct Shell {
     pub dev: Arc<dyn BlockDevice>,
-    #[allow(unused)]
     pub filetable: Vec<OpenFile>,
     pub cwd: PathBuf,
+    pub writeback: Option<Writeback>,
+    pub dry_run: bool, // rm prints what it would remove
+    // every line entered is appended here, for replay to run again
+    pub record: Option<File>,
 }
 
-fn canonicalize(path: PathBuf) -> PathBuf {
-    // eliminate the . and .. in the path
-    let mut stack = Vec::new();
-    // root
-    stack.push(std::path::Component::RootDir);
-    for component in path.components() {
-        match component {
-            std::path::Component::Normal(_) => stack.push(component),
-            std::path::Component::ParentDir => {
-                stack.pop();
-            }
-            _ => {}
-        }
+impl Shell {
+    pub fn new(image_path: PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::new_at(image_path, Path::new("/"))
     }
-    // if empty, is the root
-    if stack.is_empty() {
-        stack.push(std::path::Component::RootDir);
+
+    // mount with the directory subroot as "/"
+    pub fn new_at(image_path: PathBuf, subroot: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self::mount_at(open_image(&image_path)?, subroot)?)
     }
-    stack.iter().fold(PathBuf::new(), |mut acc, x| {
-        acc.push(x);
-        acc
-    })
-}
 
-impl Shell {
-    pub fn new(image_path: PathBuf) -> Self {
-        Builder::new()
+    // mount the image on filedisk, with subroot as "/"
+    pub fn mount_at(filedisk: Arc<dyn BlockDevice>, subroot: &Path) -> Result<Self, FsError> {
+        let _ = Builder::new()
             .is_test(true)
             .filter_level(log::LevelFilter::Error)
-            .init();
-        let file: File = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(false)
-            .open(image_path)
-            .unwrap();
-        let filedisk = Arc::new(FileDisk::new(file));
-        unsafe { SB.init(filedisk.clone()) };
-        unsafe { LOG_MANAGER.init(&SB, filedisk.clone()) };
+            .try_init();
+        init_superblock(filedisk.clone())?;
+        LOG_MANAGER.init(&sb(), filedisk.clone());
+        set_root(ROOTINO);
+        let sub = resolve(filedisk.clone(), subroot)?;
+        if sub.read_disk_inode(|diskinode| diskinode.ftype) != FileType::Dir as u8 {
+            return Err(FsError::NotDirectory);
+        }
+        set_root(sub.0.inum);
         let root = fileopen(
             filedisk.clone(),
             &PathBuf::from("/".to_string()),
             OpenMode::ORdonly,
         );
-        Self {
+        Ok(Self {
             dev: filedisk,
-            filetable: vec![root.unwrap()],
+            // stdout starts out unopened, which is the terminal
+            filetable: vec![root.unwrap(), OpenFile::default()],
             cwd: PathBuf::from("/".to_string()),
-        }
+            writeback: None,
+            dry_run: false,
+            record: None,
+        })
     }
 
     pub fn repr(&mut self) {
@@ -117,143 +289,452 @@ impl Shell {
             std::io::stdout().flush().unwrap();
             let mut input = String::new();
             std::io::stdin().read_line(&mut input).unwrap();
-            let mut args = input.split_whitespace();
-            // just enter
-            if args.clone().count() == 0 {
-                continue;
+            if !self.run_line(&input) {
+                break;
             }
-            let cmd = args.next().unwrap();
-            // print prompt
-            match cmd {
-                "exit" => {
-                    break;
-                }
-                "ls" => {
-                    let path = match args.next() {
-                        Some(path) => PathBuf::from(self.cwd.clone()).join(path),
-                        None => self.cwd.clone(),
-                    };
-                    self.ls(PathBuf::from(path));
-                }
-                "cat" => {
-                    let arg = args.next().unwrap();
-                    let path = if arg.starts_with("/") {
-                        PathBuf::from(arg)
-                    } else {
-                        canonicalize(PathBuf::from(self.cwd.clone()).join(arg))
-                    };
-                    self.cat(PathBuf::from(path));
-                }
-                "cd" => {
-                    let arg = args.next().unwrap();
-                    let path = if arg.starts_with("/") {
-                        PathBuf::from(arg)
-                    } else {
-                        canonicalize(PathBuf::from(self.cwd.clone()).join(arg))
-                    };
-                    self.cd(PathBuf::from(path));
-                }
-                "write" => {
-                    let from = args.next().unwrap();
-                    let arg = args.next().unwrap();
-                    let to = if arg.starts_with("/") {
-                        PathBuf::from(arg)
-                    } else {
-                        canonicalize(PathBuf::from(self.cwd.clone()).join(arg))
-                    };
-                    self.write(PathBuf::from(from), PathBuf::from(to));
-                }
-                "mkdir" => {
-                    let arg = args.next().unwrap();
-                    let path = if arg.starts_with("/") {
-                        PathBuf::from(arg)
-                    } else {
-                        canonicalize(PathBuf::from(self.cwd.clone()).join(arg))
-                    };
-                    self.mkdir(PathBuf::from(path));
-                }
-                "touch" => {
-                    let arg = args.next().unwrap();
-                    let path = if arg.starts_with("/") {
-                        PathBuf::from(arg)
-                    } else {
-                        canonicalize(PathBuf::from(self.cwd.clone()).join(arg))
-                    };
-                    self.touch(PathBuf::from(path));
-                }
-                "rm" => {
-                    let arg = args.next().unwrap();
-                    let path = if arg.starts_with("/") {
-                        PathBuf::from(arg)
-                    } else {
-                        canonicalize(PathBuf::from(self.cwd.clone()).join(arg))
-                    };
-                    self.rm(PathBuf::from(path));
-                }
-                "test" => {
-                    self.test();
+        }
+        sync_all();
+    }
+
+    // run one line of input, false once it is "exit"
+    fn run_line(&mut self, input: &str) -> bool {
+        // "cmd args > path" sends the output of cmd to path
+        let words = input.split_whitespace().collect::<Vec<_>>();
+        let (words, redirect) = match words.iter().position(|word| *word == ">") {
+            Some(i) if i + 1 < words.len() => (words[..i].to_vec(), Some(words[i + 1])),
+            _ => (words, None),
+        };
+        let mut args = words.into_iter();
+        // just enter
+        if args.clone().count() == 0 {
+            return true;
+        }
+        // written before the command runs, so a command that panics is in the script
+        if let Some(record) = self.record.as_mut() {
+            if let Err(e) = writeln!(record, "{}", input.trim()) {
+                println!("record: {}", e);
+                self.record = None;
+            }
+        }
+        if let Some(arg) = redirect {
+            if let Err(e) = self.abs(arg).and_then(|path| self.redirect(path)) {
+                println!("{}: {}", arg, e);
+                return true;
+            }
+        }
+        let cmd = args.next().unwrap();
+        if cmd == "exit" {
+            return false;
+        }
+        if let Err(e) = self.exec(cmd, &mut args) {
+            println!("{}: {}", cmd, e);
+        }
+        self.restore_stdout();
+        true
+    }
+
+    // run the lines of a script a shell recorded, up to an "exit".
+    // returns the number of lines run
+    fn replay(&mut self, script: &str) -> usize {
+        let mut n = 0;
+        for line in script.lines() {
+            println!("{} $ {}", self.cwd.to_str().unwrap(), line);
+            n += 1;
+            if !self.run_line(line) {
+                break;
+            }
+        }
+        sync_all();
+        n
+    }
+
+    // arg as a canonical path, relative ones are taken from the cwd
+    fn abs(&self, arg: &str) -> Result<PathBuf, FsError> {
+        canonicalize(&self.cwd.join(arg))
+    }
+
+    // run one command, the errors of its arguments are returned
+    fn exec(&mut self, cmd: &str, args: &mut std::vec::IntoIter<&str>) -> Result<(), CmdError> {
+        match cmd {
+            "ls" => {
+                let path = match args.next() {
+                    Some(arg) => self.abs(arg)?,
+                    None => self.cwd.clone(),
+                };
+                self.ls(path);
+            }
+            "lsof" => {
+                self.lsof();
+            }
+            "stats" => {
+                self.stats();
+            }
+            "frag" => {
+                // without a path, the whole image
+                match args.next() {
+                    Some(arg) => {
+                        let path = self.abs(arg)?;
+                        self.frag(path);
+                    }
+                    None => self.frag_image(),
                 }
-                _ => {
-                    println!("command not found: {}", cmd);
+            }
+            "hash" => {
+                let path = self.abs(need(args, "hash PATH")?)?;
+                self.hash(path);
+            }
+            "stat" => {
+                let path = self.abs(need(args, "stat PATH")?)?;
+                self.stat(path);
+            }
+            "cat" => {
+                let path = self.abs(need(args, "cat PATH")?)?;
+                self.cat(path);
+            }
+            "cd" => {
+                let path = self.abs(need(args, "cd PATH")?)?;
+                self.cd(path);
+            }
+            "write" => {
+                let usage = "write HOST_PATH PATH";
+                let from = need(args, usage)?;
+                let to = self.abs(need(args, usage)?)?;
+                self.write(PathBuf::from(from), to);
+            }
+            "mkdir" => {
+                let path = self.abs(need(args, "mkdir PATH")?)?;
+                self.mkdir(path);
+            }
+            "mkfifo" => {
+                let path = self.abs(need(args, "mkfifo PATH")?)?;
+                self.mkfifo(path);
+            }
+            "touch" => {
+                let path = self.abs(need(args, "touch PATH")?)?;
+                self.touch(path);
+            }
+            "reflink" => {
+                let usage = "reflink FROM TO";
+                let from = self.abs(need(args, usage)?)?;
+                let to = self.abs(need(args, usage)?)?;
+                self.reflink(from, to);
+            }
+            "mv" => {
+                let usage = "mv FROM TO";
+                let from = self.abs(need(args, usage)?)?;
+                let to = self.abs(need(args, usage)?)?;
+                self.mv(from, to);
+            }
+            "symlink" => {
+                // the target is stored as given, relative or not
+                let usage = "symlink TARGET PATH";
+                let target = need(args, usage)?.to_string();
+                let path = self.abs(need(args, usage)?)?;
+                self.symlink(target, path);
+            }
+            "setxattr" => {
+                let usage = "setxattr PATH NAME [VALUE]";
+                let path = self.abs(need(args, usage)?)?;
+                let name = need(args, usage)?;
+                let value = args.next().unwrap_or("");
+                self.setxattr(path, name, value);
+            }
+            "getxattr" => {
+                // without a name, the names set on path
+                let path = self.abs(need(args, "getxattr PATH [NAME]")?)?;
+                self.getxattr(path, args.next());
+            }
+            "rm" => {
+                // -r removes a directory with everything under it
+                let usage = "rm [-r] PATH";
+                let mut arg = need(args, usage)?;
+                let recursive = arg == "-r";
+                if recursive {
+                    arg = need(args, usage)?;
                 }
+                let path = self.abs(arg)?;
+                self.rm(path, recursive);
+            }
+            _ => {
+                println!("command not found: {}", cmd);
             }
         }
-        sync_all();
+        Ok(())
+    }
+
+    // send the output of the commands to path until restore_stdout,
+    // path is created or truncated
+    fn redirect(&mut self, path: PathBuf) -> Result<(), FsError> {
+        let file = match fileopen(self.dev.clone(), &path, OpenMode::OCreate) {
+            Err(FsError::AlreadyExists) => fileopen(self.dev.clone(), &path, OpenMode::OTrunc),
+            file => file,
+        }?;
+        filedup2(&file, &mut self.filetable[STDOUT_FD]);
+        fileclose(file);
+        Ok(())
+    }
+
+    fn restore_stdout(&mut self) {
+        filedup2(&OpenFile::default(), &mut self.filetable[STDOUT_FD]);
+    }
+
+    // where the output of a command goes
+    fn stdout(&self) -> Box<dyn Write> {
+        let out = &self.filetable[STDOUT_FD];
+        if out.0.lock().unwrap().ty == FDType::Free {
+            Box::new(std::io::stdout())
+        } else {
+            Box::new(FileWriter::new(filedup(out)))
+        }
     }
 
     fn ls(&self, path: PathBuf) {
-        let fd = fileopen(self.dev.clone(), &path, OpenMode::ORdonly).unwrap();
-        let mut entries = vec![];
+        self.ls_to(path, &mut self.stdout());
+    }
+
+    // print each entry as it is read, nothing is buffered
+    fn ls_to(&self, path: PathBuf, out: &mut dyn Write) {
+        let fd = match fileopen(self.dev.clone(), &path, OpenMode::ODirectory) {
+            Ok(fd) => fd,
+            Err(e) => {
+                let _ = writeln!(out, "ls: {}: {}", path.display(), e);
+                return;
+            }
+        };
         // print header
-        let mut entry = [0u8; std::mem::size_of::<DirEntry>()];
-        while fileread(&fd, &mut entry) > 0 {
-            entries.push(unsafe {
-                std::mem::transmute::<[u8; std::mem::size_of::<DirEntry>()], DirEntry>(entry)
-            });
-        }
-        println!(
+        let _ = writeln!(
+            out,
             "{:<12} {:<12} {:<12} {:<12}",
             "name", "type", "size", "nlink"
         );
 
-        // file open and fstat
-        for entry in entries {
-            if entry.inum == 0 {
-                continue;
-            }
-            let name = std::str::from_utf8(entry.name.as_slice())
-                .unwrap()
-                .trim_matches(char::from(0));
-            // canonicalize the path
-            let fpath = canonicalize(PathBuf::from(path.clone()).join(name));
-            let mut file = fileopen(self.dev.clone(), &fpath, OpenMode::ORdonly).unwrap();
-            let stat = filestat(&mut file);
+        // the type is in the dirent, size and nlink come from the inode by
+        // its number, no path is looked up. a symlink is listed itself, like lstat
+        for entry in readdir(&fd) {
+            let name = entry_name(&entry);
+            let dinode = get_inode(self.dev.clone(), entry.inum).read_disk_inode(|d| *d);
+            // an image made before the dirents kept it
+            let ftype = match entry_ftype(&entry) {
+                0 => dinode.ftype,
+                ftype => ftype,
+            };
             // print
-            println!(
+            let _ = writeln!(
+                out,
                 "{:<12} {:<12} {:<12} {:<12}",
                 name,
-                match stat.ty {
-                    FileType::Free => "free",
-                    FileType::File => "file",
-                    FileType::Dir => "dir",
+                match FileType::from_u8(ftype) {
+                    Some(FileType::Free) | None => "free",
+                    Some(FileType::File) => "file",
+                    Some(FileType::Dir) => "dir",
+                    Some(FileType::Device) => "dev",
+                    Some(FileType::Fifo) => "fifo",
+                    Some(FileType::Symlink) => "link",
                 },
-                stat.size,
-                stat.nlink
+                dinode.size,
+                dinode.nlink
             );
         }
         fileclose(fd);
     }
 
+    // the completions of a partial path argument: the names in its directory
+    // that start with its last component, a directory with "/" after it.
+    // what was typed up to the last component is kept as it was typed
+    #[allow(unused)]
+    fn complete(&self, partial: &str) -> Vec<String> {
+        let (typed_dir, prefix) = match partial.rfind('/') {
+            Some(i) => partial.split_at(i + 1),
+            None => ("", partial),
+        };
+        let fd = match self
+            .abs(typed_dir)
+            .and_then(|dir| fileopen(self.dev.clone(), &dir, OpenMode::ODirectory))
+        {
+            Ok(fd) => fd,
+            Err(_) => return vec![],
+        };
+        let mut completions = vec![];
+        for entry in readdir(&fd) {
+            let name = entry_name(&entry);
+            if name == "." || name == ".." || !name.starts_with(prefix) {
+                continue;
+            }
+            // an image made before the dirents kept the type
+            let ftype = match entry_ftype(&entry) {
+                0 => get_inode(self.dev.clone(), entry.inum).read_disk_inode(|d| d.ftype),
+                ftype => ftype,
+            };
+            let mut completion = format!("{}{}", typed_dir, name);
+            if ftype == FileType::Dir as u8 {
+                completion.push('/');
+            }
+            completions.push(completion);
+        }
+        fileclose(fd);
+        completions.sort();
+        completions
+    }
+
     fn cat(&self, path: PathBuf) {
-        let mut fd = fileopen(self.dev.clone(), &path, OpenMode::ORdonly).unwrap();
-        let mut dst = vec![0; 1024];
-        while fileread(&mut fd, &mut dst) > 0 {
-            print!("{}", String::from_utf8(dst.clone()).unwrap());
-            dst.fill(0);
+        self.cat_to(path, &mut self.stdout());
+    }
+
+    fn cat_to(&self, path: PathBuf, out: &mut dyn Write) {
+        let fd = match fileopen(self.dev.clone(), &path, OpenMode::ORdonly) {
+            Ok(fd) => fd,
+            Err(e) => {
+                let _ = writeln!(out, "cat: {}: {}", path.display(), e);
+                return;
+            }
+        };
+        // the raw dirents are no use to print
+        if filestat(&fd).is_ok_and(|st| st.ty == FileType::Dir) {
+            let _ = writeln!(out, "cat: {}: Is a directory", path.display());
+        } else {
+            match file_read_to_end(&fd) {
+                Ok(data) => {
+                    let _ = write!(out, "{}", String::from_utf8_lossy(&data));
+                }
+                Err(e) => {
+                    let _ = writeln!(out, "cat: {}: {}", path.display(), e);
+                }
+            }
         }
         fileclose(fd);
     }
 
+    fn hash(&self, path: PathBuf) {
+        self.hash_to(path, &mut self.stdout());
+    }
+
+    fn hash_to(&self, path: PathBuf, out: &mut dyn Write) {
+        let hash = fileopen(self.dev.clone(), &path, OpenMode::ORdonly).and_then(|fd| {
+            let hash = filehash(&fd);
+            fileclose(fd);
+            hash
+        });
+        let _ = match hash {
+            Ok(hash) => writeln!(out, "{}  {}", to_hex(&hash), path.display()),
+            Err(e) => writeln!(out, "hash: {}: {}", path.display(), e),
+        };
+    }
+
+    fn stat(&self, path: PathBuf) {
+        self.stat_to(path, &mut self.stdout());
+    }
+
+    fn stat_to(&self, path: PathBuf, out: &mut dyn Write) {
+        let fd = match fileopen(self.dev.clone(), &path, OpenMode::ORdonly) {
+            Ok(fd) => fd,
+            Err(e) => {
+                let _ = writeln!(out, "stat: {}: {}", path.display(), e);
+                return;
+            }
+        };
+        let st = filestat(&fd);
+        fileclose(fd);
+        let st = match st {
+            Ok(st) => st,
+            Err(e) => {
+                let _ = writeln!(out, "stat: {}: {}", path.display(), e);
+                return;
+            }
+        };
+        let _ = writeln!(out, "  File: {}", path.display());
+        let _ = writeln!(out, "  Type: {}  Size: {}", st.ty, st.size);
+        let _ = writeln!(
+            out,
+            "Device: {}  Inode: {}  Generation: {}  Links: {}",
+            st.dev, st.ino, st.generation, st.nlink
+        );
+        if st.ty == FileType::Device {
+            let _ = writeln!(out, "Major: {}  Minor: {}", st.major, st.minor);
+        }
+    }
+
+    fn frag(&self, path: PathBuf) {
+        self.frag_to(path, &mut self.stdout());
+    }
+
+    fn frag_to(&self, path: PathBuf, out: &mut dyn Write) {
+        let frag = resolve(self.dev.clone(), &path)
+            .and_then(|ip| ip.read_disk_inode(|dinode| fragmentation(dinode, self.dev.clone())));
+        let _ = match frag {
+            Ok(frag) => {
+                writeln!(
+                    out,
+                    "{}: {} blocks in {} runs, fragmentation {:.2}",
+                    path.display(),
+                    frag.blocks,
+                    frag.runs,
+                    frag.score()
+                )
+            }
+            Err(e) => writeln!(out, "frag: {}: {}", path.display(), e),
+        };
+    }
+
+    fn frag_image(&self) {
+        self.frag_image_to(&mut self.stdout());
+    }
+
+    fn frag_image_to(&self, out: &mut dyn Write) {
+        let _ = match image_fragmentation(self.dev.clone()) {
+            Ok(frag) => writeln!(
+                out,
+                "{} files, {} blocks in {} runs, fragmentation {:.2}",
+                frag.files,
+                frag.blocks,
+                frag.runs,
+                frag.score()
+            ),
+            Err(e) => writeln!(out, "frag: {}", e),
+        };
+    }
+
+    fn lsof(&self) {
+        self.lsof_to(&mut self.stdout());
+    }
+
+    fn lsof_to(&self, out: &mut dyn Write) {
+        let _ = writeln!(
+            out,
+            "{:<24} {:<6} {:<12} {:<6}",
+            "path", "mode", "offset", "refs"
+        );
+        for info in lsof() {
+            let _ = writeln!(
+                out,
+                "{:<24} {:<6} {:<12} {:<6}",
+                info.path.display(),
+                info.mode(),
+                info.offset,
+                info.refs
+            );
+        }
+    }
+
+    fn stats(&self) {
+        self.stats_to(&mut self.stdout());
+    }
+
+    fn stats_to(&self, out: &mut dyn Write) {
+        let log = log_stats();
+        let _ = writeln!(out, "log transactions committed: {}", log.committed);
+        let _ = writeln!(out, "log blocks logged: {}", log.blocks_logged);
+        let _ = writeln!(
+            out,
+            "log most blocks in a commit: {}/{}",
+            log.max_logged, LOGSIZE
+        );
+        let _ = writeln!(out, "log_begin sleeps: {}", log.begin_sleeps);
+        let _ = writeln!(out, "buffer misses: {}", buffer_misses());
+        for (thread, ms) in log_inspect() {
+            let _ = writeln!(out, "log transaction open: {:?} for {} ms", thread, ms);
+        }
+    }
+
     fn cd(&mut self, path: PathBuf) {
         // iter and change cwd
         let mut path = path;
@@ -273,19 +754,77 @@ impl Shell {
     }
 
     fn write(&mut self, from: PathBuf, to: PathBuf) {
+        self.write_with_progress(from, to, &mut std::io::stdout());
+    }
+
+    // copy from into to, printing the bytes done, the total and the throughput
+    // on one line that each chunk overwrites
+    fn write_with_progress(&mut self, from: PathBuf, to: PathBuf, out: &mut dyn Write) {
         // from is the true file system
         // to is the virtual file system
-        let mut from = std::fs::File::open(from).unwrap();
+        let (mut from, total) = match std::fs::File::open(&from)
+            .and_then(|file| file.metadata().map(|meta| (file, meta.len())))
+        {
+            Ok(from) => from,
+            Err(e) => {
+                let _ = writeln!(out, "write: {}: {}", from.display(), e);
+                return;
+            }
+        };
         let mut dst = vec![0; 1024];
-        let mut to = fileopen(self.dev.clone(), &to, OpenMode::OWronly).unwrap();
+        // a large file is copied around the buffer cache, so it does not evict the working set
+        let open = if total >= DIRECT_MIN {
+            fileopen_direct
+        } else {
+            fileopen
+        };
+        let mut to = match open(self.dev.clone(), &to, OpenMode::OWronly) {
+            Ok(file) => FileWriter::new(file),
+            Err(e) => {
+                let _ = writeln!(out, "write: {}: {}", to.display(), e);
+                return;
+            }
+        };
+        let start = Instant::now();
+        let mut done = 0;
+        let mut written = Ok(());
         loop {
-            let n = from.read(&mut dst).unwrap();
-            filewrite(&mut to, &dst[0..n]);
-            if n < 1024 {
+            // a host path that is a directory opens, and only fails here
+            let n = match from.read(&mut dst) {
+                Ok(n) => n,
+                Err(e) => {
+                    written = Err(e);
+                    break;
+                }
+            };
+            if n == 0 {
+                break;
+            }
+            written = to.write_all(&dst[0..n]);
+            if written.is_err() {
                 break;
             }
+            done += n as u64;
+            let secs = start.elapsed().as_secs_f64().max(f64::EPSILON);
+            let _ = write!(
+                out,
+                "\r{}/{} bytes ({}%) {:.2} MB/s",
+                done,
+                total,
+                done * 100 / total.max(1),
+                done as f64 / secs / (1024.0 * 1024.0)
+            );
+            let _ = out.flush();
+        }
+        if total == 0 {
+            let _ = write!(out, "\r0/0 bytes (100%)");
         }
-        fileclose(to);
+        let _ = writeln!(out);
+        // the flush writes the tail of the last block
+        if let Err(e) = written.and_then(|_| to.flush()) {
+            let _ = writeln!(out, "write: {}", e);
+        }
+        fileclose(to.into_inner());
     }
 
     fn mkdir(&mut self, path: PathBuf) {
@@ -297,6 +836,12 @@ impl Shell {
         }
     }
 
+    fn mkfifo(&mut self, path: PathBuf) {
+        if let Err(e) = fs::file::mkfifo(self.dev.clone(), &path) {
+            println!("mkfifo: {}", e);
+        }
+    }
+
     fn touch(&mut self, path: PathBuf) {
         match fs::file::fileopen(self.dev.clone(), &path, OpenMode::OCreate) {
             Ok(_) => {}
@@ -306,23 +851,144 @@ impl Shell {
         }
     }
 
-    fn rm(&mut self, path: PathBuf) {
-        // check not dir 
-        fs::file::fileunlink(self.dev.clone(), &path).unwrap();
+    fn reflink(&mut self, from: PathBuf, to: PathBuf) {
+        match fs::file::filereflink(self.dev.clone(), &from, &to) {
+            Ok(_) => {}
+            Err(e) => {
+                println!("reflink: {}", e);
+            }
+        }
+    }
+
+    fn mv(&mut self, from: PathBuf, to: PathBuf) {
+        if let Err(e) = fs::file::filerename(self.dev.clone(), &from, &to) {
+            println!("mv: {}", e);
+        }
+    }
+
+    fn setxattr(&mut self, path: PathBuf, name: &str, value: &str) {
+        if let Err(e) = setxattr(self.dev.clone(), &path, name, value.as_bytes()) {
+            println!("setxattr: {}: {}", path.display(), e);
+        }
+    }
+
+    fn getxattr(&self, path: PathBuf, name: Option<&str>) {
+        self.getxattr_to(path, name, &mut self.stdout());
     }
 
-    fn test(&mut self) {
-        self.mkdir("/test".to_string().into());
-        self.touch("/test/jerry".to_string().into());
-        let mut file = fileopen(self.dev.clone(), &"/test/jerry".to_string().into(), OpenMode::OWronly).unwrap();
-        // 800 random bytes
-        let mut buf = [0; 800].map(|_| rand::random::<u8>());
-        filewrite(&mut file, &buf);
-        fileseek(&mut file, 500, 0).unwrap();
-        let n = fileread(&mut file, &mut buf);
-        filewrite(&mut file, &buf[0..n]);
-        self.ls("/test".to_string().into());
+    fn getxattr_to(&self, path: PathBuf, name: Option<&str>, out: &mut dyn Write) {
+        let ret = match name {
+            Some(name) => getxattr(self.dev.clone(), &path, name)
+                .map(|value| vec![String::from_utf8_lossy(&value).into_owned()]),
+            None => listxattr(self.dev.clone(), &path),
+        };
+        match ret {
+            Ok(lines) => lines.iter().for_each(|line| {
+                let _ = writeln!(out, "{}", line);
+            }),
+            Err(e) => {
+                let _ = writeln!(out, "getxattr: {}: {}", path.display(), e);
+            }
+        }
     }
+
+    fn symlink(&mut self, target: String, path: PathBuf) {
+        if let Err(e) = fs::file::symlink(self.dev.clone(), &target, &path) {
+            println!("symlink: {}", e);
+        }
+    }
+
+    fn rm(&mut self, path: PathBuf, recursive: bool) {
+        self.rm_to(path, recursive, &mut self.stdout());
+    }
+
+    // the whole tree is looked at before anything is unlinked, a dry run stops there
+    fn rm_to(&mut self, path: PathBuf, recursive: bool, out: &mut dyn Write) {
+        let plan = match removal_plan(self.dev.clone(), &path, recursive) {
+            Ok(plan) => plan,
+            Err(e) => {
+                let _ = writeln!(out, "rm: {}: {}", path.display(), e);
+                return;
+            }
+        };
+        for removal in plan {
+            if self.dry_run {
+                let _ = match removal.freed {
+                    Some(n) => writeln!(
+                        out,
+                        "rm: would remove {} (inode {}, {} blocks freed)",
+                        removal.path.display(),
+                        removal.inum,
+                        n
+                    ),
+                    None => writeln!(
+                        out,
+                        "rm: would remove {} (inode {} still linked)",
+                        removal.path.display(),
+                        removal.inum
+                    ),
+                };
+            } else if let Err(e) = fs::file::fileunlink(self.dev.clone(), &removal.path) {
+                let _ = writeln!(out, "rm: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+// the image at image_path as a device, a gzip archive of an image is mounted read-only
+// the errors name the image, a command prints them after its own name
+fn open_image(image_path: &Path) -> io::Result<Arc<dyn BlockDevice>> {
+    let named = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", image_path.display(), e));
+    let mut file = File::open(image_path).map_err(named)?;
+    let mut magic = [0u8; 2];
+    let gzip = file.read_exact(&mut magic).is_ok() && is_gzip(&magic);
+    if gzip {
+        file.rewind().map_err(named)?;
+        return Ok(Arc::new(GzRamDisk::new(file).map_err(named)?));
+    }
+    let file: File = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(false)
+        .open(image_path)
+        .map_err(named)?;
+    Ok(Arc::new(FileDisk::new(file)))
+}
+
+// open an image file for a command that reads it as it is, or exit
+fn open_or_exit(cmd: &str, path: &Path, write: bool) -> File {
+    match OpenOptions::new().read(true).write(write).open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("{}: {}: {}", cmd, path.display(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// on SIGINT, SIGTERM or SIGHUP unmount dev and exit, instead of leaving the
+// image in use. the signals are blocked before any other thread starts, so
+// they all reach the one waiting here
+fn unmount_on_signal(dev: Arc<dyn BlockDevice>) {
+    let set = unsafe {
+        let mut set = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for sig in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            libc::sigaddset(&mut set, sig);
+        }
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+        set
+    };
+    std::thread::spawn(move || {
+        let mut sig = 0;
+        if unsafe { libc::sigwait(&set, &mut sig) } != 0 {
+            return;
+        }
+        eprintln!("shell: signal {}, unmounting", sig);
+        unmount(dev);
+        std::process::exit(128 + sig);
+    });
 }
 
 fn main() {
@@ -331,53 +997,450 @@ fn main() {
     // set log level
     builder.filter_level(log::LevelFilter::Info);
     let cli = CLI::parse();
+    let dry_run = cli.dry_run;
     // match subcommands
     match cli.commands {
-        Commands::Mkfs { path, size } => {
-            // just print and raise not implementd
-            println!("mkfs: path: {:?}, size: {}", path, size);
-            mkfs::mkfs(path, size * 1024);
+        Commands::Mkfs { path, size, force } => {
+            builder.target(Target::Stdout).is_test(true).init();
+            if dry_run {
+                let sb = mkfs::layout(size * 1024);
+                for path in path {
+                    println!(
+                        "mkfs: would format {:?}: {} blocks, {} inodes, {} data blocks",
+                        path, sb.size, sb.ninodes, sb.nblocks
+                    );
+                }
+                return;
+            }
+            // hold the locks while formatting, so nobody mounts a half written image
+            let _locks = path
+                .iter()
+                .filter(|path| path.exists() && !force)
+                .map(|path| match lock_image(path) {
+                    Ok(lock) => lock,
+                    Err(e) => {
+                        eprintln!("mkfs: {}", e);
+                        std::process::exit(1);
+                    }
+                })
+                .collect::<Vec<_>>();
+            // mkfs writes the same image every time, so a mirror starts out in sync
+            for path in path {
+                println!("mkfs: path: {:?}, size: {}", path, size);
+                mkfs::mkfs(path, size * 1024);
+            }
+        }
+        Commands::Shell {
+            path,
+            writeback_interval,
+            writeback_rate,
+            cache_mode,
+            buffer_retries,
+            resident_inodes,
+            verify_writes,
+            alloc_policy,
+            trace,
+            force,
+            subroot,
+            audit,
+            record,
+        } => {
+            if let Err(e) = set_audit(audit.as_deref()) {
+                eprintln!("shell: {}: {}", audit.unwrap().display(), e);
+                std::process::exit(1);
+            }
+            let record = match &record {
+                Some(path) => match OpenOptions::new().create(true).append(true).open(path) {
+                    Ok(file) => Some(file),
+                    Err(e) => {
+                        eprintln!("shell: {}: {}", path.display(), e);
+                        std::process::exit(1);
+                    }
+                },
+                None => None,
+            };
+            set_cache_mode(cache_mode);
+            set_buffer_retries(buffer_retries);
+            set_resident_inodes(resident_inodes);
+            set_verify_writes(verify_writes);
+            set_alloc_policy(alloc_policy);
+            if trace {
+                builder
+                    .filter_level(log::LevelFilter::Error)
+                    .filter(Some("namei"), log::LevelFilter::Trace)
+                    .init();
+            }
+            let _locks = path
+                .iter()
+                .filter(|_| !force)
+                .map(|path| match lock_image(path) {
+                    Ok(lock) => lock,
+                    Err(e) => {
+                        eprintln!("shell: {}", e);
+                        std::process::exit(1);
+                    }
+                })
+                .collect::<Vec<_>>();
+            let devs = path
+                .iter()
+                .map(|path| open_image(path))
+                .collect::<io::Result<Vec<_>>>();
+            let mut devs = match devs {
+                Ok(devs) => devs,
+                Err(e) => {
+                    eprintln!("shell: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let dev = match devs.len() {
+                1 => devs.remove(0),
+                _ => Arc::new(MirrorDisk::new(devs.remove(0), devs.remove(0))),
+            };
+            let mut shell = match Shell::mount_at(dev, &subroot) {
+                Ok(shell) => shell,
+                Err(e) => {
+                    eprintln!("shell: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            // nothing is written to a read-only image, so it cannot be left dirty
+            if !read_only() {
+                mark_in_use(shell.dev.clone(), true);
+            }
+            unmount_on_signal(shell.dev.clone());
+            shell.dry_run = dry_run;
+            shell.writeback = writeback_interval.map(|ms| {
+                let interval = Duration::from_millis(ms);
+                start_writeback(interval, interval, writeback_rate)
+            });
+            shell.record = record;
+            shell.repr();
+            shell.writeback = None;
+            unmount(shell.dev.clone());
+        }
+        Commands::Replay { path, script } => {
+            let script = match std::fs::read_to_string(&script) {
+                Ok(script) => script,
+                Err(e) => {
+                    eprintln!("replay: {}: {}", script.display(), e);
+                    std::process::exit(1);
+                }
+            };
+            let _lock = match lock_image(&path) {
+                Ok(lock) => lock,
+                Err(e) => {
+                    eprintln!("replay: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let mut shell = match Shell::new(path) {
+                Ok(shell) => shell,
+                Err(e) => {
+                    eprintln!("replay: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            if !read_only() {
+                mark_in_use(shell.dev.clone(), true);
+            }
+            unmount_on_signal(shell.dev.clone());
+            shell.dry_run = dry_run;
+            let n = shell.replay(&script);
+            println!("replay: {} commands run", n);
+            unmount(shell.dev.clone());
+        }
+        Commands::Fsck {
+            path,
+            rebuild_bitmap,
+            clear_in_use,
+            jobs,
+        } => {
+            // the flag is only cleared when no shell has the image mounted
+            let _lock = if clear_in_use {
+                match lock_image(&path) {
+                    Ok(lock) => Some(lock),
+                    Err(e) => {
+                        eprintln!("fsck: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            } else {
+                None
+            };
+            let file = open_or_exit("fsck", &path, true);
+            let dev: Arc<dyn BlockDevice> = Arc::new(FileDisk::new(file));
+            if rebuild_bitmap {
+                match fs::fsck::rebuild_bitmap(dev.clone()) {
+                    Ok(changed) => println!("fsck: bitmap rebuilt, {} bits changed", changed),
+                    Err(problem) => {
+                        println!("fsck: cannot rebuild the bitmap: {}", problem);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            // the phase and its percentage, rewritten in place on a terminal
+            let tty = std::io::stderr().is_terminal();
+            let progress = |phase: fs::fsck::Phase, done: u32, total: u32| {
+                if tty {
+                    eprint!("\rfsck: {} {}%", phase, done * 100 / total.max(1));
+                    if done == total {
+                        eprintln!();
+                    }
+                }
+            };
+            let mut problems = fs::fsck::fsck_progress(dev.clone(), jobs, &progress);
+            if clear_in_use && problems == [fs::fsck::Problem::InUse] {
+                fs::fsck::clear_in_use(dev).unwrap();
+                println!("fsck: in-use flag cleared");
+                problems.clear();
+            }
+            for problem in problems.iter() {
+                println!("fsck: {}", problem);
+            }
+            if !problems.is_empty() {
+                std::process::exit(1);
+            }
+            println!("fsck: clean");
+        }
+        Commands::Status { path } => {
+            let file = open_or_exit("status", &path, false);
+            let dev: Arc<dyn BlockDevice> = Arc::new(FileDisk::new(file));
+            match fs::fsck::status(dev) {
+                Ok(status) => {
+                    if status.in_use {
+                        println!("status: in use or not unmounted cleanly");
+                    }
+                    if status.logged > 0 {
+                        println!(
+                            "status: {} blocks in the log, installed at the next mount",
+                            status.logged
+                        );
+                    }
+                    if !status.clean() {
+                        println!("status: dirty, running fsck is advisable");
+                        std::process::exit(1);
+                    }
+                    println!("status: clean");
+                }
+                Err(problem) => {
+                    println!("status: {}", problem);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Diff { base, current } => {
+            let open = |path: PathBuf| -> Arc<dyn BlockDevice> {
+                Arc::new(FileDisk::new(open_or_exit("diff", &path, false)))
+            };
+            let diff = fs::diff::diff(open(base), open(current));
+            for block in diff.blocks {
+                println!("block {}", block);
+            }
+            for inum in diff.inodes {
+                println!("inode {}", inum);
+            }
+        }
+        Commands::Export { path, tar } => {
+            let _lock = match lock_image(&path) {
+                Ok(lock) => lock,
+                Err(e) => {
+                    eprintln!("export: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let shell = match Shell::new(path) {
+                Ok(shell) => shell,
+                Err(e) => {
+                    eprintln!("export: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let mut out = BufWriter::new(File::create(&tar).unwrap());
+            if let Err(e) = tar::export(shell.dev.clone(), Path::new("/"), &mut out) {
+                eprintln!("export: {}: {}", tar.display(), e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Import { tar, path, size } => {
+            let mut archive = match File::open(&tar) {
+                Ok(file) => BufReader::new(file),
+                Err(e) => {
+                    eprintln!("import: {}: {}", tar.display(), e);
+                    std::process::exit(1);
+                }
+            };
+            // nobody may have the image open while it is formatted
+            let _lock = if path.exists() {
+                match lock_image(&path) {
+                    Ok(lock) => Some(lock),
+                    Err(e) => {
+                        eprintln!("import: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            } else {
+                None
+            };
+            mkfs::mkfs(path.clone(), size * 1024);
+            let shell = match Shell::new(path) {
+                Ok(shell) => shell,
+                Err(e) => {
+                    eprintln!("import: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let ret = tar::import(shell.dev.clone(), Path::new("/"), &mut archive);
+            sync_all();
+            match ret {
+                Ok(count) => println!("import: {} entries", count),
+                Err(e) => {
+                    eprintln!("import: {}: {}", tar.display(), e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Shrink { path } => {
+            let _lock = match lock_image(&path) {
+                Ok(lock) => lock,
+                Err(e) => {
+                    eprintln!("shrink: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let shell = match Shell::new(path) {
+                Ok(shell) => shell,
+                Err(e) => {
+                    eprintln!("shrink: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            if read_only() {
+                eprintln!("shrink: {}", FsError::ReadOnly);
+                std::process::exit(1);
+            }
+            // the replayed log is on disk before the blocks it freed are dropped
+            sync_all();
+            if dry_run {
+                let runs = free_extents(shell.dev.clone());
+                for (start, n) in runs.iter() {
+                    println!("shrink: would discard blocks {}..{}", start, start + n);
+                }
+                let n = runs.iter().map(|(_, n)| n).sum::<u32>();
+                println!("shrink: {} free blocks would be discarded", n);
+                return;
+            }
+            let n = discard_free(shell.dev.clone());
+            shell.dev.flush();
+            println!("shrink: {} free blocks discarded", n);
+        }
+        Commands::Upgrade { path } => {
+            let _lock = match lock_image(&path) {
+                Ok(lock) => lock,
+                Err(e) => {
+                    eprintln!("upgrade: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let shell = match Shell::new(path.clone()) {
+                Ok(shell) => shell,
+                Err(e) => {
+                    eprintln!("upgrade: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            if sb().version() >= FS_VERSION {
+                println!("upgrade: already version {}", sb().version());
+                return;
+            }
+            // an earlier backup is left alone, it may be the only good copy
+            let mut backup = path.clone().into_os_string();
+            backup.push(".bak");
+            let backup = PathBuf::from(backup);
+            if backup.exists() {
+                eprintln!("upgrade: {} exists", backup.display());
+                std::process::exit(1);
+            }
+            // the image as mounted, with the log replayed
+            sync_all();
+            if let Err(e) = std::fs::copy(&path, &backup) {
+                eprintln!("upgrade: {}: {}", backup.display(), e);
+                std::process::exit(1);
+            }
+            match upgrade(shell.dev.clone()) {
+                Ok(from) => println!(
+                    "upgrade: version {} to {}, the old image is in {}",
+                    from,
+                    FS_VERSION,
+                    backup.display()
+                ),
+                Err(e) => {
+                    eprintln!("upgrade: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Selftest { path } => {
+            if !selftest::selftest(path) {
+                std::process::exit(1);
+            }
+        }
+        Commands::Bench {
+            path,
+            workload,
+            rounds,
+        } => {
+            for result in bench::bench(path, workload, rounds) {
+                println!("{}", result);
+            }
         }
-        Commands::Shell { path } => Shell::new(path).repr(),
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::canonicalize;
+    use std::path::PathBuf;
+
+    use crate::fs::{
+        buffer::sync_all,
+        error::FsError,
+        file::{file_read_to_end, fileclose, filelink, fileopen, filewrite, lsof, mkdir, OpenMode},
+        fsck::fsck,
+        inode::{canonicalize, find_inode},
+        testutil::{TestImage, TEST_IMAGE_SIZE},
+    };
 
     #[test]
     fn test_canonicalize() {
         let path = std::path::PathBuf::from("/usr/bin/../bin/./ls");
         assert_eq!(
-            canonicalize(path.clone()),
-            std::path::PathBuf::from("/usr/bin/ls")
+            canonicalize(&path),
+            Ok(std::path::PathBuf::from("/usr/bin/ls"))
         );
-        println!("{:?}", canonicalize(path));
+        println!("{:?}", canonicalize(&path));
     }
 
     #[test]
     fn test_ls() {
-        let shell = super::Shell::new(std::path::PathBuf::from("./test.img"));
+        let shell = super::Shell::new(std::path::PathBuf::from("./test.img")).unwrap();
         shell.ls(std::path::PathBuf::from("/"));
     }
 
     #[test]
     fn test_cat() {
-        let shell = super::Shell::new(std::path::PathBuf::from("./test.img"));
+        let shell = super::Shell::new(std::path::PathBuf::from("./test.img")).unwrap();
         shell.cat(std::path::PathBuf::from("/test"));
     }
 
     #[test]
     fn test_touch() {
-        let mut shell = super::Shell::new(std::path::PathBuf::from("./test.img"));
+        let mut shell = super::Shell::new(std::path::PathBuf::from("./test.img")).unwrap();
         shell.touch(std::path::PathBuf::from("/test"));
         shell.ls(std::path::PathBuf::from("/"));
     }
 
     #[test]
     fn test_mkdirs() {
-        let mut shell = super::Shell::new(std::path::PathBuf::from("./test.img"));
+        let mut shell = super::Shell::new(std::path::PathBuf::from("./test.img")).unwrap();
         shell.mkdir(std::path::PathBuf::from("/bin"));
         shell.mkdir(std::path::PathBuf::from("/etc"));
         shell.mkdir(std::path::PathBuf::from("/home"));
@@ -403,14 +1466,412 @@ mod test {
         );
         shell.ls(std::path::PathBuf::from("/home/photos"));
         println!("");
-        shell.rm(std::path::PathBuf::from("/home/photos/nishino.jpg"));
+        shell.rm(std::path::PathBuf::from("/home/photos/nishino.jpg"), false);
         shell.ls(std::path::PathBuf::from("/home/photos"));
         println!("");
     }
 
     #[test]
-    fn test_test() {
-        let mut shell = super::Shell::new(std::path::PathBuf::from("./test.img"));
-        shell.test();
+    fn test_complete() {
+        let image = TestImage::new("shell_complete");
+        let mut shell = super::Shell::new(image.path.clone()).unwrap();
+        shell.mkdir(PathBuf::from("/home"));
+        shell.mkdir(PathBuf::from("/home/texts"));
+        shell.touch(PathBuf::from("/home/todo"));
+        assert_eq!(shell.complete("/ho"), ["/home/"]);
+        shell.touch(PathBuf::from("/hold"));
+        assert_eq!(shell.complete("/ho"), ["/hold", "/home/"]);
+        assert_eq!(shell.complete("/home/"), ["/home/texts/", "/home/todo"]);
+        assert_eq!(
+            shell.complete("/home/./t"),
+            ["/home/./texts/", "/home/./todo"]
+        );
+        assert_eq!(shell.complete("/x"), Vec::<String>::new());
+        // relative to the cwd, through ".." too
+        shell.cd(PathBuf::from("/home"));
+        assert_eq!(shell.complete("te"), ["texts/"]);
+        assert_eq!(shell.complete("texts/../to"), ["texts/../todo"]);
+        assert_eq!(shell.complete("../hol"), ["../hold"]);
+        // a directory that is not there, or is a file
+        assert_eq!(shell.complete("/nope/a"), Vec::<String>::new());
+        assert_eq!(shell.complete("todo/a"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_non_utf8_name() {
+        use crate::fs::{
+            buffer::wait_buffer_block, fs::ROOTINO, inode::get_inode, inode::DirEntry,
+        };
+        let image = TestImage::new("shell_non_utf8");
+        let mut shell = super::Shell::new(image.path.clone()).unwrap();
+        shell.touch(PathBuf::from("/hold"));
+        // a name left by another tool, not valid UTF-8
+        let block = get_inode(shell.dev.clone(), ROOTINO).read_disk_inode(|d| d.addrs[0]);
+        let buffer = wait_buffer_block(block, shell.dev.clone());
+        let mut guard = buffer.write().unwrap();
+        let off = (0..512)
+            .step_by(std::mem::size_of::<DirEntry>())
+            .find(|&off| guard.read(off, |entry: &DirEntry| entry.name.starts_with(b"hold")))
+            .unwrap();
+        guard.sync_write(off, |entry: &mut DirEntry| entry.name[1] = 0xff);
+        drop(guard);
+        let mut out = vec![];
+        shell.ls_to(PathBuf::from("/"), &mut out);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("h\u{fffd}ld"), "{}", out);
+        assert_eq!(shell.complete("/h"), ["/h\u{fffd}ld"]);
+    }
+
+    #[test]
+    fn test_open_missing_image() {
+        let path = std::env::temp_dir().join("fatpigeorz_missing.img");
+        let e = super::open_image(&path).err().unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+        assert!(e.to_string().starts_with(&format!("{}: ", path.display())));
+        assert!(super::Shell::new(path).is_err());
+    }
+
+    #[test]
+    fn test_touch_missing_parent() {
+        let image = TestImage::new("touch_missing_parent");
+        let mut shell = super::Shell::new(image.path.clone()).unwrap();
+        let missing = PathBuf::from("/missing/file");
+        assert_eq!(
+            fileopen(shell.dev.clone(), &missing, OpenMode::OCreate).err(),
+            Some(FsError::NotFound)
+        );
+        assert_eq!(
+            mkdir(shell.dev.clone(), &PathBuf::from("/missing/dir")),
+            Err(FsError::NotFound)
+        );
+        // the shell keeps working after the failed touch
+        shell.touch(missing.clone());
+        shell.touch(PathBuf::from("/file"));
+        assert!(find_inode(shell.dev.clone(), &missing).is_none());
+        assert!(find_inode(shell.dev.clone(), &PathBuf::from("/file")).is_some());
+    }
+
+    #[test]
+    fn test_ls_streams_entries() {
+        let image = TestImage::new("ls_streams");
+        let mut shell = super::Shell::new(image.path.clone()).unwrap();
+        shell.mkdir(PathBuf::from("/many"));
+        for i in 0..300 {
+            shell.touch(PathBuf::from(format!("/many/f{}", i)));
+        }
+        let mut out = vec![];
+        shell.ls_to(PathBuf::from("/many"), &mut out);
+        let out = String::from_utf8(out).unwrap();
+        let names = out
+            .lines()
+            .skip(1)
+            .map(|line| line.split_whitespace().next().unwrap())
+            .collect::<Vec<_>>();
+        let mut expected = vec![".".to_string(), "..".to_string()];
+        expected.extend((0..300).map(|i| format!("f{}", i)));
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn test_rm_dry_run() {
+        let image = TestImage::new("rm_dry_run");
+        let mut shell = super::Shell::new(image.path.clone()).unwrap();
+        shell.mkdir(PathBuf::from("/t"));
+        shell.mkdir(PathBuf::from("/t/sub"));
+        let path = PathBuf::from("/t/sub/f");
+        let file = fileopen(shell.dev.clone(), &path, OpenMode::OCreate).unwrap();
+        filewrite(&file, &[1; 2000]).unwrap();
+        fileclose(file);
+        shell.touch(PathBuf::from("/t/e"));
+        // a second link outside the tree keeps the inode
+        let (e, kept) = (PathBuf::from("/t/e"), PathBuf::from("/kept"));
+        filelink(shell.dev.clone(), &e, &kept).unwrap();
+        let dev = shell.dev.clone();
+        let inum = |path: &str| find_inode(dev.clone(), &PathBuf::from(path)).map(|ip| ip.0.inum);
+        let before = ["/t", "/t/sub", "/t/sub/f", "/t/e"].map(inum);
+
+        shell.dry_run = true;
+        let mut out = vec![];
+        shell.rm_to(PathBuf::from("/t"), false, &mut out);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "rm: /t: directory not empty\n"
+        );
+        let mut out = vec![];
+        shell.rm_to(PathBuf::from("/t"), true, &mut out);
+        let [t, sub, f, e] = before.map(Option::unwrap);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "rm: would remove /t/sub/f (inode {}, 4 blocks freed)\n\
+                 rm: would remove /t/sub (inode {}, 1 blocks freed)\n\
+                 rm: would remove /t/e (inode {} still linked)\n\
+                 rm: would remove /t (inode {}, 1 blocks freed)\n",
+                f, sub, e, t
+            )
+        );
+        assert_eq!(["/t", "/t/sub", "/t/sub/f", "/t/e"].map(inum), before);
+        assert_eq!(fsck(shell.dev.clone()), []);
+
+        shell.dry_run = false;
+        let mut out = vec![];
+        shell.rm_to(PathBuf::from("/t"), true, &mut out);
+        assert!(out.is_empty());
+        assert_eq!(["/t", "/t/sub", "/t/sub/f", "/t/e"].map(inum), [None; 4]);
+        assert_eq!(inum("/kept"), Some(e));
+        sync_all();
+        assert_eq!(fsck(shell.dev.clone()), []);
+    }
+
+    #[test]
+    fn test_cat_directory() {
+        let image = TestImage::new("cat_directory");
+        let mut shell = super::Shell::new(image.path.clone()).unwrap();
+        shell.mkdir(PathBuf::from("/dir"));
+        let mut out = vec![];
+        shell.cat_to(PathBuf::from("/dir"), &mut out);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "cat: /dir: Is a directory\n"
+        );
+    }
+
+    #[test]
+    fn test_redirect_cat() {
+        let image = TestImage::new("redirect_cat");
+        let mut shell = super::Shell::new(image.path.clone()).unwrap();
+        let a = fileopen(shell.dev.clone(), &PathBuf::from("/a"), OpenMode::OCreate).unwrap();
+        filewrite(&a, b"hello, redirect\n").unwrap();
+        fileclose(a);
+        // cat a > b
+        shell.redirect(PathBuf::from("/b")).unwrap();
+        shell.cat(PathBuf::from("/a"));
+        shell.restore_stdout();
+        let b = fileopen(shell.dev.clone(), &PathBuf::from("/b"), OpenMode::ORdonly).unwrap();
+        assert_eq!(file_read_to_end(&b).unwrap(), b"hello, redirect\n");
+        fileclose(b);
+        // the slot let go of b, so the last close freed its entry
+        let refs = lsof()
+            .iter()
+            .filter(|info| info.path.as_os_str() == "/b")
+            .map(|info| info.refs)
+            .collect::<Vec<_>>();
+        assert_eq!(refs, Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_redirect_reports() {
+        let image = TestImage::new("redirect_reports");
+        let mut shell = super::Shell::new(image.path.clone()).unwrap();
+        let a = fileopen(shell.dev.clone(), &PathBuf::from("/a"), OpenMode::OCreate).unwrap();
+        filewrite(&a, &[1; 3 * 512]).unwrap();
+        fileclose(a);
+        // hash a > out; frag a >> out ...
+        shell.redirect(PathBuf::from("/out")).unwrap();
+        shell.hash(PathBuf::from("/a"));
+        shell.stat(PathBuf::from("/a"));
+        shell.frag(PathBuf::from("/a"));
+        shell.frag_image();
+        shell.lsof();
+        shell.stats();
+        shell.restore_stdout();
+        let out = fileopen(shell.dev.clone(), &PathBuf::from("/out"), OpenMode::ORdonly).unwrap();
+        let text = String::from_utf8(file_read_to_end(&out).unwrap()).unwrap();
+        fileclose(out);
+        let lines = text.lines().collect::<Vec<_>>();
+        assert!(lines[0].ends_with("  /a"), "{}", text);
+        assert_eq!(lines[1], "  File: /a", "{}", text);
+        assert_eq!(lines[2], "  Type: File  Size: 1536", "{}", text);
+        assert!(lines[3].contains("  Generation: "), "{}", text);
+        assert!(lines[3].ends_with("  Links: 1"), "{}", text);
+        assert!(lines[4].starts_with("/a: 3 blocks in 1 runs"), "{}", text);
+        assert!(lines[5].contains(" files, "), "{}", text);
+        assert!(lines[6].starts_with("path "), "{}", text);
+        assert!(text.contains("\nlog transactions committed: "), "{}", text);
+        assert!(text.contains("\nbuffer misses: "), "{}", text);
+    }
+
+    #[test]
+    fn test_write_progress() {
+        let image = TestImage::new("write_progress");
+        let mut shell = super::Shell::new(image.path.clone()).unwrap();
+        let from = std::env::temp_dir().join(format!("fatpigeorz_progress_{}", std::process::id()));
+        std::fs::write(&from, vec![0x5a; 3000]).unwrap();
+        shell.touch(PathBuf::from("/copy"));
+        let mut out = vec![];
+        shell.write_with_progress(from.clone(), PathBuf::from("/copy"), &mut out);
+        std::fs::remove_file(&from).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let last = out.trim_end().rsplit('\r').next().unwrap();
+        assert!(last.starts_with("3000/3000 bytes (100%)"), "{:?}", out);
+        assert_eq!(out.matches('\r').count(), 3);
+    }
+
+    #[test]
+    fn test_truncated_image() {
+        let image = TestImage::new("truncated_image");
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&image.path)
+            .unwrap()
+            .set_len(100 * 512)
+            .unwrap();
+        let err = super::Shell::new(image.path.clone()).err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<FsError>(),
+            Some(&FsError::Truncated {
+                size: 100,
+                expected: 4096
+            })
+        );
+        assert_eq!(
+            err.to_string(),
+            "image truncated: 100 blocks, but the superblock claims 4096"
+        );
+    }
+
+    #[test]
+    fn test_selftest() {
+        let image = TestImage::new("selftest");
+        // an image already there is not formatted
+        let before = std::fs::read(&image.path).unwrap();
+        assert!(!crate::selftest::selftest(image.path.clone()));
+        assert!(std::fs::read(&image.path).unwrap() == before);
+        let scratch = image.path.with_extension("selftest");
+        assert!(crate::selftest::selftest(scratch.clone()));
+        assert!(!scratch.exists());
+    }
+
+    #[test]
+    fn test_xattr_commands() {
+        let image = TestImage::new("xattr_commands");
+        let mut shell = super::Shell::new(image.path.clone()).unwrap();
+        shell.touch(PathBuf::from("/f"));
+        shell.setxattr(PathBuf::from("/f"), "mime", "text/plain");
+        shell.setxattr(PathBuf::from("/f"), "tag", "red");
+        let get = |name: Option<&str>| {
+            let mut out = vec![];
+            shell.getxattr_to(PathBuf::from("/f"), name, &mut out);
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(get(Some("mime")), "text/plain\n");
+        assert_eq!(get(None), "mime\ntag\n");
+        assert_eq!(
+            get(Some("size")),
+            "getxattr: /f: no such file or directory\n"
+        );
+    }
+
+    #[test]
+    fn test_record_replay() {
+        let image = TestImage::new("record_replay");
+        let script = image.path.with_extension("script");
+        let data = image.path.with_extension("data");
+        let copy = image.path.with_extension("copy");
+        std::fs::write(&data, [7u8; 3000]).unwrap();
+        let lines = [
+            "mkdir /d".to_string(),
+            "touch /d/a".to_string(),
+            format!("write {} /d/a", data.display()),
+            "touch /d/b".to_string(),
+            "rm /d/b".to_string(),
+            "mv /d/a /d/c".to_string(),
+            "ls /d > /d/list".to_string(),
+        ];
+        let state = |shell: &super::Shell| {
+            sync_all();
+            assert_eq!(fsck(shell.dev.clone()), []);
+            let mut out = vec![];
+            shell.ls_to(PathBuf::from("/"), &mut out);
+            shell.ls_to(PathBuf::from("/d"), &mut out);
+            for path in ["/d/c", "/d/list"] {
+                let path = PathBuf::from(path);
+                let file = fileopen(shell.dev.clone(), &path, OpenMode::ORdonly).unwrap();
+                out.extend(file_read_to_end(&file).unwrap());
+                fileclose(file);
+            }
+            out
+        };
+        let mut shell = super::Shell::new(image.path.clone()).unwrap();
+        shell.record = Some(std::fs::File::create(&script).unwrap());
+        for line in &lines {
+            assert!(shell.run_line(line));
+        }
+        // an empty line is not recorded
+        assert!(shell.run_line("\n"));
+        assert!(!shell.run_line("exit"));
+        shell.record = None;
+        let recorded = std::fs::read_to_string(&script).unwrap();
+        assert_eq!(recorded, lines.join("\n") + "\nexit\n");
+        let before = state(&shell);
+
+        // the same commands on a fresh image, nothing after the exit runs
+        crate::mkfs::mkfs(copy.clone(), TEST_IMAGE_SIZE);
+        let mut replayed = super::Shell::new(copy.clone()).unwrap();
+        let more = recorded + "touch /late\n";
+        assert_eq!(replayed.replay(&more), lines.len() + 1);
+        assert_eq!(state(&replayed), before);
+        let late = find_inode(replayed.dev.clone(), &PathBuf::from("/late"));
+        assert!(late.is_none());
+        for path in [script, data, copy] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_malformed_commands() {
+        let image = TestImage::new("malformed_commands");
+        let mut shell = super::Shell::new(image.path.clone()).unwrap();
+        let host = image.path.with_extension("host");
+        std::fs::write(&host, b"from the host").unwrap();
+        let usage = |shell: &mut super::Shell, line: &str| {
+            let mut args = line.split_whitespace().collect::<Vec<_>>().into_iter();
+            let cmd = args.next().unwrap();
+            shell.exec(cmd, &mut args).err().map(|e| e.to_string())
+        };
+        assert_eq!(
+            usage(&mut shell, "cat"),
+            Some("usage: cat PATH".to_string())
+        );
+        assert_eq!(
+            usage(&mut shell, "write /only"),
+            Some("usage: write HOST_PATH PATH".to_string())
+        );
+        assert_eq!(
+            usage(&mut shell, "rm -r"),
+            Some("usage: rm [-r] PATH".to_string())
+        );
+        // a missing argument, or a path that is not there, and the session goes on
+        let missing = format!("{}.missing", host.display());
+        let bare = [
+            "cat", "cd", "hash", "stat", "mkdir", "mkfifo", "touch", "write", "rm", "rm -r",
+            "getxattr",
+        ];
+        let short = [
+            "setxattr /f",
+            "mv /a",
+            "reflink /a",
+            "symlink t",
+            "cat /nothing",
+        ];
+        let paths = [
+            format!("write {} /f", missing),
+            format!("write {} /nothing", host.display()),
+            format!("write {} /f", std::env::temp_dir().display()),
+        ];
+        shell.touch(PathBuf::from("/f"));
+        let lines = bare
+            .iter()
+            .chain(&short)
+            .copied()
+            .chain(paths.iter().map(String::as_str));
+        for line in lines {
+            assert!(shell.run_line(line), "{}", line);
+        }
+        assert!(shell.run_line(&format!("write {} /f", host.display())));
+        let mut out = vec![];
+        shell.cat_to(PathBuf::from("/f"), &mut out);
+        assert_eq!(out, b"from the host");
+        std::fs::remove_file(host).unwrap();
     }
 }
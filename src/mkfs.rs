@@ -2,11 +2,9 @@ use crate::fs::fs::*;
 use crate::fs::inode::*;
 use crate::fs::log::*;
 use crate::fs::superblock::*;
-use env_logger::{Builder, Target};
 use log::info;
 use std::{
     fs::{File, OpenOptions},
-    io::*,
     os::unix::prelude::FileExt,
     path::PathBuf,
 };
@@ -22,38 +20,45 @@ fn read_block(file: &mut File, block_id: u32, buf: &mut [u8]) {
 }
 
 // Disk layout:
-// [ boot block | sb block | log | inode blocks | free bit map | data blocks ]
+// [ boot block | sb block | log | inode blocks | free bit map | refcount map | xattr map | data blocks | backup sb block ]
 pub fn mkfs(path: PathBuf, size: u32) {
-    Builder::new()
-        .target(Target::Stdout)
-        .is_test(true)
-        .filter_level(log::LevelFilter::Info)
-        .init();
-
     let mut file = OpenOptions::new()
         .read(true)
         .write(true)
         .create(true)
         .open(path)
         .unwrap();
-    file.set_len(size as u64).unwrap();
-    let buf = vec![0; size as usize];
-    file.write_all(buf.as_ref()).unwrap();
+    let sb = layout(size);
+    // the superblock goes last, an image without its magic number was not
+    // finished and mounting it fails with NotFormatted. the copies of an
+    // earlier format go first, so a mkfs killed early does not leave them
+    let zero = [0; BLOCK_SIZE as usize];
+    write_block(&mut file, SB_BLOCK, &zero);
+    write_block(&mut file, sb.size - 1, &zero);
+    file.sync_data().unwrap();
+    write_metadata(&mut file, &sb);
+    file.sync_data().unwrap();
+    write_superblock(&mut file, &sb);
+}
 
+// the superblock of an image of size bytes, before anything is written
+pub fn layout(size: u32) -> SuperBlock {
     // size must be multiple of BLOCK_SIZE
     assert_eq!(size % BLOCK_SIZE, 0);
 
     // metadata
     let fs_size = size / BLOCK_SIZE;
-    let nbitmap = fs_size / (BLOCK_SIZE * 8);
+    let nbitmap = fs_size.div_ceil(BPB);
     let ninodeblocks = NINODES / IPB;
+    let nrefmap = fs_size.div_ceil(RPB);
+    let nxattrmap = NINODES.div_ceil(XPB);
     let nlog = LOGSIZE;
-    let nmeta = 2 + nlog + ninodeblocks + nbitmap;
+    let nmeta = 2 + nlog + ninodeblocks + nbitmap + nrefmap + nxattrmap;
 
     // superblock
     let mut sb = SuperBlock::new();
-    sb.size = fs_size as u32;
-    sb.nblocks = (fs_size - nmeta) as u32;
+    sb.size = fs_size;
+    sb.nblocks = fs_size - nmeta - 1;
     sb.ninodes = NINODES;
     sb.nlog = nlog;
     // 0 is reserved for root inode
@@ -61,11 +66,15 @@ pub fn mkfs(path: PathBuf, size: u32) {
     sb.logstart = 2;
     sb.inodestart = 2 + nlog;
     sb.bmapstart = 2 + nlog + ninodeblocks;
+    sb.refstart = 2 + nlog + ninodeblocks + nbitmap;
+    sb.xattrstart = 2 + nlog + ninodeblocks + nbitmap + nrefmap;
+    sb.feature_incompat = INCOMPAT_INLINE_DATA | INCOMPAT_DIRENT_FTYPE;
+    sb.feature_ro_compat = RO_COMPAT_XATTR | RO_COMPAT_REFLINK;
 
     // log the metadata
     info!(
-        "fs_size: {}, nbitmap: {}, ninodeblocks: {}, nlog: {}, nmeta: {}",
-        fs_size, nbitmap, ninodeblocks, nlog, nmeta
+        "fs_size: {}, nbitmap: {}, nrefmap: {}, nxattrmap: {}, ninodeblocks: {}, nlog: {}, nmeta: {}",
+        fs_size, nbitmap, nrefmap, nxattrmap, ninodeblocks, nlog, nmeta
     );
 
     // list  size
@@ -93,49 +102,80 @@ pub fn mkfs(path: PathBuf, size: u32) {
         2 + nlog + ninodeblocks + nbitmap - 1
     );
     info!(
-        "data blocks: {} - {}",
+        "refcount map: {} - {}",
         2 + nlog + ninodeblocks + nbitmap,
-        fs_size - 1
+        2 + nlog + ninodeblocks + nbitmap + nrefmap - 1
     );
+    info!(
+        "xattr map: {} - {}",
+        2 + nlog + ninodeblocks + nbitmap + nrefmap,
+        nmeta - 1
+    );
+    info!("data blocks: {} - {}", nmeta, fs_size - 2);
+    info!("backup super block: {}", fs_size - 1);
+    sb
+}
 
+fn write_superblock(file: &mut File, sb: &SuperBlock) {
     // serialize sb
     let mut buf = [0; 512];
+    let disk_sb = sb.to_le();
     unsafe {
         std::ptr::copy(
-            &sb as *const SuperBlock as *const u8,
+            &disk_sb as *const SuperBlock as *const u8,
             buf.as_mut_ptr(),
             std::mem::size_of::<SuperBlock>(),
         );
     }
-    info!("write superblock at block {}", 1);
-    write_block(&mut file, SB_BLOCK, &buf);
+    info!("write backup superblock at block {}", sb.size - 1);
+    write_block(file, sb.size - 1, &buf);
+    info!("write superblock at block {}", SB_BLOCK);
+    write_block(file, SB_BLOCK, &buf);
+}
+
+// everything but the superblock
+fn write_metadata(file: &mut File, sb: &SuperBlock) {
+    file.set_len((sb.size * BLOCK_SIZE) as u64).unwrap();
+    let buf = vec![0; (sb.size * BLOCK_SIZE) as usize];
+    file.write_all_at(buf.as_ref(), 0).unwrap();
 
     // the first free block that we can allocate
-    let mut freeblock = nmeta;
+    let mut freeblock = sb.data_start();
     let mut freeino = ROOTINO;
 
     // write root inode
-    let rootino = ialloc(&mut file, &sb, FileType::Dir, &mut freeino);
+    let rootino = ialloc(file, sb, FileType::Dir, &mut freeino);
     assert_eq!(rootino, ROOTINO);
 
-    let mut de = DirEntry::default();
-    de.inum = rootino;
+    let mut de = DirEntry {
+        inum: rootino,
+        ftype: FileType::Dir as u8,
+        ..Default::default()
+    };
     // de.name = ".".to_string();
     nameassign(&mut de.name, &".".to_string());
-    let buf = unsafe { std::mem::transmute::<DirEntry, [u8; std::mem::size_of::<DirEntry>()]>(de) };
-    iappend(&mut file, rootino, &sb, &buf, &mut freeblock);
+    let buf = unsafe {
+        std::mem::transmute::<DirEntry, [u8; std::mem::size_of::<DirEntry>()]>(de.to_le())
+    };
+    iappend(file, rootino, sb, &buf, &mut freeblock);
 
-    let mut de = DirEntry::default();
-    de.inum = rootino;
+    let mut de = DirEntry {
+        inum: rootino,
+        ftype: FileType::Dir as u8,
+        ..Default::default()
+    };
     nameassign(&mut de.name, &"..".to_string());
-    let buf = unsafe { std::mem::transmute::<DirEntry, [u8; std::mem::size_of::<DirEntry>()]>(de) };
-    iappend(&mut file, rootino, &sb, &buf, &mut freeblock);
+    let buf = unsafe {
+        std::mem::transmute::<DirEntry, [u8; std::mem::size_of::<DirEntry>()]>(de.to_le())
+    };
+    iappend(file, rootino, sb, &buf, &mut freeblock);
 
-    // fix size of root
-    let dinode = rinode(&mut file, &sb, rootino);
-    winode(&mut file, &sb, rootino, dinode);
+    // fix size of root, its "." and ".." are its two links
+    let mut dinode = rinode(file, sb, rootino);
+    dinode.nlink = DIR_NLINK;
+    winode(file, sb, rootino, dinode);
 
-    balloc(&mut file, &sb, freeblock);
+    balloc(file, sb, freeblock);
 }
 
 fn balloc(file: &mut File, sb: &SuperBlock, used: u32) {
@@ -147,16 +187,23 @@ fn balloc(file: &mut File, sb: &SuperBlock, used: u32) {
     }
     info!("balloc: write bitmap block at block {}", sb.bmapstart);
     write_block(file, sb.bmapstart, &buf);
+    // the backup superblock in the last block
+    let backup = sb.size - 1;
+    read_block(file, sb.bmapstart + backup / BPB, &mut buf);
+    buf[(backup % BPB) as usize / 8] |= 1 << (backup % 8);
+    write_block(file, sb.bmapstart + backup / BPB, &buf);
 }
 
 fn ialloc(file: &mut File, sb: &SuperBlock, filetype: FileType, freeinode: &mut u32) -> u32 {
     let inum = *freeinode;
     *freeinode += 1;
 
-    let mut dinode = DiskInode::default();
-    dinode.ftype = filetype as u16;
-    dinode.nlink = 1;
-    dinode.size = 0;
+    let dinode = DiskInode {
+        generation: 1,
+        ftype: filetype as u8,
+        nlink: 1,
+        ..Default::default()
+    };
     // write
     winode(file, sb, inum, dinode);
     inum
@@ -176,7 +223,7 @@ fn iappend(file: &mut File, inum: u32, sb: &SuperBlock, data: &[u8], freeblock:
     );
     while n > 0 {
         let fbn = off / BLOCK_SIZE;
-        assert!(fbn < MAXFILE as u32);
+        assert!(fbn < MAXFILE);
         // read block
         if fbn < NDIRECT {
             if dinode.addrs[fbn as usize] == 0 {
@@ -201,13 +248,15 @@ fn iappend(file: &mut File, inum: u32, sb: &SuperBlock, data: &[u8], freeblock:
                 )
             };
             read_block(file, dinode.addrs[NDIRECT as usize], &mut buf);
+            indirect = indirect.to_le();
             if indirect[fbn as usize - NDIRECT as usize] == 0 {
                 indirect[fbn as usize - NDIRECT as usize] = *freeblock;
                 *freeblock += 1;
                 // write indirect
+                let mut disk = indirect.to_le();
                 let buf = unsafe {
                     std::slice::from_raw_parts_mut(
-                        indirect.as_mut_ptr() as *mut u8,
+                        disk.as_mut_ptr() as *mut u8,
                         BLOCK_SIZE as usize,
                     )
                 };
@@ -216,7 +265,7 @@ fn iappend(file: &mut File, inum: u32, sb: &SuperBlock, data: &[u8], freeblock:
             dst_block = indirect[fbn as usize - NDIRECT as usize];
         }
         // write the data to block
-        let bytes = std::cmp::min(n as u32, (fbn + 1) * BLOCK_SIZE - off);
+        let bytes = std::cmp::min(n, (fbn + 1) * BLOCK_SIZE - off);
         // read dst block
         let mut buf = [0; BLOCK_SIZE as usize];
         info!("iappend: read block {} to write", dst_block);
@@ -248,7 +297,7 @@ fn rinode(file: &mut File, sb: &SuperBlock, inum: u32) -> DiskInode {
     // use transmute instead
     unsafe {
         let ptr = buf.as_ptr() as *const DiskInode;
-        return *ptr.add(inum as usize % IPB as usize);
+        (*ptr.add(inum as usize % IPB as usize)).to_le()
     }
 }
 
@@ -256,7 +305,7 @@ fn winode(file: &mut File, sb: &SuperBlock, inum: u32, dinode: DiskInode) {
     let mut buf = [0; BLOCK_SIZE as usize];
     unsafe {
         let ptr = buf.as_mut_ptr() as *mut DiskInode;
-        ptr.add(inum as usize % IPB as usize).write(dinode);
+        ptr.add(inum as usize % IPB as usize).write(dinode.to_le());
     }
     info!(
         "winode: write inode block at block {}",
@@ -279,4 +328,33 @@ mod test {
     fn test_mkfs() {
         mkfs("./test.img".into(), 512 * 512 * 8);
     }
+
+    #[test]
+    fn test_interrupted_mkfs() {
+        use crate::fs::{error::FsError, filedisk::FileDisk};
+        use std::sync::Arc;
+        let path =
+            std::env::temp_dir().join(format!("fatpigeorz_partial_{}.img", std::process::id()));
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        // killed just before the superblock
+        let sb = layout(512 * 512 * 8);
+        write_metadata(&mut file, &sb);
+        let disk = |file: &File| -> Arc<dyn BlockDevice> {
+            Arc::new(FileDisk::new(file.try_clone().unwrap()))
+        };
+        let mut mounted = SuperBlock::new();
+        assert_eq!(mounted.init(disk(&file)), Err(FsError::NotFormatted));
+        // the finished image mounts
+        write_superblock(&mut file, &sb);
+        let mut mounted = SuperBlock::new();
+        mounted.init(disk(&file)).unwrap();
+        assert_eq!(mounted, sb);
+        std::fs::remove_file(path).unwrap();
+    }
 }
@@ -0,0 +1,179 @@
+use crate::bench::mount;
+use crate::fs::{
+    buffer::{sync_all, wait_buffer_block},
+    file::{
+        file_read_to_end, fileclose, fileopen, filepwrite, fileunlink, filewrite, mkdir, OpenMode,
+    },
+    filedisk::lock_image,
+    fs::{BlockDevice, FileType, BLOCK_SIZE, BPB, IPB, ROOTINO},
+    fsck::fsck,
+    inode::{find_inode, DiskInode},
+    superblock::sb,
+};
+use crate::mkfs::mkfs;
+use std::{fs::OpenOptions, panic::AssertUnwindSafe, path::PathBuf, sync::Arc};
+
+const SELFTEST_IMAGE_SIZE: u32 = 512 * 512 * 8;
+// past the direct blocks, so the indirect block is used too
+const BIG_FILE_SIZE: usize = 20 * BLOCK_SIZE as usize;
+
+type Step = fn(Arc<dyn BlockDevice>);
+
+const STEPS: [(&str, Step); 6] = [
+    ("mkdir", step_mkdir),
+    ("write and read back", step_write_read),
+    ("overwrite", step_overwrite),
+    ("small file", step_small_file),
+    ("unlink", step_unlink),
+    ("fsck", step_fsck),
+];
+
+// the blocks and inodes in use, like statfs reports them
+fn usage(dev: Arc<dyn BlockDevice>) -> (u32, u32) {
+    let (size, bmapstart, inodestart, ninodes) = {
+        let sb = sb();
+        (sb.size, sb.bmapstart, sb.inodestart, sb.ninodes)
+    };
+    let blocks = (0..size)
+        .filter(|&b| {
+            wait_buffer_block(bmapstart + b / BPB, dev.clone())
+                .read()
+                .unwrap()
+                .read((b % BPB) as usize / 8, |byte: &u8| {
+                    byte & (1 << (b % 8)) != 0
+                })
+        })
+        .count() as u32;
+    let inodes = (ROOTINO..ninodes)
+        .filter(|&inum| {
+            let off = inum % IPB * std::mem::size_of::<DiskInode>() as u32;
+            wait_buffer_block(inodestart + inum / IPB, dev.clone())
+                .read()
+                .unwrap()
+                .read(off as usize, |dinode: &DiskInode| {
+                    dinode.ftype != FileType::Free as u8
+                })
+        })
+        .count() as u32;
+    (blocks, inodes)
+}
+
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+fn read_all(dev: Arc<dyn BlockDevice>, path: &str) -> Vec<u8> {
+    let file = fileopen(dev, &PathBuf::from(path), OpenMode::ORdonly).unwrap();
+    let data = file_read_to_end(&file).unwrap();
+    fileclose(file);
+    data
+}
+
+fn step_mkdir(dev: Arc<dyn BlockDevice>) {
+    mkdir(dev.clone(), &PathBuf::from("/selftest")).unwrap();
+    mkdir(dev.clone(), &PathBuf::from("/selftest/dir")).unwrap();
+    assert!(find_inode(dev.clone(), &PathBuf::from("/selftest/dir")).is_some());
+}
+
+fn step_write_read(dev: Arc<dyn BlockDevice>) {
+    let path = PathBuf::from("/selftest/dir/big");
+    let data = pattern(BIG_FILE_SIZE, 0);
+    let file = fileopen(dev.clone(), &path, OpenMode::OCreate).unwrap();
+    assert_eq!(filewrite(&file, &data), Ok(data.len()));
+    fileclose(file);
+    assert!(read_all(dev, "/selftest/dir/big") == data);
+}
+
+fn step_overwrite(dev: Arc<dyn BlockDevice>) {
+    let path = PathBuf::from("/selftest/dir/big");
+    let mut data = pattern(BIG_FILE_SIZE, 0);
+    let patch = pattern(3 * BLOCK_SIZE as usize, 0x5a);
+    // across block boundaries, in the direct and the indirect blocks
+    for off in [100, 11 * BLOCK_SIZE as usize + 7] {
+        let file = fileopen(dev.clone(), &path, OpenMode::ORdwr).unwrap();
+        assert_eq!(filepwrite(&file, &patch, off as u64), Ok(patch.len()));
+        fileclose(file);
+        data[off..off + patch.len()].copy_from_slice(&patch);
+    }
+    assert!(read_all(dev, "/selftest/dir/big") == data);
+}
+
+fn step_small_file(dev: Arc<dyn BlockDevice>) {
+    let path = PathBuf::from("/selftest/small");
+    let file = fileopen(dev.clone(), &path, OpenMode::OCreate).unwrap();
+    filewrite(&file, b"hello").unwrap();
+    fileclose(file);
+    assert_eq!(read_all(dev, "/selftest/small"), b"hello");
+}
+
+fn step_unlink(dev: Arc<dyn BlockDevice>) {
+    for path in [
+        "/selftest/small",
+        "/selftest/dir/big",
+        "/selftest/dir",
+        "/selftest",
+    ] {
+        fileunlink(dev.clone(), &PathBuf::from(path)).unwrap();
+        assert!(find_inode(dev.clone(), &PathBuf::from(path)).is_none());
+    }
+}
+
+fn step_fsck(dev: Arc<dyn BlockDevice>) {
+    sync_all();
+    assert_eq!(fsck(dev), vec![]);
+}
+
+// format a scratch image at path, run every step on it and remove it.
+// a file already at path may be a real image, it is refused and left alone.
+// returns whether all the steps passed
+pub fn selftest(path: PathBuf) -> bool {
+    if let Err(e) = OpenOptions::new().write(true).create_new(true).open(&path) {
+        println!("selftest: {}: {}", path.display(), e);
+        return false;
+    }
+    // locked for the run, so nothing mounts the scratch image meanwhile
+    let _lock = match lock_image(&path) {
+        Ok(lock) => lock,
+        Err(e) => {
+            println!("selftest: {}", e);
+            let _ = std::fs::remove_file(&path);
+            return false;
+        }
+    };
+    mkfs(path.clone(), SELFTEST_IMAGE_SIZE);
+    let dev = mount(path.clone());
+    let baseline = usage(dev.clone());
+    let mut failed = 0;
+    for (name, step) in STEPS {
+        let ok = std::panic::catch_unwind(AssertUnwindSafe(|| step(dev.clone()))).is_ok();
+        println!(
+            "selftest: {:<24} {}",
+            name,
+            if ok { "ok" } else { "FAILED" }
+        );
+        if !ok {
+            failed += 1;
+        }
+    }
+    // everything made was removed, so the usage is back where it started.
+    // an open reclaims the table entries of the files closed above,
+    // which still hold their inodes
+    fileclose(fileopen(dev.clone(), &PathBuf::from("/"), OpenMode::ORdonly).unwrap());
+    let after = usage(dev.clone());
+    println!(
+        "selftest: {:<24} {}",
+        "usage back to baseline",
+        if after == baseline { "ok" } else { "FAILED" }
+    );
+    if after != baseline {
+        failed += 1;
+    }
+    println!(
+        "selftest: {} passed, {} failed",
+        STEPS.len() + 1 - failed,
+        failed
+    );
+    sync_all();
+    let _ = std::fs::remove_file(&path);
+    failed == 0
+}
@@ -0,0 +1,580 @@
+// the tree in an image as a POSIX ustar archive, so standard tools can read it,
+// and an archive unpacked into an image
+use std::{
+    io::{Error, ErrorKind, Read, Result, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use crate::fs::{
+    error::FsError,
+    file::{
+        fileclose, filelink, fileopen, fileopen_direct, fileread, filewrite_all, mkdir_all, mkfifo,
+        mknod, readdir, symlink, OpenFile, OpenMode, DIRECT_MIN,
+    },
+    fs::{BlockDevice, FileType, BLOCK_SIZE},
+    inode::{canonicalize, device_number, entry_name, get_inode, readlink},
+};
+
+const RECORD: usize = 512;
+// the file system keeps no permissions or times, so every entry gets these
+const DIR_MODE: u32 = 0o755;
+const FILE_MODE: u32 = 0o644;
+const LINK_MODE: u32 = 0o777;
+
+// one ustar header
+struct Header {
+    name: String,
+    mode: u32,
+    size: u32,
+    typeflag: u8,
+    linkname: String,
+    major: u16,
+    minor: u16,
+}
+
+impl Header {
+    fn new(name: String, mode: u32, typeflag: u8) -> Self {
+        Self {
+            name,
+            mode,
+            size: 0,
+            typeflag,
+            linkname: String::new(),
+            major: 0,
+            minor: 0,
+        }
+    }
+
+    fn encode(&self) -> Result<[u8; RECORD]> {
+        let mut block = [0u8; RECORD];
+        // a name too long for the name field goes in two, split at a '/'
+        let (prefix, name) = split_name(&self.name).ok_or_else(|| too_long(&self.name))?;
+        if self.linkname.len() > 100 {
+            return Err(too_long(&self.linkname));
+        }
+        block[..name.len()].copy_from_slice(name.as_bytes());
+        octal(&mut block[100..108], self.mode);
+        octal(&mut block[108..116], 0);
+        octal(&mut block[116..124], 0);
+        octal(&mut block[124..136], self.size);
+        octal(&mut block[136..148], 0);
+        block[156] = self.typeflag;
+        block[157..157 + self.linkname.len()].copy_from_slice(self.linkname.as_bytes());
+        block[257..263].copy_from_slice(b"ustar\0");
+        block[263..265].copy_from_slice(b"00");
+        octal(&mut block[329..337], self.major as u32);
+        octal(&mut block[337..345], self.minor as u32);
+        block[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+        // the checksum is taken with its own field as spaces
+        block[148..156].fill(b' ');
+        let sum = block.iter().map(|b| *b as u32).sum::<u32>();
+        octal(&mut block[148..155], sum);
+        Ok(block)
+    }
+
+    // the header in block, the name still without a long name that came before
+    fn decode(block: &[u8; RECORD]) -> Result<Self> {
+        let sum = block[..148]
+            .iter()
+            .chain([b' '; 8].iter())
+            .chain(block[156..].iter())
+            .map(|b| *b as u32)
+            .sum::<u32>();
+        if parse_octal(&block[148..156])? != sum {
+            return Err(bad_archive("header checksum mismatch"));
+        }
+        let mut name = field(&block[..100]);
+        // the prefix field is only there in ustar, older archives have other data in it
+        if &block[257..262] == b"ustar" && block[345] != 0 {
+            name = format!("{}/{}", field(&block[345..500]), name);
+        }
+        Ok(Self {
+            name,
+            mode: parse_octal(&block[100..108])?,
+            size: parse_octal(&block[124..136])?,
+            typeflag: block[156],
+            linkname: field(&block[157..257]),
+            major: parse_octal(&block[329..337])? as u16,
+            minor: parse_octal(&block[337..345])? as u16,
+        })
+    }
+}
+
+// zero padded octal digits and a NUL, filling the field
+fn octal(field: &mut [u8], n: u32) {
+    let digits = format!("{:0width$o}", n, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+// (prefix, name) for the two name fields of 155 and 100 bytes
+fn split_name(path: &str) -> Option<(&str, &str)> {
+    if path.len() <= 100 {
+        return Some(("", path));
+    }
+    path.match_indices('/')
+        .map(|(i, _)| (&path[..i], &path[i + 1..]))
+        .find(|(prefix, name)| prefix.len() <= 155 && name.len() <= 100 && !name.is_empty())
+}
+
+// a NUL terminated string field, or the whole field if it is full
+fn field(bytes: &[u8]) -> String {
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
+
+// an octal number padded with NULs or spaces, an empty field is 0
+fn parse_octal(field: &[u8]) -> Result<u32> {
+    // the base-256 of GNU tar is only used for numbers too big for a u32
+    if field[0] & 0x80 != 0 {
+        return Err(bad_archive("number too large"));
+    }
+    let digits = String::from_utf8_lossy(field);
+    let digits = digits.trim_matches(|c: char| c == '\0' || c == ' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u32::from_str_radix(digits, 8).map_err(|_| bad_archive("bad number in header"))
+}
+
+fn bad_archive(what: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("bad tar archive: {}", what))
+}
+
+fn too_long(name: &str) -> Error {
+    Error::other(format!("{}: name too long for a tar header", name))
+}
+
+// write the tree under dir to out, named relative to dir.
+// parents come before their children, and the archive ends with the two
+// zero records
+pub fn export(dev: Arc<dyn BlockDevice>, dir: &Path, out: &mut dyn Write) -> Result<()> {
+    export_dir(dev, dir, "", out)?;
+    out.write_all(&[0u8; 2 * RECORD])?;
+    out.flush()
+}
+
+fn export_dir(
+    dev: Arc<dyn BlockDevice>,
+    dir: &Path,
+    prefix: &str,
+    out: &mut dyn Write,
+) -> Result<()> {
+    // the entries are read first, so only one directory is open at a time
+    let fd = fileopen(dev.clone(), dir, OpenMode::ODirectory).map_err(Error::other)?;
+    let entries = readdir(&fd)
+        .filter(|entry| !matches!(entry_name(entry).as_str(), "." | ".."))
+        .collect::<Vec<_>>();
+    fileclose(fd);
+    for entry in entries {
+        let name = entry_name(&entry);
+        let path = dir.join(&name);
+        let name = format!("{}{}", prefix, name);
+        let mut ip = get_inode(dev.clone(), entry.inum);
+        let dinode = ip.read_disk_inode(|diskinode| *diskinode);
+        match FileType::from_u8(dinode.ftype) {
+            Some(FileType::Dir) => {
+                let name = format!("{}/", name);
+                out.write_all(&Header::new(name.clone(), DIR_MODE, b'5').encode()?)?;
+                export_dir(dev.clone(), &path, &name, out)?;
+            }
+            Some(FileType::File) => {
+                let mut header = Header::new(name, FILE_MODE, b'0');
+                header.size = dinode.size;
+                out.write_all(&header.encode()?)?;
+                export_file(dev.clone(), &path, dinode.size, out)?;
+            }
+            Some(FileType::Symlink) => {
+                let mut header = Header::new(name, LINK_MODE, b'2');
+                header.linkname = readlink(&mut ip).map_err(Error::other)?;
+                out.write_all(&header.encode()?)?;
+            }
+            Some(FileType::Fifo) => {
+                out.write_all(&Header::new(name, FILE_MODE, b'6').encode()?)?;
+            }
+            Some(FileType::Device) => {
+                let mut header = Header::new(name, FILE_MODE, b'3');
+                (header.major, header.minor) = device_number(&dinode);
+                out.write_all(&header.encode()?)?;
+            }
+            Some(FileType::Free) | None => {
+                log::warn!(
+                    "export: {}: inode {} is free, skipped",
+                    path.display(),
+                    entry.inum
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+// the size bytes of the file at path a block at a time, padded to a whole record
+fn export_file(
+    dev: Arc<dyn BlockDevice>,
+    path: &Path,
+    size: u32,
+    out: &mut dyn Write,
+) -> Result<()> {
+    // a large file is read around the buffer cache, so it does not evict the working set
+    let open = if size as u64 >= DIRECT_MIN {
+        fileopen_direct
+    } else {
+        fileopen
+    };
+    let fd = open(dev, path, OpenMode::ORdonly).map_err(Error::other)?;
+    let mut buf = [0u8; BLOCK_SIZE as usize];
+    let mut done = 0;
+    let ret = loop {
+        let n = match fileread(&fd, &mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(n) => n.min((size - done) as usize),
+            Err(e) => break Err(Error::other(e)),
+        };
+        if let Err(e) = out.write_all(&buf[..n]) {
+            break Err(e);
+        }
+        done += n as u32;
+    };
+    fileclose(fd);
+    ret?;
+    if done != size {
+        return Err(Error::other(format!(
+            "{}: file shrank while exported",
+            path.display()
+        )));
+    }
+    let pad = (RECORD - size as usize % RECORD) % RECORD;
+    out.write_all(&vec![0u8; pad])
+}
+
+// read the entries of archive into the tree under dir, which must exist.
+// the parents of an entry are created when the archive has none for them or
+// lists them later, a file already there is replaced. returns the number of
+// entries created
+pub fn import(dev: Arc<dyn BlockDevice>, dir: &Path, archive: &mut dyn Read) -> Result<usize> {
+    let mut count = 0;
+    // a GNU long name or link name, or a pax header, for the next entry
+    let mut long_name = None;
+    let mut long_link = None;
+    loop {
+        let mut block = [0u8; RECORD];
+        archive.read_exact(&mut block)?;
+        // the end of the archive, a second zero record follows
+        if block.iter().all(|b| *b == 0) {
+            break;
+        }
+        let mut header = Header::decode(&block)?;
+        let padded = header.size as usize + (RECORD - header.size as usize % RECORD) % RECORD;
+        match header.typeflag {
+            b'L' | b'K' | b'x' => {
+                let mut data = vec![0u8; padded];
+                archive.read_exact(&mut data)?;
+                data.truncate(header.size as usize);
+                match header.typeflag {
+                    b'L' => long_name = Some(field(&data)),
+                    b'K' => long_link = Some(field(&data)),
+                    _ => {
+                        let (path, linkpath) = pax_paths(&data)?;
+                        long_name = path.or(long_name);
+                        long_link = linkpath.or(long_link);
+                    }
+                }
+                continue;
+            }
+            _ => {}
+        }
+        header.name = long_name.take().unwrap_or(header.name);
+        header.linkname = long_link.take().unwrap_or(header.linkname);
+        // a name can not leave dir, ".." stops at its top
+        let name = canonicalize(Path::new(&header.name))
+            .map_err(|e| Error::other(format!("{}: {}", header.name, e)))?;
+        let path = dir.join(name.strip_prefix("/").unwrap());
+        if path == dir {
+            skip(archive, padded)?;
+            continue;
+        }
+        let created = import_entry(dev.clone(), dir, &path, &header, archive)
+            .map_err(|e| Error::other(format!("{}: {}", header.name, e)))?;
+        let data = if created && is_file(header.typeflag) {
+            header.size as usize
+        } else {
+            0
+        };
+        skip(archive, padded - data)?;
+        count += created as usize;
+    }
+    Ok(count)
+}
+
+fn is_file(typeflag: u8) -> bool {
+    matches!(typeflag, b'0' | 0 | b'7')
+}
+
+// create one entry at path, the data of a file is read from archive.
+// false if the entry has a type the file system has no inode for
+fn import_entry(
+    dev: Arc<dyn BlockDevice>,
+    dir: &Path,
+    path: &Path,
+    header: &Header,
+    archive: &mut dyn Read,
+) -> Result<bool> {
+    let parent = path.parent().unwrap();
+    if header.typeflag != b'5' {
+        mkdir_all(dev.clone(), parent).map_err(Error::other)?;
+    }
+    let path = path.to_path_buf();
+    match header.typeflag {
+        b'5' => mkdir_all(dev, &path).map_err(Error::other)?,
+        typeflag if is_file(typeflag) => {
+            let file = match fileopen(dev.clone(), &path, OpenMode::OCreate) {
+                Err(FsError::AlreadyExists) => fileopen(dev, &path, OpenMode::OTrunc),
+                file => file,
+            }
+            .map_err(Error::other)?;
+            let ret = import_data(&file, header.size as usize, archive);
+            fileclose(file);
+            ret?;
+        }
+        b'2' => symlink(dev, &header.linkname, &path).map_err(Error::other)?,
+        b'1' => {
+            let target = canonicalize(Path::new(&header.linkname)).map_err(Error::other)?;
+            let target = dir.join(target.strip_prefix("/").unwrap());
+            // tar links a name given twice to its first copy
+            if target != path {
+                filelink(dev, &target, &path).map_err(Error::other)?
+            }
+        }
+        b'3' | b'4' => mknod(dev, &path, header.major, header.minor).map_err(Error::other)?,
+        b'6' => mkfifo(dev, &path).map_err(Error::other)?,
+        typeflag => {
+            log::warn!(
+                "import: {}: entry type {:?} skipped",
+                path.display(),
+                typeflag as char
+            );
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// copy size bytes from archive into file a block at a time
+fn import_data(file: &OpenFile, size: usize, archive: &mut dyn Read) -> Result<()> {
+    let mut buf = [0u8; BLOCK_SIZE as usize];
+    let mut left = size;
+    while left > 0 {
+        let n = left.min(buf.len());
+        archive.read_exact(&mut buf[..n])?;
+        filewrite_all(file, &buf[..n]).map_err(Error::other)?;
+        left -= n;
+    }
+    Ok(())
+}
+
+fn skip(archive: &mut dyn Read, n: usize) -> Result<()> {
+    let skipped = std::io::copy(&mut archive.take(n as u64), &mut std::io::sink())?;
+    if skipped != n as u64 {
+        return Err(Error::from(ErrorKind::UnexpectedEof));
+    }
+    Ok(())
+}
+
+// the path and linkpath records of a pax extended header,
+// each record is "<length> <key>=<value>\n"
+fn pax_paths(mut data: &[u8]) -> Result<(Option<String>, Option<String>)> {
+    let (mut path, mut linkpath) = (None, None);
+    while !data.is_empty() {
+        let space = data
+            .iter()
+            .position(|b| *b == b' ')
+            .ok_or_else(|| bad_archive("bad pax record"))?;
+        let len = std::str::from_utf8(&data[..space])
+            .ok()
+            .and_then(|len| len.parse::<usize>().ok())
+            .filter(|len| *len > space && *len <= data.len())
+            .ok_or_else(|| bad_archive("bad pax record"))?;
+        let record = String::from_utf8_lossy(&data[space + 1..len - 1]).into_owned();
+        match record.split_once('=') {
+            Some(("path", value)) => path = Some(value.to_string()),
+            Some(("linkpath", value)) => linkpath = Some(value.to_string()),
+            _ => {}
+        }
+        data = &data[len..];
+    }
+    Ok((path, linkpath))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        path::{Path, PathBuf},
+        sync::Arc,
+    };
+
+    use super::{export, import, Header, RECORD};
+    use crate::fs::{
+        buffer::sync_all,
+        file::{
+            file_read_to_end, fileclose, fileopen, filewrite, mkdir, mkfifo, readdir, symlink,
+            OpenMode,
+        },
+        fs::{BlockDevice, FileType, BLOCK_SIZE},
+        inode::{entry_name, get_inode, readlink, resolve_nofollow},
+        testutil::TestImage,
+    };
+
+    // every path under dir with its type, parents first
+    fn tree(dev: Arc<dyn BlockDevice>, dir: &Path) -> Vec<(String, FileType)> {
+        let fd = fileopen(dev.clone(), dir, OpenMode::ODirectory).unwrap();
+        let mut entries = readdir(&fd)
+            .filter(|entry| !matches!(entry_name(entry).as_str(), "." | ".."))
+            .collect::<Vec<_>>();
+        fileclose(fd);
+        entries.sort_by_key(entry_name);
+        let mut paths = vec![];
+        for entry in entries {
+            let path = dir.join(entry_name(&entry));
+            let ftype = get_inode(dev.clone(), entry.inum).read_disk_inode(|d| d.ftype);
+            let ftype = FileType::from_u8(ftype).unwrap();
+            paths.push((path.to_str().unwrap().to_string(), ftype));
+            if ftype == FileType::Dir {
+                paths.extend(tree(dev.clone(), &path));
+            }
+        }
+        paths
+    }
+
+    fn read(dev: Arc<dyn BlockDevice>, path: &str) -> Vec<u8> {
+        let fd = fileopen(dev, &PathBuf::from(path), OpenMode::ORdonly).unwrap();
+        let data = file_read_to_end(&fd).unwrap();
+        fileclose(fd);
+        data
+    }
+
+    // the names in an archive, in the order of their headers
+    fn names(archive: &[u8]) -> Vec<String> {
+        let mut names = vec![];
+        let mut off = 0;
+        while archive[off..off + RECORD].iter().any(|b| *b != 0) {
+            let header = Header::decode(archive[off..off + RECORD].try_into().unwrap()).unwrap();
+            names.push(header.name);
+            off += RECORD + (header.size as usize).div_ceil(RECORD) * RECORD;
+        }
+        names
+    }
+
+    #[test]
+    fn test_export() {
+        let image = TestImage::new("tar_export");
+        let dev = image.mount();
+        mkdir(dev.clone(), &PathBuf::from("/d")).unwrap();
+        mkdir(dev.clone(), &PathBuf::from("/d/e")).unwrap();
+        let big = (0..3 * BLOCK_SIZE + 100)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let files = [
+            ("/small", b"hello, tar\n".to_vec()),
+            ("/empty", vec![]),
+            ("/d/big", big),
+            ("/d/e/deep", b"deep\n".to_vec()),
+        ];
+        for (path, data) in files.iter() {
+            let fd = fileopen(dev.clone(), &PathBuf::from(path), OpenMode::OCreate).unwrap();
+            filewrite(&fd, data).unwrap();
+            fileclose(fd);
+        }
+        symlink(dev.clone(), "d/e/deep", &PathBuf::from("/link")).unwrap();
+        mkfifo(dev.clone(), &PathBuf::from("/fifo")).unwrap();
+        sync_all();
+
+        let mut archive = vec![];
+        export(dev.clone(), &PathBuf::from("/"), &mut archive).unwrap();
+        assert_eq!(archive.len() % 512, 0);
+        assert!(archive.ends_with(&[0u8; 1024]));
+
+        // the archive GNU tar unpacked to this tree when it was checked in
+        assert!(archive == include_bytes!("../testdata/export.tar"));
+    }
+
+    #[test]
+    fn test_export_long_names() {
+        let image = TestImage::new("tar_long_names");
+        let dev = image.mount();
+        // deeper than the 100 bytes of the name field
+        let mut path = PathBuf::from("/");
+        for i in 0..10 {
+            path.push(format!("directory_{:02}", i));
+            mkdir(dev.clone(), &path).unwrap();
+        }
+        path.push("file");
+        let fd = fileopen(dev.clone(), &path, OpenMode::OCreate).unwrap();
+        filewrite(&fd, b"far down").unwrap();
+        fileclose(fd);
+
+        let mut archive = vec![];
+        export(dev.clone(), &PathBuf::from("/directory_00"), &mut archive).unwrap();
+        let names = names(&archive);
+        let last = path.strip_prefix("/directory_00").unwrap();
+        assert!(last.as_os_str().len() > 100);
+        assert_eq!(names.last().map(String::as_str), last.to_str());
+        assert_eq!(names.len(), 10);
+    }
+
+    #[test]
+    fn test_import() {
+        let image = TestImage::new("tar_import");
+        let dev = image.mount();
+        let big = (0..5 * BLOCK_SIZE + 17)
+            .map(|i| (i % 253) as u8)
+            .collect::<Vec<_>>();
+        // GNU tar of a tree on the host: the files, an empty directory, a
+        // symlink to d/big, and hard and small as two links to one file
+        let archive = include_bytes!("../testdata/import.tar");
+        let count = import(dev.clone(), Path::new("/"), &mut &archive[..]);
+        assert_eq!(count.unwrap(), 8);
+        sync_all();
+        let dev = image.mount();
+        assert_eq!(
+            tree(dev.clone(), Path::new("/")),
+            [
+                ("/d".to_string(), FileType::Dir),
+                ("/d/big".to_string(), FileType::File),
+                ("/d/e".to_string(), FileType::Dir),
+                ("/d/e/none".to_string(), FileType::File),
+                ("/empty".to_string(), FileType::Dir),
+                ("/hard".to_string(), FileType::File),
+                ("/link".to_string(), FileType::Symlink),
+                ("/small".to_string(), FileType::File),
+            ]
+        );
+        assert_eq!(read(dev.clone(), "/small"), b"hello, import\n");
+        assert_eq!(read(dev.clone(), "/hard"), b"hello, import\n");
+        let inum = |path: &str| {
+            resolve_nofollow(dev.clone(), Path::new(path))
+                .unwrap()
+                .0
+                .inum
+        };
+        assert_eq!(inum("/hard"), inum("/small"));
+        assert_eq!(read(dev.clone(), "/d/big"), big);
+        assert_eq!(read(dev.clone(), "/d/e/none"), b"");
+        let mut link = resolve_nofollow(dev.clone(), Path::new("/link")).unwrap();
+        assert_eq!(readlink(&mut link).unwrap(), "d/big");
+
+        // children named before their parents, or without them, and a name too
+        // long for the header, which GNU tar puts in an entry of its own. GNU
+        // tar of d/e/none, the deep file and d on the same tree
+        let long = ["long_directory_name"; 6].join("/");
+        let deep = format!("{}/deep", long);
+        let archive = include_bytes!("../testdata/import_order.tar");
+        mkdir(dev.clone(), Path::new("/sub")).unwrap();
+        let count = import(dev.clone(), Path::new("/sub"), &mut &archive[..]);
+        assert_eq!(count.unwrap(), 6);
+        assert_eq!(read(dev.clone(), &format!("/sub/{}", deep)), b"deep\n");
+        assert_eq!(read(dev.clone(), "/sub/d/big"), big);
+        assert_eq!(tree(dev.clone(), Path::new("/sub")).len(), 11);
+    }
+}